@@ -0,0 +1,24 @@
+// Tables partitioned by market and time keep a market's rows of one day, by their timestamp in
+// microseconds, in one partition
+pub const BUCKET: i64 = 24 * 60 * 60 * 1_000_000;
+
+pub fn bucket(timestamp: i64) -> i64 {
+    timestamp.div_euclid(BUCKET)
+}
+// The buckets from the one of `from` to the one of `to`, oldest first
+pub fn buckets(from: i64, to: i64) -> Vec<i64> {
+    (bucket(from)..=bucket(to)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_day_of_timestamps_shares_a_bucket() {
+        assert_eq!(bucket(0), bucket(BUCKET - 1));
+        assert_eq!(bucket(BUCKET), 1);
+        assert_eq!(buckets(BUCKET - 1, 2 * BUCKET + 5), vec![0, 1, 2]);
+        assert!(buckets(BUCKET, 0).is_empty());
+    }
+}
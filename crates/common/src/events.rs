@@ -0,0 +1,316 @@
+use std::{ collections::HashMap, fmt };
+
+use serde::{ de::DeserializeOwned, Deserialize, Serialize };
+use serde_json::value::RawValue;
+
+use crate::{ Id, OrderId, OrderSide, OrderStatus, Price, Quantity, Symbol, TradeId };
+
+// Bump whenever an event below changes shape, consumers reject anything else.
+pub const SCHEMA_VERSION: u16 = 1;
+
+pub const TRADE: &str = "trade";
+pub const ORDER_UPDATE: &str = "order_update";
+pub const DEPTH: &str = "depth";
+pub const TICKER: &str = "ticker";
+pub const BALANCE: &str = "balance";
+
+pub trait Event: Serialize + DeserializeOwned {
+    const TOPIC: &'static str;
+    // Second half of the channel name, the symbol for market events and the user id for balances
+    fn key(&self) -> String;
+}
+
+pub fn topic<E: Event>(key: &str) -> String {
+    format!("{}:{}", E::TOPIC, key)
+}
+pub fn topic_key(channel: &str) -> Option<&str> {
+    channel.split_once(':').map(|(_, key)| key)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Envelope<T> {
+    pub version: u16,
+    pub data: T,
+}
+
+#[derive(Debug)]
+pub enum EventError {
+    Malformed(serde_json::Error),
+    UnsupportedVersion(u16),
+}
+impl fmt::Display for EventError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EventError::Malformed(err) => write!(f, "Malformed event: {}", err),
+            EventError::UnsupportedVersion(version) =>
+                write!(f, "Unsupported event schema version {}, expected {}", version, SCHEMA_VERSION),
+        }
+    }
+}
+
+// Returns the channel to publish on and the serialized payload
+pub fn encode<E: Event>(event: &E) -> (String, String) {
+    let channel = topic::<E>(&event.key());
+    let envelope = Envelope {
+        version: SCHEMA_VERSION,
+        data: event,
+    };
+    let payload = serde_json::to_string(&envelope).expect("Events always serialize");
+    (channel, payload)
+}
+pub fn decode<E: Event>(payload: &str) -> Result<E, EventError> {
+    let envelope: Envelope<Box<RawValue>> = serde_json
+        ::from_str(payload)
+        .map_err(EventError::Malformed)?;
+    if envelope.version != SCHEMA_VERSION {
+        return Err(EventError::UnsupportedVersion(envelope.version));
+    }
+    serde_json::from_str(envelope.data.get()).map_err(EventError::Malformed)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Trade {
+    pub id: TradeId,
+    pub symbol: Symbol,
+    pub price: Price,
+    pub quantity: Quantity,
+    pub quote_quantity: Quantity,
+    pub is_buyer_maker: bool,
+    pub timestamp: u64,
+}
+impl Event for Trade {
+    const TOPIC: &'static str = TRADE;
+    fn key(&self) -> String {
+        self.symbol.clone()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OrderUpdate {
+    pub order_id: OrderId,
+    pub client_order_id: OrderId,
+    pub trade_id: TradeId,
+    pub user_id: Id,
+    pub trade_timestamp: u64,
+    pub order_side: OrderSide,
+    pub order_status: OrderStatus,
+    pub symbol: Symbol,
+    pub price: Price,
+    pub executed_quantity: Quantity,
+    pub executed_quote_quantity: Quantity,
+}
+impl Event for OrderUpdate {
+    const TOPIC: &'static str = ORDER_UPDATE;
+    fn key(&self) -> String {
+        self.symbol.clone()
+    }
+}
+
+// Levels are sorted best price first
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Depth {
+    pub symbol: Symbol,
+    pub bids: Vec<(Price, Quantity)>,
+    pub asks: Vec<(Price, Quantity)>,
+    pub timestamp: u64,
+}
+impl Event for Depth {
+    const TOPIC: &'static str = DEPTH;
+    fn key(&self) -> String {
+        self.symbol.clone()
+    }
+}
+
+// Over the trades of the last 24 hours
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Ticker {
+    pub symbol: Symbol,
+    pub base_volume: Quantity,
+    pub quote_volume: Quantity,
+    pub price_change: Price,
+    pub price_change_percent: Price,
+    pub high_price: Price,
+    pub low_price: Price,
+    pub last_price: Price,
+}
+impl Event for Ticker {
+    const TOPIC: &'static str = TICKER;
+    fn key(&self) -> String {
+        self.symbol.clone()
+    }
+}
+
+// Balances are keyed by asset name
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Balance {
+    pub user_id: Id,
+    pub balance: HashMap<String, Quantity>,
+    pub locked_balance: HashMap<String, Quantity>,
+}
+impl Event for Balance {
+    const TOPIC: &'static str = BALANCE;
+    fn key(&self) -> String {
+        self.user_id.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+    use serde_json::{ json, Value };
+
+    use super::*;
+
+    fn order_update() -> OrderUpdate {
+        OrderUpdate {
+            order_id: 7,
+            client_order_id: 3,
+            trade_id: 11,
+            user_id: 2,
+            trade_timestamp: 1_718_000_000_000_000,
+            order_side: OrderSide::Bid,
+            order_status: OrderStatus::PartiallyFilled,
+            symbol: "SOL_USDT".to_string(),
+            price: dec!(150.5),
+            executed_quantity: dec!(2),
+            executed_quote_quantity: dec!(301),
+        }
+    }
+    fn trade() -> Trade {
+        Trade {
+            id: 11,
+            symbol: "SOL_USDT".to_string(),
+            price: dec!(150.5),
+            quantity: dec!(2),
+            quote_quantity: dec!(301),
+            is_buyer_maker: false,
+            timestamp: 1_718_000_000_000_000,
+        }
+    }
+
+    // The engine publishes with `encode`, wss subscribes with `topic` and routes with `topic_key`
+    #[test]
+    fn producer_channels_match_consumer_subscriptions() {
+        let symbol = "SOL_USDT";
+        let (trade_channel, _) = encode(&trade());
+        assert_eq!(trade_channel, topic::<Trade>(symbol));
+        assert_eq!(trade_channel, "trade:SOL_USDT");
+        assert_eq!(topic_key(&trade_channel), Some(symbol));
+
+        let (order_update_channel, _) = encode(&order_update());
+        assert_eq!(order_update_channel, topic::<OrderUpdate>(symbol));
+        assert_eq!(order_update_channel, "order_update:SOL_USDT");
+
+        let depth = Depth {
+            symbol: symbol.to_string(),
+            bids: Vec::new(),
+            asks: Vec::new(),
+            timestamp: 1,
+        };
+        let (depth_channel, _) = encode(&depth);
+        assert_eq!(depth_channel, topic::<Depth>(symbol));
+        assert_eq!(depth_channel, "depth:SOL_USDT");
+
+        let balance = Balance {
+            user_id: 42,
+            balance: HashMap::new(),
+            locked_balance: HashMap::new(),
+        };
+        let (balance_channel, _) = encode(&balance);
+        assert_eq!(balance_channel, topic::<Balance>("42"));
+        assert_eq!(balance_channel, "balance:42");
+        assert_eq!(topic::<Ticker>(symbol), "ticker:SOL_USDT");
+    }
+    #[test]
+    fn order_update_wire_format() {
+        let (_, payload) = encode(&order_update());
+        let payload: Value = serde_json::from_str(&payload).unwrap();
+        assert_eq!(
+            payload,
+            json!({
+                "version": SCHEMA_VERSION,
+                "data": {
+                    "order_id": 7,
+                    "client_order_id": 3,
+                    "trade_id": 11,
+                    "user_id": 2,
+                    "trade_timestamp": 1_718_000_000_000_000u64,
+                    "order_side": "Bid",
+                    "order_status": "PartiallyFilled",
+                    "symbol": "SOL_USDT",
+                    "price": "150.5",
+                    "executed_quantity": "2",
+                    "executed_quote_quantity": "301"
+                }
+            })
+        );
+    }
+    #[test]
+    fn trade_wire_format() {
+        let (_, payload) = encode(&trade());
+        let payload: Value = serde_json::from_str(&payload).unwrap();
+        assert_eq!(
+            payload,
+            json!({
+                "version": SCHEMA_VERSION,
+                "data": {
+                    "id": 11,
+                    "symbol": "SOL_USDT",
+                    "price": "150.5",
+                    "quantity": "2",
+                    "quote_quantity": "301",
+                    "is_buyer_maker": false,
+                    "timestamp": 1_718_000_000_000_000u64
+                }
+            })
+        );
+    }
+    #[test]
+    fn every_event_round_trips() {
+        let (_, payload) = encode(&trade());
+        assert_eq!(decode::<Trade>(&payload).unwrap(), trade());
+        let (_, payload) = encode(&order_update());
+        assert_eq!(decode::<OrderUpdate>(&payload).unwrap(), order_update());
+
+        let depth = Depth {
+            symbol: "BTC_USDT".to_string(),
+            bids: vec![(dec!(100), dec!(1.5)), (dec!(99), dec!(3))],
+            asks: vec![(dec!(101), dec!(0.25))],
+            timestamp: 1,
+        };
+        let (_, payload) = encode(&depth);
+        assert_eq!(decode::<Depth>(&payload).unwrap(), depth);
+
+        let ticker = Ticker {
+            symbol: "BTC_USDT".to_string(),
+            base_volume: dec!(10),
+            quote_volume: dec!(1000),
+            price_change: dec!(-1),
+            price_change_percent: dec!(-0.99),
+            high_price: dec!(102),
+            low_price: dec!(98),
+            last_price: dec!(100),
+        };
+        let (_, payload) = encode(&ticker);
+        assert_eq!(decode::<Ticker>(&payload).unwrap(), ticker);
+
+        let balance = Balance {
+            user_id: 1,
+            balance: HashMap::from([("USDT".to_string(), dec!(25))]),
+            locked_balance: HashMap::from([("USDT".to_string(), dec!(5))]),
+        };
+        let (_, payload) = encode(&balance);
+        assert_eq!(decode::<Balance>(&payload).unwrap(), balance);
+    }
+    #[test]
+    fn rejects_other_schema_versions() {
+        let (_, payload) = encode(&trade());
+        let payload = payload.replacen(
+            &format!("\"version\":{}", SCHEMA_VERSION),
+            &format!("\"version\":{}", SCHEMA_VERSION + 1),
+            1
+        );
+        assert!(matches!(decode::<Trade>(&payload), Err(EventError::UnsupportedVersion(_))));
+        assert!(matches!(decode::<Trade>("{}"), Err(EventError::Malformed(_))));
+    }
+}
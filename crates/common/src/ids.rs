@@ -0,0 +1,52 @@
+use crate::Id;
+
+// Ids are `namespace << SEQUENCE_BITS | sequence`. Every market allocates its order and trade ids
+// in its own namespace and users have one too, so ids never collide across markets and each
+// counter is recovered from the journal of the thread that owns it. Namespaces stop at 31 so
+// ids stay below 2^53 and survive JSON clients that read numbers as doubles.
+pub const SEQUENCE_BITS: u32 = 48;
+pub const MAX_NAMESPACE: u16 = 31;
+pub const USERS_NAMESPACE: u16 = 0;
+// Markets take the namespaces below these, requests of the user thread have their own
+pub const WITHDRAWALS_NAMESPACE: u16 = 16;
+
+pub fn compose(namespace: u16, sequence: u64) -> Id {
+    assert!(namespace <= MAX_NAMESPACE, "Id namespace {} is out of range", namespace);
+    assert!(sequence < 1 << SEQUENCE_BITS, "Id sequence of namespace {} is exhausted", namespace);
+    ((namespace as u64) << SEQUENCE_BITS) | sequence
+}
+// The id before the first one of the namespace, counters start here and are incremented
+pub fn base(namespace: u16) -> Id {
+    compose(namespace, 0)
+}
+pub fn namespace(id: Id) -> u16 {
+    (id >> SEQUENCE_BITS) as u16
+}
+pub fn sequence(id: Id) -> u64 {
+    id & ((1 << SEQUENCE_BITS) - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_back_into_namespace_and_sequence() {
+        let id = compose(3, 42);
+        assert_eq!(namespace(id), 3);
+        assert_eq!(sequence(id), 42);
+        assert_eq!(base(3) + 42, id);
+        assert_eq!(compose(USERS_NAMESPACE, 7), 7);
+    }
+    #[test]
+    fn namespaces_never_overlap() {
+        let last_of_first = compose(1, (1 << SEQUENCE_BITS) - 1);
+        assert_eq!(last_of_first + 1, base(2));
+        assert!(compose(MAX_NAMESPACE, (1 << SEQUENCE_BITS) - 1) < 1 << 53);
+    }
+    #[test]
+    #[should_panic]
+    fn rejects_namespace_out_of_range() {
+        compose(MAX_NAMESPACE + 1, 1);
+    }
+}
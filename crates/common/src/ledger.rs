@@ -0,0 +1,195 @@
+use enum_stringify::EnumStringify;
+use scylla::{ batch::Batch, transport::errors::QueryError, Session };
+use serde::{ Deserialize, Serialize };
+use strum_macros::EnumIter;
+
+use crate::{ numeric::Numeric, Id, Quantity };
+
+// Every balance movement is recorded as entries that never change once written. The entries of
+// one movement share the kind and reference, and per asset they always sum up to zero: what
+// leaves one account enters another.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LedgerEntry {
+    pub user_id: Id,
+    pub kind: LedgerKind,
+    // The deposit id of deposits, the withdrawal id of withdrawals, the transfer id of internal
+    // transfers, the adjustment id of adjustments, the order id of locks and unlocks, the trade
+    // id of trade legs and fees, the loan id of borrows and repayments, the hour of interest and
+    // the funding interval of funding payments
+    pub reference: u64,
+    // Position of the entry within its movement
+    pub leg: u8,
+    pub account: Account,
+    pub asset: String,
+    // Signed, positive amounts are credited to the account
+    pub amount: Quantity,
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, EnumIter, EnumStringify)]
+pub enum LedgerKind {
+    Deposit,
+    Withdrawal,
+    Lock,
+    Unlock,
+    Trade,
+    // Between two accounts of the exchange, like a master and its sub-accounts
+    Transfer,
+    // Made by hand by an operator, with a reason code
+    Adjustment,
+    // Charged on trades into Fees, no market charges one yet
+    Fee,
+    // Margin loans, interest is charged every hour they are open
+    Borrow,
+    Repay,
+    Interest,
+    // Paid between longs and shorts of a perpetual market at every funding interval
+    Funding,
+}
+
+// A user's balance of an asset is split between what they have available and what their open
+// orders lock. External is the other side of deposits and withdrawals, the world outside the
+// exchange, and Fees is where charged fees and interest go. Borrowed is what a margin account
+// owes, loans are drawn from it and repaid into it. Position is the margin posted to perpetual
+// positions, and Settlement the other side of their realized PnL and funding payments. Insurance
+// covers the losses of positions past their margin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, EnumIter, EnumStringify)]
+pub enum Account {
+    Available,
+    Locked,
+    External,
+    Fees,
+    Borrowed,
+    Position,
+    Settlement,
+    Insurance,
+}
+
+// What the entries of one movement share
+#[derive(Debug, Clone, Copy)]
+pub struct Movement {
+    pub kind: LedgerKind,
+    pub reference: u64,
+    pub timestamp: u64,
+}
+impl Movement {
+    pub fn new(kind: LedgerKind, reference: u64, timestamp: u64) -> Movement {
+        Movement { kind, reference, timestamp }
+    }
+    // Legs as (user, account, asset, amount), numbered in the order they are given
+    pub fn entries(&self, legs: Vec<(Id, Account, String, Quantity)>) -> Vec<LedgerEntry> {
+        legs.into_iter()
+            .enumerate()
+            .map(|(leg, (user_id, account, asset, amount))| LedgerEntry {
+                user_id,
+                kind: self.kind,
+                reference: self.reference,
+                leg: leg as u8,
+                account,
+                asset,
+                amount,
+                timestamp: self.timestamp,
+            })
+            .collect()
+    }
+    // Moves an amount between two accounts of the same user
+    pub fn transfer(
+        &self,
+        user_id: Id,
+        asset: String,
+        from: Account,
+        to: Account,
+        amount: Quantity
+    ) -> Vec<LedgerEntry> {
+        self.entries(vec![(user_id, from, asset.clone(), -amount), (user_id, to, asset, amount)])
+    }
+}
+
+pub const NEW_LEDGER_ENTRY: &str =
+    r#"
+    INSERT INTO keyspace_1.ledger_table (
+        user_id,
+        timestamp,
+        kind,
+        reference,
+        leg,
+        account,
+        asset,
+        amount
+    ) VALUES (?, ?, ?, ?, ?, ?, ?, ?);
+"#;
+// user_id, timestamp, kind, reference, leg, account, asset, amount
+pub type LedgerRow = (i64, i64, String, i64, i32, String, String, Numeric);
+
+impl LedgerEntry {
+    pub fn to_row(&self) -> LedgerRow {
+        (
+            self.user_id as i64,
+            self.timestamp as i64,
+            self.kind.to_string(),
+            self.reference as i64,
+            self.leg as i32,
+            self.account.to_string(),
+            self.asset.clone(),
+            Numeric(self.amount),
+        )
+    }
+}
+// Entries are keyed by their movement and leg, so writing them again is a no-op
+pub async fn insert_entries(session: &Session, entries: &[LedgerEntry]) -> Result<(), QueryError> {
+    if entries.is_empty() {
+        return Ok(());
+    }
+    let mut batch: Batch = Default::default();
+    for _ in entries {
+        batch.append_statement(NEW_LEDGER_ENTRY);
+    }
+    let prepared_batch: Batch = session.prepare_batch(&batch).await?;
+    let values: Vec<LedgerRow> = entries.iter().map(LedgerEntry::to_row).collect();
+    session.batch(&prepared_batch, values).await?;
+    Ok(())
+}
+
+// Whether every asset moved by the entries nets out to zero
+pub fn is_balanced(entries: &[LedgerEntry]) -> bool {
+    let mut assets: Vec<&String> = entries
+        .iter()
+        .map(|entry| &entry.asset)
+        .collect();
+    assets.sort();
+    assets.dedup();
+    assets.iter().all(|asset| {
+        let total: Quantity = entries
+            .iter()
+            .filter(|entry| &&entry.asset == asset)
+            .map(|entry| entry.amount)
+            .sum();
+        total.is_zero()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    #[test]
+    fn transfers_balance_and_number_their_legs() {
+        let entries = Movement::new(LedgerKind::Lock, 7, 1).transfer(
+            2,
+            "USDT".to_string(),
+            Account::Available,
+            Account::Locked,
+            dec!(15)
+        );
+        assert!(is_balanced(&entries));
+        assert_eq!(entries[0].amount, dec!(-15));
+        assert_eq!((entries[0].leg, entries[1].leg), (0, 1));
+        assert_eq!(entries[1].account, Account::Locked);
+
+        let mut unbalanced = entries.clone();
+        unbalanced[1].amount = dec!(14);
+        assert!(!is_balanced(&unbalanced));
+    }
+}
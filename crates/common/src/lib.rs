@@ -0,0 +1,32 @@
+use enum_stringify::EnumStringify;
+use rust_decimal::Decimal;
+use serde::{ Deserialize, Serialize };
+use strum_macros::EnumIter;
+
+pub mod buckets;
+pub mod events;
+pub mod ids;
+pub mod ledger;
+pub mod listen_key;
+pub mod numeric;
+pub mod query_tables;
+
+pub type Symbol = String;
+pub type Id = u64;
+pub type OrderId = u64;
+pub type TradeId = u64;
+pub type Price = Decimal;
+pub type Quantity = Decimal;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, EnumIter, EnumStringify)]
+pub enum OrderSide {
+    Bid,
+    Ask,
+}
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, EnumIter, EnumStringify)]
+pub enum OrderStatus {
+    InProgress,
+    Filled,
+    PartiallyFilled,
+    Cancelled,
+}
@@ -0,0 +1,8 @@
+// Listen keys let a websocket connection prove which user it streams for. The backend issues
+// them to signed requests and keeps them in redis with the user's id, where they expire unless
+// renewed. The wss service looks them up there before streaming anything private.
+pub const LISTEN_KEY_TTL: u64 = 60 * 60;
+
+pub fn redis_key(listen_key: &str) -> String {
+    format!("listen_key:{}", listen_key)
+}
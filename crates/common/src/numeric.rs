@@ -0,0 +1,142 @@
+use std::{ error::Error, fmt::Display, str::FromStr };
+
+use rust_decimal::Decimal;
+use scylla::{
+    cql_to_rust::{ FromCqlVal, FromCqlValError },
+    frame::{ response::result::{ ColumnType, CqlValue }, value::CqlDecimal },
+    serialize::{
+        value::SerializeCql,
+        writers::{ CellWriter, WrittenCellProof },
+        SerializationError,
+    },
+};
+use serde::{ Deserialize, Serialize };
+
+// Prices, quantities and balances are stored as Scylla `decimal`s. The unscaled value and the
+// scale of a `Decimal` map onto the varint and the scale of a CQL decimal, so nothing is lost on
+// the way in. Values that do not fit a `Decimal` on the way out are errors.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NumericError {
+    NotANumber(String),
+    OutOfRange,
+}
+impl Display for NumericError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NumericError::NotANumber(value) => write!(f, "{:?} is not a number", value),
+            NumericError::OutOfRange => write!(f, "decimal does not fit 96 bits and 28 places"),
+        }
+    }
+}
+impl Error for NumericError {}
+
+pub fn encode(value: Decimal) -> CqlDecimal {
+    let bytes = value.mantissa().to_be_bytes();
+    // the shortest two's complement that keeps the sign
+    let mut start = 0;
+    while start < bytes.len() - 1 {
+        let redundant = match bytes[start] {
+            0x00 => bytes[start + 1] & 0x80 == 0,
+            0xff => bytes[start + 1] & 0x80 != 0,
+            _ => false,
+        };
+        if !redundant {
+            break;
+        }
+        start += 1;
+    }
+    CqlDecimal::from_signed_be_bytes_slice_and_exponent(&bytes[start..], value.scale() as i32)
+}
+pub fn decode(value: &CqlDecimal) -> Result<Decimal, NumericError> {
+    let (bytes, scale) = value.as_signed_be_bytes_slice_and_exponent();
+    if bytes.len() > 16 {
+        return Err(NumericError::OutOfRange);
+    }
+    let fill = match bytes.first() {
+        Some(byte) if byte & 0x80 != 0 => 0xff,
+        _ => 0x00,
+    };
+    let mut mantissa = [fill; 16];
+    mantissa[16 - bytes.len()..].copy_from_slice(bytes);
+    let mut mantissa = i128::from_be_bytes(mantissa);
+    let mut scale = scale;
+    // a negative scale multiplies by a power of ten
+    while scale < 0 {
+        mantissa = mantissa.checked_mul(10).ok_or(NumericError::OutOfRange)?;
+        scale += 1;
+    }
+    Decimal::try_from_i128_with_scale(mantissa, scale as u32).map_err(|_| NumericError::OutOfRange)
+}
+// Values written as text before they were stored as decimals
+pub fn parse(value: &str) -> Result<Decimal, NumericError> {
+    Decimal::from_str(value).map_err(|_| NumericError::NotANumber(value.to_string()))
+}
+
+// A `Decimal` column or map value of a Scylla row
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Numeric(pub Decimal);
+
+impl From<Decimal> for Numeric {
+    fn from(value: Decimal) -> Self {
+        Numeric(value)
+    }
+}
+impl From<Numeric> for Decimal {
+    fn from(value: Numeric) -> Self {
+        value.0
+    }
+}
+impl SerializeCql for Numeric {
+    fn serialize<'b>(
+        &self,
+        typ: &ColumnType,
+        writer: CellWriter<'b>
+    ) -> Result<WrittenCellProof<'b>, SerializationError> {
+        encode(self.0).serialize(typ, writer)
+    }
+}
+impl FromCqlVal<CqlValue> for Numeric {
+    fn from_cql(cql_val: CqlValue) -> Result<Self, FromCqlValError> {
+        match cql_val {
+            CqlValue::Decimal(value) =>
+                decode(&value)
+                    .map(Numeric)
+                    .map_err(|_| FromCqlValError::BadVal),
+            _ => Err(FromCqlValError::BadCqlType),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    #[test]
+    fn decimals_round_trip_through_cql() {
+        for value in [dec!(0), dec!(1), dec!(-1), dec!(128), dec!(-129), dec!(0.00000001)] {
+            assert_eq!(decode(&encode(value)), Ok(value));
+        }
+        for value in [Decimal::MAX, Decimal::MIN, dec!(12345.678900), dec!(-0.5)] {
+            let decoded = decode(&encode(value)).unwrap();
+            assert_eq!((decoded, decoded.scale()), (value, value.scale()));
+        }
+        // 255 is 0x00ff, without the leading zero it would read back as -1
+        let (bytes, scale) = encode(dec!(2.55)).into_signed_be_bytes_and_exponent();
+        assert_eq!((bytes, scale), (vec![0x00, 0xff], 2));
+    }
+    #[test]
+    fn values_that_do_not_fit_are_errors() {
+        let too_long = CqlDecimal::from_signed_be_bytes_and_exponent(vec![0x01; 17], 0);
+        assert_eq!(decode(&too_long), Err(NumericError::OutOfRange));
+        let too_precise = CqlDecimal::from_signed_be_bytes_and_exponent(vec![0x01], 29);
+        assert_eq!(decode(&too_precise), Err(NumericError::OutOfRange));
+        let thousand = CqlDecimal::from_signed_be_bytes_and_exponent(vec![0x01], -3);
+        assert_eq!(decode(&thousand), Ok(dec!(1000)));
+        assert_eq!(parse("1.5x"), Err(NumericError::NotANumber("1.5x".to_string())));
+        assert_eq!(parse("1.5"), Ok(dec!(1.5)));
+    }
+}
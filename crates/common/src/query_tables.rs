@@ -0,0 +1,55 @@
+// Statements that write orders, cancels and trades into the tables that find them by user and by
+// market, shared by whatever writes the rows they copy. The ones by market take the `bucket` of
+// the row's timestamp last.
+pub const ORDER_BY_USER_STATEMENT: &str =
+    r#"
+    INSERT INTO keyspace_1.order_by_user_table (user_id, symbol, timestamp, id)
+    VALUES (?, ?, ?, ?);
+"#;
+pub const USER_SYMBOL_STATEMENT: &str =
+    r#"
+    INSERT INTO keyspace_1.user_symbol_table (user_id, symbol) VALUES (?, ?);
+"#;
+pub const ORDER_BY_MARKET_STATEMENT: &str =
+    r#"
+    INSERT INTO keyspace_1.order_by_market_table (
+        id,
+        user_id,
+        symbol,
+        price,
+        initial_quantity,
+        filled_quantity,
+        quote_quantity,
+        filled_quote_quantity,
+        order_type,
+        order_side,
+        order_status,
+        timestamp,
+        bucket
+    ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);
+"#;
+pub const CANCEL_ORDER_BY_MARKET_STATEMENT: &str =
+    r#"
+    INSERT INTO keyspace_1.cancel_order_by_market_table (
+        id,
+        user_id,
+        order_side,
+        symbol,
+        price,
+        timestamp,
+        bucket
+    ) VALUES (?, ?, ?, ?, ?, ?, ?);
+"#;
+pub const TRADE_BY_MARKET_STATEMENT: &str =
+    r#"
+    INSERT INTO keyspace_1.trade_by_market_table (
+        id,
+        symbol,
+        quantity,
+        quote_quantity,
+        is_buyer_maker,
+        price,
+        timestamp,
+        bucket
+    ) VALUES (?, ?, ?, ?, ?, ?, ?, ?);
+"#;
@@ -0,0 +1,155 @@
+use std::{ error::Error, str::FromStr };
+
+use scylla::transport::errors::QueryError;
+
+use crate::{
+    auth::{ AdminKey, AdminRole },
+    db::{ schema::AuditEntry, scylla_tables::{ ScyllaAdminKey, ScyllaAuditEntry }, ScyllaDb },
+};
+
+const MICROS_PER_DAY: i64 = 86_400_000_000;
+
+// The audit table is partitioned by the day an action was taken on
+pub fn audit_day(timestamp: i64) -> i64 {
+    timestamp / MICROS_PER_DAY
+}
+
+impl ScyllaAdminKey {
+    fn to_admin_key(&self) -> AdminKey {
+        AdminKey {
+            key: self.key.clone(),
+            secret: self.secret.clone(),
+            name: self.name.clone(),
+            roles: self.roles
+                .iter()
+                .map(|role| AdminRole::from_str(role).unwrap())
+                .collect(),
+            allowed_ips: self.allowed_ips.clone(),
+            created_at: self.created_at,
+        }
+    }
+}
+impl AdminKey {
+    fn to_scylla_admin_key(&self) -> ScyllaAdminKey {
+        ScyllaAdminKey {
+            key: self.key.clone(),
+            name: self.name.clone(),
+            secret: self.secret.clone(),
+            roles: self.roles
+                .iter()
+                .map(|role| role.to_string())
+                .collect(),
+            allowed_ips: self.allowed_ips.clone(),
+            created_at: self.created_at,
+        }
+    }
+}
+impl AuditEntry {
+    fn to_scylla_audit_entry(&self) -> ScyllaAuditEntry {
+        ScyllaAuditEntry {
+            day: audit_day(self.timestamp),
+            timestamp: self.timestamp,
+            id: self.id,
+            admin: self.admin.clone(),
+            action: self.action.clone(),
+            target: self.target.clone(),
+            reason: self.reason.clone(),
+            request: self.request.clone(),
+            outcome: self.outcome.clone(),
+        }
+    }
+}
+impl ScyllaAuditEntry {
+    fn into_audit_entry(self) -> AuditEntry {
+        AuditEntry {
+            id: self.id,
+            timestamp: self.timestamp,
+            admin: self.admin,
+            action: self.action,
+            target: self.target,
+            reason: self.reason,
+            request: self.request,
+            outcome: self.outcome,
+        }
+    }
+}
+impl ScyllaDb {
+    pub async fn new_admin_key(&self, admin_key: &AdminKey) -> Result<(), QueryError> {
+        let s =
+            r#"
+            INSERT INTO keyspace_1.admin_key_table (
+                key,
+                name,
+                secret,
+                roles,
+                allowed_ips,
+                created_at
+            ) VALUES (?, ?, ?, ?, ?, ?);
+        "#;
+        self.session.query(s, admin_key.to_scylla_admin_key()).await?;
+        Ok(())
+    }
+    pub async fn get_admin_key(&self, key: &str) -> Result<AdminKey, Box<dyn Error>> {
+        let s =
+            r#"
+            SELECT
+                key,
+                name,
+                secret,
+                roles,
+                allowed_ips,
+                created_at
+            FROM keyspace_1.admin_key_table
+            WHERE key = ?;
+        "#;
+        let res = self.session.query(s, (key,)).await?;
+        let mut admin_keys = res.rows_typed::<ScyllaAdminKey>()?;
+        let admin_key = admin_keys
+            .next()
+            .transpose()?
+            .ok_or(QueryError::InvalidMessage("Admin key does not exist in db".to_string()))?;
+        Ok(admin_key.to_admin_key())
+    }
+    // Recording the entry again with its outcome overwrites the pending one
+    pub async fn record_admin_action(&self, entry: &AuditEntry) -> Result<(), QueryError> {
+        let s =
+            r#"
+            INSERT INTO keyspace_1.admin_action_table (
+                day,
+                timestamp,
+                id,
+                admin,
+                action,
+                target,
+                reason,
+                request,
+                outcome
+            ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?);
+        "#;
+        self.session.query(s, entry.to_scylla_audit_entry()).await?;
+        Ok(())
+    }
+    pub async fn get_audit(&self, day: i64) -> Result<Vec<AuditEntry>, Box<dyn Error>> {
+        let s =
+            r#"
+            SELECT
+                day,
+                timestamp,
+                id,
+                admin,
+                action,
+                target,
+                reason,
+                request,
+                outcome
+            FROM keyspace_1.admin_action_table
+            WHERE day = ?;
+        "#;
+        let res = self.session.query(s, (day,)).await?;
+        let entries = res.rows_typed::<ScyllaAuditEntry>()?;
+        let entries: Vec<AuditEntry> = entries
+            .map(|entry| entry.unwrap().into_audit_entry())
+            .collect();
+        Ok(entries)
+    }
+}
@@ -0,0 +1,106 @@
+use std::{ error::Error, str::FromStr };
+
+use scylla::transport::errors::QueryError;
+
+use crate::{
+    auth::{ ApiKey, Permission },
+    db::{ schema::Id, scylla_tables::ScyllaApiKey, ScyllaDb },
+};
+
+impl ScyllaApiKey {
+    fn to_api_key(&self) -> ApiKey {
+        ApiKey {
+            key: self.key.clone(),
+            secret: self.secret.clone(),
+            user_id: self.user_id,
+            permissions: self.permissions
+                .iter()
+                .map(|permission| Permission::from_str(permission).unwrap())
+                .collect(),
+            allowed_ips: self.allowed_ips.clone(),
+            created_at: self.created_at,
+        }
+    }
+}
+impl ApiKey {
+    fn to_scylla_api_key(&self) -> ScyllaApiKey {
+        ScyllaApiKey {
+            key: self.key.clone(),
+            user_id: self.user_id,
+            secret: self.secret.clone(),
+            permissions: self.permissions
+                .iter()
+                .map(|permission| permission.to_string())
+                .collect(),
+            allowed_ips: self.allowed_ips.clone(),
+            created_at: self.created_at,
+        }
+    }
+}
+impl ScyllaDb {
+    pub async fn new_api_key(&self, api_key: &ApiKey) -> Result<(), QueryError> {
+        let s =
+            r#"
+            INSERT INTO keyspace_1.api_key_table (
+                key,
+                user_id,
+                secret,
+                permissions,
+                allowed_ips,
+                created_at
+            ) VALUES (?, ?, ?, ?, ?, ?);
+        "#;
+        self.session.query(s, api_key.to_scylla_api_key()).await?;
+        Ok(())
+    }
+    pub async fn get_api_key(&self, key: &str) -> Result<ApiKey, Box<dyn Error>> {
+        let s =
+            r#"
+            SELECT
+                key,
+                user_id,
+                secret,
+                permissions,
+                allowed_ips,
+                created_at
+            FROM keyspace_1.api_key_table
+            WHERE key = ?;
+        "#;
+        let res = self.session.query(s, (key,)).await?;
+        let mut api_keys = res.rows_typed::<ScyllaApiKey>()?;
+        let api_key = api_keys
+            .next()
+            .transpose()?
+            .ok_or(QueryError::InvalidMessage("API key does not exist in db".to_string()))?;
+        Ok(api_key.to_api_key())
+    }
+    pub async fn get_api_keys(&self, user_id: Id) -> Result<Vec<ApiKey>, Box<dyn Error>> {
+        let s =
+            r#"
+            SELECT
+                key,
+                user_id,
+                secret,
+                permissions,
+                allowed_ips,
+                created_at
+            FROM keyspace_1.api_key_table
+            WHERE user_id = ?;
+        "#;
+        let res = self.session.query(s, (user_id,)).await?;
+        let api_keys = res.rows_typed::<ScyllaApiKey>()?;
+        let api_keys: Vec<ApiKey> = api_keys
+            .map(|api_key| api_key.unwrap().to_api_key())
+            .collect();
+        Ok(api_keys)
+    }
+    pub async fn delete_api_key(&self, key: &str) -> Result<(), QueryError> {
+        let s =
+            r#"
+            DELETE FROM keyspace_1.api_key_table
+            WHERE key = ?;
+        "#;
+        self.session.query(s, (key,)).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,51 @@
+use std::{ error::Error, str::FromStr };
+
+use crate::db::{
+    invalid_value,
+    schema::{ Asset, Deposit, DepositState, Id },
+    scylla_tables::ScyllaDeposit,
+    ScyllaDb,
+};
+
+impl ScyllaDeposit {
+    fn to_deposit(&self) -> Result<Deposit, Box<dyn Error>> {
+        let invalid = |column: &str, value: &str| invalid_value("deposit_table", column, value);
+        Ok(Deposit {
+            id: self.id,
+            tx_id: self.tx_id.clone(),
+            user_id: self.user_id,
+            asset: Asset::from_str(&self.asset).ok_or_else(|| invalid("asset", &self.asset))?,
+            amount: self.amount.0,
+            confirmations: self.confirmations as u32,
+            state: DepositState::from_str(&self.state).map_err(|_| invalid("state", &self.state))?,
+            seen_at: self.seen_at,
+            updated_at: self.updated_at,
+        })
+    }
+}
+impl ScyllaDb {
+    // Newest deposits first, pending ones included
+    pub async fn get_deposits(&self, user_id: Id) -> Result<Vec<Deposit>, Box<dyn Error>> {
+        let s =
+            r#"
+            SELECT
+                id,
+                tx_id,
+                user_id,
+                asset,
+                amount,
+                confirmations,
+                state,
+                seen_at,
+                updated_at
+            FROM keyspace_1.deposit_table
+            WHERE user_id = ?;
+        "#;
+        let res = self.session.query(s, (user_id,)).await?;
+        let deposits = res.rows_typed::<ScyllaDeposit>()?;
+        let deposits = deposits
+            .map(|deposit| deposit?.to_deposit())
+            .collect::<Result<Vec<Deposit>, Box<dyn Error>>>()?;
+        Ok(deposits)
+    }
+}
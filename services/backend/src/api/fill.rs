@@ -0,0 +1,72 @@
+use std::{ error::Error, str::FromStr };
+
+use bytes::Bytes;
+use scylla::query::Query;
+
+use crate::db::{
+    invalid_value,
+    schema::{ Fill, FillPage, FillRole, Id, OrderSide },
+    scylla_tables::ScyllaFill,
+    ScyllaDb,
+};
+
+impl ScyllaFill {
+    fn to_fill(&self) -> Result<Fill, Box<dyn Error>> {
+        let invalid = |column: &str, value: &str| invalid_value("fill_table", column, value);
+        Ok(Fill {
+            trade_id: self.trade_id,
+            symbol: self.symbol.to_string(),
+            role: FillRole::from_str(&self.role).map_err(|_| invalid("role", &self.role))?,
+            order_id: self.order_id,
+            counterparty_order_id: self.counterparty_order_id,
+            side: OrderSide::from_str(&self.side).map_err(|_| invalid("side", &self.side))?,
+            price: self.price.0,
+            quantity: self.quantity.0,
+            quote_quantity: self.quote_quantity.0,
+            fee: self.fee.0,
+            fee_asset: self.fee_asset.to_string(),
+            timestamp: self.timestamp,
+        })
+    }
+}
+impl ScyllaDb {
+    // Newest fills first, `page` is the `next_page` of the previous page
+    pub async fn get_user_trades(
+        &self,
+        user_id: Id,
+        symbol: &str,
+        page_size: i32,
+        page: Option<String>
+    ) -> Result<FillPage, Box<dyn Error>> {
+        let s =
+            r#"
+            SELECT
+                trade_id,
+                symbol,
+                role,
+                order_id,
+                counterparty_order_id,
+                side,
+                price,
+                quantity,
+                quote_quantity,
+                fee,
+                fee_asset,
+                timestamp
+            FROM keyspace_1.fill_table
+            WHERE user_id = ? AND symbol = ?;
+        "#;
+        let paging_state = match page {
+            Some(page) => Some(Bytes::from(hex::decode(page)?)),
+            None => None,
+        };
+        let query = Query::new(s).with_page_size(page_size);
+        let res = self.session.query_paged(query, (user_id, symbol), paging_state).await?;
+        let next_page = res.paging_state.as_ref().map(hex::encode);
+        let fills = res.rows_typed::<ScyllaFill>()?;
+        let fills = fills
+            .map(|fill| fill?.to_fill())
+            .collect::<Result<Vec<Fill>, Box<dyn Error>>>()?;
+        Ok(FillPage { fills, next_page })
+    }
+}
@@ -0,0 +1,66 @@
+use std::{ error::Error, str::FromStr };
+
+use bytes::Bytes;
+use common::ledger::{ Account, LedgerEntry, LedgerKind };
+use scylla::query::Query;
+
+use crate::db::{
+    invalid_value,
+    schema::{ Id, LedgerPage },
+    scylla_tables::ScyllaLedgerEntry,
+    ScyllaDb,
+};
+
+impl ScyllaLedgerEntry {
+    fn to_ledger_entry(&self) -> Result<LedgerEntry, Box<dyn Error>> {
+        let invalid = |column: &str, value: &str| invalid_value("ledger_table", column, value);
+        Ok(LedgerEntry {
+            user_id: self.user_id as u64,
+            kind: LedgerKind::from_str(&self.kind).map_err(|_| invalid("kind", &self.kind))?,
+            reference: self.reference as u64,
+            leg: self.leg as u8,
+            account: Account::from_str(&self.account).map_err(|_| {
+                invalid("account", &self.account)
+            })?,
+            asset: self.asset.to_string(),
+            amount: self.amount.0,
+            timestamp: self.timestamp as u64,
+        })
+    }
+}
+impl ScyllaDb {
+    // Newest entries first, `page` is the `next_page` of the previous page
+    pub async fn get_ledger(
+        &self,
+        user_id: Id,
+        page_size: i32,
+        page: Option<String>
+    ) -> Result<LedgerPage, Box<dyn Error>> {
+        let s =
+            r#"
+            SELECT
+                user_id,
+                timestamp,
+                kind,
+                reference,
+                leg,
+                account,
+                asset,
+                amount
+            FROM keyspace_1.ledger_table
+            WHERE user_id = ?;
+        "#;
+        let paging_state = match page {
+            Some(page) => Some(Bytes::from(hex::decode(page)?)),
+            None => None,
+        };
+        let query = Query::new(s).with_page_size(page_size);
+        let res = self.session.query_paged(query, (user_id,), paging_state).await?;
+        let next_page = res.paging_state.as_ref().map(hex::encode);
+        let entries = res.rows_typed::<ScyllaLedgerEntry>()?;
+        let entries = entries
+            .map(|entry| entry?.to_ledger_entry())
+            .collect::<Result<Vec<LedgerEntry>, Box<dyn Error>>>()?;
+        Ok(LedgerPage { entries, next_page })
+    }
+}
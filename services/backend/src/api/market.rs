@@ -1,9 +1,15 @@
-use std::{ error::Error, str::FromStr };
+use std::error::Error;
 
+use common::numeric::parse;
 use rust_decimal::Decimal;
 use scylla::transport::errors::QueryError;
 
-use crate::db::{schema::{Asset, Exchange, Market, Symbol}, scylla_tables::ScyllaMarket, ScyllaDb};
+use crate::db::{
+    invalid_value,
+    schema::{ Asset, Exchange, Market, Symbol },
+    scylla_tables::ScyllaMarket,
+    ScyllaDb,
+};
 
 impl Market {
     pub fn new(
@@ -44,18 +50,19 @@ impl Market {
 }
 
 impl ScyllaMarket {
-    fn from_scylla_market(&self) -> Market {
-        Market {
-            base: Asset::from_str(&self.base).unwrap(),
-            max_price: Decimal::from_str(&self.max_price).unwrap(),
-            max_quantity: Decimal::from_str(&self.max_quantity).unwrap(),
-            min_price: Decimal::from_str(&self.min_price).unwrap(),
-            min_quantity: Decimal::from_str(&self.min_quantity).unwrap(),
-            quote: Asset::from_str(&self.quote).unwrap(),
-            step_size: Decimal::from_str(&self.step_size).unwrap(),
+    fn from_scylla_market(&self) -> Result<Market, Box<dyn Error>> {
+        let invalid = |column: &str, value: &str| invalid_value("market_table", column, value);
+        Ok(Market {
+            base: Asset::from_str(&self.base).ok_or_else(|| invalid("base", &self.base))?,
+            max_price: parse(&self.max_price)?,
+            max_quantity: parse(&self.max_quantity)?,
+            min_price: parse(&self.min_price)?,
+            min_quantity: parse(&self.min_quantity)?,
+            quote: Asset::from_str(&self.quote).ok_or_else(|| invalid("quote", &self.quote))?,
+            step_size: parse(&self.step_size)?,
             symbol: self.symbol.to_string(),
-            tick_size: Decimal::from_str(&self.tick_size).unwrap(),
-        }
+            tick_size: parse(&self.tick_size)?,
+        })
     }
 }
 
@@ -101,8 +108,29 @@ impl ScyllaDb {
             .next()
             .transpose()?
             .ok_or(QueryError::InvalidMessage("Market does not exist in db".to_string()))?;
-        let market = scylla_market.from_scylla_market();
-        Ok(market)
+        scylla_market.from_scylla_market()
+    }
+    pub async fn get_markets(&self) -> Result<Vec<Market>, Box<dyn Error>> {
+        let s =
+            r#"
+            SELECT
+                symbol,
+                base,
+                quote,
+                max_price,
+                min_price,
+                tick_size,
+                max_quantity,
+                min_quantity,
+                step_size
+            FROM keyspace_1.market_table;
+        "#;
+        let res = self.session.query(s, &[]).await?;
+        let markets = res.rows_typed::<ScyllaMarket>()?;
+        let markets = markets
+            .map(|market| market?.from_scylla_market())
+            .collect::<Result<Vec<Market>, Box<dyn Error>>>()?;
+        Ok(markets)
     }
     pub async fn update_market(&self, market: &mut Market) -> Result<(), Box<dyn Error>> {
         let market = market.to_scylla_market();
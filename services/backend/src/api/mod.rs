@@ -2,4 +2,12 @@ pub mod user;
 pub mod market;
 pub mod order;
 pub mod ticker;
-pub mod trade;
\ No newline at end of file
+pub mod trade;
+pub mod ledger;
+pub mod portfolio;
+pub mod fill;
+pub mod withdrawal;
+pub mod deposit;
+pub mod transfer;
+pub mod api_key;
+pub mod admin;
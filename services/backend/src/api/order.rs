@@ -1,15 +1,30 @@
 use std::{ error::Error, str::FromStr };
+use bytes::Bytes;
 use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
-use scylla::transport::errors::QueryError;
+use scylla::{ query::Query, transport::errors::QueryError };
 
 use crate::db::{
     get_epoch_micros,
-    schema::{ Id, Order, OrderId, OrderSide, OrderStatus, OrderType, Price, Quantity, Symbol },
+    schema::{
+        Id,
+        Order,
+        OrderId,
+        OrderPage,
+        OrderSide,
+        OrderStatus,
+        OrderType,
+        Price,
+        Quantity,
+        Symbol,
+    },
     scylla_tables::ScyllaOrder,
     ScyllaDb,
 };
 
+// The most orders one `IN` query fetches by id
+pub const MAX_ORDERS_PAGE_SIZE: i32 = 100;
+
 impl Order {
     pub fn new(
         id: OrderId,
@@ -42,11 +57,11 @@ impl Order {
             timestamp: self.timestamp,
             user_id: self.user_id,
             symbol: self.symbol.to_string(),
-            filled_quantity: self.filled_quantity.to_string(),
-            filled_quote_quantity: self.filled_quote_quantity.to_string(),
-            quote_quantity: self.quote_quantity.to_string(),
-            price: self.price.to_string(),
-            initial_quantity: self.initial_quantity.to_string(),
+            filled_quantity: self.filled_quantity.into(),
+            filled_quote_quantity: self.filled_quote_quantity.into(),
+            quote_quantity: self.quote_quantity.into(),
+            price: self.price.into(),
+            initial_quantity: self.initial_quantity.into(),
             order_side: self.order_side.to_string(),
             order_status: self.order_status.to_string(),
             order_type: self.order_type.to_string(),
@@ -60,11 +75,11 @@ impl ScyllaOrder {
             timestamp: self.timestamp,
             user_id: self.user_id,
             symbol: self.symbol.to_string(),
-            filled_quote_quantity: Decimal::from_str(&self.filled_quote_quantity).unwrap(),
-            quote_quantity: Decimal::from_str(&self.quote_quantity).unwrap(),
-            filled_quantity: Decimal::from_str(&self.filled_quantity).unwrap(),
-            price: Decimal::from_str(&self.price).unwrap(),
-            initial_quantity: Decimal::from_str(&self.initial_quantity).unwrap(),
+            filled_quote_quantity: self.filled_quote_quantity.into(),
+            quote_quantity: self.quote_quantity.into(),
+            filled_quantity: self.filled_quantity.into(),
+            price: self.price.into(),
+            initial_quantity: self.initial_quantity.into(),
             order_side: OrderSide::from_str(&self.order_side).unwrap(),
             order_status: OrderStatus::from_str(&self.order_status).unwrap(),
             order_type: OrderType::from_str(&self.order_type).unwrap(),
@@ -73,7 +88,7 @@ impl ScyllaOrder {
 }
 
 impl ScyllaDb {
-    pub async fn new_order(&self, order: Order) -> Result<(), QueryError> {
+    pub async fn new_order(&self, order: Order) -> Result<(), Box<dyn Error>> {
         let s =
             r#"
             INSERT INTO keyspace_1.order_table (
@@ -92,10 +107,36 @@ impl ScyllaDb {
             ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);
         "#;
         let order = order.to_scylla_order();
-        self.session.query(s, order).await?;
+        self.session.query(s, &order).await?;
+        self.index_order(&order).await?;
         Ok(())
     }
-    pub async fn get_users_orders(&self, user_id: i64) -> Result<Vec<Order>, Box<dyn Error>> {
+    // Every market the user has placed orders in
+    pub async fn get_user_symbols(&self, user_id: i64) -> Result<Vec<Symbol>, Box<dyn Error>> {
+        let s = "SELECT symbol FROM keyspace_1.user_symbol_table WHERE user_id = ?;";
+        let res = self.session.query(s, (user_id,)).await?;
+        let symbols = res
+            .rows_typed::<(String,)>()?
+            .map(|row| row.map(|(symbol,)| symbol))
+            .collect::<Result<_, _>>()?;
+        Ok(symbols)
+    }
+    // Newest first, `page` is the `next_page` of the previous page. A page is read from
+    // order_by_user_table and its orders fetched by id, so it is never larger than
+    // MAX_ORDERS_PAGE_SIZE
+    pub async fn get_users_orders(
+        &self,
+        user_id: i64,
+        symbol: &str,
+        page_size: i32,
+        page: Option<String>
+    ) -> Result<OrderPage, Box<dyn Error>> {
+        let ids_s =
+            r#"
+            SELECT id
+            FROM keyspace_1.order_by_user_table
+            WHERE user_id = ? AND symbol = ?;
+        "#;
         let s =
             r#"
             SELECT
@@ -112,12 +153,28 @@ impl ScyllaDb {
                 order_status,
                 timestamp
             FROM keyspace_1.order_table
-            WHERE user_id = ? ALLOW FILTERING;
+            WHERE id IN ? AND symbol = ?;
         "#;
-        let res = self.session.query(s, (user_id,)).await?;
-        let mut orders = res.rows_typed::<ScyllaOrder>()?;
-        let orders: Vec<Order> = orders.map(|order| order.unwrap().from_scylla_order()).collect();
-        Ok(orders)
+        let paging_state = match page {
+            Some(page) => Some(Bytes::from(hex::decode(page)?)),
+            None => None,
+        };
+        let query = Query::new(ids_s).with_page_size(page_size.min(MAX_ORDERS_PAGE_SIZE));
+        let res = self.session.query_paged(query, (user_id, symbol), paging_state).await?;
+        let next_page = res.paging_state.as_ref().map(hex::encode);
+        let ids: Vec<OrderId> = res
+            .rows_typed::<(OrderId,)>()?
+            .map(|row| row.map(|(id,)| id))
+            .collect::<Result<_, _>>()?;
+        let mut orders: Vec<Order> = Vec::new();
+        if !ids.is_empty() {
+            let res = self.session.query(s, (&ids, symbol)).await?;
+            for order in res.rows_typed::<ScyllaOrder>()? {
+                orders.push(order?.from_scylla_order());
+            }
+        }
+        orders.sort_by_key(|order| std::cmp::Reverse((order.timestamp, order.id)));
+        Ok(OrderPage { orders, next_page })
     }
     pub async fn get_order(
         &self,
@@ -0,0 +1,218 @@
+use std::{ collections::{ BTreeMap, HashMap, VecDeque }, error::Error };
+
+use rust_decimal_macros::dec;
+
+use crate::db::{
+    schema::{
+        Exchange,
+        Fill,
+        Id,
+        MarketPnl,
+        OrderSide,
+        Portfolio,
+        PortfolioSnapshot,
+        Price,
+        Quantity,
+        Valuation,
+    },
+    scylla_tables::ScyllaPortfolioSnapshot,
+    ScyllaDb,
+};
+
+// One side of a spot trade as fill_table recorded it, bought quantities are positive
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpotFill {
+    pub trade_id: u64,
+    pub timestamp: u64,
+    pub base: String,
+    pub quote: String,
+    pub quantity: Quantity,
+    pub price: Price,
+}
+
+// Rows of a market's fill_table partition, perpetual fills are left out and both rows of a
+// trade with itself cancel out. Returns the fills oldest first.
+pub fn spot_fills(rows: &[Fill]) -> Vec<SpotFill> {
+    let mut rows_of_trade: HashMap<Id, usize> = HashMap::new();
+    for row in rows {
+        *rows_of_trade.entry(row.trade_id).or_default() += 1;
+    }
+    let mut fills: Vec<SpotFill> = rows
+        .iter()
+        .filter(|row| rows_of_trade[&row.trade_id] == 1)
+        .filter(|row| !row.symbol.ends_with("_PERP"))
+        .filter_map(|row| {
+            let exchange = Exchange::from_symbol(row.symbol.clone()).ok()?;
+            let quantity = match row.side {
+                OrderSide::Bid => row.quantity,
+                OrderSide::Ask => -row.quantity,
+            };
+            Some(SpotFill {
+                trade_id: row.trade_id as u64,
+                timestamp: row.timestamp as u64,
+                base: exchange.base.to_string(),
+                quote: exchange.quote.to_string(),
+                quantity,
+                price: row.price,
+            })
+        })
+        .collect();
+    fills.sort_by_key(|fill| (fill.timestamp, fill.trade_id));
+    fills
+}
+
+// Realized and unrealized PnL per market, first in first out. `prices` are by base asset.
+pub fn fifo_pnl(fills: &[SpotFill], prices: &HashMap<String, Price>) -> Vec<MarketPnl> {
+    let mut markets: BTreeMap<String, (VecDeque<(Quantity, Price)>, MarketPnl)> = BTreeMap::new();
+    for fill in fills {
+        let symbol = format!("{}_{}", fill.base, fill.quote);
+        let (lots, pnl) = markets.entry(symbol.clone()).or_insert_with(|| {
+            (VecDeque::new(), MarketPnl { symbol, ..Default::default() })
+        });
+        if fill.quantity > dec!(0) {
+            pnl.bought += fill.quantity;
+            lots.push_back((fill.quantity, fill.price));
+            continue;
+        }
+        pnl.sold -= fill.quantity;
+        let mut to_close = -fill.quantity;
+        while to_close > dec!(0) {
+            let Some((quantity, price)) = lots.front_mut() else {
+                break;
+            };
+            let closed = to_close.min(*quantity);
+            pnl.realized_pnl += closed * (fill.price - *price);
+            *quantity -= closed;
+            to_close -= closed;
+            if quantity.is_zero() {
+                lots.pop_front();
+            }
+        }
+    }
+    markets
+        .into_values()
+        .map(|(lots, mut pnl)| {
+            pnl.open_quantity = lots.iter().map(|(quantity, _)| *quantity).sum();
+            pnl.cost_basis = lots
+                .iter()
+                .map(|(quantity, price)| quantity * price)
+                .sum();
+            let base = pnl.symbol.split('_').next().unwrap_or_default();
+            pnl.unrealized_pnl = prices
+                .get(base)
+                .map(|price| pnl.open_quantity * price - pnl.cost_basis);
+            pnl
+        })
+        .collect()
+}
+
+impl Portfolio {
+    pub fn new(
+        valuation: Valuation,
+        fills: &[SpotFill],
+        history: Vec<PortfolioSnapshot>
+    ) -> Portfolio {
+        let prices: HashMap<String, Price> = valuation.holdings
+            .iter()
+            .filter_map(|holding| Some((holding.asset.to_string(), holding.price?)))
+            .collect();
+        let markets = fifo_pnl(fills, &prices);
+        let realized_pnl = markets
+            .iter()
+            .map(|market| market.realized_pnl)
+            .sum();
+        let unrealized_pnl = markets
+            .iter()
+            .filter_map(|market| market.unrealized_pnl)
+            .sum();
+        Portfolio { valuation, markets, realized_pnl, unrealized_pnl, history }
+    }
+}
+
+impl ScyllaDb {
+    pub async fn get_portfolio_history(
+        &self,
+        user_id: Id,
+        days: i32
+    ) -> Result<Vec<PortfolioSnapshot>, Box<dyn Error>> {
+        let s =
+            r#"
+            SELECT
+                day,
+                value,
+                timestamp
+            FROM keyspace_1.portfolio_table
+            WHERE user_id = ?
+            LIMIT ?;
+        "#;
+        let res = self.session.query(s, (user_id, days)).await?;
+        let snapshots = res.rows_typed::<ScyllaPortfolioSnapshot>()?;
+        let snapshots = snapshots
+            .map(|snapshot| {
+                let snapshot = snapshot?;
+                Ok(PortfolioSnapshot {
+                    day: snapshot.day,
+                    value: snapshot.value.0,
+                    timestamp: snapshot.timestamp,
+                })
+            })
+            .collect::<Result<Vec<PortfolioSnapshot>, Box<dyn Error>>>()?;
+        Ok(snapshots)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::schema::FillRole;
+
+    use super::*;
+
+    fn row(trade_id: Id, side: OrderSide, quantity: Quantity, price: Price) -> Fill {
+        Fill {
+            trade_id,
+            symbol: "SOL_USDT".to_string(),
+            role: FillRole::Taker,
+            order_id: trade_id,
+            counterparty_order_id: 0,
+            side,
+            price,
+            quantity,
+            quote_quantity: quantity * price,
+            fee: dec!(0),
+            fee_asset: "USDT".to_string(),
+            timestamp: trade_id,
+        }
+    }
+
+    #[test]
+    fn sells_realize_against_the_oldest_buys() {
+        // fill_table returns a market newest first
+        let mut rows = vec![
+            row(3, OrderSide::Ask, dec!(3), dec!(30)),
+            row(2, OrderSide::Bid, dec!(2), dec!(20)),
+            row(1, OrderSide::Bid, dec!(2), dec!(10))
+        ];
+        // a trade with itself moves nothing
+        rows.push(row(4, OrderSide::Bid, dec!(5), dec!(25)));
+        rows.push(row(4, OrderSide::Ask, dec!(5), dec!(25)));
+        let mut perp = row(5, OrderSide::Bid, dec!(1), dec!(25));
+        perp.symbol = "SOL_USDT_PERP".to_string();
+        rows.push(perp);
+        let fills = spot_fills(&rows);
+        assert_eq!(fills.len(), 3);
+        assert_eq!((fills[2].quantity, fills[2].price), (dec!(-3), dec!(30)));
+
+        let prices = HashMap::from([("SOL".to_string(), dec!(25))]);
+        let markets = fifo_pnl(&fills, &prices);
+        // 2 at 10 and 1 at 20 sold at 30, 1 at 20 still open
+        assert_eq!(markets, vec![MarketPnl {
+            symbol: "SOL_USDT".to_string(),
+            bought: dec!(4),
+            sold: dec!(3),
+            open_quantity: dec!(1),
+            cost_basis: dec!(20),
+            realized_pnl: dec!(50),
+            unrealized_pnl: Some(dec!(5)),
+        }]);
+    }
+}
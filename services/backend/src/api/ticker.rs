@@ -22,13 +22,13 @@ impl Ticker {
     fn to_scylla_ticker(&self) -> ScyllaTicker {
         ScyllaTicker {
             symbol: self.symbol.to_string(),
-            base_volume: self.base_volume.to_string(),
-            high_price: self.high_price.to_string(),
-            last_price: self.last_price.to_string(),
-            low_price: self.low_price.to_string(),
-            price_change: self.price_change.to_string(),
-            price_change_percent: self.price_change_percent.to_string(),
-            quote_volume: self.quote_volume.to_string(),
+            base_volume: self.base_volume.into(),
+            high_price: self.high_price.into(),
+            last_price: self.last_price.into(),
+            low_price: self.low_price.into(),
+            price_change: self.price_change.into(),
+            price_change_percent: self.price_change_percent.into(),
+            quote_volume: self.quote_volume.into(),
         }
     }
 }
@@ -36,13 +36,13 @@ impl ScyllaTicker {
     fn from_scylla_ticker(&self) -> Ticker {
         Ticker {
             symbol: self.symbol.to_string(),
-            base_volume: Decimal::from_str(&self.base_volume).unwrap(),
-            high_price: Decimal::from_str(&self.high_price).unwrap(),
-            last_price: Decimal::from_str(&self.last_price).unwrap(),
-            low_price: Decimal::from_str(&self.low_price).unwrap(),
-            price_change: Decimal::from_str(&self.price_change).unwrap(),
-            price_change_percent: Decimal::from_str(&self.price_change_percent).unwrap(),
-            quote_volume: Decimal::from_str(&self.quote_volume).unwrap(),
+            base_volume: self.base_volume.into(),
+            high_price: self.high_price.into(),
+            last_price: self.last_price.into(),
+            low_price: self.low_price.into(),
+            price_change: self.price_change.into(),
+            price_change_percent: self.price_change_percent.into(),
+            quote_volume: self.quote_volume.into(),
         }
     }
 }
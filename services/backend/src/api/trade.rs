@@ -3,6 +3,12 @@ use std::error::Error;
 use rust_decimal::prelude::*;
 use scylla::transport::errors::QueryError;
 
+use common::{
+    buckets::{ bucket, buckets, BUCKET },
+    query_tables::TRADE_BY_MARKET_STATEMENT,
+};
+use scylla::batch::Batch;
+
 use crate::db::{
     get_epoch_micros,
     schema::{ Price, Quantity, Symbol, Trade },
@@ -10,6 +16,9 @@ use crate::db::{
     ScyllaDb,
 };
 
+const RECENT_TRADES: usize = 500;
+const RECENT_TRADES_LOOKBACK: i64 = 30 * BUCKET;
+
 impl Trade {
     pub fn new(
         id: i64,
@@ -35,9 +44,9 @@ impl Trade {
             id: self.id,
             symbol: self.symbol.to_string(),
             is_buyer_maker: self.is_buyer_maker,
-            price: self.price.to_string(),
-            quantity: self.quantity.to_string(),
-            quote_quantity: self.quote_quantity.to_string(),
+            price: self.price.into(),
+            quantity: self.quantity.into(),
+            quote_quantity: self.quote_quantity.into(),
             timestamp: self.timestamp,
         }
     }
@@ -48,9 +57,9 @@ impl ScyllaTrade {
             id: self.id,
             symbol: self.symbol.to_string(),
             is_buyer_maker: self.is_buyer_maker,
-            price: Decimal::from_str(&self.price).unwrap(),
-            quantity: Decimal::from_str(&self.quantity).unwrap(),
-            quote_quantity: Decimal::from_str(&self.quote_quantity).unwrap(),
+            price: self.price.into(),
+            quantity: self.quantity.into(),
+            quote_quantity: self.quote_quantity.into(),
             timestamp: self.timestamp,
         }
     }
@@ -70,9 +79,27 @@ impl ScyllaDb {
             ) VALUES (?, ?, ?, ?, ?, ?, ?);
         "#;
         let trade = trade.to_scylla_trade();
-        self.session.query(s, trade).await?;
+        let mut batch: Batch = Default::default();
+        batch.append_statement(s);
+        batch.append_statement(TRADE_BY_MARKET_STATEMENT);
+        let prepared_batch: Batch = self.session.prepare_batch(&batch).await?;
+        let bucket = bucket(trade.timestamp);
+        self.session.batch(&prepared_batch, (
+            &trade,
+            (
+                trade.id,
+                &trade.symbol,
+                &trade.quantity,
+                &trade.quote_quantity,
+                trade.is_buyer_maker,
+                &trade.price,
+                trade.timestamp,
+                bucket,
+            ),
+        )).await?;
         Ok(())
     }
+    // The market's latest trades newest first, looking back a day at a time
     pub async fn get_trades(&self, symbol: Symbol) -> Result<Vec<Trade>, Box<dyn Error>> {
         let s =
             r#"
@@ -84,12 +111,22 @@ impl ScyllaDb {
                 is_buyer_maker,
                 price,
                 timestamp
-            FROM keyspace_1.trade_table
-            WHERE symbol = ? ALLOW FILTERING;
+            FROM keyspace_1.trade_by_market_table
+            WHERE symbol = ? AND bucket = ?
+            LIMIT ?;
         "#;
-        let res = self.session.query(s, (symbol,)).await?;
-        let mut trades = res.rows_typed::<ScyllaTrade>()?;
-        let trades: Vec<Trade> = trades.map(|trade| trade.unwrap().from_scylla_trade()).collect();
+        let now = get_epoch_micros() as i64;
+        let mut trades: Vec<Trade> = Vec::new();
+        for bucket in buckets(now - RECENT_TRADES_LOOKBACK, now).into_iter().rev() {
+            let limit = (RECENT_TRADES - trades.len()) as i32;
+            let res = self.session.query(s, (&symbol, bucket, limit)).await?;
+            for trade in res.rows_typed::<ScyllaTrade>()? {
+                trades.push(trade?.from_scylla_trade());
+            }
+            if trades.len() >= RECENT_TRADES {
+                break;
+            }
+        }
         Ok(trades)
     }
     pub async fn get_trade(&self, trade_id: i64, symbol: Symbol) -> Result<Trade, Box<dyn Error>> {
@@ -0,0 +1,62 @@
+use std::{ error::Error, str::FromStr };
+
+use crate::db::{
+    invalid_value,
+    schema::{ Asset, Id, TransferRecord, User },
+    scylla_tables::ScyllaTransfer,
+    ScyllaDb,
+};
+
+impl ScyllaTransfer {
+    fn to_transfer_record(&self) -> Result<TransferRecord, Box<dyn Error>> {
+        let invalid = |column: &str, value: &str| invalid_value("transfer_table", column, value);
+        Ok(TransferRecord {
+            id: self.id,
+            user_id: self.user_id,
+            counterparty_id: self.counterparty_id,
+            asset: Asset::from_str(&self.asset).ok_or_else(|| invalid("asset", &self.asset))?,
+            amount: self.amount.0,
+            timestamp: self.timestamp,
+        })
+    }
+}
+impl ScyllaDb {
+    // Newest transfers first, both the ones the user sent and received
+    pub async fn get_transfers(&self, user_id: Id) -> Result<Vec<TransferRecord>, Box<dyn Error>> {
+        let s =
+            r#"
+            SELECT
+                user_id,
+                id,
+                counterparty_id,
+                asset,
+                amount,
+                timestamp
+            FROM keyspace_1.transfer_table
+            WHERE user_id = ?;
+        "#;
+        let res = self.session.query(s, (user_id,)).await?;
+        let transfers = res.rows_typed::<ScyllaTransfer>()?;
+        let transfers = transfers
+            .map(|transfer| transfer?.to_transfer_record())
+            .collect::<Result<Vec<TransferRecord>, Box<dyn Error>>>()?;
+        Ok(transfers)
+    }
+    pub async fn get_sub_accounts(&self, master_id: Id) -> Result<Vec<User>, Box<dyn Error>> {
+        let s =
+            r#"
+            SELECT id
+            FROM keyspace_1.sub_account_table
+            WHERE master_id = ?;
+        "#;
+        let res = self.session.query(s, (master_id,)).await?;
+        let mut sub_accounts = Vec::new();
+        for row in res.rows_typed::<(i64,)>()? {
+            let (id,) = row?;
+            let mut sub_account = self.get_user(id).await?;
+            sub_account.master_id = Some(master_id);
+            sub_accounts.push(sub_account);
+        }
+        Ok(sub_accounts)
+    }
+}
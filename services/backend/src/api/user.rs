@@ -5,6 +5,8 @@ use rust_decimal_macros::dec;
 use scylla::transport::errors::QueryError;
 use strum::IntoEnumIterator;
 
+use common::numeric::Numeric;
+
 use crate::db::{ schema::{ Asset, Id, Quantity, User }, scylla_tables::ScyllaUser, ScyllaDb };
 
 #[derive(Debug)]
@@ -19,13 +21,13 @@ impl ScyllaUser {
         let mut balance_map: HashMap<Asset, Quantity> = HashMap::new();
         for (asset_str, balance) in &self.balance {
             let asset = Asset::from_str(&asset_str).unwrap();
-            let balance = Decimal::from_str(&balance).unwrap();
+            let balance = balance.0;
             balance_map.insert(asset, balance);
         }
         let mut locked_balance_map: HashMap<Asset, Quantity> = HashMap::new();
         for (asset_str, locked_balance) in &self.locked_balance {
             let asset = Asset::from_str(&asset_str).unwrap();
-            let locked_balance = Decimal::from_str(&locked_balance).unwrap();
+            let locked_balance = locked_balance.0;
             locked_balance_map.insert(asset, locked_balance);
         }
 
@@ -33,6 +35,8 @@ impl ScyllaUser {
             id: self.id,
             balance: balance_map,
             locked_balance: locked_balance_map,
+            master_id: None,
+            frozen: false,
         }
     }
 }
@@ -50,6 +54,8 @@ impl User {
             id,
             balance,
             locked_balance,
+            master_id: None,
+            frozen: false,
         }
     }
     pub fn lock_amount(&mut self, asset: &Asset, quantity: Quantity) {
@@ -101,13 +107,13 @@ impl User {
         Ok(balance - locked_balance)
     }
     pub fn to_scylla_user(&self) -> ScyllaUser {
-        let mut scylla_balance: HashMap<String, String> = HashMap::new();
+        let mut scylla_balance: HashMap<String, Numeric> = HashMap::new();
         for (asset, balance) in &self.balance {
-            scylla_balance.insert(asset.to_string(), balance.to_string());
+            scylla_balance.insert(asset.to_string(), Numeric(*balance));
         }
-        let mut scylla_locked_balance: HashMap<String, String> = HashMap::new();
+        let mut scylla_locked_balance: HashMap<String, Numeric> = HashMap::new();
         for (asset, balance) in &self.locked_balance {
-            scylla_locked_balance.insert(asset.to_string(), balance.to_string());
+            scylla_locked_balance.insert(asset.to_string(), Numeric(*balance));
         }
         ScyllaUser {
             id: self.id,
@@ -0,0 +1,55 @@
+use std::{ error::Error, str::FromStr };
+
+use crate::db::{
+    invalid_value,
+    schema::{ Asset, Id, Withdrawal, WithdrawalState },
+    scylla_tables::ScyllaWithdrawal,
+    ScyllaDb,
+};
+
+impl ScyllaWithdrawal {
+    fn to_withdrawal(&self) -> Result<Withdrawal, Box<dyn Error>> {
+        let invalid = |column: &str, value: &str| invalid_value("withdrawal_table", column, value);
+        Ok(Withdrawal {
+            id: self.id,
+            user_id: self.user_id,
+            asset: Asset::from_str(&self.asset).ok_or_else(|| invalid("asset", &self.asset))?,
+            quantity: self.quantity.0,
+            destination: self.destination.clone(),
+            state: WithdrawalState::from_str(&self.state).map_err(|_| {
+                invalid("state", &self.state)
+            })?,
+            tx_id: self.tx_id.clone(),
+            reason: self.reason.clone(),
+            requested_at: self.requested_at,
+            updated_at: self.updated_at,
+        })
+    }
+}
+impl ScyllaDb {
+    // Newest withdrawals first
+    pub async fn get_withdrawals(&self, user_id: Id) -> Result<Vec<Withdrawal>, Box<dyn Error>> {
+        let s =
+            r#"
+            SELECT
+                id,
+                user_id,
+                asset,
+                quantity,
+                destination,
+                state,
+                tx_id,
+                reason,
+                requested_at,
+                updated_at
+            FROM keyspace_1.withdrawal_table
+            WHERE user_id = ?;
+        "#;
+        let res = self.session.query(s, (user_id,)).await?;
+        let withdrawals = res.rows_typed::<ScyllaWithdrawal>()?;
+        let withdrawals = withdrawals
+            .map(|withdrawal| withdrawal?.to_withdrawal())
+            .collect::<Result<Vec<Withdrawal>, Box<dyn Error>>>()?;
+        Ok(withdrawals)
+    }
+}
@@ -1,15 +1,22 @@
 use std::{ net::TcpListener, sync::Mutex };
 
-use actix_web::{ web::{ self, scope }, App, HttpServer };
+use actix_web::{ middleware::from_fn, web::{ self, scope, ServiceConfig }, App, HttpServer };
 use redis::{ Connection, PubSub };
 
 use crate::{
+    auth::authenticate,
+    rate_limit::{ limit_ip, limit_key, RateLimiter, RateLimits },
     db::ScyllaDb,
     routes::{
+        admin::*,
+        api_key::*,
+        listen_key::*,
+        margin::*,
         order::*,
         ping::ping,
         trades::trades,
         user::*,
+        withdrawal::*,
     },
 };
 
@@ -40,22 +47,40 @@ pub struct AppState {
     pub scylla_db: Mutex<ScyllaDb>,
     pub redis_connection: Mutex<Connection>,
     pub reqwest: Mutex<reqwest::Client>,
+    pub rate_limiter: RateLimiter,
+}
+impl AppState {
+    // A handle of its own, so a request never holds the lock while it waits on scylla
+    pub fn scylla_db(&self) -> ScyllaDb {
+        self.scylla_db.lock().unwrap().clone()
+    }
+}
+// The mock custody route is only served by builds with the `mock-custody` feature
+fn custody_routes(cfg: &mut ServiceConfig) {
+    #[cfg(feature = "mock-custody")]
+    cfg.service(scope("/custody").service(crate::routes::custody::report_transfer)); // /transfers
 }
 async fn run<'a>(listener: TcpListener) -> Result<actix_web::dev::Server, std::io::Error> {
     let uri = "127.0.0.1";
     let redis_uri = "redis://127.0.0.1:6379";
     let mut redis_connection = connect_redis(&redis_uri);
     let scylla_db = ScyllaDb::create_session(uri).await.unwrap();
-    scylla_db.initialize().await.unwrap();
+    // Refuses to serve a schema the code does not match
+    scylla_db.initialize().await.map_err(|err| std::io::Error::other(err.to_string()))?;
     let app_state = web::Data::new(AppState {
         scylla_db: Mutex::new(scylla_db),
         redis_connection: Mutex::new(redis_connection),
         reqwest: Mutex::new(reqwest::Client::new()),
+        rate_limiter: RateLimiter::new(RateLimits::load()),
     });
     let server = HttpServer::new(move || {
         App::new().service(
             scope("/api/v1")
                 .app_data(app_state.clone())
+                // The last one wrapped runs first: address, signature, then key
+                .wrap(from_fn(limit_key))
+                .wrap(from_fn(authenticate))
+                .wrap(from_fn(limit_ip))
                 .service(ping)
                 .service(execute_order)
                 .service(get_open_order)
@@ -66,10 +91,53 @@ async fn run<'a>(listener: TcpListener) -> Result<actix_web::dev::Server, std::i
                 .service(
                     scope("/user")
                         .service(new_user) // /new
-                        .service(get_user) // ?id
-                        .service(deposit) // /deposit
+                        .service(get_user)
                         .service(withdraw) // /withdraw
                         .service(orders_history) // /orders
+                        .service(ledger) // /ledger?limit&page
+                        .service(user_trades) // /trades?symbol&limit&page
+                        .service(withdrawals) // /withdrawals
+                        .service(deposits) // /deposits
+                        .service(new_sub_account) // /sub-accounts
+                        .service(sub_accounts) // /sub-accounts
+                        .service(transfer) // /transfer
+                        .service(transfers) // /transfers
+                        .service(new_api_key) // /api-keys
+                        .service(api_keys) // /api-keys
+                        .service(delete_api_key) // /api-keys/{key}
+                        .service(new_listen_key) // /listen-key
+                        .service(renew_listen_key) // /listen-key
+                        .service(delete_listen_key) // /listen-key
+                        .service(margin_account) // /margin
+                        .service(set_margin_mode) // /margin
+                        .service(borrow) // /margin/borrow
+                        .service(repay) // /margin/repay
+                        .service(positions) // /positions
+                        .service(portfolio) // /portfolio
+                )
+                .service(
+                    scope("/admin")
+                        .service(market_states) // /markets
+                        .service(market_state) // /markets/{symbol}
+                        .service(halt_market) // /markets/{symbol}/halt
+                        .service(resume_market) // /markets/{symbol}/resume
+                        .service(set_price_band) // /markets/{symbol}/price-band
+                        .service(user_state) // /users/{id}
+                        .service(cancel_user_orders) // /users/{id}/cancel-orders
+                        .service(adjust_balance) // /users/{id}/adjust-balance
+                        .service(freeze_user) // /users/{id}/freeze
+                        .service(unfreeze_user) // /users/{id}/unfreeze
+                        .service(risk_limits) // /users/{id}/risk-limits
+                        .service(set_risk_limits) // /users/{id}/risk-limits
+                        .service(audit_log) // /audit?day
+                        .service(
+                            scope("/withdrawals")
+                                .service(approve_withdrawal) // /{id}/approve
+                                .service(sent_withdrawal) // /{id}/sent
+                                .service(complete_withdrawal) // /{id}/complete
+                                .service(fail_withdrawal) // /{id}/fail
+                        )
+                        .configure(custody_routes)
                 )
         )
     })
@@ -0,0 +1,387 @@
+use std::{ collections::HashSet, str::FromStr };
+
+use actix_web::{
+    body::MessageBody,
+    dev::{ ServiceRequest, ServiceResponse },
+    error::{ ErrorForbidden, ErrorInternalServerError, ErrorUnauthorized },
+    http::Method,
+    middleware::Next,
+    web::{ Bytes, Data },
+    Error,
+    HttpMessage,
+};
+use enum_stringify::EnumStringify;
+use ring::{ hmac, rand::{ SecureRandom, SystemRandom } };
+use serde::{ Deserialize, Serialize };
+
+use crate::{ app::AppState, db::{ get_epoch_micros, schema::Id } };
+
+// Signed requests carry the key, the time they were signed in milliseconds and the hex
+// HMAC-SHA256 of `timestamp + method + path and query + body` under the key's secret. They are
+// refused once `recv window` milliseconds old, or when signed more than a second ahead.
+pub const API_KEY_HEADER: &str = "X-API-KEY";
+pub const TIMESTAMP_HEADER: &str = "X-TIMESTAMP";
+pub const SIGNATURE_HEADER: &str = "X-SIGNATURE";
+pub const RECV_WINDOW_HEADER: &str = "X-RECV-WINDOW";
+pub const DEFAULT_RECV_WINDOW: u64 = 5000;
+pub const MAX_RECV_WINDOW: u64 = 60000;
+const MAX_CLOCK_AHEAD: u64 = 1000;
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+    EnumStringify
+)]
+pub enum Permission {
+    Read,
+    Trade,
+    Withdraw,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub key: String,
+    pub secret: String,
+    pub user_id: Id,
+    pub permissions: HashSet<Permission>,
+    pub allowed_ips: HashSet<String>,
+    pub created_at: i64,
+}
+impl ApiKey {
+    pub fn generate(
+        user_id: Id,
+        permissions: HashSet<Permission>,
+        allowed_ips: HashSet<String>
+    ) -> ApiKey {
+        ApiKey {
+            key: random_hex(16),
+            secret: random_hex(32),
+            user_id,
+            permissions,
+            allowed_ips,
+            created_at: get_epoch_micros() as i64,
+        }
+    }
+    pub fn all_permissions(user_id: Id) -> ApiKey {
+        let permissions = HashSet::from([
+            Permission::Read,
+            Permission::Trade,
+            Permission::Withdraw,
+        ]);
+        ApiKey::generate(user_id, permissions, HashSet::new())
+    }
+}
+pub fn random_hex(len: usize) -> String {
+    let mut bytes = vec![0; len];
+    SystemRandom::new().fill(&mut bytes).expect("Could not generate random bytes");
+    hex::encode(bytes)
+}
+
+// Who signed the request, handlers take the user from here instead of from the request
+#[derive(Debug, Clone)]
+pub struct Authenticated {
+    pub user_id: Id,
+    pub permissions: HashSet<Permission>,
+}
+
+// Operators sign admin requests the same way with keys of their own, which grant roles instead
+// of permissions. Viewers inspect, operators act on markets and accounts, treasury moves funds.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+    EnumStringify
+)]
+pub enum AdminRole {
+    Viewer,
+    Operator,
+    Treasury,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminKey {
+    pub key: String,
+    pub secret: String,
+    // Who the key belongs to, recorded with every action taken with it
+    pub name: String,
+    pub roles: HashSet<AdminRole>,
+    pub allowed_ips: HashSet<String>,
+    pub created_at: i64,
+}
+impl AdminKey {
+    pub fn generate(
+        name: String,
+        roles: HashSet<AdminRole>,
+        allowed_ips: HashSet<String>
+    ) -> AdminKey {
+        AdminKey {
+            key: random_hex(16),
+            secret: random_hex(32),
+            name,
+            roles,
+            allowed_ips,
+            created_at: get_epoch_micros() as i64,
+        }
+    }
+}
+#[derive(Debug, Clone)]
+pub struct Admin {
+    pub name: String,
+    pub roles: HashSet<AdminRole>,
+}
+
+pub fn sign(secret: &str, payload: &[u8]) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    hex::encode(hmac::sign(&key, payload).as_ref())
+}
+pub fn verify(secret: &str, payload: &[u8], signature: &str) -> bool {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    match hex::decode(signature) {
+        Ok(signature) => hmac::verify(&key, payload, &signature).is_ok(),
+        Err(_) => false,
+    }
+}
+pub fn signature_payload(
+    timestamp: &str,
+    method: &str,
+    path_and_query: &str,
+    body: &[u8]
+) -> Vec<u8> {
+    let mut payload = format!("{}{}{}", timestamp, method, path_and_query).into_bytes();
+    payload.extend_from_slice(body);
+    payload
+}
+pub fn within_recv_window(timestamp: u64, recv_window: u64, now: u64) -> bool {
+    timestamp <= now + MAX_CLOCK_AHEAD && now.saturating_sub(timestamp) <= recv_window
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Access {
+    // Market data and signing up
+    Public,
+    User(Permission),
+    Admin(AdminRole),
+}
+pub fn required_access(method: &Method, path: &str) -> Access {
+    let path = path.trim_start_matches("/api/v1");
+    if let Some(path) = path.strip_prefix("/admin") {
+        return Access::Admin(required_role(method, path));
+    }
+    // Routes that move funds for the exchange are only ever reached with an admin key, even
+    // when mounted outside of the admin scope
+    if moves_funds(path) {
+        return Access::Admin(required_role(method, path));
+    }
+    match (method, path) {
+        (&Method::GET, "/ping") | (&Method::GET, "/trades") | (&Method::POST, "/user/new") => {
+            Access::Public
+        }
+        (&Method::POST, "/user/withdraw") | (&Method::POST, "/user/transfer") => {
+            Access::User(Permission::Withdraw)
+        }
+        // Listen keys only ever stream what the key could read
+        (_, "/user/listen-key") => Access::User(Permission::Read),
+        (&Method::GET, _) => Access::User(Permission::Read),
+        _ => Access::User(Permission::Trade),
+    }
+}
+fn moves_funds(path: &str) -> bool {
+    path.starts_with("/withdrawals/") ||
+        path.starts_with("/custody/") ||
+        path.ends_with("/adjust-balance")
+}
+fn required_role(method: &Method, path: &str) -> AdminRole {
+    match method {
+        &Method::GET => AdminRole::Viewer,
+        _ if moves_funds(path) => AdminRole::Treasury,
+        _ => AdminRole::Operator,
+    }
+}
+
+fn header<'a>(req: &'a ServiceRequest, name: &str) -> Result<&'a str, Error> {
+    req.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .ok_or_else(|| ErrorUnauthorized(format!("Missing {} header", name)))
+}
+
+// Checks the signature against the secret of the key, putting the body back for the handler
+async fn verify_signature(
+    req: &mut ServiceRequest,
+    timestamp: &str,
+    signature: &str,
+    secret: &str
+) -> Result<(), Error> {
+    let body = req.extract::<Bytes>().await?;
+    let path_and_query = req
+        .uri()
+        .path_and_query()
+        .map(|path_and_query| path_and_query.as_str())
+        .unwrap_or(req.path());
+    let payload = signature_payload(timestamp, req.method().as_str(), path_and_query, &body);
+    if !verify(secret, &payload, signature) {
+        return Err(ErrorUnauthorized("Invalid signature"));
+    }
+    req.set_payload(body.into());
+    Ok(())
+}
+// Any address may use a key without an allow-list
+pub fn ip_allowed(allowed_ips: &HashSet<String>, ip: Option<&str>) -> bool {
+    allowed_ips.is_empty() || ip.is_some_and(|ip| allowed_ips.contains(ip))
+}
+fn check_ip(req: &ServiceRequest, allowed_ips: &HashSet<String>) -> Result<(), Error> {
+    let ip = req.peer_addr().map(|addr| addr.ip().to_string());
+    match ip_allowed(allowed_ips, ip.as_deref()) {
+        true => Ok(()),
+        false => Err(ErrorForbidden("IP address is not allowed for this key")),
+    }
+}
+
+pub async fn authenticate(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let access = required_access(req.method(), req.path());
+    if access == Access::Public {
+        return next.call(req).await;
+    }
+    let key = header(&req, API_KEY_HEADER)?.to_string();
+    let timestamp = header(&req, TIMESTAMP_HEADER)?.to_string();
+    let signature = header(&req, SIGNATURE_HEADER)?.to_string();
+    let recv_window = match req.headers().get(RECV_WINDOW_HEADER) {
+        Some(value) =>
+            value
+                .to_str()
+                .ok()
+                .and_then(|value| u64::from_str(value).ok())
+                .filter(|window| *window <= MAX_RECV_WINDOW)
+                .ok_or_else(|| ErrorUnauthorized("Invalid recv window"))?,
+        None => DEFAULT_RECV_WINDOW,
+    };
+    let signed_at = u64::from_str(&timestamp).map_err(|_| ErrorUnauthorized("Invalid timestamp"))?;
+    let now = get_epoch_micros() / 1000;
+    if !within_recv_window(signed_at, recv_window, now as u64) {
+        return Err(ErrorUnauthorized("Request is outside of the recv window"));
+    }
+    let app_state = req
+        .app_data::<Data<AppState>>()
+        .cloned()
+        .ok_or_else(|| ErrorInternalServerError("Missing app state"))?;
+    match access {
+        Access::User(permission) => {
+            let api_key = app_state
+                .scylla_db()
+                .get_api_key(&key).await
+                .map_err(|_| ErrorUnauthorized("Invalid API key"))?;
+            verify_signature(&mut req, &timestamp, &signature, &api_key.secret).await?;
+            check_ip(&req, &api_key.allowed_ips)?;
+            if !api_key.permissions.contains(&permission) {
+                return Err(ErrorForbidden(format!("Key is missing the {} permission", permission)));
+            }
+            req.extensions_mut().insert(Authenticated {
+                user_id: api_key.user_id,
+                permissions: api_key.permissions,
+            });
+        }
+        Access::Admin(role) => {
+            let admin_key = app_state
+                .scylla_db()
+                .get_admin_key(&key).await
+                .map_err(|_| ErrorUnauthorized("Invalid admin key"))?;
+            verify_signature(&mut req, &timestamp, &signature, &admin_key.secret).await?;
+            check_ip(&req, &admin_key.allowed_ips)?;
+            if !admin_key.roles.contains(&role) {
+                return Err(ErrorForbidden(format!("Key is missing the {} role", role)));
+            }
+            req.extensions_mut().insert(Admin { name: admin_key.name, roles: admin_key.roles });
+        }
+        Access::Public => {}
+    }
+    next.call(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signatures_cover_the_whole_request() {
+        let payload = signature_payload("1700000000000", "POST", "/api/v1/order", b"{\"a\":1}");
+        let signature = sign("secret", &payload);
+        assert!(verify("secret", &payload, &signature));
+        assert!(!verify("other secret", &payload, &signature));
+        let tampered = signature_payload("1700000000000", "POST", "/api/v1/order", b"{\"a\":2}");
+        assert!(!verify("secret", &tampered, &signature));
+        assert!(!verify("secret", &payload, "not hex"));
+    }
+    #[test]
+    fn recv_window_bounds_the_timestamp() {
+        let now = 1_000_000;
+        assert!(within_recv_window(now - DEFAULT_RECV_WINDOW, DEFAULT_RECV_WINDOW, now));
+        assert!(!within_recv_window(now - DEFAULT_RECV_WINDOW - 1, DEFAULT_RECV_WINDOW, now));
+        assert!(within_recv_window(now + MAX_CLOCK_AHEAD, DEFAULT_RECV_WINDOW, now));
+        assert!(!within_recv_window(now + MAX_CLOCK_AHEAD + 1, DEFAULT_RECV_WINDOW, now));
+    }
+    #[test]
+    fn routes_need_the_permission_of_what_they_do() {
+        assert_eq!(required_access(&Method::GET, "/api/v1/trades"), Access::Public);
+        assert_eq!(required_access(&Method::POST, "/api/v1/user/new"), Access::Public);
+        let read = required_access(&Method::GET, "/api/v1/user/ledger");
+        assert_eq!(read, Access::User(Permission::Read));
+        // a user's own trades are private, unlike the trades of a market
+        let user_trades = required_access(&Method::GET, "/api/v1/user/trades");
+        assert_eq!(user_trades, Access::User(Permission::Read));
+        let trade = required_access(&Method::POST, "/api/v1/order");
+        assert_eq!(trade, Access::User(Permission::Trade));
+        let withdraw = required_access(&Method::POST, "/api/v1/user/withdraw");
+        assert_eq!(withdraw, Access::User(Permission::Withdraw));
+        let listen_key = required_access(&Method::POST, "/api/v1/user/listen-key");
+        assert_eq!(listen_key, Access::User(Permission::Read));
+    }
+    #[test]
+    fn admin_routes_need_the_role_of_what_they_do() {
+        let inspect = required_access(&Method::GET, "/api/v1/admin/markets");
+        assert_eq!(inspect, Access::Admin(AdminRole::Viewer));
+        let halt = required_access(&Method::POST, "/api/v1/admin/markets/SOL_USDT/halt");
+        assert_eq!(halt, Access::Admin(AdminRole::Operator));
+        let adjust = required_access(&Method::POST, "/api/v1/admin/users/1/adjust-balance");
+        assert_eq!(adjust, Access::Admin(AdminRole::Treasury));
+        let approve = required_access(&Method::POST, "/api/v1/admin/withdrawals/1/approve");
+        assert_eq!(approve, Access::Admin(AdminRole::Treasury));
+        let report = required_access(&Method::POST, "/api/v1/admin/custody/transfers");
+        assert_eq!(report, Access::Admin(AdminRole::Treasury));
+    }
+    #[test]
+    fn fund_moving_routes_are_never_public() {
+        let complete = required_access(&Method::POST, "/api/v1/withdrawals/1/complete");
+        assert_eq!(complete, Access::Admin(AdminRole::Treasury));
+        let report = required_access(&Method::POST, "/api/v1/custody/transfers");
+        assert_eq!(report, Access::Admin(AdminRole::Treasury));
+        let adjust = required_access(&Method::POST, "/api/v1/users/1/adjust-balance");
+        assert_eq!(adjust, Access::Admin(AdminRole::Treasury));
+    }
+    #[test]
+    fn keys_only_allow_listed_addresses() {
+        let mut key = ApiKey::all_permissions(1);
+        assert!(ip_allowed(&key.allowed_ips, Some("10.0.0.1")));
+        key.allowed_ips.insert("10.0.0.2".to_string());
+        assert!(!ip_allowed(&key.allowed_ips, Some("10.0.0.1")));
+        assert!(ip_allowed(&key.allowed_ips, Some("10.0.0.2")));
+        assert!(!ip_allowed(&key.allowed_ips, None));
+        assert_ne!(key.key, ApiKey::all_permissions(1).key);
+    }
+}
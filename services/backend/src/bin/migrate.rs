@@ -0,0 +1,28 @@
+use std::{ env, process };
+
+use backend::db::{ migrations::MIGRATIONS, ScyllaDb };
+
+const USAGE: &str =
+    "Usage: migrate
+Creates the tables that do not exist yet and applies the schema migrations that were not.
+Stop the engine and the db-filler first, tables are rewritten while they are converted.";
+
+#[tokio::main]
+async fn main() {
+    if env::args().len() > 1 {
+        eprintln!("{}", USAGE);
+        process::exit(1);
+    }
+    let scylla_db = ScyllaDb::create_session("127.0.0.1").await.unwrap();
+    scylla_db.create_tables().await.unwrap();
+    let versions = scylla_db.migrate().await.unwrap_or_else(|err| {
+        eprintln!("Migration failed, run it again to pick up where it stopped: {}", err);
+        process::exit(1);
+    });
+    if versions.is_empty() {
+        println!("Nothing to migrate");
+    }
+    for migration in MIGRATIONS.iter().filter(|m| versions.contains(&m.version)) {
+        println!("Applied {}: {}", migration.version, migration.description);
+    }
+}
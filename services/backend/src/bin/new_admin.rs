@@ -0,0 +1,38 @@
+use std::{ collections::HashSet, env, process, str::FromStr };
+
+use backend::{ auth::{ AdminKey, AdminRole }, db::ScyllaDb };
+
+const USAGE: &str =
+    "Usage: new_admin <NAME> <ROLE>...
+Creates an admin key and prints it with its secret, which is not shown again.
+
+    ROLE                Viewer, Operator or Treasury";
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (name, roles) = match args.split_first() {
+        Some((name, roles)) if !roles.is_empty() => (name.clone(), roles),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(1);
+        }
+    };
+    let roles: HashSet<AdminRole> = roles
+        .iter()
+        .map(|role| {
+            AdminRole::from_str(role).unwrap_or_else(|_| {
+                eprintln!("Unknown role {}\n{}", role, USAGE);
+                process::exit(1);
+            })
+        })
+        .collect();
+    let scylla_db = ScyllaDb::create_session("127.0.0.1").await.unwrap();
+    scylla_db.initialize().await.unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    });
+    let admin_key = AdminKey::generate(name, roles, HashSet::new());
+    scylla_db.new_admin_key(&admin_key).await.unwrap();
+    println!("{}", serde_json::to_string_pretty(&admin_key).unwrap());
+}
@@ -0,0 +1,340 @@
+use std::collections::HashSet;
+
+use bytes::Bytes;
+use common::{
+    buckets::bucket,
+    query_tables::{
+        CANCEL_ORDER_BY_MARKET_STATEMENT,
+        ORDER_BY_MARKET_STATEMENT,
+        ORDER_BY_USER_STATEMENT,
+        TRADE_BY_MARKET_STATEMENT,
+        USER_SYMBOL_STATEMENT,
+    },
+};
+use scylla::{ batch::Batch, frame::response::result::CqlValue, query::Query };
+
+use crate::result::Result;
+
+use super::{
+    get_epoch_micros,
+    native_decimals::DECIMAL_TABLES,
+    scylla_tables::ScyllaOrder,
+    ScyllaDb,
+};
+
+// Schema changes made after the tables `initialize` creates. Each one is applied once, in order
+// of version, and recorded in `schema_migration_table`. Statements and backfills must be safe to
+// run again, a migration that fails part way is applied from the start on the next run.
+pub struct Migration {
+    pub version: i32,
+    pub description: &'static str,
+    pub statements: &'static [&'static str],
+    pub backfill: Option<Backfill>,
+}
+// Work a migration does on what was written before it, beyond its statements
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backfill {
+    NativeDecimals,
+    QueryTables,
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Prices, quantities and balances as decimals instead of text",
+        statements: &[],
+        backfill: Some(Backfill::NativeDecimals),
+    },
+    Migration {
+        version: 2,
+        description: "Orders by user and market, and orders, cancels and trades by market and day",
+        statements: &[
+            r#"
+            CREATE TABLE IF NOT EXISTS keyspace_1.order_by_user_table (
+                user_id bigint,
+                symbol text,
+                timestamp bigint,
+                id bigint,
+                PRIMARY KEY ((user_id, symbol), timestamp, id)
+            ) WITH CLUSTERING ORDER BY (timestamp DESC, id DESC);
+            "#,
+            r#"
+            CREATE TABLE IF NOT EXISTS keyspace_1.user_symbol_table (
+                user_id bigint,
+                symbol text,
+                PRIMARY KEY (user_id, symbol)
+            );
+            "#,
+            r#"
+            CREATE TABLE IF NOT EXISTS keyspace_1.order_by_market_table (
+                symbol text,
+                bucket bigint,
+                timestamp bigint,
+                id bigint,
+                user_id bigint,
+                price decimal,
+                initial_quantity decimal,
+                filled_quantity decimal,
+                quote_quantity decimal,
+                filled_quote_quantity decimal,
+                order_type text,
+                order_side text,
+                order_status text,
+                PRIMARY KEY ((symbol, bucket), timestamp, id)
+            ) WITH CLUSTERING ORDER BY (timestamp ASC, id ASC);
+            "#,
+            r#"
+            CREATE TABLE IF NOT EXISTS keyspace_1.cancel_order_by_market_table (
+                symbol text,
+                bucket bigint,
+                timestamp bigint,
+                id bigint,
+                user_id bigint,
+                order_side text,
+                price decimal,
+                PRIMARY KEY ((symbol, bucket), timestamp, id)
+            ) WITH CLUSTERING ORDER BY (timestamp ASC, id ASC);
+            "#,
+            r#"
+            CREATE TABLE IF NOT EXISTS keyspace_1.trade_by_market_table (
+                symbol text,
+                bucket bigint,
+                timestamp bigint,
+                id bigint,
+                quantity decimal,
+                quote_quantity decimal,
+                is_buyer_maker boolean,
+                price decimal,
+                PRIMARY KEY ((symbol, bucket), timestamp, id)
+            ) WITH CLUSTERING ORDER BY (timestamp DESC, id DESC);
+            "#,
+        ],
+        backfill: Some(Backfill::QueryTables),
+    },
+];
+
+const BACKFILL_PAGE_SIZE: i32 = 1000;
+
+impl ScyllaDb {
+    // Versions of the migrations not recorded yet
+    pub async fn pending_migrations(&self) -> Result<Vec<i32>> {
+        self.create_schema_migration_table().await?;
+        let applied = self.applied_migrations().await?;
+        let pending = MIGRATIONS
+            .iter()
+            .map(|migration| migration.version)
+            .filter(|version| !applied.contains(version))
+            .collect();
+        Ok(pending)
+    }
+    // Applies the migrations not recorded yet and returns their versions
+    pub async fn migrate(&self) -> Result<Vec<i32>> {
+        let pending = self.pending_migrations().await?;
+        let mut versions = Vec::new();
+        for migration in MIGRATIONS.iter().filter(|m| pending.contains(&m.version)) {
+            for statement in migration.statements {
+                self.session.query(*statement, &[]).await?;
+            }
+            if let Some(backfill) = migration.backfill {
+                self.backfill(backfill).await?;
+            }
+            self.record_migration(migration).await?;
+            versions.push(migration.version);
+        }
+        Ok(versions)
+    }
+    async fn create_schema_migration_table(&self) -> Result<()> {
+        let create_schema_migration_table: &str =
+            r#"
+        CREATE TABLE IF NOT EXISTS keyspace_1.schema_migration_table (
+            version int PRIMARY KEY,
+            description text,
+            applied_at bigint
+        );
+      "#;
+        self.session.query(create_schema_migration_table, &[]).await?;
+        Ok(())
+    }
+    async fn applied_migrations(&self) -> Result<HashSet<i32>> {
+        let s = "SELECT version FROM keyspace_1.schema_migration_table;";
+        let res = self.session.query(s, &[]).await?;
+        let versions = res.rows_typed::<(i32,)>()?;
+        let mut applied = HashSet::new();
+        for version in versions {
+            applied.insert(version?.0);
+        }
+        Ok(applied)
+    }
+    async fn record_migration(&self, migration: &Migration) -> Result<()> {
+        let s =
+            r#"
+            INSERT INTO keyspace_1.schema_migration_table (
+                version,
+                description,
+                applied_at
+            ) VALUES (?, ?, ?);
+        "#;
+        self.session.query(s, (
+            migration.version,
+            migration.description,
+            get_epoch_micros() as i64,
+        )).await?;
+        Ok(())
+    }
+    async fn backfill(&self, backfill: Backfill) -> Result<()> {
+        match backfill {
+            Backfill::NativeDecimals => {
+                self.convert_decimals(DECIMAL_TABLES).await?;
+            }
+            Backfill::QueryTables => {
+                self.backfill_orders().await?;
+                self.backfill_cancel_orders().await?;
+                self.backfill_trades().await?;
+            }
+        }
+        Ok(())
+    }
+    // Rows are copied as they are stored, whatever the types of their columns at the time
+    async fn backfill_rows(
+        &self,
+        select: &str,
+        copy: impl Fn(Vec<Option<CqlValue>>) -> Vec<(&'static str, Vec<Option<CqlValue>>)>
+    ) -> Result<()> {
+        let mut paging_state: Option<Bytes> = None;
+        loop {
+            let query = Query::new(select).with_page_size(BACKFILL_PAGE_SIZE);
+            let res = self.session.query_paged(query, &[], paging_state).await?;
+            paging_state = res.paging_state.clone();
+            for row in res.rows.unwrap_or_default() {
+                for (statement, values) in copy(row.columns) {
+                    self.session.query(statement, values).await?;
+                }
+            }
+            if paging_state.is_none() {
+                return Ok(());
+            }
+        }
+    }
+    async fn backfill_orders(&self) -> Result<()> {
+        let s =
+            r#"
+            SELECT
+                id,
+                user_id,
+                symbol,
+                price,
+                initial_quantity,
+                filled_quantity,
+                quote_quantity,
+                filled_quote_quantity,
+                order_type,
+                order_side,
+                order_status,
+                timestamp
+            FROM keyspace_1.order_table;
+        "#;
+        self.backfill_rows(s, |order| {
+            let (id, user_id, symbol, timestamp) = (
+                order[0].clone(),
+                order[1].clone(),
+                order[2].clone(),
+                order[11].clone(),
+            );
+            let mut by_market = order;
+            by_market.push(bucket_of(&timestamp));
+            vec![
+                (ORDER_BY_USER_STATEMENT, vec![user_id.clone(), symbol.clone(), timestamp, id]),
+                (USER_SYMBOL_STATEMENT, vec![user_id, symbol]),
+                (ORDER_BY_MARKET_STATEMENT, by_market)
+            ]
+        }).await
+    }
+    // The order as order_table has it when it is written, later fills and cancels only update
+    // order_table
+    pub async fn index_order(&self, order: &ScyllaOrder) -> Result<()> {
+        let mut batch: Batch = Default::default();
+        batch.append_statement(ORDER_BY_USER_STATEMENT);
+        batch.append_statement(USER_SYMBOL_STATEMENT);
+        batch.append_statement(ORDER_BY_MARKET_STATEMENT);
+        let prepared_batch: Batch = self.session.prepare_batch(&batch).await?;
+        self.session.batch(&prepared_batch, (
+            (order.user_id, &order.symbol, order.timestamp, order.id),
+            (order.user_id, &order.symbol),
+            (
+                order.id,
+                order.user_id,
+                &order.symbol,
+                order.price,
+                order.initial_quantity,
+                order.filled_quantity,
+                order.quote_quantity,
+                order.filled_quote_quantity,
+                &order.order_type,
+                &order.order_side,
+                &order.order_status,
+                order.timestamp,
+                bucket(order.timestamp),
+            ),
+        )).await?;
+        Ok(())
+    }
+    // cancel_order_table is only there in databases made before cancels were written by market
+    async fn backfill_cancel_orders(&self) -> Result<()> {
+        if self.table_columns("cancel_order_table").await?.is_empty() {
+            return Ok(());
+        }
+        let s =
+            r#"
+            SELECT
+                id,
+                user_id,
+                order_side,
+                symbol,
+                price,
+                timestamp
+            FROM keyspace_1.cancel_order_table;
+        "#;
+        self.backfill_rows(s, |mut cancel| {
+            cancel.push(bucket_of(&cancel[5]));
+            vec![(CANCEL_ORDER_BY_MARKET_STATEMENT, cancel)]
+        }).await
+    }
+    async fn backfill_trades(&self) -> Result<()> {
+        let s =
+            r#"
+            SELECT
+                id,
+                symbol,
+                quantity,
+                quote_quantity,
+                is_buyer_maker,
+                price,
+                timestamp
+            FROM keyspace_1.trade_table;
+        "#;
+        self.backfill_rows(s, |mut trade| {
+            trade.push(bucket_of(&trade[6]));
+            vec![(TRADE_BY_MARKET_STATEMENT, trade)]
+        }).await
+    }
+}
+
+fn bucket_of(timestamp: &Option<CqlValue>) -> Option<CqlValue> {
+    let timestamp = timestamp.as_ref().and_then(|timestamp| timestamp.as_bigint());
+    Some(CqlValue::BigInt(bucket(timestamp.unwrap_or_default())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrations_are_ordered_and_can_run_again() {
+        for (index, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, (index as i32) + 1);
+            for statement in migration.statements {
+                assert!(statement.contains("IF NOT EXISTS"), "{}", migration.description);
+            }
+        }
+    }
+}
@@ -11,9 +11,13 @@ use scylla::{
 
 pub mod schema;
 pub mod scylla_tables;
+pub mod migrations;
+pub mod native_decimals;
 
+// Cheap to clone, every clone queries through the same session
+#[derive(Clone)]
 pub struct ScyllaDb {
-    pub session: Session,
+    pub session: Arc<Session>,
 }
 
 impl ScyllaDb {
@@ -30,18 +34,13 @@ impl ScyllaDb {
             .build().await?;
 
         Ok(ScyllaDb {
-            session,
+            session: Arc::new(session),
         })
     }
-    pub async fn new_user_id(&self) -> Result<i64, Box<dyn Error>> {
-        let s = r#"
-        SELECT COUNT(*) FROM keyspace_1.user_table;
-            "#;
-        let res = self.session.query(s, &[]).await?;
-        let mut res = res.rows_typed::<(i64,)>()?;
-        let total_users = res.next().transpose()?.unwrap().0;
-        Ok(total_users + 1)
-    }
+}
+// The error of a text column that holds none of the values it can take
+pub fn invalid_value(table: &str, column: &str, value: &str) -> QueryError {
+    QueryError::InvalidMessage(format!("{}.{} holds an invalid value {:?}", table, column, value))
 }
 pub fn get_epoch_micros() -> u128 {
     SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros()
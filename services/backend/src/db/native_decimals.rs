@@ -0,0 +1,273 @@
+use bytes::Bytes;
+use common::numeric::{ encode, parse };
+use scylla::{ frame::response::result::CqlValue, query::Query };
+
+use crate::result::Result;
+
+use super::ScyllaDb;
+
+// Tables whose prices, quantities and balances were written as text. A column's type cannot be
+// changed in place, so each table is copied to `<table>_text`, dropped, created again with
+// `decimal` columns and filled from the copy, which is kept. Every step can be run again, a
+// conversion that stopped part way picks up from the copy.
+pub struct DecimalTable {
+    pub name: &'static str,
+    // Columns and keys, with DECIMAL where the text columns were
+    pub definition: &'static str,
+    pub decimals: &'static [&'static str],
+    pub decimal_maps: &'static [&'static str],
+}
+
+pub const DECIMAL_TABLES: &[DecimalTable] = &[
+    DecimalTable {
+        name: "user_table",
+        definition: r#"(
+            id bigint PRIMARY KEY,
+            balance map<text, DECIMAL>,
+            locked_balance map<text, DECIMAL>,
+            borrowed map<text, DECIMAL>,
+            interest map<text, DECIMAL>,
+            margin_mode text,
+            positions map<text, text>
+        )"#,
+        decimals: &[],
+        decimal_maps: &["balance", "locked_balance", "borrowed", "interest"],
+    },
+    DecimalTable {
+        name: "order_table",
+        definition: r#"(
+            id bigint,
+            user_id bigint,
+            symbol text,
+            price DECIMAL,
+            initial_quantity DECIMAL,
+            filled_quantity DECIMAL,
+            quote_quantity DECIMAL,
+            filled_quote_quantity DECIMAL,
+            order_type text,
+            order_side text,
+            order_status text,
+            timestamp bigint,
+            PRIMARY KEY (id, symbol)
+        )"#,
+        decimals: &[
+            "price",
+            "initial_quantity",
+            "filled_quantity",
+            "quote_quantity",
+            "filled_quote_quantity",
+        ],
+        decimal_maps: &[],
+    },
+    // Only there in databases made before cancels were written by market
+    DecimalTable {
+        name: "cancel_order_table",
+        definition: r#"(
+            id bigint,
+            user_id bigint,
+            order_side text,
+            symbol text,
+            price DECIMAL,
+            timestamp bigint,
+            PRIMARY KEY (id, symbol)
+        )"#,
+        decimals: &["price"],
+        decimal_maps: &[],
+    },
+    DecimalTable {
+        name: "trade_table",
+        definition: r#"(
+            id bigint,
+            symbol text,
+            quantity DECIMAL,
+            quote_quantity DECIMAL,
+            is_buyer_maker boolean,
+            price DECIMAL,
+            timestamp bigint,
+            PRIMARY KEY (id, symbol)
+        )"#,
+        decimals: &["quantity", "quote_quantity", "price"],
+        decimal_maps: &[],
+    },
+    DecimalTable {
+        name: "ticker_table",
+        definition: r#"(
+            symbol text PRIMARY KEY,
+            base_volume DECIMAL,
+            quote_volume DECIMAL,
+            price_change DECIMAL,
+            price_change_percent DECIMAL,
+            high_price DECIMAL,
+            low_price DECIMAL,
+            last_price DECIMAL
+        )"#,
+        decimals: &[
+            "base_volume",
+            "quote_volume",
+            "price_change",
+            "price_change_percent",
+            "high_price",
+            "low_price",
+            "last_price",
+        ],
+        decimal_maps: &[],
+    },
+];
+
+const COPY_PAGE_SIZE: i32 = 1000;
+
+impl DecimalTable {
+    fn create(&self, name: &str, decimal: &str) -> String {
+        format!(
+            "CREATE TABLE IF NOT EXISTS keyspace_1.{} {};",
+            name,
+            self.definition.replace("DECIMAL", decimal)
+        )
+    }
+    fn backup(&self) -> String {
+        format!("{}_text", self.name)
+    }
+    // A column of the text copy as it goes into the decimal table, values that are not numbers
+    // stop the conversion with the table, column and value in the error
+    fn convert(&self, column: &str, value: Option<CqlValue>) -> Result<Option<CqlValue>> {
+        let decimal = |value: CqlValue| -> Result<CqlValue> {
+            match value {
+                CqlValue::Text(text) =>
+                    parse(&text)
+                        .map(|value| CqlValue::Decimal(encode(value)))
+                        .map_err(|err| format!("{}.{}: {}", self.name, column, err).into()),
+                value => Ok(value),
+            }
+        };
+        match value {
+            Some(value) if self.decimals.contains(&column) => Ok(Some(decimal(value)?)),
+            Some(CqlValue::Map(entries)) if self.decimal_maps.contains(&column) => {
+                let entries = entries
+                    .into_iter()
+                    .map(|(key, value)| Ok((key, decimal(value)?)))
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(Some(CqlValue::Map(entries)))
+            }
+            value => Ok(value),
+        }
+    }
+}
+
+impl ScyllaDb {
+    pub async fn convert_decimals(&self, tables: &[DecimalTable]) -> Result<()> {
+        for table in tables {
+            self.convert_decimal_table(table).await?;
+        }
+        Ok(())
+    }
+    async fn convert_decimal_table(&self, table: &DecimalTable) -> Result<()> {
+        let backup = table.backup();
+        let columns = self.table_columns(table.name).await?;
+        let converted = table.decimals
+            .iter()
+            .chain(table.decimal_maps)
+            .all(|decimal| {
+                columns.iter().any(|(column, kind)| column == decimal && kind.contains("decimal"))
+            });
+        if !columns.is_empty() && !converted {
+            self.session.query(table.create(&backup, "text"), &[]).await?;
+            let names: Vec<String> = columns.into_iter().map(|(column, _)| column).collect();
+            self.copy_rows(table, table.name, &backup, &names, false).await?;
+            self.session.query(format!("DROP TABLE keyspace_1.{};", table.name), &[]).await?;
+        }
+        let backup_columns = self.table_columns(&backup).await?;
+        if backup_columns.is_empty() {
+            return Ok(());
+        }
+        self.session.query(table.create(table.name, "decimal"), &[]).await?;
+        let names: Vec<String> = backup_columns.into_iter().map(|(column, _)| column).collect();
+        self.copy_rows(table, &backup, table.name, &names, true).await
+    }
+    // Column names and types, none when the table does not exist
+    pub(super) async fn table_columns(&self, table: &str) -> Result<Vec<(String, String)>> {
+        let s =
+            r#"
+            SELECT column_name, type
+            FROM system_schema.columns
+            WHERE keyspace_name = 'keyspace_1' AND table_name = ?;
+        "#;
+        let res = self.session.query(s, (table,)).await?;
+        let columns = res
+            .rows_typed::<(String, String)>()?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(columns)
+    }
+    async fn copy_rows(
+        &self,
+        table: &DecimalTable,
+        from: &str,
+        to: &str,
+        columns: &[String],
+        convert: bool
+    ) -> Result<()> {
+        let select = format!("SELECT {} FROM keyspace_1.{};", columns.join(", "), from);
+        let insert = format!(
+            "INSERT INTO keyspace_1.{} ({}) VALUES ({});",
+            to,
+            columns.join(", "),
+            vec!["?"; columns.len()].join(", ")
+        );
+        let insert = self.session.prepare(insert).await?;
+        let mut paging_state: Option<Bytes> = None;
+        loop {
+            let query = Query::new(select.clone()).with_page_size(COPY_PAGE_SIZE);
+            let res = self.session.query_paged(query, &[], paging_state).await?;
+            paging_state = res.paging_state.clone();
+            for row in res.rows.unwrap_or_default() {
+                let values = if convert {
+                    columns
+                        .iter()
+                        .zip(row.columns)
+                        .map(|(column, value)| table.convert(column, value))
+                        .collect::<Result<Vec<_>>>()?
+                } else {
+                    row.columns
+                };
+                self.session.execute(&insert, values).await?;
+            }
+            if paging_state.is_none() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    #[test]
+    fn text_columns_become_decimals_and_corrupt_values_are_errors() {
+        let user_table = &DECIMAL_TABLES[0];
+        let balance = CqlValue::Map(
+            vec![(CqlValue::Text("SOL".to_string()), CqlValue::Text("1.50".to_string()))]
+        );
+        let converted = user_table.convert("balance", Some(balance)).unwrap();
+        let expected = CqlValue::Map(
+            vec![(CqlValue::Text("SOL".to_string()), CqlValue::Decimal(encode(dec!(1.50))))]
+        );
+        assert_eq!(converted, Some(expected));
+        let positions = Some(CqlValue::Text("{}".to_string()));
+        assert_eq!(user_table.convert("positions", positions.clone()).unwrap(), positions);
+
+        let order_table = &DECIMAL_TABLES[1];
+        let corrupt = order_table.convert("price", Some(CqlValue::Text("12,5".to_string())));
+        assert_eq!(
+            corrupt.unwrap_err().to_string(),
+            "order_table.price: \"12,5\" is not a number"
+        );
+        assert_eq!(order_table.convert("price", None).unwrap(), None);
+        for table in DECIMAL_TABLES {
+            for column in table.decimals.iter().chain(table.decimal_maps) {
+                assert!(table.definition.contains(&format!("{} ", column)), "{}", table.name);
+            }
+        }
+    }
+}
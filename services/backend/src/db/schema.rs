@@ -1,9 +1,11 @@
 use std::{ collections::HashMap, error::Error };
+use common::ledger::LedgerEntry;
 use enum_stringify::EnumStringify;
 use rust_decimal::Decimal;
 use serde::{ Deserialize, Serialize };
 use strum::IntoEnumIterator;
 use strum_macros::EnumIter;
+use uuid::Uuid;
 
 pub type Id = i64;
 pub type OrderId = i64;
@@ -38,7 +40,14 @@ impl Exchange {
         let quote_str = symbols.get(1).ok_or(SymbolError::InvalidSymbol)?;
         let base = Asset::from_str(&base_str).ok_or(SymbolError::InvalidSymbol)?;
         let quote = Asset::from_str(&quote_str).ok_or(SymbolError::InvalidSymbol)?;
-        let exchange = Exchange::new(base, quote);
+        let exchange = match symbols.get(2) {
+            None => Exchange::new(base, quote),
+            // perpetual markets are quoted in the assets of their spot market
+            Some(&"PERP") => Exchange { base, quote, symbol: symbol.clone() },
+            Some(_) => {
+                return Err(SymbolError::InvalidSymbol);
+            }
+        };
         Ok(exchange)
     }
 }
@@ -132,6 +141,237 @@ pub struct User {
     pub id: i64,
     pub balance: HashMap<Asset, Quantity>,
     pub locked_balance: HashMap<Asset, Quantity>,
+    // Set on sub-accounts, the account they belong to
+    #[serde(default)]
+    pub master_id: Option<Id>,
+    // Frozen accounts cannot place orders, withdraw or transfer
+    #[serde(default)]
+    pub frozen: bool,
+}
+// One page of a user's orders in a market, `next_page` is passed back to get the one after it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OrderPage {
+    pub orders: Vec<Order>,
+    pub next_page: Option<String>,
+}
+// One page of a user's ledger, `next_page` is passed back to get the one after it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LedgerPage {
+    pub entries: Vec<LedgerEntry>,
+    pub next_page: Option<String>,
+}
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, EnumStringify)]
+pub enum FillRole {
+    Taker,
+    Maker,
+}
+// A user's side of a trade, the counterparty is only known by its order
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Fill {
+    pub trade_id: Id,
+    pub symbol: Symbol,
+    pub role: FillRole,
+    pub order_id: OrderId,
+    pub counterparty_order_id: OrderId,
+    pub side: OrderSide,
+    pub price: Price,
+    pub quantity: Quantity,
+    pub quote_quantity: Quantity,
+    // Zero in the quote asset until the market charges fees
+    pub fee: Quantity,
+    pub fee_asset: String,
+    pub timestamp: i64,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FillPage {
+    pub fills: Vec<Fill>,
+    pub next_page: Option<String>,
+}
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, EnumStringify)]
+pub enum WithdrawalState {
+    Locked,
+    Approved,
+    Sent,
+    Completed,
+    Failed,
+}
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Withdrawal {
+    pub id: Id,
+    pub user_id: Id,
+    pub asset: Asset,
+    pub quantity: Quantity,
+    pub destination: String,
+    pub state: WithdrawalState,
+    pub tx_id: Option<String>,
+    pub reason: Option<String>,
+    pub requested_at: i64,
+    pub updated_at: i64,
+}
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Transfer {
+    pub id: Id,
+    pub from: Id,
+    pub to: Id,
+    pub asset: Asset,
+    pub quantity: Quantity,
+    pub timestamp: i64,
+}
+// A transfer as one of its accounts sees it, `amount` is negative for the account it left
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TransferRecord {
+    pub id: Id,
+    pub user_id: Id,
+    pub counterparty_id: Id,
+    pub asset: Asset,
+    pub amount: Quantity,
+    pub timestamp: i64,
+}
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize, EnumStringify)]
+pub enum AdjustmentReason {
+    DepositCorrection,
+    WithdrawalCorrection,
+    TradeCorrection,
+    Compensation,
+    Other,
+}
+// A balance changed by hand, `amount` is negative for debits
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Adjustment {
+    pub id: Id,
+    pub user_id: Id,
+    pub asset: Asset,
+    pub amount: Quantity,
+    pub reason: AdjustmentReason,
+    pub timestamp: i64,
+}
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct RiskLimits {
+    pub max_open_orders: usize,
+    pub max_order_notional: Quantity,
+    pub max_open_notional: Quantity,
+    pub max_orders_per_second: u32,
+}
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AccountRisk {
+    pub user_id: Id,
+    pub limits: RiskLimits,
+    pub open_notional: HashMap<Symbol, Quantity>,
+}
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub enum MarginMode {
+    Cross,
+    Isolated(Symbol),
+}
+// Assets and liabilities are valued in USDT, the level is one over the other
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MarginAccount {
+    pub user_id: Id,
+    pub mode: Option<MarginMode>,
+    pub assets: Option<Quantity>,
+    pub liabilities: Option<Quantity>,
+    pub level: Option<Decimal>,
+    pub borrowed: HashMap<Asset, Quantity>,
+    pub interest: HashMap<Asset, Quantity>,
+}
+// Unrealized PnL is valued at the mark price of the perpetual's spot market, while it has one
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Position {
+    pub symbol: Symbol,
+    pub quantity: Quantity,
+    pub entry_price: Price,
+    pub margin: Quantity,
+    pub mark_price: Option<Price>,
+    pub unrealized_pnl: Option<Quantity>,
+}
+// What is held of an asset less what is owed of it, valued in USDT by the engine at the last
+// trade price of the asset's market
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Holding {
+    pub asset: Asset,
+    pub balance: Quantity,
+    pub locked: Quantity,
+    pub owed: Quantity,
+    pub price: Option<Price>,
+    pub value: Option<Quantity>,
+}
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Valuation {
+    pub user_id: Id,
+    pub value: Quantity,
+    pub holdings: Vec<Holding>,
+    pub positions: Vec<Position>,
+    pub timestamp: u64,
+}
+// Sells realize PnL against the oldest buys still open, what stays open is valued at the price
+// of the market. Assets that came in by deposit have no cost and are not counted.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct MarketPnl {
+    pub symbol: Symbol,
+    pub bought: Quantity,
+    pub sold: Quantity,
+    pub open_quantity: Quantity,
+    pub cost_basis: Quantity,
+    pub realized_pnl: Quantity,
+    pub unrealized_pnl: Option<Quantity>,
+}
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PortfolioSnapshot {
+    pub day: i64,
+    pub value: Quantity,
+    pub timestamp: i64,
+}
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Portfolio {
+    pub valuation: Valuation,
+    pub markets: Vec<MarketPnl>,
+    pub realized_pnl: Quantity,
+    pub unrealized_pnl: Quantity,
+    // Newest day first
+    pub history: Vec<PortfolioSnapshot>,
+}
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MarketSummary {
+    pub symbol: Symbol,
+    pub halted: bool,
+    pub last_price: Option<Price>,
+    pub max_price_deviation: Option<Decimal>,
+    pub bid_levels: u64,
+    pub ask_levels: u64,
+    pub open_orders: u64,
+    pub last_order_id: Id,
+    pub last_trade_id: Id,
+}
+// An action taken through the admin routes, with the request that was made and what the engine
+// answered
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AuditEntry {
+    pub id: Uuid,
+    pub timestamp: i64,
+    pub admin: String,
+    pub action: String,
+    pub target: String,
+    pub reason: String,
+    pub request: String,
+    pub outcome: String,
+}
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, EnumStringify)]
+pub enum DepositState {
+    Pending,
+    Credited,
+}
+// A transfer custody reported, credited once it has enough confirmations
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Deposit {
+    pub id: Id,
+    pub tx_id: String,
+    pub user_id: Id,
+    pub asset: Asset,
+    pub amount: Quantity,
+    pub confirmations: u32,
+    pub state: DepositState,
+    pub seen_at: i64,
+    pub updated_at: i64,
 }
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Trade {
@@ -1,20 +1,47 @@
 use crate::result::Result;
 
 use super::{ schema::OrderId, ScyllaDb };
-use std::collections::HashMap;
+use common::numeric::Numeric;
+use std::collections::{ HashMap, HashSet };
 
 use scylla::{ FromRow, SerializeRow };
 use serde::{ Deserialize, Serialize };
+use uuid::Uuid;
 
 impl ScyllaDb {
+    // Only the base tables are made here, migrations rewrite tables and are applied by the
+    // `migrate` binary with the engine and the db-filler stopped
     pub async fn initialize(&self) -> Result<()> {
+        self.create_tables().await?;
+        let pending = self.pending_migrations().await?;
+        if !pending.is_empty() {
+            let message = format!(
+                "Migrations {:?} are pending, run `cargo run -p backend --bin migrate` first",
+                pending
+            );
+            return Err(message.into());
+        }
+        Ok(())
+    }
+    pub async fn create_tables(&self) -> Result<()> {
         self.create_keyspace().await?;
         self.create_user_table().await?;
         self.create_order_table().await?;
-        self.cancel_order_table().await?;
         self.create_trade_table().await?;
         self.create_market_table().await?;
         self.create_ticker_table().await?;
+        self.create_ledger_table().await?;
+        self.create_withdrawal_table().await?;
+        self.create_deposit_table().await?;
+        self.create_sub_account_table().await?;
+        self.create_transfer_table().await?;
+        self.create_api_key_table().await?;
+        self.create_adjustment_table().await?;
+        self.create_admin_key_table().await?;
+        self.create_admin_action_table().await?;
+        self.create_portfolio_table().await?;
+        self.create_fill_table().await?;
+        self.create_last_id_table().await?;
 
         Ok(())
     }
@@ -36,8 +63,12 @@ impl ScyllaDb {
             r#"
         CREATE TABLE IF NOT EXISTS keyspace_1.user_table (
             id bigint PRIMARY KEY,
-            balance map<text, text>,
-            locked_balance map<text, text>
+            balance map<text, decimal>,
+            locked_balance map<text, decimal>,
+            borrowed map<text, decimal>,
+            interest map<text, decimal>,
+            margin_mode text,
+            positions map<text, text>
         );
       "#;
         self.session.query(create_user_table, &[]).await?;
@@ -50,11 +81,11 @@ impl ScyllaDb {
             id bigint,
             user_id bigint,
             symbol text,
-            price text,
-            initial_quantity text,
-            filled_quantity text, 
-            quote_quantity text,
-            filled_quote_quantity text,
+            price decimal,
+            initial_quantity decimal,
+            filled_quantity decimal, 
+            quote_quantity decimal,
+            filled_quote_quantity decimal,
             order_type text,
             order_side text,
             order_status text,
@@ -65,32 +96,16 @@ impl ScyllaDb {
         self.session.query(create_order_table, &[]).await?;
         Ok(())
     }
-    async fn cancel_order_table(&self) -> Result<()> {
-        let create_order_table: &str =
-            r#"
-        CREATE TABLE IF NOT EXISTS keyspace_1.cancel_order_table (
-            id bigint,
-            user_id bigint,
-            order_side text,
-            symbol text,
-            price text,
-            timestamp bigint,
-            PRIMARY KEY (id, symbol)
-        );
-      "#;
-        self.session.query(create_order_table, &[]).await?;
-        Ok(())
-    }
     async fn create_trade_table(&self) -> Result<()> {
         let create_trade_table: &str =
             r#"
         CREATE TABLE IF NOT EXISTS keyspace_1.trade_table (
             id bigint,
             symbol text,
-            quantity text,
-            quote_quantity text,
+            quantity decimal,
+            quote_quantity decimal,
             is_buyer_maker boolean,
-            price text,
+            price decimal,
             timestamp bigint,
             PRIMARY KEY (id, symbol)
         );
@@ -122,18 +137,258 @@ impl ScyllaDb {
             r#"
         CREATE TABLE IF NOT EXISTS keyspace_1.ticker_table (
             symbol text PRIMARY KEY,
-            base_volume text,
-            quote_volume text,
-            price_change text,
-            price_change_percent text,
-            high_price text,
-            low_price text,
-            last_price text
+            base_volume decimal,
+            quote_volume decimal,
+            price_change decimal,
+            price_change_percent decimal,
+            high_price decimal,
+            low_price decimal,
+            last_price decimal
         );
       "#;
         self.session.query(create_ticker_table, &[]).await?;
         Ok(())
     }
+    // A user's entries newest first, the movement they belong to keeps them unique
+    async fn create_ledger_table(&self) -> Result<()> {
+        let create_ledger_table: &str =
+            r#"
+        CREATE TABLE IF NOT EXISTS keyspace_1.ledger_table (
+            user_id bigint,
+            timestamp bigint,
+            kind text,
+            reference bigint,
+            leg int,
+            account text,
+            asset text,
+            amount decimal,
+            PRIMARY KEY (user_id, timestamp, kind, reference, leg)
+        ) WITH CLUSTERING ORDER BY (timestamp DESC, kind ASC, reference ASC, leg ASC);
+      "#;
+        self.session.query(create_ledger_table, &[]).await?;
+        Ok(())
+    }
+    // A user's withdrawals newest first, upserted by the engine whenever one moves on
+    async fn create_withdrawal_table(&self) -> Result<()> {
+        let create_withdrawal_table: &str =
+            r#"
+        CREATE TABLE IF NOT EXISTS keyspace_1.withdrawal_table (
+            id bigint,
+            user_id bigint,
+            asset text,
+            quantity decimal,
+            destination text,
+            state text,
+            tx_id text,
+            reason text,
+            requested_at bigint,
+            updated_at bigint,
+            PRIMARY KEY (user_id, id)
+        ) WITH CLUSTERING ORDER BY (id DESC);
+      "#;
+        // What the engine recovers without a snapshot, the withdrawals still open and what
+        // users withdrew per day against their daily limit
+        let create_open_withdrawal_table: &str =
+            r#"
+        CREATE TABLE IF NOT EXISTS keyspace_1.open_withdrawal_table (
+            id bigint PRIMARY KEY,
+            user_id bigint,
+            asset text,
+            quantity decimal,
+            destination text,
+            state text,
+            tx_id text,
+            reason text,
+            requested_at bigint,
+            updated_at bigint
+        );
+      "#;
+        let create_withdrawal_total_table: &str =
+            r#"
+        CREATE TABLE IF NOT EXISTS keyspace_1.withdrawal_total_table (
+            day bigint,
+            user_id bigint,
+            asset text,
+            total decimal,
+            PRIMARY KEY (day, user_id, asset)
+        );
+      "#;
+        self.session.query(create_withdrawal_table, &[]).await?;
+        self.session.query(create_open_withdrawal_table, &[]).await?;
+        self.session.query(create_withdrawal_total_table, &[]).await?;
+        Ok(())
+    }
+    // A user's custody deposits newest first, pending ones included
+    async fn create_deposit_table(&self) -> Result<()> {
+        let create_deposit_table: &str =
+            r#"
+        CREATE TABLE IF NOT EXISTS keyspace_1.deposit_table (
+            id bigint,
+            tx_id text,
+            user_id bigint,
+            asset text,
+            amount decimal,
+            confirmations int,
+            state text,
+            seen_at bigint,
+            updated_at bigint,
+            PRIMARY KEY (user_id, id)
+        ) WITH CLUSTERING ORDER BY (id DESC);
+      "#;
+        self.session.query(create_deposit_table, &[]).await?;
+        Ok(())
+    }
+    async fn create_sub_account_table(&self) -> Result<()> {
+        let create_sub_account_table: &str =
+            r#"
+        CREATE TABLE IF NOT EXISTS keyspace_1.sub_account_table (
+            master_id bigint,
+            id bigint,
+            PRIMARY KEY (master_id, id)
+        );
+      "#;
+        self.session.query(create_sub_account_table, &[]).await?;
+        Ok(())
+    }
+    // Every transfer is written once for each of its accounts, newest first
+    async fn create_transfer_table(&self) -> Result<()> {
+        let create_transfer_table: &str =
+            r#"
+        CREATE TABLE IF NOT EXISTS keyspace_1.transfer_table (
+            user_id bigint,
+            id bigint,
+            counterparty_id bigint,
+            asset text,
+            amount decimal,
+            timestamp bigint,
+            PRIMARY KEY (user_id, id)
+        ) WITH CLUSTERING ORDER BY (id DESC);
+      "#;
+        self.session.query(create_transfer_table, &[]).await?;
+        Ok(())
+    }
+    // The last id of each counter of the engine's user thread
+    async fn create_last_id_table(&self) -> Result<()> {
+        let create_last_id_table: &str =
+            r#"
+        CREATE TABLE IF NOT EXISTS keyspace_1.last_id_table (
+            name text PRIMARY KEY,
+            id bigint
+        );
+      "#;
+        self.session.query(create_last_id_table, &[]).await?;
+        Ok(())
+    }
+    async fn create_api_key_table(&self) -> Result<()> {
+        let create_api_key_table: &str =
+            r#"
+        CREATE TABLE IF NOT EXISTS keyspace_1.api_key_table (
+            key text PRIMARY KEY,
+            user_id bigint,
+            secret text,
+            permissions set<text>,
+            allowed_ips set<text>,
+            created_at bigint
+        );
+      "#;
+        let create_user_index: &str =
+            r#"
+        CREATE INDEX IF NOT EXISTS ON keyspace_1.api_key_table (user_id);
+      "#;
+        self.session.query(create_api_key_table, &[]).await?;
+        self.session.query(create_user_index, &[]).await?;
+        Ok(())
+    }
+    async fn create_adjustment_table(&self) -> Result<()> {
+        let create_adjustment_table: &str =
+            r#"
+        CREATE TABLE IF NOT EXISTS keyspace_1.adjustment_table (
+            user_id bigint,
+            id bigint,
+            asset text,
+            amount decimal,
+            reason text,
+            timestamp bigint,
+            PRIMARY KEY (user_id, id)
+        ) WITH CLUSTERING ORDER BY (id DESC);
+      "#;
+        self.session.query(create_adjustment_table, &[]).await?;
+        Ok(())
+    }
+    async fn create_admin_key_table(&self) -> Result<()> {
+        let create_admin_key_table: &str =
+            r#"
+        CREATE TABLE IF NOT EXISTS keyspace_1.admin_key_table (
+            key text PRIMARY KEY,
+            name text,
+            secret text,
+            roles set<text>,
+            allowed_ips set<text>,
+            created_at bigint
+        );
+      "#;
+        self.session.query(create_admin_key_table, &[]).await?;
+        Ok(())
+    }
+    // Keyed by an id as well, so two actions taken in one microsecond are both kept
+    async fn create_admin_action_table(&self) -> Result<()> {
+        let create_admin_action_table: &str =
+            r#"
+        CREATE TABLE IF NOT EXISTS keyspace_1.admin_action_table (
+            day bigint,
+            timestamp bigint,
+            id uuid,
+            admin text,
+            action text,
+            target text,
+            reason text,
+            request text,
+            outcome text,
+            PRIMARY KEY (day, timestamp, id)
+        ) WITH CLUSTERING ORDER BY (timestamp DESC, id ASC);
+      "#;
+        self.session.query(create_admin_action_table, &[]).await?;
+        Ok(())
+    }
+    async fn create_portfolio_table(&self) -> Result<()> {
+        let create_portfolio_table: &str =
+            r#"
+        CREATE TABLE IF NOT EXISTS keyspace_1.portfolio_table (
+            user_id bigint,
+            day bigint,
+            value decimal,
+            holdings text,
+            timestamp bigint,
+            PRIMARY KEY (user_id, day)
+        ) WITH CLUSTERING ORDER BY (day DESC);
+      "#;
+        self.session.query(create_portfolio_table, &[]).await?;
+        Ok(())
+    }
+    // A user's fills in a market newest first, written by the db-filler with the trade
+    async fn create_fill_table(&self) -> Result<()> {
+        let create_fill_table: &str =
+            r#"
+        CREATE TABLE IF NOT EXISTS keyspace_1.fill_table (
+            user_id bigint,
+            symbol text,
+            timestamp bigint,
+            trade_id bigint,
+            role text,
+            order_id bigint,
+            counterparty_order_id bigint,
+            side text,
+            price decimal,
+            quantity decimal,
+            quote_quantity decimal,
+            fee decimal,
+            fee_asset text,
+            PRIMARY KEY ((user_id, symbol), timestamp, trade_id, role)
+        ) WITH CLUSTERING ORDER BY (timestamp DESC, trade_id DESC, role ASC);
+      "#;
+        self.session.query(create_fill_table, &[]).await?;
+        Ok(())
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, SerializeRow, FromRow)]
@@ -141,11 +396,11 @@ pub struct ScyllaOrder {
     pub id: OrderId,
     pub user_id: i64,
     pub symbol: String,
-    pub price: String,
-    pub initial_quantity: String,
-    pub filled_quantity: String,
-    pub quote_quantity: String,
-    pub filled_quote_quantity: String,
+    pub price: Numeric,
+    pub initial_quantity: Numeric,
+    pub filled_quantity: Numeric,
+    pub quote_quantity: Numeric,
+    pub filled_quote_quantity: Numeric,
     pub order_type: String,
     pub order_side: String,
     pub order_status: String,
@@ -155,30 +410,30 @@ pub struct ScyllaOrder {
 #[derive(Debug, Clone, Deserialize, Serialize, SerializeRow, FromRow)]
 pub struct ScyllaUser {
     pub id: i64,
-    pub balance: HashMap<String, String>,
-    pub locked_balance: HashMap<String, String>,
+    pub balance: HashMap<String, Numeric>,
+    pub locked_balance: HashMap<String, Numeric>,
 }
 #[derive(Debug, Serialize, Deserialize, SerializeRow, FromRow)]
 pub struct ScyllaTrade {
     pub id: i64,
     pub symbol: String,
-    pub quantity: String,
-    pub quote_quantity: String,
+    pub quantity: Numeric,
+    pub quote_quantity: Numeric,
     pub is_buyer_maker: bool,
-    pub price: String,
+    pub price: Numeric,
     pub timestamp: i64,
 }
 
 #[derive(Debug, Deserialize, Serialize, SerializeRow, FromRow)]
 pub struct ScyllaTicker {
     pub symbol: String,
-    pub base_volume: String,
-    pub quote_volume: String,
-    pub price_change: String,
-    pub price_change_percent: String,
-    pub high_price: String,
-    pub low_price: String,
-    pub last_price: String,
+    pub base_volume: Numeric,
+    pub quote_volume: Numeric,
+    pub price_change: Numeric,
+    pub price_change_percent: Numeric,
+    pub high_price: Numeric,
+    pub low_price: Numeric,
+    pub last_price: Numeric,
 }
 
 #[derive(Debug, Deserialize, Serialize, SerializeRow, FromRow)]
@@ -193,3 +448,108 @@ pub struct ScyllaMarket {
     pub min_quantity: String,
     pub step_size: String,
 }
+
+#[derive(Debug, Deserialize, Serialize, SerializeRow, FromRow)]
+pub struct ScyllaLedgerEntry {
+    pub user_id: i64,
+    pub timestamp: i64,
+    pub kind: String,
+    pub reference: i64,
+    pub leg: i32,
+    pub account: String,
+    pub asset: String,
+    pub amount: Numeric,
+}
+
+#[derive(Debug, Deserialize, Serialize, SerializeRow, FromRow)]
+pub struct ScyllaFill {
+    pub trade_id: i64,
+    pub symbol: String,
+    pub role: String,
+    pub order_id: i64,
+    pub counterparty_order_id: i64,
+    pub side: String,
+    pub price: Numeric,
+    pub quantity: Numeric,
+    pub quote_quantity: Numeric,
+    pub fee: Numeric,
+    pub fee_asset: String,
+    pub timestamp: i64,
+}
+
+#[derive(Debug, Deserialize, Serialize, SerializeRow, FromRow)]
+pub struct ScyllaPortfolioSnapshot {
+    pub day: i64,
+    pub value: Numeric,
+    pub timestamp: i64,
+}
+
+#[derive(Debug, Deserialize, Serialize, SerializeRow, FromRow)]
+pub struct ScyllaWithdrawal {
+    pub id: i64,
+    pub user_id: i64,
+    pub asset: String,
+    pub quantity: Numeric,
+    pub destination: String,
+    pub state: String,
+    pub tx_id: Option<String>,
+    pub reason: Option<String>,
+    pub requested_at: i64,
+    pub updated_at: i64,
+}
+
+#[derive(Debug, Deserialize, Serialize, SerializeRow, FromRow)]
+pub struct ScyllaDeposit {
+    pub id: i64,
+    pub tx_id: String,
+    pub user_id: i64,
+    pub asset: String,
+    pub amount: Numeric,
+    pub confirmations: i32,
+    pub state: String,
+    pub seen_at: i64,
+    pub updated_at: i64,
+}
+
+#[derive(Debug, Deserialize, Serialize, SerializeRow, FromRow)]
+pub struct ScyllaTransfer {
+    pub user_id: i64,
+    pub id: i64,
+    pub counterparty_id: i64,
+    pub asset: String,
+    pub amount: Numeric,
+    pub timestamp: i64,
+}
+
+#[derive(Debug, Deserialize, Serialize, SerializeRow, FromRow)]
+pub struct ScyllaApiKey {
+    pub key: String,
+    pub user_id: i64,
+    pub secret: String,
+    pub permissions: HashSet<String>,
+    pub allowed_ips: HashSet<String>,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Deserialize, Serialize, SerializeRow, FromRow)]
+pub struct ScyllaAdminKey {
+    pub key: String,
+    pub name: String,
+    pub secret: String,
+    pub roles: HashSet<String>,
+    pub allowed_ips: HashSet<String>,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Deserialize, Serialize, SerializeRow, FromRow)]
+pub struct ScyllaAuditEntry {
+    pub day: i64,
+    pub timestamp: i64,
+    pub id: Uuid,
+    pub admin: String,
+    pub action: String,
+    pub target: String,
+    pub reason: String,
+    pub request: String,
+    pub outcome: String,
+}
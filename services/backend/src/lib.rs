@@ -4,6 +4,8 @@ pub mod app;
 pub mod db;
 pub mod result;
 pub mod api;
+pub mod auth;
+pub mod rate_limit;
 
 #[cfg(test)]
 pub mod tests;
@@ -0,0 +1,330 @@
+use std::{ collections::HashMap, sync::Mutex };
+
+use actix_web::{
+    body::{ EitherBody, MessageBody },
+    dev::{ ServiceRequest, ServiceResponse },
+    http::{ header::{ HeaderMap, HeaderName, HeaderValue, RETRY_AFTER }, Method },
+    middleware::Next,
+    web::Data,
+    Error,
+    HttpResponse,
+};
+use config::{ Config, File };
+use serde::{ Deserialize, Serialize };
+
+use crate::{ app::AppState, auth::{ required_access, Access, API_KEY_HEADER }, db::get_epoch_micros };
+
+pub const LIMIT_HEADER: &str = "ratelimit-limit";
+pub const REMAINING_HEADER: &str = "ratelimit-remaining";
+// Seconds until the bucket is full again
+pub const RESET_HEADER: &str = "ratelimit-reset";
+// How often buckets that filled up again are dropped
+const SWEEP_INTERVAL: u128 = 60_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EndpointClass {
+    Orders,
+    Cancels,
+    // Everything else, reads and account changes
+    Queries,
+}
+pub fn endpoint_class(method: &Method, path: &str) -> EndpointClass {
+    match (method, path.trim_start_matches("/api/v1")) {
+        (&Method::POST, "/order") => EndpointClass::Orders,
+        (&Method::DELETE, "/order") | (&Method::DELETE, "/orders") => EndpointClass::Cancels,
+        _ => EndpointClass::Queries,
+    }
+}
+
+// A bucket holds up to `burst` requests and gets `per_second` of them back every second
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Limit {
+    pub burst: u32,
+    pub per_second: f64,
+}
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ClassLimits {
+    pub orders: Limit,
+    pub cancels: Limit,
+    pub queries: Limit,
+}
+impl ClassLimits {
+    fn limit(&self, class: EndpointClass) -> Limit {
+        match class {
+            EndpointClass::Orders => self.orders,
+            EndpointClass::Cancels => self.cancels,
+            EndpointClass::Queries => self.queries,
+        }
+    }
+}
+// Several keys can share an address, so addresses get more than a single key
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RateLimits {
+    pub per_key: ClassLimits,
+    pub per_ip: ClassLimits,
+}
+impl Default for RateLimits {
+    fn default() -> RateLimits {
+        RateLimits {
+            per_key: ClassLimits {
+                orders: Limit { burst: 50, per_second: 10.0 },
+                cancels: Limit { burst: 100, per_second: 20.0 },
+                queries: Limit { burst: 60, per_second: 10.0 },
+            },
+            per_ip: ClassLimits {
+                orders: Limit { burst: 100, per_second: 20.0 },
+                cancels: Limit { burst: 200, per_second: 40.0 },
+                queries: Limit { burst: 120, per_second: 20.0 },
+            },
+        }
+    }
+}
+impl Limit {
+    // A bucket that never refills or never holds a request would refuse everything
+    fn is_valid(&self) -> bool {
+        self.burst > 0 && self.per_second.is_finite() && self.per_second > 0.0
+    }
+}
+impl ClassLimits {
+    fn is_valid(&self) -> bool {
+        self.orders.is_valid() && self.cancels.is_valid() && self.queries.is_valid()
+    }
+}
+impl RateLimits {
+    // Read from `rate_limits` in config/base.yaml, the defaults when it is not there
+    pub fn load() -> RateLimits {
+        let limits = Config::builder()
+            .add_source(File::with_name("config/base").required(false))
+            .build()
+            .and_then(|config| config.get::<RateLimits>("rate_limits"))
+            .unwrap_or_default();
+        if !limits.is_valid() {
+            panic!("Rate limits need a burst and a rate per second above 0: {:?}", limits);
+        }
+        limits
+    }
+    pub fn is_valid(&self) -> bool {
+        self.per_key.is_valid() && self.per_ip.is_valid()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TokenBucket {
+    tokens: f64,
+    updated_at: u128,
+}
+impl TokenBucket {
+    pub fn full(limit: Limit, now: u128) -> TokenBucket {
+        TokenBucket { tokens: limit.burst as f64, updated_at: now }
+    }
+    fn refill(&mut self, limit: Limit, now: u128) {
+        let elapsed = now.saturating_sub(self.updated_at) as f64 / 1_000_000.0;
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst as f64);
+        self.updated_at = now;
+    }
+    // Takes a token, or tells how many seconds until there is one
+    pub fn take(&mut self, limit: Limit, now: u128) -> Result<Usage, u64> {
+        self.refill(limit, now);
+        if self.tokens < 1.0 {
+            return Err(((1.0 - self.tokens) / limit.per_second).ceil() as u64);
+        }
+        self.tokens -= 1.0;
+        Ok(self.usage(limit))
+    }
+    fn usage(&self, limit: Limit) -> Usage {
+        Usage {
+            limit: limit.burst,
+            remaining: self.tokens.floor() as u32,
+            reset: ((limit.burst as f64 - self.tokens) / limit.per_second).ceil() as u64,
+        }
+    }
+    fn is_full(&self, limit: Limit, now: u128) -> bool {
+        let mut bucket = *self;
+        bucket.refill(limit, now);
+        bucket.tokens >= limit.burst as f64
+    }
+}
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Usage {
+    pub limit: u32,
+    pub remaining: u32,
+    pub reset: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Subject {
+    Key(String),
+    Ip(String),
+}
+struct Buckets {
+    buckets: HashMap<(Subject, EndpointClass), TokenBucket>,
+    swept_at: u128,
+}
+pub struct RateLimiter {
+    limits: RateLimits,
+    buckets: Mutex<Buckets>,
+}
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> RateLimiter {
+        let buckets = Buckets { buckets: HashMap::new(), swept_at: 0 };
+        RateLimiter { limits, buckets: Mutex::new(buckets) }
+    }
+    fn limit(&self, subject: &Subject, class: EndpointClass) -> Limit {
+        match subject {
+            Subject::Key(_) => self.limits.per_key.limit(class),
+            Subject::Ip(_) => self.limits.per_ip.limit(class),
+        }
+    }
+    // Takes a token from the bucket of the subject for the class, or tells how many seconds
+    // until there is one. A full bucket is the same as none, so those are dropped once a sweep
+    // interval has passed.
+    pub fn check(&self, subject: Subject, class: EndpointClass, now: u128) -> Result<Usage, u64> {
+        let limit = self.limit(&subject, class);
+        let mut buckets = self.buckets.lock().unwrap();
+        if now.saturating_sub(buckets.swept_at) >= SWEEP_INTERVAL {
+            buckets.buckets.retain(|(subject, class), bucket| {
+                !bucket.is_full(self.limit(subject, *class), now)
+            });
+            buckets.swept_at = now;
+        }
+        buckets.buckets
+            .entry((subject, class))
+            .or_insert(TokenBucket::full(limit, now))
+            .take(limit, now)
+    }
+}
+
+fn set_header(headers: &mut HeaderMap, name: &'static str, value: u64) {
+    headers.insert(HeaderName::from_static(name), HeaderValue::from(value));
+}
+
+// The usage of the emptier bucket is reported, the key's is set first as it is checked last
+fn report_usage(headers: &mut HeaderMap, usage: Usage) {
+    let remaining = headers
+        .get(REMAINING_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u32>().ok());
+    if remaining.is_some_and(|remaining| remaining <= usage.remaining) {
+        return;
+    }
+    set_header(headers, LIMIT_HEADER, usage.limit as u64);
+    set_header(headers, REMAINING_HEADER, usage.remaining as u64);
+    set_header(headers, RESET_HEADER, usage.reset);
+}
+
+async fn limit<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+    subject: Option<Subject>
+) -> Result<ServiceResponse<EitherBody<B>>, Error> {
+    let app_state = req.app_data::<Data<AppState>>().cloned();
+    let (Some(app_state), Some(subject)) = (app_state, subject) else {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    };
+    let class = endpoint_class(req.method(), req.path());
+    match app_state.rate_limiter.check(subject, class, get_epoch_micros()) {
+        Ok(usage) => {
+            let mut res = next.call(req).await?;
+            report_usage(res.headers_mut(), usage);
+            Ok(res.map_into_left_body())
+        }
+        Err(retry_after) => {
+            let res = HttpResponse::TooManyRequests()
+                .insert_header((RETRY_AFTER, retry_after))
+                .json("Too many requests, retry later");
+            Ok(req.into_response(res).map_into_right_body())
+        }
+    }
+}
+
+// Runs before `authenticate`, so requests that fail it still count against their address
+pub async fn limit_ip(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let ip = req.peer_addr().map(|addr| Subject::Ip(addr.ip().to_string()));
+    limit(req, next, ip).await
+}
+
+// Runs after `authenticate`, so the key it limits is one whose signature was checked. Public
+// routes are not signed, a key sent to them is not checked.
+pub async fn limit_key(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let key = match required_access(req.method(), req.path()) {
+        Access::Public => None,
+        _ =>
+            req
+                .headers()
+                .get(API_KEY_HEADER)
+                .and_then(|value| value.to_str().ok())
+                .map(|key| Subject::Key(key.to_string())),
+    };
+    limit(req, next, key).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u128 = 1_000_000;
+
+    #[test]
+    fn buckets_refill_at_their_rate() {
+        let limit = Limit { burst: 2, per_second: 1.0 };
+        let mut bucket = TokenBucket::full(limit, 0);
+        assert_eq!(bucket.take(limit, 0).unwrap().remaining, 1);
+        bucket.take(limit, 0).unwrap();
+        assert_eq!(bucket.take(limit, SECOND / 2), Err(1));
+        let usage = bucket.take(limit, SECOND).unwrap();
+        assert_eq!(usage, Usage { limit: 2, remaining: 0, reset: 2 });
+        bucket.take(limit, 10 * SECOND).unwrap();
+        assert_eq!(bucket.take(limit, 10 * SECOND).unwrap().remaining, 0);
+    }
+    #[test]
+    fn keys_and_addresses_are_limited_per_class() {
+        let mut limits = RateLimits::default();
+        limits.per_key.orders = Limit { burst: 1, per_second: 1.0 };
+        let limiter = RateLimiter::new(limits);
+        let orders = EndpointClass::Orders;
+        let key = |key: &str| Subject::Key(key.to_string());
+        let ip = || Subject::Ip("1.1.1.1".to_string());
+        limiter.check(key("a"), orders, 0).unwrap();
+        assert_eq!(limiter.check(key("a"), orders, 0), Err(1));
+        limiter.check(key("a"), EndpointClass::Cancels, 0).unwrap();
+        limiter.check(key("b"), orders, 0).unwrap();
+        limiter.check(ip(), orders, 0).unwrap();
+        let usage = limiter.check(ip(), orders, 0).unwrap();
+        assert_eq!(usage.remaining, limits.per_ip.orders.burst - 2);
+    }
+    #[test]
+    fn full_buckets_are_swept_on_an_interval() {
+        let limiter = RateLimiter::new(RateLimits::default());
+        let count = || limiter.buckets.lock().unwrap().buckets.len();
+        let orders = EndpointClass::Orders;
+        limiter.check(Subject::Ip("1.1.1.1".to_string()), orders, SWEEP_INTERVAL).unwrap();
+        limiter.check(Subject::Ip("2.2.2.2".to_string()), orders, SWEEP_INTERVAL).unwrap();
+        assert_eq!(count(), 2);
+        limiter.check(Subject::Ip("3.3.3.3".to_string()), orders, SWEEP_INTERVAL + SECOND).unwrap();
+        assert_eq!(count(), 3);
+        limiter.check(Subject::Ip("3.3.3.3".to_string()), orders, 2 * SWEEP_INTERVAL).unwrap();
+        assert_eq!(count(), 1);
+    }
+    #[test]
+    fn limits_need_a_burst_and_a_rate() {
+        assert!(RateLimits::default().is_valid());
+        let mut limits = RateLimits::default();
+        limits.per_ip.queries.per_second = 0.0;
+        assert!(!limits.is_valid());
+        let mut limits = RateLimits::default();
+        limits.per_key.cancels.burst = 0;
+        assert!(!limits.is_valid());
+    }
+    #[test]
+    fn requests_are_classed_by_what_they_do() {
+        assert_eq!(endpoint_class(&Method::POST, "/api/v1/order"), EndpointClass::Orders);
+        assert_eq!(endpoint_class(&Method::DELETE, "/api/v1/orders"), EndpointClass::Cancels);
+        assert_eq!(endpoint_class(&Method::GET, "/api/v1/order"), EndpointClass::Queries);
+    }
+}
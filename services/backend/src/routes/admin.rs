@@ -0,0 +1,506 @@
+use std::{ collections::HashMap, thread, time::{ Duration, Instant } };
+
+use actix_web::{ web::{ Data, Json, Path, Query, ReqData }, HttpResponse };
+use redis::{ Connection, Value };
+use scylla::transport::errors::QueryError;
+use serde::{ Deserialize, Serialize };
+use serde_json::{ from_str, to_string };
+use tokio::time::sleep;
+use uuid::Uuid;
+
+use super::*;
+use crate::{
+    api::admin::audit_day,
+    app::AppState,
+    auth::Admin,
+    db::{ get_epoch_micros, schema::{ AccountRisk, Adjustment, AuditEntry, MarketSummary, User } },
+};
+
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+const RESPONSE_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+// Pushes a request onto one of the engine's queues and waits for what it responds
+pub fn engine_request(
+    con: &mut Connection,
+    queue: &str,
+    sub_id: i64,
+    req: String
+) -> Result<String, String> {
+    redis::cmd("LPUSH").arg(queue).arg(req).query::<Value>(con).map_err(|err| err.to_string())?;
+    let deadline = Instant::now() + RESPONSE_TIMEOUT;
+    while Instant::now() < deadline {
+        if let Ok(response) = redis::cmd("RPOP").arg(sub_id).query::<String>(con) {
+            return Ok(response);
+        }
+        thread::sleep(RESPONSE_POLL_INTERVAL);
+    }
+    Err(format!("no response from {} within {:?}", queue, RESPONSE_TIMEOUT))
+}
+
+// Nothing reads the queue of a market that does not exist, so requests to one are refused
+async fn known_market(app_state: &AppState, symbol: &Symbol) -> Result<(), HttpResponse> {
+    let markets = match app_state.scylla_db().get_markets().await {
+        Ok(markets) => markets,
+        Err(err) => {
+            return Err(HttpResponse::InternalServerError().json(err.to_string()));
+        }
+    };
+    match markets.iter().any(|market| &market.symbol == symbol) {
+        true => Ok(()),
+        false => Err(HttpResponse::NotFound().json(format!("Unknown market {}", symbol))),
+    }
+}
+
+fn new_sub_id() -> i64 {
+    uuid::Uuid::new_v4().as_u64_pair().0 as i64
+}
+
+const PENDING_OUTCOME: &str = "pending";
+const OUTCOME_ATTEMPTS: u64 = 3;
+
+pub fn audit_entry(
+    admin: &Admin,
+    action: &str,
+    target: String,
+    reason: String,
+    request: &impl Serialize
+) -> AuditEntry {
+    AuditEntry {
+        id: Uuid::new_v4(),
+        timestamp: get_epoch_micros() as i64,
+        admin: admin.name.clone(),
+        action: action.to_string(),
+        target,
+        reason,
+        request: to_string(request).unwrap(),
+        outcome: PENDING_OUTCOME.to_string(),
+    }
+}
+// Every action that changes something is recorded before the engine gets it, and again with
+// what came of it. An action that cannot be recorded is not taken.
+pub async fn audited_request(
+    app_state: &AppState,
+    mut entry: AuditEntry,
+    queue: &str,
+    sub_id: i64
+) -> Result<String, String> {
+    if let Err(err) = record_action(app_state, &entry).await {
+        return Err(format!("Could not record the action: {}", err));
+    }
+    let response = {
+        let con = &mut app_state.redis_connection.lock().unwrap();
+        engine_request(con, queue, sub_id, entry.request.clone())
+    };
+    entry.outcome = match &response {
+        Ok(response) => response.clone(),
+        Err(err) => err.clone(),
+    };
+    record_outcome(app_state, &entry).await;
+    response
+}
+async fn record_action(app_state: &AppState, entry: &AuditEntry) -> Result<(), QueryError> {
+    app_state.scylla_db().record_admin_action(entry).await
+}
+// The action is taken by now, so recording what came of it is retried
+async fn record_outcome(app_state: &AppState, entry: &AuditEntry) {
+    for attempt in 1..=OUTCOME_ATTEMPTS {
+        match record_action(app_state, entry).await {
+            Ok(()) => {
+                return;
+            }
+            Err(err) if attempt == OUTCOME_ATTEMPTS => {
+                eprintln!("Could not record the outcome of admin action {}: {}", entry.id, err);
+            }
+            Err(_) => sleep(Duration::from_millis(100 * attempt)).await,
+        }
+    }
+}
+
+fn market_summary(con: &mut Connection, symbol: Symbol) -> Result<String, String> {
+    let sub_id = new_sub_id();
+    let queue = format!("queues:{}", symbol);
+    let control = MarketControl { symbol, sub_id, timestamp: get_epoch_micros() as i64 };
+    let req = to_string(&EngineRequests::MarketState(control)).unwrap();
+    engine_request(con, &queue, sub_id, req)
+}
+
+#[actix_web::get("/markets")]
+pub async fn market_states(app_state: Data<AppState>) -> HttpResponse {
+    let markets = match app_state.scylla_db().get_markets().await {
+        Ok(markets) => markets,
+        Err(err) => {
+            return HttpResponse::InternalServerError().json(err.to_string());
+        }
+    };
+    let con = &mut app_state.redis_connection.lock().unwrap();
+    let mut summaries: Vec<MarketSummary> = Vec::new();
+    for market in markets {
+        match market_summary(con, market.symbol) {
+            Ok(response) =>
+                match from_str::<MarketSummary>(&response) {
+                    Ok(summary) => summaries.push(summary),
+                    Err(_) => {
+                        return HttpResponse::BadRequest().json(response);
+                    }
+                }
+            Err(err) => {
+                return HttpResponse::InternalServerError().json(err.to_string());
+            }
+        }
+    }
+    HttpResponse::Ok().json(summaries)
+}
+
+#[actix_web::get("/markets/{symbol}")]
+pub async fn market_state(symbol: Path<Symbol>, app_state: Data<AppState>) -> HttpResponse {
+    let symbol = symbol.into_inner();
+    if let Err(response) = known_market(&app_state, &symbol).await {
+        return response;
+    }
+    let con = &mut app_state.redis_connection.lock().unwrap();
+    match market_summary(con, symbol) {
+        Ok(response) =>
+            match from_str::<MarketSummary>(&response) {
+                Ok(summary) => HttpResponse::Ok().json(summary),
+                Err(_) => HttpResponse::BadRequest().json(response),
+            }
+        Err(err) => HttpResponse::InternalServerError().json(err),
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AdminReason {
+    reason: String,
+}
+
+async fn control_market(
+    app_state: Data<AppState>,
+    admin: &Admin,
+    symbol: Symbol,
+    reason: String,
+    halt: bool
+) -> HttpResponse {
+    if reason.trim().is_empty() {
+        return HttpResponse::BadRequest().json("A reason is required");
+    }
+    if let Err(response) = known_market(&app_state, &symbol).await {
+        return response;
+    }
+    let sub_id = new_sub_id();
+    let timestamp = get_epoch_micros() as i64;
+    let control = MarketControl { symbol: symbol.clone(), sub_id, timestamp };
+    let (action, req) = match halt {
+        true => ("halt_market", EngineRequests::Halt(control)),
+        false => ("resume_market", EngineRequests::Resume(control)),
+    };
+    let queue = format!("queues:{}", symbol);
+    let entry = audit_entry(admin, action, symbol, reason, &req);
+    let response = match audited_request(&app_state, entry, &queue, sub_id).await {
+        Ok(response) => response,
+        Err(err) => {
+            return HttpResponse::InternalServerError().json(err);
+        }
+    };
+    match from_str::<MarketSummary>(&response) {
+        Ok(summary) => HttpResponse::Ok().json(summary),
+        Err(_) => HttpResponse::BadRequest().json(response),
+    }
+}
+
+#[actix_web::post("/markets/{symbol}/halt")]
+pub async fn halt_market(
+    symbol: Path<Symbol>,
+    body: Json<AdminReason>,
+    admin: ReqData<Admin>,
+    app_state: Data<AppState>
+) -> HttpResponse {
+    control_market(app_state, &admin, symbol.into_inner(), body.0.reason, true).await
+}
+
+#[actix_web::post("/markets/{symbol}/resume")]
+pub async fn resume_market(
+    symbol: Path<Symbol>,
+    body: Json<AdminReason>,
+    admin: ReqData<Admin>,
+    app_state: Data<AppState>
+) -> HttpResponse {
+    control_market(app_state, &admin, symbol.into_inner(), body.0.reason, false).await
+}
+
+#[derive(Deserialize)]
+pub struct PriceBandParams {
+    max_deviation: Option<Decimal>,
+    reason: String,
+}
+#[actix_web::put("/markets/{symbol}/price-band")]
+pub async fn set_price_band(
+    symbol: Path<Symbol>,
+    body: Json<PriceBandParams>,
+    admin: ReqData<Admin>,
+    app_state: Data<AppState>
+) -> HttpResponse {
+    let symbol = symbol.into_inner();
+    if body.reason.trim().is_empty() {
+        return HttpResponse::BadRequest().json("A reason is required");
+    }
+    if let Err(response) = known_market(&app_state, &symbol).await {
+        return response;
+    }
+    let sub_id = new_sub_id();
+    let req = EngineRequests::SetPriceBand(PriceBand {
+        symbol: symbol.clone(),
+        max_deviation: body.max_deviation,
+        sub_id,
+        timestamp: get_epoch_micros() as i64,
+    });
+    let queue = format!("queues:{}", symbol);
+    let entry = audit_entry(&admin, "set_price_band", symbol, body.0.reason, &req);
+    let response = match audited_request(&app_state, entry, &queue, sub_id).await {
+        Ok(response) => response,
+        Err(err) => {
+            return HttpResponse::InternalServerError().json(err);
+        }
+    };
+    match from_str::<MarketSummary>(&response) {
+        Ok(summary) => HttpResponse::Ok().json(summary),
+        Err(_) => HttpResponse::BadRequest().json(response),
+    }
+}
+
+// What was cancelled in the markets that answered, and why the others did not
+#[derive(Serialize)]
+pub struct CancelledOrders {
+    cancelled: Vec<Order>,
+    failed: HashMap<Symbol, String>,
+}
+#[derive(Deserialize)]
+pub struct CancelUserOrders {
+    // Every market when not given
+    symbol: Option<Symbol>,
+    reason: String,
+}
+#[actix_web::post("/users/{id}/cancel-orders")]
+pub async fn cancel_user_orders(
+    path: Path<Id>,
+    body: Json<CancelUserOrders>,
+    admin: ReqData<Admin>,
+    app_state: Data<AppState>
+) -> HttpResponse {
+    let user_id = path.into_inner();
+    if body.reason.trim().is_empty() {
+        return HttpResponse::BadRequest().json("A reason is required");
+    }
+    let symbols = match body.symbol.clone() {
+        Some(symbol) => {
+            if let Err(response) = known_market(&app_state, &symbol).await {
+                return response;
+            }
+            vec![symbol]
+        }
+        None =>
+            match app_state.scylla_db().get_markets().await {
+                Ok(markets) =>
+                    markets
+                        .into_iter()
+                        .map(|market| market.symbol)
+                        .collect(),
+                Err(err) => {
+                    return HttpResponse::InternalServerError().json(err.to_string());
+                }
+            }
+    };
+    // A market that fails does not keep the others from being cancelled
+    let mut result = CancelledOrders { cancelled: Vec::new(), failed: HashMap::new() };
+    for symbol in symbols {
+        let sub_id = new_sub_id();
+        let req = EngineRequests::CancelAll(CancelAll {
+            user_id,
+            symbol: symbol.clone(),
+            sub_id,
+            timestamp: get_epoch_micros() as i64,
+        });
+        let queue = format!("queues:{}", symbol);
+        let target = format!("{}:{}", user_id, symbol);
+        let entry = audit_entry(&admin, "cancel_orders", target, body.reason.clone(), &req);
+        let response = match audited_request(&app_state, entry, &queue, sub_id).await {
+            Ok(response) => response,
+            Err(err) => {
+                result.failed.insert(symbol, err);
+                continue;
+            }
+        };
+        match from_str::<Vec<Order>>(&response) {
+            Ok(orders) => result.cancelled.extend(orders),
+            Err(_) => {
+                result.failed.insert(symbol, response);
+            }
+        }
+    }
+    match result.failed.is_empty() {
+        true => HttpResponse::Ok().json(result),
+        false => HttpResponse::InternalServerError().json(result),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AdjustBalanceParams {
+    asset: Asset,
+    amount: Quantity,
+    reason: AdjustmentReason,
+    note: String,
+}
+#[actix_web::post("/users/{id}/adjust-balance")]
+pub async fn adjust_balance(
+    path: Path<Id>,
+    body: Json<AdjustBalanceParams>,
+    admin: ReqData<Admin>,
+    app_state: Data<AppState>
+) -> HttpResponse {
+    let user_id = path.into_inner();
+    if body.note.trim().is_empty() {
+        return HttpResponse::BadRequest().json("A note explaining the adjustment is required");
+    }
+    let sub_id = new_sub_id();
+    let req = UserRequests::AdjustBalance(AdjustBalance {
+        user_id,
+        asset: body.asset,
+        amount: body.amount,
+        reason: body.reason,
+        sub_id,
+    });
+    let reason = format!("{}: {}", body.reason, body.note);
+    let entry = audit_entry(&admin, "adjust_balance", user_id.to_string(), reason, &req);
+    let response = match audited_request(&app_state, entry, "queues:user", sub_id).await {
+        Ok(response) => response,
+        Err(err) => {
+            return HttpResponse::InternalServerError().json(err);
+        }
+    };
+    match from_str::<Adjustment>(&response) {
+        Ok(adjustment) => HttpResponse::Ok().json(adjustment),
+        Err(_) => HttpResponse::BadRequest().json(response),
+    }
+}
+
+async fn freeze_account(
+    app_state: Data<AppState>,
+    admin: &Admin,
+    user_id: Id,
+    reason: String,
+    frozen: bool
+) -> HttpResponse {
+    if reason.trim().is_empty() {
+        return HttpResponse::BadRequest().json("A reason is required");
+    }
+    let sub_id = new_sub_id();
+    let req = UserRequests::FreezeAccount(FreezeAccount { user_id, frozen, sub_id });
+    let action = if frozen { "freeze_account" } else { "unfreeze_account" };
+    let entry = audit_entry(admin, action, user_id.to_string(), reason, &req);
+    let response = match audited_request(&app_state, entry, "queues:user", sub_id).await {
+        Ok(response) => response,
+        Err(err) => {
+            return HttpResponse::InternalServerError().json(err);
+        }
+    };
+    match from_str::<User>(&response) {
+        Ok(user) => HttpResponse::Ok().json(user),
+        Err(_) => HttpResponse::BadRequest().json(response),
+    }
+}
+
+#[actix_web::post("/users/{id}/freeze")]
+pub async fn freeze_user(
+    path: Path<Id>,
+    body: Json<AdminReason>,
+    admin: ReqData<Admin>,
+    app_state: Data<AppState>
+) -> HttpResponse {
+    freeze_account(app_state, &admin, path.into_inner(), body.0.reason, true).await
+}
+
+#[actix_web::post("/users/{id}/unfreeze")]
+pub async fn unfreeze_user(
+    path: Path<Id>,
+    body: Json<AdminReason>,
+    admin: ReqData<Admin>,
+    app_state: Data<AppState>
+) -> HttpResponse {
+    freeze_account(app_state, &admin, path.into_inner(), body.0.reason, false).await
+}
+
+#[actix_web::get("/users/{id}")]
+pub async fn user_state(path: Path<Id>, app_state: Data<AppState>) -> HttpResponse {
+    let con = &mut app_state.redis_connection.lock().unwrap();
+    let sub_id = new_sub_id();
+    let req = UserRequests::GetUserBalances(GetUserBalances { user_id: path.into_inner(), sub_id });
+    match engine_request(con, "queues:user", sub_id, to_string(&req).unwrap()) {
+        Ok(response) =>
+            match from_str::<User>(&response) {
+                Ok(user) => HttpResponse::Ok().json(user),
+                Err(_) => HttpResponse::NotFound().json(response),
+            }
+        Err(err) => HttpResponse::InternalServerError().json(err),
+    }
+}
+
+#[actix_web::get("/users/{id}/risk-limits")]
+pub async fn risk_limits(path: Path<Id>, app_state: Data<AppState>) -> HttpResponse {
+    let con = &mut app_state.redis_connection.lock().unwrap();
+    let sub_id = new_sub_id();
+    let req = UserRequests::GetRiskLimits(GetRiskLimits { user_id: path.into_inner(), sub_id });
+    match engine_request(con, "queues:user", sub_id, to_string(&req).unwrap()) {
+        Ok(response) =>
+            match from_str::<AccountRisk>(&response) {
+                Ok(risk) => HttpResponse::Ok().json(risk),
+                Err(_) => HttpResponse::NotFound().json(response),
+            }
+        Err(err) => HttpResponse::InternalServerError().json(err),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RiskLimitsParams {
+    // The defaults when not given
+    limits: Option<RiskLimits>,
+    reason: String,
+}
+#[actix_web::put("/users/{id}/risk-limits")]
+pub async fn set_risk_limits(
+    path: Path<Id>,
+    body: Json<RiskLimitsParams>,
+    admin: ReqData<Admin>,
+    app_state: Data<AppState>
+) -> HttpResponse {
+    let user_id = path.into_inner();
+    if body.reason.trim().is_empty() {
+        return HttpResponse::BadRequest().json("A reason is required");
+    }
+    let sub_id = new_sub_id();
+    let req = UserRequests::SetRiskLimits(SetRiskLimits { user_id, limits: body.limits, sub_id });
+    let (target, reason) = (user_id.to_string(), body.0.reason);
+    let entry = audit_entry(&admin, "set_risk_limits", target, reason, &req);
+    let response = match audited_request(&app_state, entry, "queues:user", sub_id).await {
+        Ok(response) => response,
+        Err(err) => {
+            return HttpResponse::InternalServerError().json(err);
+        }
+    };
+    match from_str::<AccountRisk>(&response) {
+        Ok(risk) => HttpResponse::Ok().json(risk),
+        Err(_) => HttpResponse::BadRequest().json(response),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AuditQuery {
+    // Days since the epoch, today when not given
+    day: Option<i64>,
+}
+#[actix_web::get("/audit")]
+pub async fn audit_log(query: Query<AuditQuery>, app_state: Data<AppState>) -> HttpResponse {
+    let day = query.day.unwrap_or(audit_day(get_epoch_micros() as i64));
+    match app_state.scylla_db().get_audit(day).await {
+        Ok(entries) => HttpResponse::Ok().json(entries),
+        Err(err) => HttpResponse::InternalServerError().json(err.to_string()),
+    }
+}
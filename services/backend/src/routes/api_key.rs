@@ -0,0 +1,103 @@
+use std::collections::HashSet;
+
+use actix_web::{ web::{ Data, Json, Path, ReqData }, HttpResponse };
+use serde::{ Deserialize, Serialize };
+
+use crate::{
+    app::AppState,
+    auth::{ ApiKey, Authenticated, Permission },
+    db::schema::Id,
+};
+
+#[derive(Deserialize)]
+pub struct NewApiKey {
+    permissions: HashSet<Permission>,
+    #[serde(default)]
+    allowed_ips: HashSet<String>,
+    // One of the caller's sub-accounts, when the key is for it
+    user_id: Option<Id>,
+}
+// The secret is only ever shown when the key is created
+#[derive(Serialize)]
+pub struct ListedApiKey {
+    key: String,
+    user_id: Id,
+    permissions: HashSet<Permission>,
+    allowed_ips: HashSet<String>,
+    created_at: i64,
+}
+impl From<ApiKey> for ListedApiKey {
+    fn from(api_key: ApiKey) -> ListedApiKey {
+        ListedApiKey {
+            key: api_key.key,
+            user_id: api_key.user_id,
+            permissions: api_key.permissions,
+            allowed_ips: api_key.allowed_ips,
+            created_at: api_key.created_at,
+        }
+    }
+}
+
+#[actix_web::post("/api-keys")]
+pub async fn new_api_key(
+    body: Json<NewApiKey>,
+    auth: ReqData<Authenticated>,
+    app_state: Data<AppState>
+) -> HttpResponse {
+    if body.permissions.is_empty() || !body.permissions.is_subset(&auth.permissions) {
+        return HttpResponse::Forbidden().json(
+            "A key can only be given permissions the calling key has"
+        );
+    }
+    let s_db = app_state.scylla_db();
+    let user_id = match body.user_id {
+        None => auth.user_id,
+        Some(user_id) if user_id == auth.user_id => user_id,
+        Some(user_id) => {
+            match s_db.get_sub_accounts(auth.user_id).await {
+                Ok(sub_accounts) if sub_accounts.iter().any(|user| user.id == user_id) => user_id,
+                Ok(_) => {
+                    return HttpResponse::Forbidden().json("Not a sub-account of this user");
+                }
+                Err(err) => {
+                    return HttpResponse::InternalServerError().json(err.to_string());
+                }
+            }
+        }
+    };
+    let api_key = ApiKey::generate(user_id, body.permissions.clone(), body.allowed_ips.clone());
+    match s_db.new_api_key(&api_key).await {
+        Ok(_) => HttpResponse::Created().json(api_key),
+        Err(err) => HttpResponse::InternalServerError().json(err.to_string()),
+    }
+}
+
+#[actix_web::get("/api-keys")]
+pub async fn api_keys(auth: ReqData<Authenticated>, app_state: Data<AppState>) -> HttpResponse {
+    let s_db = app_state.scylla_db();
+    match s_db.get_api_keys(auth.user_id).await {
+        Ok(api_keys) => {
+            let api_keys: Vec<ListedApiKey> = api_keys.into_iter().map(From::from).collect();
+            HttpResponse::Ok().json(api_keys)
+        }
+        Err(err) => HttpResponse::InternalServerError().json(err.to_string()),
+    }
+}
+
+#[actix_web::delete("/api-keys/{key}")]
+pub async fn delete_api_key(
+    key: Path<String>,
+    auth: ReqData<Authenticated>,
+    app_state: Data<AppState>
+) -> HttpResponse {
+    let s_db = app_state.scylla_db();
+    match s_db.get_api_key(&key).await {
+        Ok(api_key) if api_key.user_id == auth.user_id => {
+            match s_db.delete_api_key(&key).await {
+                Ok(_) => HttpResponse::Ok().json(ListedApiKey::from(api_key)),
+                Err(err) => HttpResponse::InternalServerError().json(err.to_string()),
+            }
+        }
+        _ => HttpResponse::NotFound().json("API key not found"),
+    }
+}
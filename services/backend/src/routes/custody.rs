@@ -0,0 +1,31 @@
+use actix_web::{ web::{ Data, Json, ReqData }, HttpResponse };
+use serde_json::{ from_str, to_string };
+
+use super::*;
+use super::admin::{ audit_entry, audited_request };
+use crate::{ app::AppState, auth::Admin, db::schema::Deposit };
+
+// Mock custody for local runs, reports a transfer the way the engine's custody watcher would and
+// responds with the deposit it became. Reporting it again with more confirmations credits it.
+#[actix_web::post("/transfers")]
+pub async fn report_transfer(
+    body: Json<IncomingTransfer>,
+    admin: ReqData<Admin>,
+    app_state: Data<AppState>
+) -> HttpResponse {
+    let sub_id = uuid::Uuid::new_v4().as_u64_pair().0 as i64;
+    let target = body.tx_id.clone();
+    let req = UserRequests::ReportTransfer(ReportTransfer {
+        transfer: body.0,
+        sub_id: Some(sub_id),
+    });
+    let entry = audit_entry(&admin, "report_transfer", target, String::new(), &req);
+    match audited_request(&app_state, entry, "queues:user", sub_id).await {
+        Ok(response) =>
+            match from_str::<Deposit>(&response) {
+                Ok(deposit) => HttpResponse::Ok().json(deposit),
+                Err(_) => HttpResponse::BadRequest().json(response),
+            }
+        Err(err) => HttpResponse::InternalServerError().json(err),
+    }
+}
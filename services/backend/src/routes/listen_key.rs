@@ -0,0 +1,80 @@
+use actix_web::{ web::{ Data, Json, ReqData }, HttpResponse };
+use common::listen_key::{ redis_key, LISTEN_KEY_TTL };
+use redis::Value;
+use serde::{ Deserialize, Serialize };
+
+use crate::{ app::AppState, auth::{ random_hex, Authenticated } };
+
+#[derive(Serialize, Deserialize)]
+pub struct ListenKey {
+    listen_key: String,
+    // Seconds until it expires unless renewed
+    #[serde(default)]
+    expires_in: u64,
+}
+
+#[actix_web::post("/listen-key")]
+pub async fn new_listen_key(
+    auth: ReqData<Authenticated>,
+    app_state: Data<AppState>
+) -> HttpResponse {
+    let con = &mut app_state.redis_connection.lock().unwrap();
+    let listen_key = random_hex(32);
+    let response = redis
+        ::cmd("SET")
+        .arg(redis_key(&listen_key))
+        .arg(auth.user_id)
+        .arg("EX")
+        .arg(LISTEN_KEY_TTL)
+        .query::<Value>(con);
+    match response {
+        Ok(_) => HttpResponse::Created().json(ListenKey { listen_key, expires_in: LISTEN_KEY_TTL }),
+        Err(err) => HttpResponse::InternalServerError().json(err.to_string()),
+    }
+}
+
+// Renewing or deleting a key needs the key of the user it was issued to
+fn owns_listen_key(con: &mut redis::Connection, listen_key: &str, user_id: i64) -> bool {
+    let owner = redis::cmd("GET").arg(redis_key(listen_key)).query::<Option<i64>>(con);
+    matches!(owner, Ok(Some(owner)) if owner == user_id)
+}
+
+#[actix_web::put("/listen-key")]
+pub async fn renew_listen_key(
+    body: Json<ListenKey>,
+    auth: ReqData<Authenticated>,
+    app_state: Data<AppState>
+) -> HttpResponse {
+    let con = &mut app_state.redis_connection.lock().unwrap();
+    if !owns_listen_key(con, &body.listen_key, auth.user_id) {
+        return HttpResponse::NotFound().json("Listen key not found or expired");
+    }
+    let response = redis
+        ::cmd("EXPIRE")
+        .arg(redis_key(&body.listen_key))
+        .arg(LISTEN_KEY_TTL)
+        .query::<Value>(con);
+    match response {
+        Ok(_) => {
+            let listen_key = body.0.listen_key;
+            HttpResponse::Ok().json(ListenKey { listen_key, expires_in: LISTEN_KEY_TTL })
+        }
+        Err(err) => HttpResponse::InternalServerError().json(err.to_string()),
+    }
+}
+
+#[actix_web::delete("/listen-key")]
+pub async fn delete_listen_key(
+    body: Json<ListenKey>,
+    auth: ReqData<Authenticated>,
+    app_state: Data<AppState>
+) -> HttpResponse {
+    let con = &mut app_state.redis_connection.lock().unwrap();
+    if !owns_listen_key(con, &body.listen_key, auth.user_id) {
+        return HttpResponse::NotFound().json("Listen key not found or expired");
+    }
+    match redis::cmd("DEL").arg(redis_key(&body.listen_key)).query::<Value>(con) {
+        Ok(_) => HttpResponse::Ok().json(body.0),
+        Err(err) => HttpResponse::InternalServerError().json(err.to_string()),
+    }
+}
@@ -0,0 +1,86 @@
+use actix_web::{ web::{ Data, Json, ReqData }, HttpResponse };
+use serde_json::{ from_str, to_string };
+
+use super::*;
+use super::admin::engine_request;
+use crate::{ app::AppState, auth::Authenticated, db::schema::{ MarginAccount, Position } };
+
+fn new_sub_id() -> i64 {
+    uuid::Uuid::new_v4().as_u64_pair().0 as i64
+}
+// Every margin request is answered with the margin account as the engine left it
+fn margin_request(app_state: &AppState, sub_id: i64, req: UserRequests) -> HttpResponse {
+    let con = &mut app_state.redis_connection.lock().unwrap();
+    match engine_request(con, "queues:user", sub_id, to_string(&req).unwrap()) {
+        Ok(response) =>
+            match from_str::<MarginAccount>(&response) {
+                Ok(account) => HttpResponse::Ok().json(account),
+                Err(_) => HttpResponse::BadRequest().json(response),
+            }
+        Err(err) => HttpResponse::InternalServerError().json(err.to_string()),
+    }
+}
+
+#[actix_web::get("/margin")]
+pub async fn margin_account(
+    auth: ReqData<Authenticated>,
+    app_state: Data<AppState>
+) -> HttpResponse {
+    let sub_id = new_sub_id();
+    let req = UserRequests::GetMarginAccount(GetMarginAccount { user_id: auth.user_id, sub_id });
+    margin_request(&app_state, sub_id, req)
+}
+
+// Cross or isolated to one market, turning margin off needs every loan repaid
+#[actix_web::put("/margin")]
+pub async fn set_margin_mode(
+    mut body: Json<SetMarginMode>,
+    auth: ReqData<Authenticated>,
+    app_state: Data<AppState>
+) -> HttpResponse {
+    let sub_id = new_sub_id();
+    body.sub_id = sub_id;
+    body.user_id = auth.user_id;
+    margin_request(&app_state, sub_id, UserRequests::SetMarginMode(body.0))
+}
+
+#[actix_web::post("/margin/borrow")]
+pub async fn borrow(
+    mut body: Json<LoanRequest>,
+    auth: ReqData<Authenticated>,
+    app_state: Data<AppState>
+) -> HttpResponse {
+    let sub_id = new_sub_id();
+    body.sub_id = sub_id;
+    body.user_id = auth.user_id;
+    margin_request(&app_state, sub_id, UserRequests::Borrow(body.0))
+}
+
+// Interest is repaid before the loan, more than is owed is never taken
+#[actix_web::post("/margin/repay")]
+pub async fn repay(
+    mut body: Json<LoanRequest>,
+    auth: ReqData<Authenticated>,
+    app_state: Data<AppState>
+) -> HttpResponse {
+    let sub_id = new_sub_id();
+    body.sub_id = sub_id;
+    body.user_id = auth.user_id;
+    margin_request(&app_state, sub_id, UserRequests::Repay(body.0))
+}
+
+// Open perpetual positions, margined apart from the spot margin account
+#[actix_web::get("/positions")]
+pub async fn positions(auth: ReqData<Authenticated>, app_state: Data<AppState>) -> HttpResponse {
+    let sub_id = new_sub_id();
+    let req = UserRequests::GetPositions(GetPositions { user_id: auth.user_id, sub_id });
+    let con = &mut app_state.redis_connection.lock().unwrap();
+    match engine_request(con, "queues:user", sub_id, to_string(&req).unwrap()) {
+        Ok(response) =>
+            match from_str::<Vec<Position>>(&response) {
+                Ok(positions) => HttpResponse::Ok().json(positions),
+                Err(_) => HttpResponse::BadRequest().json(response),
+            }
+        Err(err) => HttpResponse::InternalServerError().json(err.to_string()),
+    }
+}
@@ -4,8 +4,10 @@ use rust_decimal::Decimal;
 use serde::{ Deserialize, Serialize };
 
 use crate::db::schema::{
+    AdjustmentReason,
     Asset,
     Id,
+    MarginMode,
     Order,
     OrderId,
     OrderSide,
@@ -13,6 +15,7 @@ use crate::db::schema::{
     OrderType,
     Price,
     Quantity,
+    RiskLimits,
     Symbol,
 };
 
@@ -20,7 +23,15 @@ pub mod order;
 pub mod user;
 pub mod ping;
 pub mod trades;
+pub mod withdrawal;
+#[cfg(feature = "mock-custody")]
+pub mod custody;
+pub mod api_key;
+pub mod listen_key;
+pub mod admin;
+pub mod margin;
 
+// Requests never name their user in the body, handlers set it from the API key that signed them
 #[derive(Debug, Serialize, Deserialize)]
 pub enum EngineRequests {
     ExecuteOrder(Order),
@@ -28,10 +39,15 @@ pub enum EngineRequests {
     CancelAll(CancelAll),
     OpenOrders(OpenOrders),
     OpenOrder(OpenOrder),
+    Halt(MarketControl),
+    Resume(MarketControl),
+    MarketState(MarketControl),
+    SetPriceBand(PriceBand),
 }
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CancelOrder {
     id: OrderId,
+    #[serde(skip_deserializing)]
     user_id: Id,
     symbol: Symbol,
     price: Decimal,
@@ -43,6 +59,7 @@ pub struct CancelOrder {
 }
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CancelAll {
+    #[serde(skip_deserializing)]
     user_id: Id,
     symbol: Symbol,
     #[serde(skip_deserializing)]
@@ -52,6 +69,7 @@ pub struct CancelAll {
 }
 #[derive(Debug, Serialize, Deserialize)]
 pub struct OpenOrders {
+    #[serde(skip_deserializing)]
     user_id: Id,
     symbol: Symbol,
     #[serde(skip_deserializing)]
@@ -59,45 +77,172 @@ pub struct OpenOrders {
 }
 #[derive(Debug, Serialize, Deserialize)]
 pub struct OpenOrder {
+    #[serde(skip_deserializing)]
     user_id: Id,
     order_id: OrderId,
     symbol: Symbol,
     #[serde(skip_deserializing)]
     sub_id: i64,
 }
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MarketControl {
+    symbol: Symbol,
+    sub_id: i64,
+    timestamp: i64,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PriceBand {
+    symbol: Symbol,
+    // Percent away from the last trade or mid price, orders are not checked without one
+    max_deviation: Option<Decimal>,
+    sub_id: i64,
+    timestamp: i64,
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum UserRequests {
     NewUser(NewUser),
-    Deposit(Deposit),
     Withdraw(Withdraw),
+    UpdateWithdrawal(UpdateWithdrawal),
+    ReportTransfer(ReportTransfer),
+    NewSubAccount(NewSubAccount),
+    Transfer(Transfer),
     GetUserBalances(GetUserBalances),
+    AdjustBalance(AdjustBalance),
+    FreezeAccount(FreezeAccount),
+    SetRiskLimits(SetRiskLimits),
+    GetRiskLimits(GetRiskLimits),
+    SetMarginMode(SetMarginMode),
+    Borrow(LoanRequest),
+    Repay(LoanRequest),
+    GetMarginAccount(GetMarginAccount),
+    GetPositions(GetPositions),
+    GetPortfolio(GetPortfolio),
 }
 #[derive(Debug, Serialize, Deserialize)]
 pub struct NewUser {
     #[serde(skip_deserializing)]
     sub_id: i64,
 }
+
 #[derive(Debug, Serialize, Deserialize)]
-pub struct Deposit {
+pub struct Withdraw {
+    #[serde(skip_deserializing)]
     user_id: Id,
     asset: Asset,
     quantity: Quantity,
+    destination: String,
     #[serde(skip_deserializing)]
     sub_id: i64,
 }
-
 #[derive(Debug, Serialize, Deserialize)]
-pub struct Withdraw {
+pub struct UpdateWithdrawal {
+    id: Id,
+    action: WithdrawalAction,
+    sub_id: i64,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub enum WithdrawalAction {
+    Approve,
+    Sent {
+        tx_id: String,
+    },
+    Complete,
+    Fail {
+        reason: String,
+    },
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReportTransfer {
+    transfer: IncomingTransfer,
+    sub_id: Option<i64>,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IncomingTransfer {
+    tx_id: String,
     user_id: Id,
     asset: Asset,
+    amount: Quantity,
+    confirmations: u32,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewSubAccount {
+    #[serde(skip_deserializing)]
+    master_id: Id,
+    #[serde(skip_deserializing)]
+    sub_id: i64,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Transfer {
+    #[serde(skip_deserializing)]
+    from: Id,
+    to: Id,
+    asset: Asset,
     quantity: Quantity,
     #[serde(skip_deserializing)]
     sub_id: i64,
 }
 #[derive(Debug, Serialize, Deserialize)]
 pub struct GetUserBalances {
+    #[serde(skip_deserializing)]
     user_id: Id,
     #[serde(skip_deserializing)]
     sub_id: i64,
 }
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AdjustBalance {
+    user_id: Id,
+    asset: Asset,
+    amount: Quantity,
+    reason: AdjustmentReason,
+    sub_id: i64,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FreezeAccount {
+    user_id: Id,
+    frozen: bool,
+    sub_id: i64,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetRiskLimits {
+    user_id: Id,
+    limits: Option<RiskLimits>,
+    sub_id: i64,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetRiskLimits {
+    user_id: Id,
+    sub_id: i64,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetMarginMode {
+    #[serde(skip_deserializing)]
+    user_id: Id,
+    mode: Option<MarginMode>,
+    #[serde(skip_deserializing)]
+    sub_id: i64,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LoanRequest {
+    #[serde(skip_deserializing)]
+    user_id: Id,
+    asset: Asset,
+    quantity: Quantity,
+    #[serde(skip_deserializing)]
+    sub_id: i64,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetMarginAccount {
+    user_id: Id,
+    sub_id: i64,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetPositions {
+    user_id: Id,
+    sub_id: i64,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetPortfolio {
+    user_id: Id,
+    sub_id: i64,
+}
@@ -1,6 +1,6 @@
 use std::time::{ Duration, Instant };
 
-use actix_web::{ web::{ Data, Json, Query }, HttpResponse };
+use actix_web::{ web::{ Data, Json, Query, ReqData }, HttpResponse };
 use redis::{ Commands, PubSub, Value };
 use rust_decimal::Decimal;
 use serde::{ Deserialize, Serialize };
@@ -10,6 +10,7 @@ use super::*;
 
 use crate::{
     app::AppState,
+    auth::Authenticated,
     db::{get_epoch_micros, schema::{
         Asset,
         Exchange,
@@ -31,14 +32,17 @@ pub struct OrderParams {
     order_side: OrderSide,
     order_type: OrderType,
     quantity: Quantity,
-    user_id: Id,
     symbol: Symbol,
 }
 #[actix_web::post("/order")]
 pub async fn execute_order(
     body: Json<OrderParams>,
+    auth: ReqData<Authenticated>,
     app_state: Data<AppState>
 ) -> actix_web::HttpResponse {
+    if body.quantity <= Decimal::ZERO {
+        return HttpResponse::BadRequest().json("The quantity of an order must be positive");
+    }
     let placed_order_time = Instant::now();
     let mut con = &mut app_state.redis_connection.lock().unwrap();
     let sub_id = uuid::Uuid::new_v4().as_u64_pair().0 as i64;
@@ -47,7 +51,7 @@ pub async fn execute_order(
     let response = {
         let order = Order::new(
             sub_id, // this field will be used to publish order response, and is not the actual order_id
-            body.user_id,
+            auth.user_id,
             body.quantity,
             body.price,
             body.order_side.clone(),
@@ -89,6 +93,7 @@ pub async fn execute_order(
 #[actix_web::delete("/orders")]
 pub async fn order_cancel_all(
     mut body: Json<CancelAll>,
+    auth: ReqData<Authenticated>,
     app_state: Data<AppState>
 ) -> HttpResponse {
     let total_time = Instant::now();
@@ -97,6 +102,7 @@ pub async fn order_cancel_all(
     let symbol = body.symbol.clone();
     let response = {
         body.sub_id = sub_id;
+        body.user_id = auth.user_id;
         body.timestamp = get_epoch_micros() as i64;
         let req = to_string(&EngineRequests::CancelAll(body.0)).unwrap();
         let res = redis
@@ -129,13 +135,18 @@ pub async fn order_cancel_all(
     response
 }
 #[actix_web::delete("/order")]
-pub async fn order_cancel(mut body: Json<CancelOrder>, app_state: Data<AppState>) -> HttpResponse {
+pub async fn order_cancel(
+    mut body: Json<CancelOrder>,
+    auth: ReqData<Authenticated>,
+    app_state: Data<AppState>
+) -> HttpResponse {
     let total_time = Instant::now();
     let mut con = &mut app_state.redis_connection.lock().unwrap();
     let sub_id = uuid::Uuid::new_v4().as_u64_pair().0 as i64;
     let symbol = body.symbol.clone();
     let response = {
         body.sub_id = sub_id;
+        body.user_id = auth.user_id;
         body.timestamp = get_epoch_micros() as i64;
         let req = to_string(&EngineRequests::CancelOrder(body.0)).unwrap();
         let res = redis
@@ -169,13 +180,18 @@ pub async fn order_cancel(mut body: Json<CancelOrder>, app_state: Data<AppState>
 }
 
 #[actix_web::get("/order")]
-pub async fn get_open_order(mut body: Json<OpenOrder>, app_state: Data<AppState>) -> HttpResponse {
+pub async fn get_open_order(
+    mut body: Json<OpenOrder>,
+    auth: ReqData<Authenticated>,
+    app_state: Data<AppState>
+) -> HttpResponse {
     let total_time = Instant::now();
     let mut con = &mut app_state.redis_connection.lock().unwrap();
     let sub_id = uuid::Uuid::new_v4().as_u64_pair().0 as i64;
     let symbol = body.symbol.clone();
     let response = {
         body.sub_id = sub_id;
+        body.user_id = auth.user_id;
         let req = to_string(&EngineRequests::OpenOrder(body.0)).unwrap();
         let res = redis
             ::cmd("LPUSH")
@@ -210,6 +226,7 @@ pub async fn get_open_order(mut body: Json<OpenOrder>, app_state: Data<AppState>
 #[actix_web::get("/orders")]
 pub async fn get_open_orders(
     mut body: Json<OpenOrders>,
+    auth: ReqData<Authenticated>,
     app_state: Data<AppState>
 ) -> HttpResponse {
     let total_time = Instant::now();
@@ -218,6 +235,7 @@ pub async fn get_open_orders(
     let symbol = body.symbol.clone();
     let response = {
         body.sub_id = sub_id;
+        body.user_id = auth.user_id;
         let req = to_string(&EngineRequests::OpenOrders(body.0)).unwrap();
         let res = redis
             ::cmd("LPUSH")
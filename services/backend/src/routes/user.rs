@@ -1,12 +1,35 @@
-use std::time::Duration;
+use std::{ error::Error, time::Duration };
 
-use actix_web::{ web::{ Data, Json, Query }, HttpResponse };
+use actix_web::{ web::{ Data, Json, Query, ReqData }, HttpResponse };
 use redis::{ Commands, Value };
 use serde::{ Deserialize, Serialize };
 use serde_json::{ from_str, to_string };
 use super::*;
-use crate::{ api::user, app::AppState, db::schema::{ Asset, Id, Quantity, User } };
+use crate::{
+    api::{ order::MAX_ORDERS_PAGE_SIZE, portfolio::{ spot_fills, SpotFill }, user },
+    app::AppState,
+    auth::{ ApiKey, Authenticated },
+    db::{ schema::{
+        self,
+        Asset,
+        Exchange,
+        Id,
+        Portfolio,
+        Quantity,
+        Symbol,
+        User,
+        Valuation,
+        Withdrawal,
+    }, ScyllaDb },
+    routes::admin::engine_request,
+};
 
+// A new user gets a first API key with every permission, further keys are made with it
+#[derive(Serialize)]
+pub struct NewUserResponse {
+    user: User,
+    api_key: ApiKey,
+}
 #[actix_web::post("/new")]
 pub async fn new_user(app_state: Data<AppState>) -> HttpResponse {
     let s_db = app_state.scylla_db.lock().unwrap();
@@ -32,7 +55,11 @@ pub async fn new_user(app_state: Data<AppState>) -> HttpResponse {
             match from_str::<User>(&response) {
                 Ok(user) => {
                     let _ = s_db.new_user(user.clone()).await;
-                    return HttpResponse::Created().json(user);
+                    let api_key = ApiKey::all_permissions(user.id);
+                    if let Err(err) = s_db.new_api_key(&api_key).await {
+                        return HttpResponse::InternalServerError().json(err.to_string());
+                    }
+                    return HttpResponse::Created().json(NewUserResponse { user, api_key });
                 }
                 Err(err) => HttpResponse::BadRequest().json(response),
             }
@@ -44,11 +71,13 @@ pub async fn new_user(app_state: Data<AppState>) -> HttpResponse {
 #[actix_web::get("")]
 pub async fn get_user(
     mut query: Query<GetUserBalances>,
+    auth: ReqData<Authenticated>,
     app_state: Data<AppState>
 ) -> actix_web::HttpResponse {
     let mut con = &mut app_state.redis_connection.lock().unwrap();
     let sub_id = uuid::Uuid::new_v4().as_u64_pair().0 as i64;
     query.sub_id = sub_id;
+    query.user_id = auth.user_id;
     let req = to_string(&UserRequests::GetUserBalances(query.0)).unwrap();
     let response = redis::cmd("LPUSH").arg("queues:user").arg(req).query::<Value>(&mut con);
     match response {
@@ -73,16 +102,17 @@ pub async fn get_user(
     }
 }
 
-#[actix_web::post("/deposit")]
-pub async fn deposit(
-    mut body: Json<Deposit>,
+#[actix_web::post("/withdraw")]
+pub async fn withdraw(
+    mut body: Json<Withdraw>,
+    auth: ReqData<Authenticated>,
     app_state: Data<AppState>
 ) -> actix_web::HttpResponse {
-    let s_db = app_state.scylla_db.lock().unwrap();
     let con = &mut app_state.redis_connection.lock().unwrap();
     let sub_id = uuid::Uuid::new_v4().as_u64_pair().0 as i64;
     body.sub_id = sub_id;
-    let req = to_string(&UserRequests::Deposit(body.0)).unwrap();
+    body.user_id = auth.user_id;
+    let req = to_string(&UserRequests::Withdraw(body.0)).unwrap();
     let response = redis::cmd("LPUSH").arg("queues:user").arg(req).query::<Value>(con);
     match response {
         Ok(_) => {
@@ -95,11 +125,9 @@ pub async fn deposit(
                 }
             }
             let response: String = response_result.unwrap();
-            match from_str::<User>(&response) {
-                Ok(user) => {
-                    let _ = s_db.update_user(&mut user.clone()).await;
-                    return HttpResponse::Created().json(user);
-                }
+            // The engine persists the withdrawal and the balance it locked
+            match from_str::<Withdrawal>(&response) {
+                Ok(withdrawal) => HttpResponse::Created().json(withdrawal),
                 Err(err) => HttpResponse::BadRequest().json(response),
             }
         }
@@ -107,16 +135,218 @@ pub async fn deposit(
     }
 }
 
-#[actix_web::post("/withdraw")]
-pub async fn withdraw(
-    mut body: Json<Withdraw>,
+#[derive(Serialize, Deserialize)]
+pub struct OrdersHistory {
+    symbol: Symbol,
+    limit: Option<i32>,
+    page: Option<String>,
+}
+const ORDERS_PAGE_SIZE: i32 = 100;
+#[actix_web::get("/history/orders")]
+pub async fn orders_history(
+    query: Query<OrdersHistory>,
+    auth: ReqData<Authenticated>,
+    app_state: Data<AppState>
+) -> actix_web::HttpResponse {
+    let limit = query.limit.unwrap_or(ORDERS_PAGE_SIZE);
+    if !(1..=MAX_ORDERS_PAGE_SIZE).contains(&limit) {
+        return HttpResponse::BadRequest().json(
+            format!("limit must be between 1 and {}", MAX_ORDERS_PAGE_SIZE)
+        );
+    }
+    let exchange = match Exchange::from_symbol(query.symbol.clone()) {
+        Ok(exchange) => exchange,
+        Err(err) => {
+            return HttpResponse::NotFound().json(err);
+        }
+    };
+    let s_db = app_state.scylla_db();
+    if let Err(err) = s_db.get_user(auth.user_id).await {
+        return HttpResponse::NotFound().json(format!("User Not Found\n {}", err));
+    }
+    let page = query.page.clone();
+    match s_db.get_users_orders(auth.user_id, &exchange.symbol, limit, page).await {
+        Ok(page) => HttpResponse::Ok().json(page),
+        Err(err) => HttpResponse::BadRequest().json(format!("Invalid page\n {}", err)),
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UserLedger {
+    limit: Option<i32>,
+    page: Option<String>,
+}
+const LEDGER_PAGE_SIZE: i32 = 100;
+const MAX_LEDGER_PAGE_SIZE: i32 = 1000;
+#[actix_web::get("/ledger")]
+pub async fn ledger(
+    query: Query<UserLedger>,
+    auth: ReqData<Authenticated>,
+    app_state: Data<AppState>
+) -> actix_web::HttpResponse {
+    let limit = query.limit.unwrap_or(LEDGER_PAGE_SIZE);
+    if !(1..=MAX_LEDGER_PAGE_SIZE).contains(&limit) {
+        return HttpResponse::BadRequest().json(
+            format!("limit must be between 1 and {}", MAX_LEDGER_PAGE_SIZE)
+        );
+    }
+    let s_db = app_state.scylla_db();
+    if let Err(err) = s_db.get_user(auth.user_id).await {
+        return HttpResponse::NotFound().json(format!("User Not Found\n {}", err));
+    }
+    match s_db.get_ledger(auth.user_id, limit, query.page.clone()).await {
+        Ok(page) => HttpResponse::Ok().json(page),
+        Err(err) => HttpResponse::BadRequest().json(format!("Invalid page\n {}", err)),
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UserTrades {
+    symbol: Symbol,
+    limit: Option<i32>,
+    page: Option<String>,
+}
+const TRADES_PAGE_SIZE: i32 = 100;
+const MAX_TRADES_PAGE_SIZE: i32 = 1000;
+#[actix_web::get("/trades")]
+pub async fn user_trades(
+    query: Query<UserTrades>,
+    auth: ReqData<Authenticated>,
+    app_state: Data<AppState>
+) -> actix_web::HttpResponse {
+    let limit = query.limit.unwrap_or(TRADES_PAGE_SIZE);
+    if !(1..=MAX_TRADES_PAGE_SIZE).contains(&limit) {
+        return HttpResponse::BadRequest().json(
+            format!("limit must be between 1 and {}", MAX_TRADES_PAGE_SIZE)
+        );
+    }
+    let exchange = match Exchange::from_symbol(query.symbol.clone()) {
+        Ok(exchange) => exchange,
+        Err(err) => {
+            return HttpResponse::NotFound().json(err);
+        }
+    };
+    let s_db = app_state.scylla_db();
+    match s_db.get_user_trades(auth.user_id, &exchange.symbol, limit, query.page.clone()).await {
+        Ok(page) => HttpResponse::Ok().json(page),
+        Err(err) => HttpResponse::BadRequest().json(format!("Invalid page\n {}", err)),
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PortfolioHistory {
+    days: Option<i32>,
+}
+const PORTFOLIO_DAYS: i32 = 30;
+const MAX_PORTFOLIO_DAYS: i32 = 366;
+// Valued by the engine at the prices of its books, PnL is worked out from the account's fills
+#[actix_web::get("/portfolio")]
+pub async fn portfolio(
+    query: Query<PortfolioHistory>,
+    auth: ReqData<Authenticated>,
+    app_state: Data<AppState>
+) -> actix_web::HttpResponse {
+    let days = query.days.unwrap_or(PORTFOLIO_DAYS);
+    if !(1..=MAX_PORTFOLIO_DAYS).contains(&days) {
+        return HttpResponse::BadRequest().json(
+            format!("days must be between 1 and {}", MAX_PORTFOLIO_DAYS)
+        );
+    }
+    let sub_id = uuid::Uuid::new_v4().as_u64_pair().0 as i64;
+    let req = UserRequests::GetPortfolio(GetPortfolio { user_id: auth.user_id, sub_id });
+    let response = {
+        let con = &mut app_state.redis_connection.lock().unwrap();
+        engine_request(con, "queues:user", sub_id, to_string(&req).unwrap())
+    };
+    let valuation = match response {
+        Ok(response) =>
+            match from_str::<Valuation>(&response) {
+                Ok(valuation) => valuation,
+                Err(_) => {
+                    return HttpResponse::BadRequest().json(response);
+                }
+            }
+        Err(err) => {
+            return HttpResponse::InternalServerError().json(err.to_string());
+        }
+    };
+    let fills = match portfolio_fills(&app_state, auth.user_id).await {
+        Ok(fills) => fills,
+        Err(err) => {
+            return HttpResponse::InternalServerError().json(err.to_string());
+        }
+    };
+    match app_state.scylla_db().get_portfolio_history(auth.user_id, days).await {
+        Ok(history) => HttpResponse::Ok().json(Portfolio::new(valuation, &fills, history)),
+        Err(err) => HttpResponse::InternalServerError().json(err.to_string()),
+    }
+}
+const PORTFOLIO_FILLS_PAGE_SIZE: i32 = 1000;
+// Every spot fill of the user, a market and a page at a time
+async fn portfolio_fills(
+    app_state: &AppState,
+    user_id: Id
+) -> Result<Vec<SpotFill>, Box<dyn Error>> {
+    let s_db = app_state.scylla_db();
+    let symbols = s_db.get_user_symbols(user_id).await?;
+    let mut rows = Vec::new();
+    for symbol in symbols.into_iter().filter(|symbol| !symbol.ends_with("_PERP")) {
+        let mut page: Option<String> = None;
+        loop {
+            let page_size = PORTFOLIO_FILLS_PAGE_SIZE;
+            let fills = s_db.get_user_trades(user_id, &symbol, page_size, page).await?;
+            let done = fills.fills.is_empty() || fills.next_page.is_none();
+            rows.extend(fills.fills);
+            if done {
+                break;
+            }
+            page = fills.next_page;
+        }
+    }
+    Ok(spot_fills(&rows))
+}
+
+#[actix_web::get("/withdrawals")]
+pub async fn withdrawals(
+    auth: ReqData<Authenticated>,
+    app_state: Data<AppState>
+) -> actix_web::HttpResponse {
+    let s_db = app_state.scylla_db();
+    if let Err(err) = s_db.get_user(auth.user_id).await {
+        return HttpResponse::NotFound().json(format!("User Not Found\n {}", err));
+    }
+    match s_db.get_withdrawals(auth.user_id).await {
+        Ok(withdrawals) => HttpResponse::Ok().json(withdrawals),
+        Err(err) => HttpResponse::InternalServerError().json(err.to_string()),
+    }
+}
+
+#[actix_web::get("/deposits")]
+pub async fn deposits(
+    auth: ReqData<Authenticated>,
+    app_state: Data<AppState>
+) -> actix_web::HttpResponse {
+    let s_db = app_state.scylla_db();
+    if let Err(err) = s_db.get_user(auth.user_id).await {
+        return HttpResponse::NotFound().json(format!("User Not Found\n {}", err));
+    }
+    match s_db.get_deposits(auth.user_id).await {
+        Ok(deposits) => HttpResponse::Ok().json(deposits),
+        Err(err) => HttpResponse::InternalServerError().json(err.to_string()),
+    }
+}
+
+#[actix_web::post("/sub-accounts")]
+pub async fn new_sub_account(
+    mut body: Json<NewSubAccount>,
+    auth: ReqData<Authenticated>,
     app_state: Data<AppState>
 ) -> actix_web::HttpResponse {
-    let s_db = app_state.scylla_db.lock().unwrap();
     let con = &mut app_state.redis_connection.lock().unwrap();
     let sub_id = uuid::Uuid::new_v4().as_u64_pair().0 as i64;
     body.sub_id = sub_id;
-    let req = to_string(&UserRequests::Withdraw(body.0)).unwrap();
+    body.master_id = auth.user_id;
+    let req = to_string(&UserRequests::NewSubAccount(body.0)).unwrap();
     let response = redis::cmd("LPUSH").arg("queues:user").arg(req).query::<Value>(con);
     match response {
         Ok(_) => {
@@ -129,40 +359,74 @@ pub async fn withdraw(
                 }
             }
             let response: String = response_result.unwrap();
+            // The engine persists the sub-account
             match from_str::<User>(&response) {
-                Ok(user) => {
-                    let _ = s_db.update_user(&mut user.clone()).await;
-                    return HttpResponse::Created().json(user);
-                }
-                Err(err) => HttpResponse::BadRequest().json(response),
+                Ok(sub_account) => HttpResponse::Created().json(sub_account),
+                Err(_) => HttpResponse::BadRequest().json(response),
             }
         }
         Err(err) => HttpResponse::InternalServerError().json(err.to_string()),
     }
 }
 
-#[derive(Serialize, Deserialize)]
-struct UserOrders {
-    user_id: Id,
+#[actix_web::get("/sub-accounts")]
+pub async fn sub_accounts(
+    auth: ReqData<Authenticated>,
+    app_state: Data<AppState>
+) -> actix_web::HttpResponse {
+    let s_db = app_state.scylla_db();
+    if let Err(err) = s_db.get_user(auth.user_id).await {
+        return HttpResponse::NotFound().json(format!("User Not Found\n {}", err));
+    }
+    match s_db.get_sub_accounts(auth.user_id).await {
+        Ok(sub_accounts) => HttpResponse::Ok().json(sub_accounts),
+        Err(err) => HttpResponse::InternalServerError().json(err.to_string()),
+    }
 }
-#[actix_web::get("/history/orders")]
-pub async fn orders_history(
-    query: Query<UserOrders>,
+
+#[actix_web::post("/transfer")]
+pub async fn transfer(
+    mut body: Json<Transfer>,
+    auth: ReqData<Authenticated>,
     app_state: Data<AppState>
 ) -> actix_web::HttpResponse {
-    let s_db = app_state.scylla_db.lock().unwrap();
-    let result = s_db.get_user(query.user_id).await;
-    match result {
-        Ok(user) => {
-            let user_orders = s_db.get_users_orders(query.user_id).await;
-            match user_orders {
-                Ok(user_orders) => HttpResponse::Ok().json(user_orders),
-                Err(err) =>
-                    HttpResponse::NotFound().json(
-                        format!("Orders Not Found\n {}", err.to_string())
-                    ),
+    let con = &mut app_state.redis_connection.lock().unwrap();
+    let sub_id = uuid::Uuid::new_v4().as_u64_pair().0 as i64;
+    body.sub_id = sub_id;
+    body.from = auth.user_id;
+    let req = to_string(&UserRequests::Transfer(body.0)).unwrap();
+    let response = redis::cmd("LPUSH").arg("queues:user").arg(req).query::<Value>(con);
+    match response {
+        Ok(_) => {
+            let mut response_result: Option<String> = None;
+            loop {
+                let result = redis::cmd("RPOP").arg(sub_id).query::<String>(con);
+                if let Ok(response) = result {
+                    response_result = Some(response);
+                    break;
+                }
+            }
+            let response: String = response_result.unwrap();
+            match from_str::<schema::Transfer>(&response) {
+                Ok(transfer) => HttpResponse::Created().json(transfer),
+                Err(_) => HttpResponse::BadRequest().json(response),
             }
         }
-        Err(err) => HttpResponse::NotFound().json(format!("User Not Found\n {}", err.to_string())),
+        Err(err) => HttpResponse::InternalServerError().json(err.to_string()),
+    }
+}
+
+#[actix_web::get("/transfers")]
+pub async fn transfers(
+    auth: ReqData<Authenticated>,
+    app_state: Data<AppState>
+) -> actix_web::HttpResponse {
+    let s_db = app_state.scylla_db();
+    if let Err(err) = s_db.get_user(auth.user_id).await {
+        return HttpResponse::NotFound().json(format!("User Not Found\n {}", err));
+    }
+    match s_db.get_transfers(auth.user_id).await {
+        Ok(transfers) => HttpResponse::Ok().json(transfers),
+        Err(err) => HttpResponse::InternalServerError().json(err.to_string()),
     }
 }
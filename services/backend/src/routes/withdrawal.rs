@@ -0,0 +1,86 @@
+use actix_web::{ web::{ Data, Json, Path, ReqData }, HttpResponse };
+use serde::{ Deserialize, Serialize };
+use serde_json::{ from_str, to_string };
+
+use super::*;
+use super::admin::{ audit_entry, audited_request };
+use crate::{ app::AppState, auth::Admin, db::schema::Withdrawal };
+
+// Moving a withdrawal on is done by admins and by whatever sends the payouts, the engine only
+// accepts the moves its state allows: approve, sent, then complete, or fail before completing.
+async fn update_withdrawal(
+    app_state: Data<AppState>,
+    admin: &Admin,
+    id: Id,
+    action: WithdrawalAction
+) -> HttpResponse {
+    let sub_id = uuid::Uuid::new_v4().as_u64_pair().0 as i64;
+    let (name, reason) = match &action {
+        WithdrawalAction::Approve => ("approve_withdrawal", String::new()),
+        WithdrawalAction::Sent { tx_id } => ("sent_withdrawal", tx_id.clone()),
+        WithdrawalAction::Complete => ("complete_withdrawal", String::new()),
+        WithdrawalAction::Fail { reason } => ("fail_withdrawal", reason.clone()),
+    };
+    let req = UserRequests::UpdateWithdrawal(UpdateWithdrawal {
+        id,
+        action,
+        sub_id,
+    });
+    let entry = audit_entry(admin, name, id.to_string(), reason, &req);
+    match audited_request(&app_state, entry, "queues:user", sub_id).await {
+        Ok(response) =>
+            match from_str::<Withdrawal>(&response) {
+                Ok(withdrawal) => HttpResponse::Ok().json(withdrawal),
+                Err(_) => HttpResponse::BadRequest().json(response),
+            }
+        Err(err) => HttpResponse::InternalServerError().json(err),
+    }
+}
+
+#[actix_web::post("/{id}/approve")]
+pub async fn approve_withdrawal(
+    path: Path<Id>,
+    admin: ReqData<Admin>,
+    app_state: Data<AppState>
+) -> HttpResponse {
+    update_withdrawal(app_state, &admin, path.into_inner(), WithdrawalAction::Approve).await
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SentWithdrawal {
+    tx_id: String,
+}
+#[actix_web::post("/{id}/sent")]
+pub async fn sent_withdrawal(
+    path: Path<Id>,
+    body: Json<SentWithdrawal>,
+    admin: ReqData<Admin>,
+    app_state: Data<AppState>
+) -> HttpResponse {
+    let action = WithdrawalAction::Sent { tx_id: body.0.tx_id };
+    update_withdrawal(app_state, &admin, path.into_inner(), action).await
+}
+
+#[actix_web::post("/{id}/complete")]
+pub async fn complete_withdrawal(
+    path: Path<Id>,
+    admin: ReqData<Admin>,
+    app_state: Data<AppState>
+) -> HttpResponse {
+    update_withdrawal(app_state, &admin, path.into_inner(), WithdrawalAction::Complete).await
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FailedWithdrawal {
+    reason: String,
+}
+#[actix_web::post("/{id}/fail")]
+pub async fn fail_withdrawal(
+    path: Path<Id>,
+    body: Json<FailedWithdrawal>,
+    admin: ReqData<Admin>,
+    app_state: Data<AppState>
+) -> HttpResponse {
+    let action = WithdrawalAction::Fail { reason: body.0.reason };
+    update_withdrawal(app_state, &admin, path.into_inner(), action).await
+}
@@ -80,8 +80,8 @@ async fn update_order() {
     order.order_status = OrderStatus::PartiallyFilled;
     scylla_db.update_order(&mut order).await.unwrap();
 
-    let updated_order = scylla_db.get_order(order_id, symbol).await.unwrap();
-    let users_order = scylla_db.get_users_orders(user_id).await.unwrap();
+    let updated_order = scylla_db.get_order(order_id, symbol.clone()).await.unwrap();
+    let users_order = scylla_db.get_users_orders(user_id, &symbol, 100, None).await.unwrap();
     println!("{:#?}", users_order);
     assert_eq!(updated_order.order_status.to_string(), OrderStatus::PartiallyFilled.to_string());
 }
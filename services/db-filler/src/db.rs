@@ -1,4 +1,10 @@
 use scylla::{ batch::Batch, frame::Compression, load_balancing, ExecutionProfile, SessionBuilder };
+use common::{
+    buckets::bucket,
+    ledger::insert_entries,
+    numeric::Numeric,
+    query_tables::TRADE_BY_MARKET_STATEMENT,
+};
 use serde_json::from_str;
 use std::{ collections::HashMap, error::Error, sync::Arc };
 
@@ -17,6 +23,11 @@ use crate::{
     User,
 };
 
+// write time, balance, locked_balance, id
+type UserValues = (i64, HashMap<String, Numeric>, HashMap<String, Numeric>, i64);
+// filled_quantity, filled_quote_quantity, order_status, id, symbol
+type OrderValues = (Numeric, Numeric, String, OrderId, Symbol);
+
 impl ScyllaDb {
     pub async fn create_session(uri: &str) -> Result<ScyllaDb, Box<dyn Error>> {
         let policy = Arc::new(load_balancing::DefaultPolicy::default());
@@ -37,7 +48,7 @@ impl ScyllaDb {
         queue_trade: &Filler,
         mut order_1: Order,
         mut order_2: Order
-    ) -> ((String, String, String, OrderId, Symbol), (String, String, String, OrderId, Symbol)) {
+    ) -> (OrderValues, OrderValues) {
         order_1.filled_quantity += queue_trade.quantity;
         order_1.filled_quote_quantity += queue_trade.quantity * queue_trade.exchange_price;
         order_2.filled_quantity += queue_trade.quantity;
@@ -66,7 +77,7 @@ impl ScyllaDb {
     pub fn get_trade_batch_values(
         &self,
         queue_trade: &Filler
-    ) -> ((i64, String, String, String, bool, String, i64), Trade) {
+    ) -> ((i64, String, Numeric, Numeric, bool, Numeric, i64), Trade) {
         let trade = Trade::new(
             queue_trade.trade_id,
             queue_trade.is_buyer_maker,
@@ -93,15 +104,22 @@ impl ScyllaDb {
         &self,
         exchange: &Exchange,
         post_users: PostUsers
-    ) -> (
-        (HashMap<String, String>, HashMap<String, String>, i64),
-        (HashMap<String, String>, HashMap<String, String>, i64),
-    ) {
+    ) -> (UserValues, UserValues) {
         let serializer_user = post_users.user.to_scylla_user();
         let serializer_client = post_users.client.to_scylla_user();
         (
-            (serializer_user.balance, serializer_user.locked_balance, serializer_user.id),
-            (serializer_client.balance, serializer_client.locked_balance, serializer_client.id),
+            (
+                post_users.stamp,
+                serializer_user.balance,
+                serializer_user.locked_balance,
+                serializer_user.id,
+            ),
+            (
+                post_users.stamp,
+                serializer_client.balance,
+                serializer_client.locked_balance,
+                serializer_client.id,
+            ),
         )
     }
     pub async fn batch_update(&self, queue_trade: Filler) -> Result<Trade, Box<dyn Error>> {
@@ -122,8 +140,11 @@ impl ScyllaDb {
         batch.append_statement(user_statement_1);
         batch.append_statement(user_statement_2);
         batch.append_statement(trade_statement);
+        batch.append_statement(TRADE_BY_MARKET_STATEMENT);
         batch.append_statement(order_statement_1);
         batch.append_statement(order_statement_2);
+        batch.append_statement(self.fill_entry_statement());
+        batch.append_statement(self.fill_entry_statement());
 
         let prepared_batch: Batch = self.session.prepare_batch(&batch).await?;
 
@@ -132,6 +153,20 @@ impl ScyllaDb {
             queue_trade.post_users.clone()
         );
         let (trade_values, trade) = self.get_trade_batch_values(&queue_trade);
+        let trade_by_market_values = (
+            trade_values.0,
+            trade_values.1.clone(),
+            trade_values.2,
+            trade_values.3,
+            trade_values.4,
+            trade_values.5,
+            trade_values.6,
+            bucket(trade_values.6),
+        );
+        let (taker_fill_values, maker_fill_values) = queue_trade.get_fill_batch_values(
+            &order_1,
+            &order_2
+        );
         let (order_1_values, order_2_values) = self.get_order_batch_values(
             &queue_trade,
             order_1,
@@ -142,9 +177,14 @@ impl ScyllaDb {
             user_1_values,
             user_2_values,
             trade_values,
+            trade_by_market_values,
             order_1_values,
             order_2_values,
+            taker_fill_values,
+            maker_fill_values,
         )).await?;
+        // Entries are keyed by their movement, so writing them again on a retry is a no-op
+        insert_entries(&self.session, &queue_trade.ledger).await?;
         Ok(trade)
     }
 }
@@ -0,0 +1,175 @@
+use common::{ ledger::Account, numeric::Numeric };
+use rust_decimal_macros::dec;
+
+use crate::{ Filler, FillRole, Id, Order, Quantity, ScyllaDb };
+
+// user_id, symbol, timestamp, trade_id, role, order_id, counterparty_order_id, side, price,
+// quantity, quote_quantity, fee, fee_asset
+pub type FillRow = (
+    i64,
+    String,
+    i64,
+    i64,
+    String,
+    i64,
+    i64,
+    String,
+    Numeric,
+    Numeric,
+    Numeric,
+    Numeric,
+    String,
+);
+
+impl Filler {
+    // What the trade's ledger moved from the user into Fees, zero in the quote when nothing was
+    fn fee(&self, user_id: Id) -> (Quantity, String) {
+        let legs = self.ledger
+            .iter()
+            .filter(|entry| entry.user_id as Id == user_id && entry.account == Account::Fees);
+        let mut fee = (dec!(0), self.exchange.quote.to_string());
+        for leg in legs {
+            fee.0 += leg.amount;
+            fee.1 = leg.asset.clone();
+        }
+        fee
+    }
+    fn fill_values(&self, order: &Order, counterparty: &Order, role: FillRole) -> FillRow {
+        let (fee, fee_asset) = self.fee(order.user_id);
+        (
+            order.user_id,
+            self.exchange.symbol.to_string(),
+            self.timestamp as i64,
+            self.trade_id,
+            role.to_string(),
+            order.id,
+            counterparty.id,
+            order.order_side.to_string(),
+            Numeric(self.exchange_price),
+            Numeric(self.quantity),
+            Numeric(self.quantity * self.exchange_price),
+            Numeric(fee),
+            fee_asset,
+        )
+    }
+    // One fill for each side, taker first. The incoming order, `order_id`, took and the resting
+    // one, `client_order_id`, made
+    pub fn get_fill_batch_values(&self, order_1: &Order, order_2: &Order) -> (FillRow, FillRow) {
+        let (taker_order, maker_order) = match order_1.id == self.order_id {
+            true => (order_1, order_2),
+            false => (order_2, order_1),
+        };
+        (
+            self.fill_values(taker_order, maker_order, FillRole::Taker),
+            self.fill_values(maker_order, taker_order, FillRole::Maker),
+        )
+    }
+}
+
+impl ScyllaDb {
+    pub fn fill_entry_statement(&self) -> &str {
+        r#"
+            INSERT INTO keyspace_1.fill_table (
+                user_id,
+                symbol,
+                timestamp,
+                trade_id,
+                role,
+                order_id,
+                counterparty_order_id,
+                side,
+                price,
+                quantity,
+                quote_quantity,
+                fee,
+                fee_asset
+            ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);
+        "#
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use common::ledger::{ LedgerEntry, LedgerKind };
+
+    use crate::{ Asset, Exchange, OrderSide, OrderStatus, OrderType, PostUsers, User };
+
+    use super::*;
+
+    fn user(id: i64) -> User {
+        User { id, balance: HashMap::new(), locked_balance: HashMap::new() }
+    }
+    fn order(id: i64, user_id: i64, order_side: OrderSide) -> Order {
+        Order {
+            id,
+            user_id,
+            symbol: "SOL_USDT".to_string(),
+            price: dec!(20),
+            initial_quantity: dec!(3),
+            filled_quantity: dec!(0),
+            quote_quantity: dec!(0),
+            filled_quote_quantity: dec!(0),
+            order_type: OrderType::Limit,
+            order_side,
+            order_status: OrderStatus::InProgress,
+            timestamp: 0,
+        }
+    }
+
+    fn filler(ledger: Vec<LedgerEntry>) -> Filler {
+        Filler {
+            trade_id: 7,
+            exchange: Exchange::new(Asset::SOL, Asset::USDT),
+            quantity: dec!(1.5),
+            exchange_price: dec!(20),
+            is_buyer_maker: true,
+            post_users: PostUsers { user: user(1), client: user(2), stamp: 0 },
+            order_status: OrderStatus::Filled,
+            client_order_status: OrderStatus::PartiallyFilled,
+            order_id: 10,
+            client_order_id: 20,
+            timestamp: 1000,
+            ledger,
+        }
+    }
+
+    #[test]
+    fn the_incoming_order_takes_and_the_resting_one_makes() {
+        let filler = filler(vec![]);
+        let incoming = order(10, 1, OrderSide::Ask);
+        let resting = order(20, 2, OrderSide::Bid);
+        // the orders are told apart by their ids, whichever comes first
+        for (order_1, order_2) in [(&incoming, &resting), (&resting, &incoming)] {
+            let (taker, maker) = filler.get_fill_batch_values(order_1, order_2);
+            assert_eq!((taker.0, taker.4.as_str(), taker.5, taker.6), (1, "Taker", 10, 20));
+            assert_eq!((maker.0, maker.4.as_str(), maker.5, maker.6), (2, "Maker", 20, 10));
+            assert_eq!((taker.7.as_str(), maker.7.as_str()), ("Ask", "Bid"));
+            for row in [&taker, &maker] {
+                assert_eq!((row.1.as_str(), row.2, row.3), ("SOL_USDT", 1000, 7));
+                assert_eq!((row.8.0, row.9.0, row.10.0), (dec!(20), dec!(1.5), dec!(30)));
+                assert_eq!((row.11.0, row.12.as_str()), (dec!(0), "USDT"));
+            }
+        }
+    }    #[test]
+    fn a_fill_carries_the_fee_charged_to_its_user() {
+        let fee = LedgerEntry {
+            user_id: 1,
+            kind: LedgerKind::Fee,
+            reference: 7,
+            leg: 0,
+            account: Account::Fees,
+            asset: "USDT".to_string(),
+            amount: dec!(0.03),
+            timestamp: 1000,
+        };
+        let filler = filler(vec![fee]);
+        let (taker, maker) = filler.get_fill_batch_values(
+            &order(10, 1, OrderSide::Ask),
+            &order(20, 2, OrderSide::Bid)
+        );
+        assert_eq!((taker.11.0, taker.12.as_str()), (dec!(0.03), "USDT"));
+        assert_eq!((maker.11.0, maker.12.as_str()), (dec!(0), "USDT"));
+    }
+}
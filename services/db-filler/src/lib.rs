@@ -1,5 +1,6 @@
 #![allow(unused)]
 use std::{ collections::HashMap, time::{ SystemTime, UNIX_EPOCH } };
+use common::{ ledger::LedgerEntry, numeric::Numeric };
 use enum_stringify::EnumStringify;
 use rust_decimal::Decimal;
 use scylla::{ FromRow, SerializeRow, Session };
@@ -11,6 +12,7 @@ pub mod db;
 pub mod user;
 pub mod order;
 pub mod trade;
+pub mod fill;
 #[derive(Debug, Clone, PartialEq, Eq, Hash, EnumIter, Serialize, Deserialize, EnumStringify)]
 pub enum Asset {
     USDT,
@@ -66,8 +68,8 @@ pub struct ScyllaDb {
 #[derive(Debug, Clone, Deserialize, Serialize, SerializeRow, FromRow)]
 pub struct ScyllaUser {
     pub id: i64,
-    pub balance: HashMap<String, String>,
-    pub locked_balance: HashMap<String, String>,
+    pub balance: HashMap<String, Numeric>,
+    pub locked_balance: HashMap<String, Numeric>,
 }
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct User {
@@ -88,11 +90,16 @@ pub struct Filler {
     order_id: OrderId,
     client_order_id: OrderId,
     timestamp: u128,
+    #[serde(default)]
+    ledger: Vec<LedgerEntry>,
 }
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct PostUsers {
     pub user: User,
     pub client: User,
+    // Balance stamp of both users' state from the engine, written as its Scylla write time so
+    // it never overwrites a newer write of the engine
+    pub stamp: i64,
 }
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Order {
@@ -114,11 +121,11 @@ pub struct ScyllaOrder {
     pub id: OrderId,
     pub user_id: i64,
     pub symbol: String,
-    pub price: String,
-    pub initial_quantity: String,
-    pub filled_quantity: String,
-    pub quote_quantity: String,
-    pub filled_quote_quantity: String,
+    pub price: Numeric,
+    pub initial_quantity: Numeric,
+    pub filled_quantity: Numeric,
+    pub quote_quantity: Numeric,
+    pub filled_quote_quantity: Numeric,
     pub order_type: String,
     pub order_side: String,
     pub order_status: String,
@@ -175,6 +182,12 @@ impl OrderType {
         Err(())
     }
 }
+// The incoming order of a trade takes, the order resting on the book makes
+#[derive(Debug, Clone, Deserialize, Serialize, EnumStringify, EnumIter)]
+pub enum FillRole {
+    Taker,
+    Maker,
+}
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Trade {
     pub id: Id,
@@ -189,10 +202,10 @@ pub struct Trade {
 pub struct ScyllaTrade {
     pub id: i64,
     pub symbol: Symbol,
-    pub quantity: String,
-    pub quote_quantity: String,
+    pub quantity: Numeric,
+    pub quote_quantity: Numeric,
     pub is_buyer_maker: bool,
-    pub price: String,
+    pub price: Numeric,
     pub timestamp: i64,
 }
 pub fn get_epoch_ms() -> u64 {
@@ -1,5 +1,4 @@
 use std::{ error::Error, str::FromStr };
-use rust_decimal::Decimal;
 use scylla::{
     query::{ self, Query },
     statement::{ Consistency, SerialConsistency },
@@ -27,11 +26,11 @@ impl Order {
             timestamp: self.timestamp,
             user_id: self.user_id,
             symbol: self.symbol.to_string(),
-            filled_quantity: self.filled_quantity.to_string(),
-            quote_quantity: self.quote_quantity.to_string(),
-            filled_quote_quantity: self.filled_quote_quantity.to_string(),
-            price: self.price.to_string(),
-            initial_quantity: self.initial_quantity.to_string(),
+            filled_quantity: self.filled_quantity.into(),
+            quote_quantity: self.quote_quantity.into(),
+            filled_quote_quantity: self.filled_quote_quantity.into(),
+            price: self.price.into(),
+            initial_quantity: self.initial_quantity.into(),
             order_side: self.order_side.to_string(),
             order_status: self.order_status.to_string(),
             order_type: self.order_type.to_string(),
@@ -45,11 +44,11 @@ impl ScyllaOrder {
             timestamp: self.timestamp,
             user_id: self.user_id,
             symbol: self.symbol.to_string(),
-            filled_quantity: Decimal::from_str(&self.filled_quantity).unwrap(),
-            filled_quote_quantity: Decimal::from_str(&self.filled_quote_quantity).unwrap(),
-            quote_quantity: Decimal::from_str(&self.quote_quantity).unwrap(),
-            price: Decimal::from_str(&self.price).unwrap(),
-            initial_quantity: Decimal::from_str(&self.initial_quantity).unwrap(),
+            filled_quantity: self.filled_quantity.into(),
+            filled_quote_quantity: self.filled_quote_quantity.into(),
+            quote_quantity: self.quote_quantity.into(),
+            price: self.price.into(),
+            initial_quantity: self.initial_quantity.into(),
             order_side: OrderSide::from_str(&self.order_side).unwrap(),
             order_status: OrderStatus::from_str(&self.order_status).unwrap(),
             order_type: OrderType::from_str(&self.order_type).unwrap(),
@@ -30,9 +30,9 @@ impl Trade {
             id: self.id,
             symbol: self.symbol.to_string(),
             is_buyer_maker: self.is_buyer_maker,
-            price: self.price.to_string(),
-            quantity: self.quantity.to_string(),
-            quote_quantity: self.quote_quantity.to_string(),
+            price: self.price.into(),
+            quantity: self.quantity.into(),
+            quote_quantity: self.quote_quantity.into(),
             timestamp: self.timestamp,
         }
     }
@@ -43,9 +43,9 @@ impl ScyllaTrade {
             id: self.id,
             symbol: self.symbol.to_string(),
             is_buyer_maker: self.is_buyer_maker,
-            price: Decimal::from_str(&self.price).unwrap(),
-            quantity: Decimal::from_str(&self.quantity).unwrap(),
-            quote_quantity: Decimal::from_str(&self.quote_quantity).unwrap(),
+            price: self.price.into(),
+            quantity: self.quantity.into(),
+            quote_quantity: self.quote_quantity.into(),
             timestamp: self.timestamp,
         }
     }
@@ -1,6 +1,6 @@
 use std::{ collections::HashMap, error::Error, str::FromStr };
 
-use rust_decimal::Decimal;
+use common::numeric::Numeric;
 use rust_decimal_macros::dec;
 use scylla::transport::errors::QueryError;
 
@@ -17,14 +17,12 @@ impl ScyllaUser {
         let mut balance_map: HashMap<Asset, Quantity> = HashMap::new();
         for (asset_str, balance) in &self.balance {
             let asset = Asset::from_str(&asset_str).unwrap();
-            let balance = Decimal::from_str(&balance).unwrap();
-            balance_map.insert(asset, balance);
+            balance_map.insert(asset, balance.0);
         }
         let mut locked_balance_map: HashMap<Asset, Quantity> = HashMap::new();
         for (asset_str, locked_balance) in &self.locked_balance {
             let asset = Asset::from_str(&asset_str).unwrap();
-            let locked_balance = Decimal::from_str(&locked_balance).unwrap();
-            locked_balance_map.insert(asset, locked_balance);
+            locked_balance_map.insert(asset, locked_balance.0);
         }
 
         User {
@@ -37,13 +35,13 @@ impl ScyllaUser {
 
 impl User {
     pub fn to_scylla_user(&self) -> ScyllaUser {
-        let mut scylla_balance: HashMap<String, String> = HashMap::new();
+        let mut scylla_balance: HashMap<String, Numeric> = HashMap::new();
         for (asset, balance) in &self.balance {
-            scylla_balance.insert(asset.to_string(), balance.to_string());
+            scylla_balance.insert(asset.to_string(), Numeric(*balance));
         }
-        let mut scylla_locked_balance: HashMap<String, String> = HashMap::new();
+        let mut scylla_locked_balance: HashMap<String, Numeric> = HashMap::new();
         for (asset, balance) in &self.locked_balance {
-            scylla_locked_balance.insert(asset.to_string(), balance.to_string());
+            scylla_locked_balance.insert(asset.to_string(), Numeric(*balance));
         }
         ScyllaUser {
             id: self.id,
@@ -99,7 +97,7 @@ impl ScyllaDb {
     pub fn update_user_statement(&self) -> &str {
         let s =
             r#"
-            UPDATE keyspace_1.user_table 
+            UPDATE keyspace_1.user_table USING TIMESTAMP ?
             SET
                 balance = ?,
                 locked_balance = ?
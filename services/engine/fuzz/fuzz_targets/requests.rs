@@ -0,0 +1,21 @@
+#![no_main]
+use engine::{ handle_order_request::EngineRequests, handle_user_requests::UserRequests };
+use libfuzzer_sys::fuzz_target;
+
+// Whatever the backend pushes to the queues must either be rejected or survive a round trip
+// through the journal unchanged
+fuzz_target!(|data: &[u8]| {
+    let Ok(payload) = std::str::from_utf8(data) else {
+        return;
+    };
+    if let Ok(request) = serde_json::from_str::<EngineRequests>(payload) {
+        let journaled = serde_json::to_string(&request).unwrap();
+        let replayed: EngineRequests = serde_json::from_str(&journaled).unwrap();
+        assert_eq!(serde_json::to_string(&replayed).unwrap(), journaled);
+    }
+    if let Ok(request) = serde_json::from_str::<UserRequests>(payload) {
+        let journaled = serde_json::to_string(&request).unwrap();
+        let replayed: UserRequests = serde_json::from_str(&journaled).unwrap();
+        assert_eq!(serde_json::to_string(&replayed).unwrap(), journaled);
+    }
+});
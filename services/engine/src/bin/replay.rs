@@ -0,0 +1,314 @@
+use std::{
+    collections::{ BTreeSet, HashMap },
+    env,
+    path::PathBuf,
+    process,
+    sync::{ Arc, Mutex },
+};
+
+use strum::IntoEnumIterator;
+
+use engine::{
+    handle_order_request::EngineRequests,
+    handle_user_requests::UserRequests,
+    journal::{ self, Journal, JournalEntry, USERS_JOURNAL },
+    matching_engine::{
+        events::NoEvents,
+        orderbook::Orderbook,
+        Exchange,
+        Id,
+        RegisteredSymbols,
+        Symbol,
+        Users,
+    },
+    replay::{
+        diff_books,
+        diff_users,
+        entries_until,
+        locked_by_orders,
+        parse_entry,
+        print_book,
+        request_timestamp,
+    },
+    snapshot::{ checkpoint_dir, checkpoint_ids, load_checkpoint, Checkpoint, SNAPSHOT_DIR },
+};
+
+const USAGE: &str =
+    "Usage: replay <SYMBOL> [options]
+Rebuilds a market's book and the balances from the journals without emitting anything. Every
+market and the users journal are replayed together, so balances include trades of all markets.
+
+    --data-dir DIR      directory the engine runs in, holding journal/ and snapshots/ (default .)
+    --since MICROS      also print every replayed request of the market from this time on
+    --until MICROS      only the market's requests made at or before this time
+    --to-seq SEQ        stop after this journal sequence of the market
+    --snapshot ID       start from this snapshot instead of empty books and balances
+    --diff ID|latest    rebuild up to this snapshot and compare its books and balances";
+
+struct Options {
+    symbol: String,
+    data_dir: PathBuf,
+    since: Option<i64>,
+    until: Option<i64>,
+    to_seq: Option<u64>,
+    snapshot: Option<u64>,
+    diff: Option<String>,
+}
+
+fn exit_with(message: String) -> ! {
+    eprintln!("{}", message);
+    process::exit(2)
+}
+fn parse_options() -> Options {
+    let mut args = env::args().skip(1);
+    let symbol = args.next().filter(|arg| !arg.starts_with("--")).unwrap_or_else(|| {
+        exit_with(USAGE.to_string())
+    });
+    let mut options = Options {
+        symbol,
+        data_dir: PathBuf::from("."),
+        since: None,
+        until: None,
+        to_seq: None,
+        snapshot: None,
+        diff: None,
+    };
+    while let Some(flag) = args.next() {
+        let value = args.next().unwrap_or_else(|| exit_with(format!("{} needs a value", flag)));
+        let number = || value.parse().unwrap_or_else(|_| exit_with(format!("Invalid {}", flag)));
+        match flag.as_str() {
+            "--data-dir" => {
+                options.data_dir = PathBuf::from(&value);
+            }
+            "--since" => {
+                options.since = Some(number());
+            }
+            "--until" => {
+                options.until = Some(number());
+            }
+            "--to-seq" => {
+                options.to_seq = Some(number() as u64);
+            }
+            "--snapshot" => {
+                options.snapshot = Some(number() as u64);
+            }
+            "--diff" => {
+                options.diff = Some(value);
+            }
+            _ => exit_with(USAGE.to_string()),
+        }
+    }
+    options
+}
+
+fn symbols() -> Vec<Symbol> {
+    RegisteredSymbols::iter().map(|symbol| symbol.to_string()).collect()
+}
+fn load(options: &Options, id: u64) -> Checkpoint {
+    let dir = checkpoint_dir(&options.data_dir.join(SNAPSHOT_DIR), id);
+    load_checkpoint(&dir, id, &symbols()).unwrap_or_else(|err| {
+        exit_with(format!("Could not load snapshot {}: {}", id, err))
+    })
+}
+fn latest(options: &Options) -> Checkpoint {
+    checkpoint_ids(&options.data_dir.join(SNAPSHOT_DIR))
+        .into_iter()
+        .find_map(|id| {
+            let dir = checkpoint_dir(&options.data_dir.join(SNAPSHOT_DIR), id);
+            load_checkpoint(&dir, id, &symbols()).ok()
+        })
+        .unwrap_or_else(|| exit_with("No complete snapshot found".to_string()))
+}
+fn read_journal(options: &Options, name: &str) -> Vec<JournalEntry> {
+    Journal::read(&options.data_dir.join(Journal::path(name))).unwrap_or_else(|err| {
+        exit_with(format!("Could not read {} journal: {}", name, err))
+    })
+}
+
+// The state everything is replayed on, with the last journal sequence it already includes.
+// Without a snapshot the journals are replayed from their first record on empty balances.
+struct Start {
+    users: Users,
+    users_seq: u64,
+    orderbooks: HashMap<Symbol, (Orderbook, u64)>,
+}
+fn start(options: &Options) -> Start {
+    let Some(id) = options.snapshot else {
+        let orderbooks = symbols()
+            .into_iter()
+            .map(|symbol| {
+                let exchange = Exchange::from_symbol(symbol.clone()).unwrap();
+                (symbol, (Orderbook::new(exchange), 0))
+            })
+            .collect();
+        return Start { users: Users::default(), users_seq: 0, orderbooks };
+    };
+    let checkpoint = load(options, id);
+    let mut users = checkpoint.users.users;
+    users.recover_last_ids();
+    let orderbooks = checkpoint.orderbooks
+        .into_iter()
+        .map(|(symbol, snapshot)| (symbol, (snapshot.orderbook, snapshot.journal_seq)))
+        .collect();
+    Start { users, users_seq: checkpoint.users.journal_seq, orderbooks }
+}
+
+fn request_user(request: &EngineRequests) -> Option<Id> {
+    match request {
+        EngineRequests::ExecuteOrder(order) | EngineRequests::Liquidate(order) =>
+            Some(order.user_id as Id),
+        EngineRequests::CancelOrder(cancel_order) => Some(cancel_order.user_id),
+        EngineRequests::CancelAll(cancel_all) => Some(cancel_all.user_id),
+        _ => None,
+    }
+}
+fn print_balances(orderbook: &Orderbook, users: &Users, entries: &[JournalEntry]) {
+    let exchange = &orderbook.exchange;
+    let user_ids: BTreeSet<Id> = entries
+        .iter()
+        .filter_map(|entry| request_user(&parse_entry(entry)))
+        .collect();
+    println!("Balances of replayed users (balance / locked)");
+    for user_id in user_ids {
+        for asset in [exchange.base, exchange.quote] {
+            let balance = users.balance(&asset, user_id).copied().unwrap_or_default();
+            let locked = users.locked_balance(&asset, user_id).copied().unwrap_or_default();
+            println!("  user {} {} {} / {}", user_id, asset, balance, locked);
+        }
+    }
+    println!("Locked in resting orders ({} / {})", exchange.base, exchange.quote);
+    for (user_id, (base, quote)) in locked_by_orders(orderbook) {
+        println!("  user {} {} / {}", user_id, base, quote);
+    }
+}
+
+fn main() {
+    let options = parse_options();
+    if Exchange::from_symbol(options.symbol.clone()).is_err() {
+        exit_with(format!("Unknown symbol {}", options.symbol));
+    }
+    let start = start(&options);
+    let users = Arc::new(Mutex::new(start.users));
+    let mut start_seqs = HashMap::from([(USERS_JOURNAL.to_string(), start.users_seq)]);
+    let mut orderbooks = HashMap::new();
+    for (symbol, (mut orderbook, seq)) in start.orderbooks {
+        orderbook.attach(users.clone(), Arc::new(NoEvents));
+        start_seqs.insert(symbol.clone(), seq);
+        orderbooks.insert(symbol, orderbook);
+    }
+    let journals: Vec<(String, Vec<JournalEntry>)> = start_seqs
+        .iter()
+        .map(|(name, seq)| {
+            let entries = read_journal(&options, name)
+                .into_iter()
+                .filter(|entry| entry.seq > *seq)
+                .collect();
+            (name.clone(), entries)
+        })
+        .collect();
+
+    let expected = options.diff.as_ref().map(|diff| {
+        match diff.as_str() {
+            "latest" => latest(&options),
+            id => load(&options, id.parse().unwrap_or_else(|_| exit_with(USAGE.to_string()))),
+        }
+    });
+    // Every journal stops where the snapshot to diff against was taken. Otherwise the market's
+    // journal is cut by sequence or time and the others where its last replayed request was.
+    let journals: Vec<(String, Vec<JournalEntry>)> = match &expected {
+        Some(checkpoint) => {
+            journals
+                .into_iter()
+                .map(|(name, entries)| {
+                    let to_seq = match checkpoint.orderbooks.get(&name) {
+                        Some(snapshot) => snapshot.journal_seq,
+                        None => checkpoint.users.journal_seq,
+                    };
+                    if to_seq < start_seqs[&name] {
+                        exit_with("Snapshot to diff against is older than the starting one".into());
+                    }
+                    (name, entries_until(&entries, Some(to_seq), None))
+                })
+                .collect()
+        }
+        None => {
+            let market = &journals.iter().find(|(name, _)| *name == options.symbol).unwrap().1;
+            let market = entries_until(market, options.to_seq, options.until);
+            let cut = options.to_seq.is_some() || options.until.is_some();
+            let last_stamp = market.iter().map(|entry| entry.stamp).max().unwrap_or(0);
+            journals
+                .into_iter()
+                .map(|(name, entries)| {
+                    match name == options.symbol {
+                        true => (name, market.clone()),
+                        false => {
+                            let entries = entries
+                                .into_iter()
+                                .filter(|entry| !cut || entry.stamp < last_stamp)
+                                .collect();
+                            (name, entries)
+                        }
+                    }
+                })
+                .collect()
+        }
+    };
+    let entries = journals
+        .iter()
+        .find(|(name, _)| *name == options.symbol)
+        .map(|(_, entries)| entries.clone())
+        .unwrap_or_default();
+
+    if let Some(since) = options.since {
+        for entry in entries.iter() {
+            let request = parse_entry(entry);
+            if request_timestamp(&request).is_some_and(|timestamp| timestamp >= since) {
+                println!("{:>10} {}", entry.seq, String::from_utf8_lossy(&entry.payload));
+            }
+        }
+    }
+    // Users that traded before the journals began are not created in them, they start empty
+    if options.snapshot.is_none() {
+        let mut users = users.lock().unwrap();
+        for (_, entries) in journals.iter().filter(|(name, _)| name != USERS_JOURNAL) {
+            for user_id in entries.iter().filter_map(|entry| request_user(&parse_entry(entry))) {
+                if !users.users.contains_key(&user_id) {
+                    users.new_user(user_id);
+                }
+            }
+        }
+    }
+    for (name, entry) in journal::merge(journals) {
+        match orderbooks.get_mut(&name) {
+            Some(orderbook) => orderbook.replay_journal(vec![entry], true),
+            None => UserRequests::replay_journal(&mut users.lock().unwrap(), vec![entry]),
+        }
+    }
+    let orderbook = orderbooks.get_mut(&options.symbol).unwrap();
+    let last_seq = entries.last().map_or(start_seqs[&options.symbol], |entry| entry.seq);
+    println!("Replayed journal {} up to sequence {}", options.symbol, last_seq);
+    print_book(orderbook);
+    print_balances(orderbook, &users.lock().unwrap(), &entries);
+
+    if let Some(checkpoint) = expected {
+        let mut diffs = Vec::new();
+        for (symbol, snapshot) in checkpoint.orderbooks.iter() {
+            let actual = &orderbooks[symbol];
+            diffs.extend(
+                diff_books(&snapshot.orderbook, actual)
+                    .into_iter()
+                    .map(|diff| format!("{} {}", symbol, diff))
+            );
+        }
+        diffs.extend(diff_users(&checkpoint.users.users, &users.lock().unwrap()));
+        if diffs.is_empty() {
+            println!("Rebuilt books and balances match snapshot {}", checkpoint.id);
+            return;
+        }
+        println!("Rebuilt books and balances differ from snapshot {}", checkpoint.id);
+        for diff in diffs {
+            println!("  {}", diff);
+        }
+        process::exit(1);
+    }
+}
@@ -1,14 +1,17 @@
 use std::{ borrow::Borrow, ops::Deref, time::Instant };
 
 use redis::{ Connection, Value };
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
 use serde::{ Deserialize, Serialize };
 use serde_json::to_string;
 use tokio::sync::mpsc::UnboundedSender;
 
 use crate::{
     error::MatchingEngineErrors,
+    journal::{ self, Journal },
+    ledger,
     orderbook::{ Order, Orderbook },
-    EventTranmitter,
     Exchange,
     Id,
     OrderCancelInfo,
@@ -19,6 +22,7 @@ use crate::{
     PersistCancel,
     PersistCancelAll,
     PersistOrderRequest,
+    PersistUnlock,
     Price,
     Quantity,
     RecievedOrder,
@@ -30,10 +34,16 @@ use crate::{
 #[derive(Debug, Serialize, Deserialize)]
 pub enum EngineRequests {
     ExecuteOrder(RecievedOrder),
+    // Market orders of the margin monitor, which skip the checks of orders users send
+    Liquidate(RecievedOrder),
     CancelOrder(CancelOrder),
     CancelAll(CancelAll),
     OpenOrders(OpenOrders),
     OpenOrder(OpenOrder),
+    Halt(MarketControl),
+    Resume(MarketControl),
+    MarketState(MarketControl),
+    SetPriceBand(PriceBand),
 }
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CancelOrder {
@@ -42,15 +52,20 @@ pub struct CancelOrder {
     pub symbol: Symbol,
     pub price: Price,
     pub order_side: OrderSide,
-    sub_id: i64,
+    pub sub_id: i64,
     pub timestamp: i64,
 }
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CancelAll {
-    user_id: Id,
-    symbol: Symbol,
+    pub user_id: Id,
+    pub symbol: Symbol,
     sub_id: i64,
-    timestamp: i64,
+    pub timestamp: i64,
+}
+impl CancelAll {
+    pub fn new(user_id: Id, symbol: Symbol, sub_id: i64) -> CancelAll {
+        CancelAll { user_id, symbol, sub_id, timestamp: ledger::now() as i64 }
+    }
 }
 #[derive(Debug, Serialize, Deserialize)]
 pub struct OpenOrders {
@@ -66,6 +81,36 @@ pub struct OpenOrder {
     sub_id: i64,
 }
 
+// Sent by operators through the admin routes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MarketControl {
+    pub symbol: Symbol,
+    sub_id: i64,
+    pub timestamp: i64,
+}
+// Without a deviation orders in the market are not checked against its reference price
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PriceBand {
+    pub symbol: Symbol,
+    pub max_deviation: Option<Decimal>,
+    sub_id: i64,
+    pub timestamp: i64,
+}
+
+// Persists a state changing request before it is applied to the orderbook. Orders pass the
+// stamp taken when their funds were locked, everything else a new one.
+fn journal_request(
+    journal: &mut Journal,
+    stamp: u64,
+    request: &EngineRequests
+) -> Result<u64, MatchingEngineErrors> {
+    let payload = to_string(request).unwrap();
+    journal.append_at(stamp, payload.as_bytes()).map_err(|err| {
+        eprintln!("Could not write to journal: {}", err);
+        MatchingEngineErrors::JournalUnavailable
+    })
+}
+
 impl EngineRequests {
     pub fn execute_order(
         start: Instant,
@@ -73,81 +118,81 @@ impl EngineRequests {
         orderbook: &mut Orderbook,
         con: &mut Connection,
         tx: UnboundedSender<PersistOrderRequest>,
-        event_tx: EventTranmitter
+        journal: &mut Journal,
+        liquidation: bool
     ) {
         println!("Recieved Order");
         let sub_id = recieved_order.id;
-        let exchange = Exchange::from_symbol(recieved_order.symbol.clone()).unwrap();
-        let (asset, locked_balance) = match recieved_order.order_type {
-            OrderType::Market => {
-                let quote = orderbook.get_quote(
-                    &recieved_order.order_side,
-                    recieved_order.initial_quantity
-                );
-                let mut users = USERS.lock().unwrap();
-                let result = users.validate_and_lock_market(
-                    quote,
-                    &recieved_order.order_side,
-                    &exchange,
-                    recieved_order.user_id as u64,
-                    recieved_order.initial_quantity
-                );
-                match result {
-                    Ok(val) => { val }
-                    Err(err) => {
-                        redis
-                            ::cmd("LPUSH")
-                            .arg(sub_id)
-                            .arg(err.to_string())
-                            .query::<Value>(con)
-                            .unwrap();
-                        return;
-                    }
-                }
-            }
-            OrderType::Limit => {
-                let mut users = USERS.lock().unwrap();
-                let result = users.validate_and_lock_limit(
-                    recieved_order.order_side.clone(),
-                    &exchange,
-                    recieved_order.user_id as u64,
-                    recieved_order.price,
-                    recieved_order.initial_quantity
-                );
-                match result {
-                    Ok(val) => { val }
-                    Err(err) => {
-                        redis
-                            ::cmd("LPUSH")
-                            .arg(sub_id)
-                            .arg(err.to_string())
-                            .query::<Value>(con)
-                            .unwrap();
-                        return;
-                    }
-                }
+        let user_id = recieved_order.user_id as u64;
+        if orderbook.halted {
+            let err = MatchingEngineErrors::MarketHalted;
+            redis::cmd("LPUSH").arg(sub_id).arg(err.to_string()).query::<Value>(con).unwrap();
+            return;
+        }
+        let locked = match liquidation {
+            true => orderbook.lock_liquidation(&recieved_order),
+            false => orderbook.lock_order(&recieved_order),
+        };
+        let (asset, locked_amount, locked_balance) = match locked {
+            Ok(val) => val,
+            Err(err) => {
+                redis::cmd("LPUSH").arg(sub_id).arg(err.to_string()).query::<Value>(con).unwrap();
+                return;
             }
         };
         let order_id = orderbook.increment_order_id();
         recieved_order.id = order_id as i64;
+        // Only accepted orders reach the journal, stamped with the id they were given
+        let request = match liquidation {
+            true => EngineRequests::Liquidate(recieved_order.clone()),
+            false => EngineRequests::ExecuteOrder(recieved_order.clone()),
+        };
+        if let Err(err) = journal_request(journal, locked_balance.journal_stamp, &request) {
+            orderbook.balances.unlock_amount(&asset, user_id, locked_amount);
+            redis::cmd("LPUSH").arg(sub_id).arg(err.to_string()).query::<Value>(con).unwrap();
+            return;
+        }
         tx.send(
             PersistOrderRequest::Save(SaveOrder {
                 locked_balance,
                 asset,
                 recieved_order: recieved_order.clone(),
+                ledger: ledger::lock(user_id, &asset, locked_amount, order_id),
             })
         );
         let (filled_quantity, filled_quote_quantity, order_status) = orderbook.process_order(
             recieved_order.clone(),
-            order_id,
-            event_tx
+            order_id
         );
+        if orderbook.exchange.is_perpetual() {
+            for persist in orderbook.balances.take_moved_positions() {
+                tx.send(PersistOrderRequest::Positions(persist));
+            }
+        }
+        if
+            let Some((asset, released, updated_locked_balance)) = orderbook.release_unspent(
+                &recieved_order,
+                locked_amount,
+                filled_quote_quantity
+            )
+        {
+            tx.send(
+                PersistOrderRequest::Unlock(PersistUnlock {
+                    user_id,
+                    asset,
+                    updated_locked_balance,
+                    ledger: ledger::unlock(user_id, &asset, released, order_id, ledger::now()),
+                })
+            );
+        }
         let response = RecievedOrder {
             id: order_id as i64,
             filled_quantity,
             filled_quote_quantity,
             initial_quantity: recieved_order.initial_quantity,
-            quote_quantity: recieved_order.quote_quantity,
+            // what the order ends up spending, its fills plus the rest at its limit price
+            quote_quantity: filled_quote_quantity +
+            (recieved_order.initial_quantity - filled_quantity) * recieved_order.price,
             user_id: recieved_order.user_id,
             order_status,
             order_type: recieved_order.order_type,
@@ -156,6 +201,10 @@ impl EngineRequests {
             symbol: recieved_order.symbol,
             timestamp: recieved_order.timestamp,
         };
+        orderbook.publish_open_notional();
+        orderbook.publish_mark_price();
+        orderbook.publish_depth();
+        orderbook.publish_ticker(ledger::now());
         println!("Processed order in {} ms", start.elapsed().as_millis());
         redis
             ::cmd("LPUSH")
@@ -169,32 +218,36 @@ impl EngineRequests {
         cancel_order: CancelOrder,
         orderbook: &mut Orderbook,
         con: &mut Connection,
-        tx: UnboundedSender<PersistOrderRequest>
+        tx: UnboundedSender<PersistOrderRequest>,
+        journal: &mut Journal
     ) {
-        let asset = match cancel_order.order_side {
-            OrderSide::Bid => { orderbook.exchange.quote }
-            OrderSide::Ask => { orderbook.exchange.base }
-        };
+        let request = EngineRequests::CancelOrder(cancel_order.clone());
+        if let Err(err) = journal_request(journal, journal::next_stamp(), &request) {
+            redis
+                ::cmd("LPUSH")
+                .arg(cancel_order.sub_id)
+                .arg(err.to_string())
+                .query::<Value>(con)
+                .unwrap();
+            return;
+        }
         let result = orderbook.cancel_order(
             cancel_order.id,
             cancel_order.user_id,
             &cancel_order.order_side,
             &cancel_order.price
         );
+        orderbook.publish_open_notional();
+        orderbook.publish_mark_price();
+        orderbook.publish_depth();
         println!("Canceled order in {}ms", start.elapsed().as_millis());
         match result {
             Ok(order) => {
                 let sub_id = cancel_order.sub_id;
-                let mut users = USERS.lock().unwrap();
-                let quantity = match order.order_side {
-                    OrderSide::Bid => order.quantity * cancel_order.price,
-                    OrderSide::Ask => order.quantity,
-                };
-                let updated_locked_balance = *users
-                    .unlock_amount(&asset, order.user_id, quantity)
-                    .locked_balance.get(&asset)
-                    .unwrap();
-                drop(users);
+                let (asset, released, updated_locked_balance) = orderbook.unlock_cancelled(
+                    &order,
+                    &cancel_order.price
+                );
                 tx.send(
                     PersistOrderRequest::Cancel(PersistCancel {
                         id: cancel_order.id,
@@ -204,7 +257,14 @@ impl EngineRequests {
                         timestamp: cancel_order.timestamp,
                         updated_locked_balance,
                         asset,
-                        user_id: cancel_order.user_id,
+                        user_id: order.user_id,
+                        ledger: ledger::unlock(
+                            order.user_id,
+                            &asset,
+                            released,
+                            cancel_order.id,
+                            ledger::now()
+                        ),
                     })
                 );
                 redis
@@ -246,14 +306,42 @@ impl EngineRequests {
         cancel_all: CancelAll,
         orderbook: &mut Orderbook,
         con: &mut Connection,
-        tx: UnboundedSender<PersistOrderRequest>
+        tx: UnboundedSender<PersistOrderRequest>,
+        journal: &mut Journal
     ) {
-        let (orders, locked_balances) = orderbook.cancel_all_orders(cancel_all.user_id);
+        let request = EngineRequests::CancelAll(cancel_all.clone());
+        if let Err(err) = journal_request(journal, journal::next_stamp(), &request) {
+            redis
+                ::cmd("LPUSH")
+                .arg(cancel_all.sub_id)
+                .arg(err.to_string())
+                .query::<Value>(con)
+                .unwrap();
+            return;
+        }
+        let (orders, locked_balances, stamp) = orderbook.cancel_all_orders(cancel_all.user_id);
+        orderbook.publish_open_notional();
+        orderbook.publish_mark_price();
+        orderbook.publish_depth();
         println!("Canceled all order in {}ms", start.elapsed().as_millis());
         if orders.len() != 0 {
+            let timestamp = ledger::now();
+            let entries = orders
+                .iter()
+                .flat_map(|o| {
+                    let remaining = o.initial_quantity - o.filled_quantity;
+                    let (asset, released) = orderbook.exchange.order_lock(
+                        &o.order_side,
+                        o.price,
+                        remaining
+                    );
+                    ledger::unlock(cancel_all.user_id, &asset, released, o.id as u64, timestamp)
+                })
+                .collect();
             tx.send(
                 PersistOrderRequest::CancelAll(PersistCancelAll {
                     locked_balances,
+                    stamp,
                     symbol: cancel_all.symbol,
                     timestamp: cancel_all.timestamp,
                     user_id: cancel_all.user_id as i64,
@@ -265,6 +353,7 @@ impl EngineRequests {
                             price: o.price,
                         })
                         .collect(),
+                    ledger: entries,
                 })
             );
         }
@@ -276,6 +365,58 @@ impl EngineRequests {
             .query::<Value>(con)
             .unwrap();
     }
+    pub fn set_halted(
+        control: MarketControl,
+        halted: bool,
+        orderbook: &mut Orderbook,
+        con: &mut Connection,
+        journal: &mut Journal
+    ) {
+        let request = match halted {
+            true => EngineRequests::Halt(control.clone()),
+            false => EngineRequests::Resume(control.clone()),
+        };
+        if let Err(err) = journal_request(journal, journal::next_stamp(), &request) {
+            redis::cmd("LPUSH").arg(control.sub_id).arg(err.to_string()).query::<Value>(con).unwrap();
+            return;
+        }
+        orderbook.halted = halted;
+        println!("{} halted: {}", orderbook.exchange.symbol, halted);
+        EngineRequests::market_state(control, orderbook, con);
+    }
+    pub fn set_price_band(
+        price_band: PriceBand,
+        orderbook: &mut Orderbook,
+        con: &mut Connection,
+        journal: &mut Journal
+    ) {
+        let sub_id = price_band.sub_id;
+        if price_band.max_deviation.is_some_and(|deviation| deviation <= dec!(0)) {
+            let err = MatchingEngineErrors::InvalidPriceBand;
+            redis::cmd("LPUSH").arg(sub_id).arg(err.to_string()).query::<Value>(con).unwrap();
+            return;
+        }
+        let request = EngineRequests::SetPriceBand(price_band.clone());
+        if let Err(err) = journal_request(journal, journal::next_stamp(), &request) {
+            redis::cmd("LPUSH").arg(sub_id).arg(err.to_string()).query::<Value>(con).unwrap();
+            return;
+        }
+        orderbook.max_price_deviation = price_band.max_deviation;
+        redis
+            ::cmd("LPUSH")
+            .arg(sub_id)
+            .arg(to_string(&orderbook.summary()).unwrap())
+            .query::<Value>(con)
+            .unwrap();
+    }
+    pub fn market_state(control: MarketControl, orderbook: &Orderbook, con: &mut Connection) {
+        redis
+            ::cmd("LPUSH")
+            .arg(control.sub_id)
+            .arg(to_string(&orderbook.summary()).unwrap())
+            .query::<Value>(con)
+            .unwrap();
+    }
     pub fn open_order(
         start: Instant,
         o_order: OpenOrder,
@@ -1,19 +1,66 @@
+use common::ledger::LedgerEntry;
 use redis::{ Commands, Connection, Value };
 use serde::{ Deserialize, Serialize };
 use serde_json::to_string;
+use tokio::sync::mpsc::UnboundedSender;
 
-use crate::{ error::MatchingEngineErrors, Asset, Id, Quantity, Users };
+use crate::{
+    error::MatchingEngineErrors,
+    journal::{ Journal, JournalEntry },
+    accounts::InternalTransfer,
+    admin::{ Adjustment, AdjustmentReason },
+    risk::RiskLimits,
+    margin::{ Loan, MarginMode, HOUR },
+    perpetual::{ FundingPayment, FUNDING_INTERVAL },
+    portfolio::DAY,
+    custody::{ DepositState, IncomingTransfer },
+    ledger,
+    withdrawal::{ Withdrawal, WithdrawalAction, WithdrawalState },
+    Asset,
+    Id,
+    PersistAdjustment,
+    PersistDeposit,
+    PersistMargin,
+    PersistOrderRequest,
+    PersistPortfolio,
+    PersistPositions,
+    PersistSubAccount,
+    PersistTransfer,
+    PersistWithdrawal,
+    Quantity,
+    Users,
+};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum UserRequests {
     NewUser(NewUser),
     Deposit(Deposit),
     Withdraw(Withdraw),
+    UpdateWithdrawal(UpdateWithdrawal),
+    ReportTransfer(ReportTransfer),
+    NewSubAccount(NewSubAccount),
+    Transfer(Transfer),
+    AdjustBalance(AdjustBalance),
+    FreezeAccount(FreezeAccount),
+    SetRiskLimits(SetRiskLimits),
+    GetRiskLimits(GetRiskLimits),
+    SetMarginMode(SetMarginMode),
+    Borrow(LoanRequest),
+    Repay(LoanRequest),
+    AccrueInterest(AccrueInterest),
+    GetMarginAccount(GetMarginAccount),
+    ChargeFunding(ChargeFunding),
+    GetPositions(GetPositions),
+    GetPortfolio(GetPortfolio),
+    SnapshotPortfolios(SnapshotPortfolios),
     GetUserBalances(GetUserBalances),
 }
 #[derive(Debug, Serialize, Deserialize)]
 pub struct NewUser {
     sub_id: i64,
+    // Assigned by the engine before the request is journaled
+    #[serde(default)]
+    id: Option<Id>,
 }
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Deposit {
@@ -21,6 +68,9 @@ pub struct Deposit {
     asset: Asset,
     quantity: Quantity,
     sub_id: i64,
+    // Assigned by the engine before the request is journaled, from the ids of custody deposits
+    #[serde(default)]
+    id: Id,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -28,7 +78,188 @@ pub struct Withdraw {
     user_id: Id,
     asset: Asset,
     quantity: Quantity,
+    #[serde(default)]
+    destination: String,
     sub_id: i64,
+    // Assigned by the engine before the request is journaled, journals from before withdrawals
+    // were approved have neither and withdraw right away
+    #[serde(default)]
+    id: Option<Id>,
+    #[serde(default)]
+    timestamp: u64,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateWithdrawal {
+    id: Id,
+    action: WithdrawalAction,
+    sub_id: i64,
+    #[serde(default)]
+    timestamp: u64,
+}
+// Sent by the custody watcher, or by the mock custody route which waits for the deposit
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReportTransfer {
+    transfer: IncomingTransfer,
+    #[serde(default)]
+    sub_id: Option<i64>,
+    #[serde(default)]
+    id: Id,
+    #[serde(default)]
+    timestamp: u64,
+}
+impl ReportTransfer {
+    pub fn new(transfer: IncomingTransfer) -> ReportTransfer {
+        ReportTransfer { transfer, sub_id: None, id: 0, timestamp: 0 }
+    }
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewSubAccount {
+    master_id: Id,
+    sub_id: i64,
+    // Assigned by the engine before the request is journaled, like the id of new users
+    #[serde(default)]
+    id: Option<Id>,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Transfer {
+    from: Id,
+    to: Id,
+    asset: Asset,
+    quantity: Quantity,
+    sub_id: i64,
+    #[serde(default)]
+    id: Id,
+    #[serde(default)]
+    timestamp: u64,
+}
+impl Transfer {
+    fn to_internal_transfer(&self) -> InternalTransfer {
+        InternalTransfer {
+            id: self.id,
+            from: self.from,
+            to: self.to,
+            asset: self.asset,
+            quantity: self.quantity,
+            timestamp: self.timestamp,
+        }
+    }
+}
+// Sent by operators through the admin routes
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AdjustBalance {
+    user_id: Id,
+    asset: Asset,
+    amount: Quantity,
+    reason: AdjustmentReason,
+    sub_id: i64,
+    #[serde(default)]
+    id: Id,
+    #[serde(default)]
+    timestamp: u64,
+}
+impl AdjustBalance {
+    fn to_adjustment(&self) -> Adjustment {
+        Adjustment {
+            id: self.id,
+            user_id: self.user_id,
+            asset: self.asset,
+            amount: self.amount,
+            reason: self.reason,
+            timestamp: self.timestamp,
+        }
+    }
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FreezeAccount {
+    user_id: Id,
+    frozen: bool,
+    sub_id: i64,
+}
+// Without limits the account goes back to the defaults
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetRiskLimits {
+    user_id: Id,
+    limits: Option<RiskLimits>,
+    sub_id: i64,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetRiskLimits {
+    user_id: Id,
+    sub_id: i64,
+}
+// Without a mode margin is turned off
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetMarginMode {
+    user_id: Id,
+    mode: Option<MarginMode>,
+    sub_id: i64,
+}
+// Borrows or repays, repayments are also sent by the margin monitor while it liquidates
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LoanRequest {
+    user_id: Id,
+    asset: Asset,
+    quantity: Quantity,
+    sub_id: i64,
+    #[serde(default)]
+    id: Id,
+    #[serde(default)]
+    timestamp: u64,
+}
+impl LoanRequest {
+    pub fn new(user_id: Id, asset: Asset, quantity: Quantity, sub_id: i64) -> LoanRequest {
+        LoanRequest { user_id, asset, quantity, sub_id, id: 0, timestamp: 0 }
+    }
+    fn to_loan(&self) -> Loan {
+        Loan {
+            id: self.id,
+            user_id: self.user_id,
+            asset: self.asset,
+            quantity: self.quantity,
+            timestamp: self.timestamp,
+        }
+    }
+}
+// Sent by the interest thread, the hour is the one it is sent in
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct AccrueInterest {
+    #[serde(default)]
+    hour: u64,
+    #[serde(default)]
+    timestamp: u64,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetMarginAccount {
+    user_id: Id,
+    sub_id: i64,
+}
+// Sent by the funding thread, the payments are worked out when the request is journaled
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ChargeFunding {
+    #[serde(default)]
+    interval: u64,
+    #[serde(default)]
+    timestamp: u64,
+    #[serde(default)]
+    payments: Vec<FundingPayment>,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetPositions {
+    user_id: Id,
+    sub_id: i64,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetPortfolio {
+    user_id: Id,
+    sub_id: i64,
+}
+// Sent by the portfolio thread once a day, every account is valued and stored for the day
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SnapshotPortfolios {
+    #[serde(default)]
+    day: u64,
+    #[serde(default)]
+    timestamp: u64,
 }
 #[derive(Debug, Serialize, Deserialize)]
 pub struct GetUserBalances {
@@ -36,24 +267,278 @@ pub struct GetUserBalances {
     sub_id: i64,
 }
 
+// Balance changing requests are journaled before they are applied, so on restart they can be
+// replayed on top of the users snapshot
 impl UserRequests {
+    pub fn journal(&self, journal: &mut Journal) -> Result<(), MatchingEngineErrors> {
+        match self {
+            UserRequests::GetUserBalances(_) |
+            UserRequests::GetRiskLimits(_) |
+            UserRequests::GetMarginAccount(_) |
+            UserRequests::GetPositions(_) |
+            UserRequests::GetPortfolio(_) |
+            UserRequests::SnapshotPortfolios(_) => Ok(()),
+            _ => {
+                let payload = to_string(self).unwrap();
+                journal
+                    .append(payload.as_bytes())
+                    .map(|_| ())
+                    .map_err(|err| {
+                        eprintln!("Could not write to users journal: {}", err);
+                        MatchingEngineErrors::JournalUnavailable
+                    })
+            }
+        }
+    }
+    // New users and withdrawals get their id, and withdrawals their time, before they are
+    // journaled, so a replay creates the same user and the same withdrawal
+    pub fn assign_ids(&mut self, users: &mut Users) {
+        match self {
+            UserRequests::NewUser(u) => {
+                u.id = Some(users.next_user_id());
+            }
+            UserRequests::Deposit(u) => {
+                u.id = users.deposits.next_deposit_id();
+            }
+            UserRequests::Withdraw(u) => {
+                u.id = Some(users.withdrawals.next_withdrawal_id());
+                u.timestamp = ledger::now();
+            }
+            UserRequests::UpdateWithdrawal(u) => {
+                u.timestamp = ledger::now();
+            }
+            UserRequests::ReportTransfer(u) => {
+                u.id = users.deposits.deposit_id(&u.transfer.tx_id);
+                u.timestamp = ledger::now();
+            }
+            UserRequests::NewSubAccount(u) => {
+                u.id = Some(users.next_user_id());
+            }
+            UserRequests::Transfer(u) => {
+                u.id = users.next_transfer_id();
+                u.timestamp = ledger::now();
+            }
+            UserRequests::AdjustBalance(u) => {
+                u.id = users.next_adjustment_id();
+                u.timestamp = ledger::now();
+            }
+            UserRequests::Borrow(u) | UserRequests::Repay(u) => {
+                u.id = users.next_loan_id();
+                u.timestamp = ledger::now();
+            }
+            UserRequests::AccrueInterest(u) => {
+                u.timestamp = ledger::now();
+                u.hour = u.timestamp / HOUR;
+            }
+            UserRequests::ChargeFunding(u) => {
+                u.timestamp = ledger::now();
+                u.interval = u.timestamp / FUNDING_INTERVAL;
+                u.payments = users.funding_payments();
+            }
+            UserRequests::SnapshotPortfolios(u) => {
+                u.timestamp = ledger::now();
+                u.day = u.timestamp / DAY;
+            }
+            _ => {}
+        }
+    }
+    // Without a snapshot users come from scylla, which may not have the latest users yet
+    pub fn recover_last_ids(users: &mut Users, entries: &[JournalEntry]) {
+        for entry in entries {
+            let request = serde_json::from_slice::<UserRequests>(&entry.payload);
+            match request {
+                Ok(UserRequests::NewUser(NewUser { id: Some(id), .. })) => {
+                    users.last_user_id = users.last_user_id.max(id);
+                }
+                Ok(UserRequests::NewSubAccount(NewSubAccount { id: Some(id), .. })) => {
+                    users.last_user_id = users.last_user_id.max(id);
+                }
+                Ok(UserRequests::Transfer(Transfer { id, .. })) => {
+                    users.last_transfer_id = users.last_transfer_id.max(id);
+                }
+                Ok(UserRequests::AdjustBalance(AdjustBalance { id, .. })) => {
+                    users.last_adjustment_id = users.last_adjustment_id.max(id);
+                }
+                Ok(UserRequests::Borrow(LoanRequest { id, .. })) => {
+                    users.margin.last_loan_id = users.margin.last_loan_id.max(id);
+                }
+                Ok(UserRequests::Repay(LoanRequest { id, .. })) => {
+                    users.margin.last_loan_id = users.margin.last_loan_id.max(id);
+                }
+                Ok(UserRequests::AccrueInterest(AccrueInterest { hour, .. })) => {
+                    users.margin.last_accrual = users.margin.last_accrual.max(hour);
+                }
+                Ok(UserRequests::ChargeFunding(ChargeFunding { interval, .. })) => {
+                    let perpetuals = &mut users.perpetuals;
+                    perpetuals.last_funding = perpetuals.last_funding.max(interval);
+                }
+                Ok(UserRequests::Withdraw(Withdraw { id: Some(id), .. })) => {
+                    let withdrawals = &mut users.withdrawals;
+                    withdrawals.last_withdrawal_id = withdrawals.last_withdrawal_id.max(id);
+                }
+                Ok(UserRequests::ReportTransfer(ReportTransfer { id, .. })) => {
+                    let deposits = &mut users.deposits;
+                    deposits.last_deposit_id = deposits.last_deposit_id.max(id);
+                }
+                Ok(UserRequests::Deposit(Deposit { id, .. })) => {
+                    let deposits = &mut users.deposits;
+                    deposits.last_deposit_id = deposits.last_deposit_id.max(id);
+                }
+                _ => {}
+            }
+        }
+        users.recover_last_ids();
+    }
+    // Where the response goes, nobody waits for the transfers the custody watcher reports or
+    // for interest, funding and portfolio snapshots
+    pub fn sub_id(&self) -> Option<i64> {
+        match self {
+            UserRequests::NewUser(u) => Some(u.sub_id),
+            UserRequests::Deposit(u) => Some(u.sub_id),
+            UserRequests::Withdraw(u) => Some(u.sub_id),
+            UserRequests::UpdateWithdrawal(u) => Some(u.sub_id),
+            UserRequests::ReportTransfer(u) => u.sub_id,
+            UserRequests::NewSubAccount(u) => Some(u.sub_id),
+            UserRequests::Transfer(u) => Some(u.sub_id),
+            UserRequests::AdjustBalance(u) => Some(u.sub_id),
+            UserRequests::FreezeAccount(u) => Some(u.sub_id),
+            UserRequests::SetRiskLimits(u) => Some(u.sub_id),
+            UserRequests::GetRiskLimits(u) => Some(u.sub_id),
+            UserRequests::SetMarginMode(u) => Some(u.sub_id),
+            UserRequests::Borrow(u) => Some(u.sub_id),
+            UserRequests::Repay(u) => Some(u.sub_id),
+            UserRequests::AccrueInterest(_) => None,
+            UserRequests::GetMarginAccount(u) => Some(u.sub_id),
+            UserRequests::ChargeFunding(_) => None,
+            UserRequests::GetPositions(u) => Some(u.sub_id),
+            UserRequests::GetPortfolio(u) => Some(u.sub_id),
+            UserRequests::SnapshotPortfolios(_) => None,
+            UserRequests::GetUserBalances(u) => Some(u.sub_id),
+        }
+    }
+    pub fn replay_journal(users: &mut Users, entries: Vec<JournalEntry>) {
+        for entry in entries {
+            let request: UserRequests = serde_json
+                ::from_slice(&entry.payload)
+                .expect("Journal entry is not a user request");
+            // It was rejected the same way when it was live, nothing was applied either time
+            if let Err(err) = UserRequests::replay(users, request) {
+                println!("Journaled user request {} is rejected again: {}", entry.seq, err);
+            }
+        }
+    }
+    fn replay(users: &mut Users, request: UserRequests) -> Result<(), MatchingEngineErrors> {
+        match request {
+            UserRequests::NewUser(u) => {
+                match u.id {
+                    Some(id) => {
+                        users.new_user(id);
+                        users.last_user_id = users.last_user_id.max(id);
+                    }
+                    None => {
+                        let id = users.next_user_id();
+                        users.new_user(id);
+                    }
+                }
+            }
+            UserRequests::Deposit(u) => {
+                let deposits = &mut users.deposits;
+                deposits.last_deposit_id = deposits.last_deposit_id.max(u.id);
+                users.deposit(&u.asset, u.quantity, u.user_id)?;
+            }
+            UserRequests::Withdraw(u) => {
+                match u.id {
+                    Some(id) => {
+                        let withdrawals = &mut users.withdrawals;
+                        withdrawals.last_withdrawal_id = withdrawals.last_withdrawal_id.max(id);
+                        users.request_withdrawal(u.to_withdrawal(id))?;
+                    }
+                    None => {
+                        withdraw_available(users, &u)?;
+                    }
+                }
+            }
+            UserRequests::UpdateWithdrawal(u) => {
+                users.update_withdrawal(u.id, u.action, u.timestamp)?;
+            }
+            UserRequests::ReportTransfer(u) => {
+                let deposits = &mut users.deposits;
+                deposits.last_deposit_id = deposits.last_deposit_id.max(u.id);
+                users.observe_transfer(u.id, u.transfer, u.timestamp)?;
+            }
+            UserRequests::NewSubAccount(u) => {
+                let id = u.id.expect("Sub-accounts are assigned an id first");
+                users.last_user_id = users.last_user_id.max(id);
+                users.new_sub_account(u.master_id, id)?;
+            }
+            UserRequests::Transfer(u) => {
+                users.last_transfer_id = users.last_transfer_id.max(u.id);
+                users.transfer(&u.to_internal_transfer())?;
+            }
+            UserRequests::AdjustBalance(u) => {
+                users.last_adjustment_id = users.last_adjustment_id.max(u.id);
+                users.adjust_balance(&u.to_adjustment())?;
+            }
+            UserRequests::FreezeAccount(u) => {
+                users.set_frozen(u.user_id, u.frozen)?;
+            }
+            UserRequests::SetRiskLimits(u) => {
+                users.set_risk_limits(u.user_id, u.limits)?;
+            }
+            UserRequests::SetMarginMode(u) => {
+                users.set_margin_mode(u.user_id, u.mode)?;
+            }
+            UserRequests::Borrow(u) => {
+                users.margin.last_loan_id = users.margin.last_loan_id.max(u.id);
+                users.borrow(&u.to_loan())?;
+            }
+            UserRequests::Repay(u) => {
+                users.margin.last_loan_id = users.margin.last_loan_id.max(u.id);
+                users.repay(&u.to_loan())?;
+            }
+            UserRequests::AccrueInterest(u) => {
+                users.accrue_interest(u.hour, u.timestamp);
+            }
+            UserRequests::ChargeFunding(u) => {
+                users.pay_funding(u.interval, &u.payments, u.timestamp);
+            }
+            UserRequests::GetUserBalances(_) |
+            UserRequests::GetRiskLimits(_) |
+            UserRequests::GetMarginAccount(_) |
+            UserRequests::GetPositions(_) |
+            UserRequests::GetPortfolio(_) |
+            UserRequests::SnapshotPortfolios(_) => {}
+        }
+        Ok(())
+    }
     pub fn new_user(users: &mut Users, u: NewUser, con: &mut Connection) {
-        let total_user = users.users.len() as u64;
-        let new_user_id = total_user + 1;
-        users.new_user(total_user + 1);
+        let new_user_id = users.new_user(u.id.expect("New users are assigned an id first"));
         let user = users.users.get(&new_user_id).unwrap();
         println!("New User Created");
         con.lpush::<i64, String, Value>(u.sub_id, to_string(user).unwrap()).unwrap();
     }
     pub fn get_user_balances(users: &mut Users, u: GetUserBalances, con: &mut Connection) {
-        let user = users.users.get(&u.user_id).unwrap();
-        con.lpush::<i64, String, Value>(u.sub_id, to_string(user).unwrap()).unwrap();
+        let response = match users.user(u.user_id) {
+            Ok(user) => to_string(user).unwrap(),
+            Err(err) => err.to_string(),
+        };
+        con.lpush::<i64, String, Value>(u.sub_id, response).unwrap();
     }
-    pub fn deposit(users: &mut Users, u: Deposit, con: &mut Connection) {
+    pub fn deposit(
+        users: &mut Users,
+        u: Deposit,
+        con: &mut Connection,
+        tx: &UnboundedSender<PersistOrderRequest>
+    ) {
         let res = users.deposit(&u.asset, u.quantity, u.user_id);
         match res {
             Ok(user) => {
                 println!("Deposited balance");
+                tx.send(
+                    PersistOrderRequest::Ledger(
+                        ledger::deposit(u.user_id, &u.asset, u.quantity, u.id)
+                    )
+                );
                 con.lpush::<i64, String, Value>(u.sub_id, to_string(user).unwrap()).unwrap();
             }
             Err(err) => {
@@ -62,19 +547,169 @@ impl UserRequests {
             }
         }
     }
-    pub fn withdraw(users: &mut Users, u: Withdraw, con: &mut Connection) {
-        let ava_b = users.available_balance(&u.asset, u.user_id);
-        match ava_b {
-            Ok(available) => {
-                if available > u.quantity {
-                    con.lpush::<i64, String, Value>(
-                        u.sub_id,
-                        MatchingEngineErrors::OverWithdrawl.to_string()
-                    ).unwrap();
-                    return;
+    pub fn withdraw(
+        users: &mut Users,
+        u: Withdraw,
+        con: &mut Connection,
+        tx: &UnboundedSender<PersistOrderRequest>
+    ) {
+        let id = u.id.expect("Withdrawals are assigned an id first");
+        let res = users.request_withdrawal(u.to_withdrawal(id));
+        UserRequests::respond_withdrawal(users, res, u.sub_id, con, tx);
+    }
+    pub fn update_withdrawal(
+        users: &mut Users,
+        u: UpdateWithdrawal,
+        con: &mut Connection,
+        tx: &UnboundedSender<PersistOrderRequest>
+    ) {
+        let res = users.update_withdrawal(u.id, u.action, u.timestamp);
+        UserRequests::respond_withdrawal(users, res, u.sub_id, con, tx);
+    }
+    fn respond_withdrawal(
+        users: &mut Users,
+        res: Result<(Withdrawal, Vec<LedgerEntry>), MatchingEngineErrors>,
+        sub_id: i64,
+        con: &mut Connection,
+        tx: &UnboundedSender<PersistOrderRequest>
+    ) {
+        match res {
+            Ok((withdrawal, ledger)) => {
+                println!("Withdrawal {} {}", withdrawal.id, withdrawal.state);
+                let user = users.users.get(&withdrawal.user_id).unwrap().clone();
+                let daily = users.withdrawals.daily[&withdrawal.user_id][&withdrawal.asset];
+                tx.send(
+                    PersistOrderRequest::Withdrawal(PersistWithdrawal {
+                        withdrawal: withdrawal.clone(),
+                        user,
+                        daily,
+                        last_withdrawal_id: users.withdrawals.last_withdrawal_id,
+                        stamp: users.stamp(),
+                        ledger,
+                    })
+                );
+                con.lpush::<i64, String, Value>(sub_id, to_string(&withdrawal).unwrap()).unwrap();
+            }
+            Err(err) => {
+                con.lpush::<i64, String, Value>(sub_id, err.to_string()).unwrap();
+            }
+        }
+    }
+    pub fn report_transfer(
+        users: &mut Users,
+        u: ReportTransfer,
+        con: &mut Connection,
+        tx: &UnboundedSender<PersistOrderRequest>
+    ) {
+        match users.observe_transfer(u.id, u.transfer, u.timestamp) {
+            Ok((deposit, ledger)) => {
+                println!("Deposit {} {}", deposit.tx_id, deposit.state);
+                let updated_balance = match deposit.state {
+                    DepositState::Credited =>
+                        Some(*users.balance(&deposit.asset, deposit.user_id).unwrap()),
+                    DepositState::Pending => None,
+                };
+                let response = to_string(&deposit).unwrap();
+                tx.send(
+                    PersistOrderRequest::Deposit(PersistDeposit {
+                        deposit,
+                        updated_balance,
+                        stamp: users.stamp(),
+                        ledger,
+                    })
+                );
+                if let Some(sub_id) = u.sub_id {
+                    con.lpush::<i64, String, Value>(sub_id, response).unwrap();
                 }
-                let user = users.withdraw(&u.asset, u.quantity, u.user_id).unwrap();
-                println!("Withdrawn balance");
+            }
+            Err(err) => {
+                println!("{}", err);
+                if let Some(sub_id) = u.sub_id {
+                    con.lpush::<i64, String, Value>(sub_id, err.to_string()).unwrap();
+                }
+            }
+        }
+    }
+    pub fn new_sub_account(
+        users: &mut Users,
+        u: NewSubAccount,
+        con: &mut Connection,
+        tx: &UnboundedSender<PersistOrderRequest>
+    ) {
+        let id = u.id.expect("Sub-accounts are assigned an id first");
+        match users.new_sub_account(u.master_id, id) {
+            Ok(sub_account) => {
+                println!("Sub-account {} of {} created", id, u.master_id);
+                tx.send(
+                    PersistOrderRequest::SubAccount(PersistSubAccount {
+                        master_id: u.master_id,
+                        sub_account: sub_account.clone(),
+                    })
+                );
+                con.lpush::<i64, String, Value>(u.sub_id, to_string(sub_account).unwrap()).unwrap();
+            }
+            Err(err) => {
+                con.lpush::<i64, String, Value>(u.sub_id, err.to_string()).unwrap();
+            }
+        }
+    }
+    pub fn transfer(
+        users: &mut Users,
+        u: Transfer,
+        con: &mut Connection,
+        tx: &UnboundedSender<PersistOrderRequest>
+    ) {
+        let transfer = u.to_internal_transfer();
+        match users.transfer(&transfer) {
+            Ok(ledger) => {
+                println!("Transferred {} {} from {} to {}", u.quantity, u.asset, u.from, u.to);
+                tx.send(
+                    PersistOrderRequest::Transfer(PersistTransfer {
+                        transfer,
+                        from_balance: *users.balance(&u.asset, u.from).unwrap(),
+                        to_balance: *users.balance(&u.asset, u.to).unwrap(),
+                        stamp: users.stamp(),
+                        ledger,
+                    })
+                );
+                con.lpush::<i64, String, Value>(u.sub_id, to_string(&transfer).unwrap()).unwrap();
+            }
+            Err(err) => {
+                con.lpush::<i64, String, Value>(u.sub_id, err.to_string()).unwrap();
+            }
+        }
+    }
+    pub fn adjust_balance(
+        users: &mut Users,
+        u: AdjustBalance,
+        con: &mut Connection,
+        tx: &UnboundedSender<PersistOrderRequest>
+    ) {
+        let adjustment = u.to_adjustment();
+        match users.adjust_balance(&adjustment) {
+            Ok(ledger) => {
+                println!("Adjusted {} {} of {} for {}", u.amount, u.asset, u.user_id, u.reason);
+                let response = to_string(&adjustment).unwrap();
+                tx.send(
+                    PersistOrderRequest::Adjustment(PersistAdjustment {
+                        balance: *users.balance(&u.asset, u.user_id).unwrap(),
+                        stamp: users.stamp(),
+                        adjustment,
+                        ledger,
+                    })
+                );
+                con.lpush::<i64, String, Value>(u.sub_id, response).unwrap();
+            }
+            Err(err) => {
+                con.lpush::<i64, String, Value>(u.sub_id, err.to_string()).unwrap();
+            }
+        }
+    }
+    // Only the engine knows an account is frozen, it is kept in the snapshot and the journal
+    pub fn freeze_account(users: &mut Users, u: FreezeAccount, con: &mut Connection) {
+        match users.set_frozen(u.user_id, u.frozen) {
+            Ok(user) => {
+                println!("Account {} frozen: {}", u.user_id, u.frozen);
                 con.lpush::<i64, String, Value>(u.sub_id, to_string(user).unwrap()).unwrap();
             }
             Err(err) => {
@@ -82,4 +717,339 @@ impl UserRequests {
             }
         }
     }
+    // Both respond with the limits in effect and what the account has resting in each market
+    pub fn set_risk_limits(users: &mut Users, u: SetRiskLimits, con: &mut Connection) {
+        match users.set_risk_limits(u.user_id, u.limits) {
+            Ok(risk) => {
+                println!("Risk limits of {} set to {:?}", u.user_id, risk.limits);
+                con.lpush::<i64, String, Value>(u.sub_id, to_string(&risk).unwrap()).unwrap();
+            }
+            Err(err) => {
+                con.lpush::<i64, String, Value>(u.sub_id, err.to_string()).unwrap();
+            }
+        }
+    }
+    pub fn get_risk_limits(users: &mut Users, u: GetRiskLimits, con: &mut Connection) {
+        let response = match users.account_risk(u.user_id) {
+            Ok(risk) => to_string(&risk).unwrap(),
+            Err(err) => err.to_string(),
+        };
+        con.lpush::<i64, String, Value>(u.sub_id, response).unwrap();
+    }
+    pub fn set_margin_mode(
+        users: &mut Users,
+        u: SetMarginMode,
+        con: &mut Connection,
+        tx: &UnboundedSender<PersistOrderRequest>
+    ) {
+        match users.set_margin_mode(u.user_id, u.mode) {
+            Ok(account) => {
+                println!("Margin of {} set to {:?}", u.user_id, account.mode);
+                let user = users.users.get(&u.user_id).unwrap().clone();
+                tx.send(
+                    PersistOrderRequest::Margin(PersistMargin {
+                        user,
+                        asset: None,
+                        stamp: users.stamp(),
+                        ledger: vec![],
+                    })
+                );
+                con.lpush::<i64, String, Value>(u.sub_id, to_string(&account).unwrap()).unwrap();
+            }
+            Err(err) => {
+                con.lpush::<i64, String, Value>(u.sub_id, err.to_string()).unwrap();
+            }
+        }
+    }
+    // Both respond with the margin account as the loan left it
+    pub fn borrow(
+        users: &mut Users,
+        u: LoanRequest,
+        con: &mut Connection,
+        tx: &UnboundedSender<PersistOrderRequest>
+    ) {
+        let res = users.borrow(&u.to_loan());
+        UserRequests::respond_loan(users, res, u, con, tx);
+    }
+    pub fn repay(
+        users: &mut Users,
+        u: LoanRequest,
+        con: &mut Connection,
+        tx: &UnboundedSender<PersistOrderRequest>
+    ) {
+        let res = users.repay(&u.to_loan());
+        UserRequests::respond_loan(users, res, u, con, tx);
+    }
+    fn respond_loan(
+        users: &mut Users,
+        res: Result<Vec<LedgerEntry>, MatchingEngineErrors>,
+        u: LoanRequest,
+        con: &mut Connection,
+        tx: &UnboundedSender<PersistOrderRequest>
+    ) {
+        match res {
+            Ok(ledger) => {
+                let user = users.users.get(&u.user_id).unwrap().clone();
+                tx.send(
+                    PersistOrderRequest::Margin(PersistMargin {
+                        user,
+                        asset: Some(u.asset),
+                        stamp: users.stamp(),
+                        ledger,
+                    })
+                );
+                let account = users.margin_account(u.user_id).unwrap();
+                con.lpush::<i64, String, Value>(u.sub_id, to_string(&account).unwrap()).unwrap();
+            }
+            Err(err) => {
+                con.lpush::<i64, String, Value>(u.sub_id, err.to_string()).unwrap();
+            }
+        }
+    }
+    pub fn accrue_interest(
+        users: &mut Users,
+        u: AccrueInterest,
+        tx: &UnboundedSender<PersistOrderRequest>
+    ) {
+        for (user_id, ledger) in users.accrue_interest(u.hour, u.timestamp) {
+            let user = users.users.get(&user_id).unwrap().clone();
+            let persist = PersistMargin { user, asset: None, stamp: users.stamp(), ledger };
+            tx.send(PersistOrderRequest::Margin(persist));
+        }
+    }
+    pub fn get_margin_account(users: &mut Users, u: GetMarginAccount, con: &mut Connection) {
+        let response = match users.margin_account(u.user_id) {
+            Ok(account) => to_string(&account).unwrap(),
+            Err(err) => err.to_string(),
+        };
+        con.lpush::<i64, String, Value>(u.sub_id, response).unwrap();
+    }
+    pub fn charge_funding(
+        users: &mut Users,
+        u: ChargeFunding,
+        tx: &UnboundedSender<PersistOrderRequest>
+    ) {
+        for (user_id, ledger) in users.pay_funding(u.interval, &u.payments, u.timestamp) {
+            let user = users.users.get(&user_id).unwrap().clone();
+            let persist = PersistPositions { user, stamp: users.stamp(), ledger };
+            tx.send(PersistOrderRequest::Positions(persist));
+        }
+    }
+    pub fn get_portfolio(users: &mut Users, u: GetPortfolio, con: &mut Connection) {
+        let response = match users.valuation(u.user_id, ledger::now()) {
+            Ok(valuation) => to_string(&valuation).unwrap(),
+            Err(err) => err.to_string(),
+        };
+        con.lpush::<i64, String, Value>(u.sub_id, response).unwrap();
+    }
+    pub fn snapshot_portfolios(
+        users: &mut Users,
+        u: SnapshotPortfolios,
+        tx: &UnboundedSender<PersistOrderRequest>
+    ) {
+        let valuations = users.valuations(u.timestamp);
+        println!("Valued {} accounts for day {}", valuations.len(), u.day);
+        tx.send(PersistOrderRequest::Portfolio(PersistPortfolio { day: u.day, valuations }));
+    }
+    pub fn get_positions(users: &mut Users, u: GetPositions, con: &mut Connection) {
+        let response = match users.positions(u.user_id) {
+            Ok(positions) => to_string(&positions).unwrap(),
+            Err(err) => err.to_string(),
+        };
+        con.lpush::<i64, String, Value>(u.sub_id, response).unwrap();
+    }
+}
+impl Withdraw {
+    fn to_withdrawal(&self, id: Id) -> Withdrawal {
+        Withdrawal {
+            id,
+            user_id: self.user_id,
+            asset: self.asset,
+            quantity: self.quantity,
+            destination: self.destination.clone(),
+            state: WithdrawalState::Locked,
+            tx_id: None,
+            reason: None,
+            requested_at: self.timestamp,
+            updated_at: self.timestamp,
+        }
+    }
+}
+// How journaled withdrawals from before the approval workflow are replayed
+fn withdraw_available(users: &mut Users, u: &Withdraw) -> Result<(), MatchingEngineErrors> {
+    let available = users.available_balance(&u.asset, u.user_id)?;
+    if available < u.quantity {
+        return Err(MatchingEngineErrors::OverWithdrawl);
+    }
+    users.withdraw(&u.asset, u.quantity, u.user_id).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{ Arc, Mutex };
+
+    use common::ids;
+    use rust_decimal_macros::dec;
+
+    use crate::{
+        handle_order_request::EngineRequests,
+        journal,
+        matching_engine::events::NoEvents,
+        orderbook::Orderbook,
+        Exchange,
+        OrderSide,
+        OrderStatus,
+        OrderType,
+        RecievedOrder,
+    };
+    use super::*;
+
+    fn entry(seq: u64, request: &UserRequests) -> JournalEntry {
+        JournalEntry { seq, stamp: seq, payload: to_string(request).unwrap().into_bytes() }
+    }
+
+    #[test]
+    fn replayed_users_keep_their_ids() {
+        let mut live = Users::default();
+        let mut requests = [
+            UserRequests::NewUser(NewUser { sub_id: 1, id: None }),
+            UserRequests::NewUser(NewUser { sub_id: 2, id: None })
+        ];
+        for request in requests.iter_mut() {
+            request.assign_ids(&mut live);
+        }
+        // a journaled user that was never persisted to scylla
+        let entries: Vec<JournalEntry> = requests
+            .iter()
+            .enumerate()
+            .map(|(index, request)| entry((index as u64) + 1, request))
+            .collect();
+        let mut recovered = Users::default();
+        recovered.new_user(1);
+        UserRequests::recover_last_ids(&mut recovered, &entries);
+        assert_eq!(recovered.next_user_id(), 3);
+
+        let mut replayed = Users::default();
+        UserRequests::replay_journal(&mut replayed, entries);
+        let mut user_ids: Vec<Id> = replayed.users.keys().copied().collect();
+        user_ids.sort();
+        assert_eq!(user_ids, vec![1, 2]);
+        assert_eq!(replayed.next_user_id(), live.next_user_id());
+    }
+    #[test]
+    fn replayed_withdrawals_end_in_the_same_state() {
+        let mut requests = [
+            UserRequests::NewUser(NewUser { sub_id: 1, id: None }),
+            UserRequests::Deposit(Deposit {
+                user_id: 1,
+                asset: Asset::BTC,
+                quantity: dec!(1),
+                sub_id: 2,
+                id: 0,
+            }),
+            UserRequests::Withdraw(Withdraw {
+                user_id: 1,
+                asset: Asset::BTC,
+                quantity: dec!(0.5),
+                destination: "btc-address".to_string(),
+                sub_id: 3,
+                id: None,
+                timestamp: 0,
+            }),
+            UserRequests::UpdateWithdrawal(UpdateWithdrawal {
+                id: ids::compose(ids::WITHDRAWALS_NAMESPACE, 1),
+                action: WithdrawalAction::Approve,
+                sub_id: 4,
+                timestamp: 0,
+            })
+        ];
+        let mut live = Users::default();
+        for request in requests.iter_mut() {
+            request.assign_ids(&mut live);
+        }
+        let entries: Vec<JournalEntry> = requests
+            .iter()
+            .enumerate()
+            .map(|(index, request)| entry((index as u64) + 1, request))
+            .collect();
+        let mut replayed = Users::default();
+        UserRequests::replay_journal(&mut replayed, entries);
+        let id = ids::compose(ids::WITHDRAWALS_NAMESPACE, 1);
+        let withdrawal = replayed.withdrawals.withdrawals.get(&id).unwrap();
+        assert_eq!(withdrawal.state, WithdrawalState::Approved);
+        assert_eq!(replayed.locked_balance(&Asset::BTC, 1).unwrap(), &dec!(0.5));
+        assert_eq!(replayed.withdrawals.next_withdrawal_id(), id + 1);
+    }
+    // The market locks the order's funds, the users thread then journals and rejects a
+    // withdrawal, and only then is the order journaled. Replay must lock before it withdraws.
+    #[test]
+    fn replays_a_withdrawal_after_the_order_it_raced() {
+        let dir = std::env::temp_dir().join(format!("velocity-journal-{}", uuid::Uuid::new_v4()));
+        let mut users_journal = Journal::open(&dir.join("users.journal")).unwrap();
+        let mut market_journal = Journal::open(&dir.join("SOL_USDT.journal")).unwrap();
+        let funded = || {
+            let mut users = Users::default();
+            users.new_user(1);
+            users.deposit(&Asset::USDT, dec!(100), 1).unwrap();
+            Arc::new(Mutex::new(users))
+        };
+        let exchange = Exchange::new(Asset::SOL, Asset::USDT);
+        let order = RecievedOrder {
+            id: ids::compose(exchange.id_namespace(), 1) as i64,
+            user_id: 1,
+            symbol: exchange.symbol.clone(),
+            price: dec!(60),
+            initial_quantity: dec!(1),
+            filled_quantity: dec!(0),
+            quote_quantity: dec!(60),
+            filled_quote_quantity: dec!(0),
+            order_type: OrderType::Limit,
+            order_side: OrderSide::Bid,
+            order_status: OrderStatus::InProgress,
+            timestamp: 1,
+        };
+        let live = funded();
+        let mut orderbook = Orderbook::new(exchange.clone());
+        orderbook.attach(live.clone(), Arc::new(NoEvents));
+        let (_, _, locked) = orderbook.lock_order(&order).unwrap();
+        {
+            let mut users = live.lock().unwrap();
+            let mut withdraw = UserRequests::Withdraw(Withdraw {
+                user_id: 1,
+                asset: Asset::USDT,
+                quantity: dec!(50),
+                destination: "usdt-address".to_string(),
+                sub_id: 1,
+                id: None,
+                timestamp: 0,
+            });
+            withdraw.assign_ids(&mut users);
+            withdraw.journal(&mut users_journal).unwrap();
+            assert!(UserRequests::replay(&mut users, withdraw).is_err());
+        }
+        let request = to_string(&EngineRequests::ExecuteOrder(order)).unwrap();
+        market_journal.append_at(locked.journal_stamp, request.as_bytes()).unwrap();
+
+        let replayed = funded();
+        let mut orderbook = Orderbook::new(exchange.clone());
+        orderbook.attach(replayed.clone(), Arc::new(NoEvents));
+        let merged = journal::merge(
+            vec![
+                ("users".to_string(), Journal::read(&dir.join("users.journal")).unwrap()),
+                (exchange.symbol.clone(), Journal::read(&dir.join("SOL_USDT.journal")).unwrap())
+            ]
+        );
+        for (name, entry) in merged {
+            match name == exchange.symbol {
+                true => orderbook.replay_journal(vec![entry], true),
+                false => UserRequests::replay_journal(&mut replayed.lock().unwrap(), vec![entry]),
+            }
+        }
+        let (live, replayed) = (live.lock().unwrap(), replayed.lock().unwrap());
+        assert_eq!(replayed.balance(&Asset::USDT, 1).unwrap(), &dec!(100));
+        assert_eq!(replayed.balance(&Asset::USDT, 1), live.balance(&Asset::USDT, 1));
+        assert_eq!(replayed.locked_balance(&Asset::USDT, 1).unwrap(), &dec!(60));
+        assert_eq!(replayed.locked_balance(&Asset::USDT, 1), live.locked_balance(&Asset::USDT, 1));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 }
@@ -0,0 +1,257 @@
+use std::{
+    fs::{ self, File, OpenOptions },
+    io::{ self, ErrorKind, Read, Seek, SeekFrom, Write },
+    path::{ Path, PathBuf },
+    sync::atomic::{ AtomicU64, Ordering },
+};
+
+use crc32fast::Hasher;
+
+pub const JOURNAL_DIR: &str = "journal";
+// Deposits, withdrawals and new users share one journal next to the market ones
+pub const USERS_JOURNAL: &str = "users";
+// seq (u64) + stamp (u64) + payload length (u32) + crc32 of seq, stamp and payload (u32)
+const HEADER_LEN: usize = 24;
+// Last stamp handed out by any journal of this process. Every scan moves it past the stamps
+// already on disk, recovery reads all journals before the threads append to them.
+static STAMP: AtomicU64 = AtomicU64::new(0);
+
+// `seq` orders the records of one journal, `stamp` orders records across all journals so the
+// users journal and the market journals can be replayed interleaved the way they were written
+#[derive(Debug, Clone, PartialEq)]
+pub struct JournalEntry {
+    pub seq: u64,
+    pub stamp: u64,
+    pub payload: Vec<u8>,
+}
+
+// Append-only log of the requests a market thread accepted, written and fsync'd before the
+// request touches the orderbook so recovery can replay them in exactly the same order.
+pub struct Journal {
+    file: File,
+    next_seq: u64,
+}
+
+impl Journal {
+    pub fn path(symbol: &str) -> PathBuf {
+        Path::new(JOURNAL_DIR).join(format!("{}.journal", symbol))
+    }
+    // Opens the journal for appending, dropping a record that was only partially written
+    pub fn open(path: &Path) -> io::Result<Journal> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+        let (entries, valid_len) = Journal::scan(&mut file)?;
+        if valid_len < file.metadata()?.len() {
+            file.set_len(valid_len)?;
+            file.sync_all()?;
+        }
+        let next_seq = entries.last().map_or(1, |entry| entry.seq + 1);
+        Ok(Journal { file, next_seq })
+    }
+    pub fn read(path: &Path) -> io::Result<Vec<JournalEntry>> {
+        match File::open(path) {
+            Ok(mut file) => Ok(Journal::scan(&mut file)?.0),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+            Err(err) => Err(err),
+        }
+    }
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+    // Returns the sequence number of the record once it is durable on disk
+    pub fn append(&mut self, payload: &[u8]) -> io::Result<u64> {
+        self.append_at(next_stamp(), payload)
+    }
+    // For a request that changed balances before it was journaled, with the stamp taken under
+    // the lock it changed them in
+    pub fn append_at(&mut self, stamp: u64, payload: &[u8]) -> io::Result<u64> {
+        let seq = self.next_seq;
+        let mut record = Vec::with_capacity(HEADER_LEN + payload.len());
+        record.extend_from_slice(&seq.to_le_bytes());
+        record.extend_from_slice(&stamp.to_le_bytes());
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&checksum(seq, stamp, payload).to_le_bytes());
+        record.extend_from_slice(payload);
+        self.file.write_all(&record)?;
+        self.file.sync_data()?;
+        self.next_seq += 1;
+        Ok(seq)
+    }
+    // A record cut short at the end of the file was never acknowledged, so it is ignored.
+    // A complete record with a bad checksum or an out of order sequence means the journal
+    // can't be trusted and recovery must stop.
+    fn scan(file: &mut File) -> io::Result<(Vec<JournalEntry>, u64)> {
+        let mut bytes = Vec::new();
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_end(&mut bytes)?;
+        let mut entries: Vec<JournalEntry> = Vec::new();
+        let mut offset = 0;
+        while bytes.len() - offset >= HEADER_LEN {
+            let header = &bytes[offset..offset + HEADER_LEN];
+            let seq = u64::from_le_bytes(header[0..8].try_into().unwrap());
+            let stamp = u64::from_le_bytes(header[8..16].try_into().unwrap());
+            let len = u32::from_le_bytes(header[16..20].try_into().unwrap()) as usize;
+            let crc = u32::from_le_bytes(header[20..24].try_into().unwrap());
+            let start = offset + HEADER_LEN;
+            if bytes.len() - start < len {
+                break;
+            }
+            let payload = &bytes[start..start + len];
+            if checksum(seq, stamp, payload) != crc {
+                return Err(
+                    io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Journal record {} is corrupted", seq)
+                    )
+                );
+            }
+            let expected_seq = entries.last().map_or(1, |entry| entry.seq + 1);
+            if seq != expected_seq {
+                return Err(
+                    io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Journal record {} found where {} was expected", seq, expected_seq)
+                    )
+                );
+            }
+            STAMP.fetch_max(stamp, Ordering::SeqCst);
+            entries.push(JournalEntry { seq, stamp, payload: payload.to_vec() });
+            offset = start + len;
+        }
+        Ok((entries, offset as u64))
+    }
+}
+
+// Stamps follow the order requests were applied to the users, the users thread journals while
+// it holds their lock and the markets take the stamp of an order when its funds are locked
+pub fn next_stamp() -> u64 {
+    STAMP.fetch_add(1, Ordering::SeqCst) + 1
+}
+
+// Puts the records of several journals back in the order they were written, each one with
+// the name of the journal it came from
+pub fn merge(journals: Vec<(String, Vec<JournalEntry>)>) -> Vec<(String, JournalEntry)> {
+    let mut merged: Vec<(String, JournalEntry)> = journals
+        .into_iter()
+        .flat_map(|(name, entries)| entries.into_iter().map(move |entry| (name.clone(), entry)))
+        .collect();
+    merged.sort_by_key(|(_, entry)| entry.stamp);
+    merged
+}
+
+fn checksum(seq: u64, stamp: u64, payload: &[u8]) -> u32 {
+    let mut hasher = Hasher::new();
+    hasher.update(&seq.to_le_bytes());
+    hasher.update(&stamp.to_le_bytes());
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{ self, OpenOptions };
+    use std::io::{ ErrorKind, Write };
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn temp_journal() -> PathBuf {
+        std::env
+            ::temp_dir()
+            .join(format!("velocity-journal-{}", uuid::Uuid::new_v4()))
+            .join("SOL_USDT.journal")
+    }
+
+    #[test]
+    fn appends_and_reads_back_in_order() {
+        let path = temp_journal();
+        let mut journal = Journal::open(&path).unwrap();
+        assert_eq!(journal.append(b"first").unwrap(), 1);
+        assert_eq!(journal.append(b"second").unwrap(), 2);
+        drop(journal);
+
+        let entries = Journal::read(&path).unwrap();
+        let read: Vec<_> = entries.iter().map(|entry| (entry.seq, entry.payload.clone())).collect();
+        assert_eq!(read, vec![(1, b"first".to_vec()), (2, b"second".to_vec())]);
+        assert!(entries[0].stamp < entries[1].stamp);
+        let journal = Journal::open(&path).unwrap();
+        assert_eq!(journal.next_seq(), 3);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+    #[test]
+    fn drops_partially_written_tail() {
+        let path = temp_journal();
+        let mut journal = Journal::open(&path).unwrap();
+        journal.append(b"complete").unwrap();
+        drop(journal);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&2u64.to_le_bytes()).unwrap();
+        file.write_all(&9u64.to_le_bytes()).unwrap();
+        file.write_all(&100u32.to_le_bytes()).unwrap();
+        drop(file);
+
+        assert_eq!(Journal::read(&path).unwrap().len(), 1);
+        let mut journal = Journal::open(&path).unwrap();
+        assert_eq!(journal.append(b"after crash").unwrap(), 2);
+        assert_eq!(Journal::read(&path).unwrap()[1].payload, b"after crash".to_vec());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+    #[test]
+    fn refuses_corrupted_records() {
+        let path = temp_journal();
+        let mut journal = Journal::open(&path).unwrap();
+        journal.append(b"untouched").unwrap();
+        journal.append(b"flipped").unwrap();
+        drop(journal);
+        let mut bytes = fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        fs::write(&path, bytes).unwrap();
+
+        assert_eq!(Journal::read(&path).unwrap_err().kind(), ErrorKind::InvalidData);
+        assert!(Journal::open(&path).is_err());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+    #[test]
+    fn merges_journals_in_the_order_they_were_written() {
+        let (users_path, market_path) = (temp_journal(), temp_journal());
+        let mut users = Journal::open(&users_path).unwrap();
+        let mut market = Journal::open(&market_path).unwrap();
+        users.append(b"deposit").unwrap();
+        market.append(b"order").unwrap();
+        users.append(b"withdraw").unwrap();
+        market.append(b"cancel").unwrap();
+
+        let merged = merge(
+            vec![
+                ("users".to_string(), Journal::read(&users_path).unwrap()),
+                ("SOL_USDT".to_string(), Journal::read(&market_path).unwrap())
+            ]
+        );
+        let order: Vec<_> = merged
+            .iter()
+            .map(|(name, entry)| (name.as_str(), entry.payload.as_slice()))
+            .collect();
+        assert_eq!(order, vec![
+            ("users", b"deposit".as_slice()),
+            ("SOL_USDT", b"order".as_slice()),
+            ("users", b"withdraw".as_slice()),
+            ("SOL_USDT", b"cancel".as_slice())
+        ]);
+        // a reopened journal keeps stamping after what is already on disk
+        drop(users);
+        let last = merged.last().unwrap().1.stamp;
+        let mut users = Journal::open(&users_path).unwrap();
+        users.append(b"after restart").unwrap();
+        assert!(Journal::read(&users_path).unwrap()[2].stamp > last);
+        for path in [users_path, market_path] {
+            fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        }
+    }
+    #[test]
+    fn missing_journal_is_empty() {
+        assert!(Journal::read(&temp_journal()).unwrap().is_empty());
+    }
+}
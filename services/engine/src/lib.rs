@@ -7,14 +7,36 @@ use std::{
     rc::Rc,
     sync::{ mpsc::Sender, Arc, Mutex },
     thread,
-    time::Instant,
+    time::{ Duration, Instant },
 };
 
 use actix_web::web;
+use common::{ events::{ self, Depth, Event, Ticker }, ledger::LedgerEntry, numeric::Numeric };
 use engine::MatchingEngine;
 use handle_order_request::{ CancelOrder, EngineRequests };
-use handle_user_requests::UserRequests;
+use handle_user_requests::{
+    AccrueInterest,
+    ChargeFunding,
+    ReportTransfer,
+    SnapshotPortfolios,
+    UserRequests,
+};
+use journal::{ Journal, USERS_JOURNAL };
 use matching_engine::*;
+use matching_engine::{
+    balances::{ BalanceStore, Locked },
+    error::MatchingEngineErrors,
+    events::{ EventSink, TradeEvents },
+    ledger::persist_ledger,
+    withdrawal::{ persist_withdrawal, DailyTotal, Withdrawal },
+    custody::{ persist_deposit, CustodyAdapter, CustodyDeposit, CustodyWatcher },
+    accounts::{ persist_sub_account, persist_transfer, InternalTransfer },
+    admin::{ persist_adjustment, Adjustment },
+    margin::persist_margin,
+    perpetual::{ persist_positions, PerpFill, PerpLeg },
+    portfolio::{ persist_portfolio, Valuation },
+    risk::Exposure,
+};
 use once_cell::sync::Lazy;
 use orderbook::Orderbook;
 use redis::{ Connection, Value };
@@ -22,6 +44,7 @@ use rust_decimal::Decimal;
 use scylla::{ Session, SessionBuilder };
 use serde::{ Deserialize, Serialize };
 use serde_json::{ from_str, to_string };
+use snapshot::CHECKPOINTER;
 use tokio::{
     runtime::{ Builder, Runtime },
     sync::mpsc::{ self, UnboundedReceiver, UnboundedSender },
@@ -30,6 +53,13 @@ use tokio::{
 pub mod matching_engine;
 pub mod handle_order_request;
 pub mod handle_user_requests;
+pub mod journal;
+pub mod liquidation;
+pub mod replay;
+#[cfg(test)]
+mod scenario;
+pub mod recovery;
+pub mod snapshot;
 pub struct AppState {
     pub matching_engine: Mutex<MatchingEngine>,
 }
@@ -45,16 +75,69 @@ pub fn connect_redis(url: &str) -> Connection {
 pub fn process_user_request() -> impl Fn() {
     || {
         let mut con = connect_redis("redis://127.0.0.1:6379");
+        let mut journal = Journal::open(&Journal::path(USERS_JOURNAL)).expect(
+            "Could not open users journal"
+        );
+        let (tx, rx) = mpsc::unbounded_channel::<PersistOrderRequest>();
+        thread::spawn(persist_requests(rx));
         loop {
+            if CHECKPOINTER.is_requested() {
+                CHECKPOINTER.snapshot_users(&journal);
+            }
             let result = redis::cmd("RPOP").arg("queues:user").query::<String>(&mut con);
             if let Ok(req_str) = result {
-                if let Ok(request) = from_str::<UserRequests>(&req_str) {
+                if let Ok(mut request) = from_str::<UserRequests>(&req_str) {
                     let mut users = USERS.lock().unwrap();
+                    request.assign_ids(&mut users);
+                    if let Err(err) = request.journal(&mut journal) {
+                        if let Some(sub_id) = request.sub_id() {
+                            redis
+                                ::cmd("LPUSH")
+                                .arg(sub_id)
+                                .arg(err.to_string())
+                                .query::<Value>(&mut con)
+                                .unwrap();
+                        }
+                        continue;
+                    }
                     match request {
                         UserRequests::NewUser(u) => UserRequests::new_user(&mut users, u, &mut con),
-                        UserRequests::Deposit(u) => UserRequests::deposit(&mut users, u, &mut con),
+                        UserRequests::Deposit(u) =>
+                            UserRequests::deposit(&mut users, u, &mut con, &tx),
                         UserRequests::Withdraw(u) =>
-                            UserRequests::withdraw(&mut users, u, &mut con),
+                            UserRequests::withdraw(&mut users, u, &mut con, &tx),
+                        UserRequests::UpdateWithdrawal(u) =>
+                            UserRequests::update_withdrawal(&mut users, u, &mut con, &tx),
+                        UserRequests::ReportTransfer(u) =>
+                            UserRequests::report_transfer(&mut users, u, &mut con, &tx),
+                        UserRequests::NewSubAccount(u) =>
+                            UserRequests::new_sub_account(&mut users, u, &mut con, &tx),
+                        UserRequests::Transfer(u) =>
+                            UserRequests::transfer(&mut users, u, &mut con, &tx),
+                        UserRequests::AdjustBalance(u) =>
+                            UserRequests::adjust_balance(&mut users, u, &mut con, &tx),
+                        UserRequests::FreezeAccount(u) =>
+                            UserRequests::freeze_account(&mut users, u, &mut con),
+                        UserRequests::SetRiskLimits(u) =>
+                            UserRequests::set_risk_limits(&mut users, u, &mut con),
+                        UserRequests::GetRiskLimits(u) =>
+                            UserRequests::get_risk_limits(&mut users, u, &mut con),
+                        UserRequests::SetMarginMode(u) =>
+                            UserRequests::set_margin_mode(&mut users, u, &mut con, &tx),
+                        UserRequests::Borrow(u) => UserRequests::borrow(&mut users, u, &mut con, &tx),
+                        UserRequests::Repay(u) => UserRequests::repay(&mut users, u, &mut con, &tx),
+                        UserRequests::AccrueInterest(u) =>
+                            UserRequests::accrue_interest(&mut users, u, &tx),
+                        UserRequests::GetMarginAccount(u) =>
+                            UserRequests::get_margin_account(&mut users, u, &mut con),
+                        UserRequests::ChargeFunding(u) =>
+                            UserRequests::charge_funding(&mut users, u, &tx),
+                        UserRequests::GetPositions(u) =>
+                            UserRequests::get_positions(&mut users, u, &mut con),
+                        UserRequests::GetPortfolio(u) =>
+                            UserRequests::get_portfolio(&mut users, u, &mut con),
+                        UserRequests::SnapshotPortfolios(u) =>
+                            UserRequests::snapshot_portfolios(&mut users, u, &tx),
                         UserRequests::GetUserBalances(u) =>
                             UserRequests::get_user_balances(&mut users, u, &mut con),
                     }
@@ -63,18 +146,112 @@ pub fn process_user_request() -> impl Fn() {
         }
     }
 }
+pub const CUSTODY_POLL_INTERVAL: Duration = Duration::from_secs(5);
+// Hands the transfers custody reports to the user thread, which credits them once confirmed
+pub fn watch_custody<A: CustodyAdapter>(adapter: A, interval: Duration) -> impl FnMut() {
+    let mut watcher = CustodyWatcher::new(adapter);
+    move || {
+        let mut con = connect_redis("redis://127.0.0.1:6379");
+        loop {
+            for transfer in watcher.poll() {
+                let request = UserRequests::ReportTransfer(ReportTransfer::new(transfer));
+                redis
+                    ::cmd("LPUSH")
+                    .arg("queues:user")
+                    .arg(to_string(&request).unwrap())
+                    .query::<Value>(&mut con)
+                    .unwrap();
+            }
+            thread::sleep(interval);
+        }
+    }
+}
+pub const INTEREST_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+// Asks the user thread to charge interest whenever a new hour starts, it charges each hour once
+pub fn charge_interest(interval: Duration) -> impl FnMut() {
+    move || {
+        let mut con = connect_redis("redis://127.0.0.1:6379");
+        let mut last_hour = None;
+        loop {
+            let hour = ledger::now() / margin::HOUR;
+            if last_hour != Some(hour) {
+                let request = UserRequests::AccrueInterest(AccrueInterest::default());
+                redis
+                    ::cmd("LPUSH")
+                    .arg("queues:user")
+                    .arg(to_string(&request).unwrap())
+                    .query::<Value>(&mut con)
+                    .unwrap();
+                last_hour = Some(hour);
+            }
+            thread::sleep(interval);
+        }
+    }
+}
+pub const FUNDING_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+// Same as interest, every funding interval is asked for once and paid once
+pub fn charge_funding(interval: Duration) -> impl FnMut() {
+    move || {
+        let mut con = connect_redis("redis://127.0.0.1:6379");
+        let mut last_interval = None;
+        loop {
+            let funding_interval = ledger::now() / perpetual::FUNDING_INTERVAL;
+            if last_interval != Some(funding_interval) {
+                let request = UserRequests::ChargeFunding(ChargeFunding::default());
+                redis
+                    ::cmd("LPUSH")
+                    .arg("queues:user")
+                    .arg(to_string(&request).unwrap())
+                    .query::<Value>(&mut con)
+                    .unwrap();
+                last_interval = Some(funding_interval);
+            }
+            thread::sleep(interval);
+        }
+    }
+}
+pub const PORTFOLIO_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+// Accounts are valued once a day, the first check of a day asks for that day's snapshot
+pub fn snapshot_portfolios(interval: Duration) -> impl FnMut() {
+    move || {
+        let mut con = connect_redis("redis://127.0.0.1:6379");
+        let mut last_day = None;
+        loop {
+            let day = ledger::now() / portfolio::DAY;
+            if last_day != Some(day) {
+                let request = UserRequests::SnapshotPortfolios(SnapshotPortfolios::default());
+                redis
+                    ::cmd("LPUSH")
+                    .arg("queues:user")
+                    .arg(to_string(&request).unwrap())
+                    .query::<Value>(&mut con)
+                    .unwrap();
+                last_day = Some(day);
+            }
+            thread::sleep(interval);
+        }
+    }
+}
 pub fn process_order(mut orderbook: Orderbook) -> impl FnMut() {
     move || {
         let mut con = connect_redis("redis://127.0.0.1:6379");
         println!("OS Thread Created For {}", orderbook.exchange.symbol);
+        let mut journal = Journal::open(&Journal::path(&orderbook.exchange.symbol)).expect(
+            "Could not open journal"
+        );
         let (tx, mut rx) = mpsc::unbounded_channel::<PersistOrderRequest>();
         thread::spawn(persist_requests(rx));
         let (event_tx, mut event_rx) = mpsc::unbounded_channel::<Vec<RedisEmit>>();
         thread::spawn(event_emitter(event_rx));
+        orderbook.attach(Arc::new(GlobalUsers), Arc::new(RedisEvents { event_tx }));
+        orderbook.publish_open_notional();
+        orderbook.publish_mark_price();
         loop {
+            if CHECKPOINTER.is_requested() {
+                CHECKPOINTER.snapshot_orderbook(&orderbook, &journal);
+            }
             let start = Instant::now();
             let tx = tx.clone();
-            let event_tx = event_tx.clone();
             let result = redis
                 ::cmd("RPOP")
                 .arg(format!("queues:{}", orderbook.exchange.symbol))
@@ -89,7 +266,18 @@ pub fn process_order(mut orderbook: Orderbook) -> impl FnMut() {
                                 &mut orderbook,
                                 &mut con,
                                 tx,
-                                event_tx
+                                &mut journal,
+                                false
+                            ),
+                        EngineRequests::Liquidate(recieved_order) =>
+                            EngineRequests::execute_order(
+                                start,
+                                recieved_order,
+                                &mut orderbook,
+                                &mut con,
+                                tx,
+                                &mut journal,
+                                true
                             ),
                         EngineRequests::CancelOrder(c_order) =>
                             EngineRequests::cancel_order(
@@ -97,7 +285,8 @@ pub fn process_order(mut orderbook: Orderbook) -> impl FnMut() {
                                 c_order,
                                 &mut orderbook,
                                 &mut con,
-                                tx
+                                tx,
+                                &mut journal
                             ),
                         EngineRequests::CancelAll(c_all) =>
                             EngineRequests::cancel_all_order(
@@ -105,12 +294,38 @@ pub fn process_order(mut orderbook: Orderbook) -> impl FnMut() {
                                 c_all,
                                 &mut orderbook,
                                 &mut con,
-                                tx
+                                tx,
+                                &mut journal
                             ),
                         EngineRequests::OpenOrders(o_orders) =>
                             EngineRequests::open_orders(start, o_orders, &mut orderbook, &mut con),
                         EngineRequests::OpenOrder(o_order) =>
                             EngineRequests::open_order(start, o_order, &mut orderbook, &mut con),
+                        EngineRequests::Halt(control) =>
+                            EngineRequests::set_halted(
+                                control,
+                                true,
+                                &mut orderbook,
+                                &mut con,
+                                &mut journal
+                            ),
+                        EngineRequests::Resume(control) =>
+                            EngineRequests::set_halted(
+                                control,
+                                false,
+                                &mut orderbook,
+                                &mut con,
+                                &mut journal
+                            ),
+                        EngineRequests::MarketState(control) =>
+                            EngineRequests::market_state(control, &orderbook, &mut con),
+                        EngineRequests::SetPriceBand(price_band) =>
+                            EngineRequests::set_price_band(
+                                price_band,
+                                &mut orderbook,
+                                &mut con,
+                                &mut journal
+                            ),
                     }
                 }
             }
@@ -122,7 +337,126 @@ pub struct RedisEmit {
     arg_1: String,
     arg_2: String,
 }
+impl RedisEmit {
+    pub fn publish<E: Event>(event: &E) -> RedisEmit {
+        let (channel, payload) = events::encode(event);
+        RedisEmit {
+            cmd: "PUBLISH".to_string(),
+            arg_1: channel,
+            arg_2: payload,
+        }
+    }
+}
 pub type EventTranmitter = UnboundedSender<Vec<RedisEmit>>;
+// Forwards the events of every trade to the emitter thread of the market
+#[derive(Debug)]
+pub struct RedisEvents {
+    pub event_tx: EventTranmitter,
+}
+impl EventSink for RedisEvents {
+    fn trade(&self, events: TradeEvents) {
+        self.event_tx.send(
+            vec![
+                RedisEmit::publish(&events.order_update),
+                RedisEmit::publish(&events.client_order_update),
+                RedisEmit::publish(&events.trade),
+                RedisEmit {
+                    cmd: "LPUSH".to_string(),
+                    arg_1: "filler".to_string(),
+                    arg_2: to_string(&events.filler).unwrap(),
+                }
+            ]
+        );
+    }
+    fn depth(&self, depth: Depth) {
+        self.event_tx.send(vec![RedisEmit::publish(&depth)]);
+    }
+    fn ticker(&self, ticker: Ticker) {
+        self.event_tx.send(vec![RedisEmit::publish(&ticker)]);
+    }
+}
+// Settles the trades of every market against the balances the user thread also works on
+#[derive(Debug)]
+pub struct GlobalUsers;
+impl BalanceStore for GlobalUsers {
+    fn lock_amount(&self, asset: &Asset, user_id: Id, quantity: Quantity) {
+        USERS.lock_amount(asset, user_id, quantity)
+    }
+    fn validate_and_lock(
+        &self,
+        asset: &Asset,
+        user_id: Id,
+        quantity: Quantity
+    ) -> Result<Locked, MatchingEngineErrors> {
+        USERS.validate_and_lock(asset, user_id, quantity)
+    }
+    fn lock_available(
+        &self,
+        asset: &Asset,
+        user_id: Id,
+        quantity: Quantity
+    ) -> Result<Locked, MatchingEngineErrors> {
+        USERS.lock_available(asset, user_id, quantity)
+    }
+    fn unlock_amount(&self, asset: &Asset, user_id: Id, quantity: Quantity) -> Locked {
+        USERS.unlock_amount(asset, user_id, quantity)
+    }
+    fn locked_balances(&self, user_id: Id) -> (HashMap<Asset, Quantity>, i64) {
+        USERS.locked_balances(user_id)
+    }
+    fn settle_trade(
+        &self,
+        exchange: &Exchange,
+        quantity: Quantity,
+        exchange_price: Price,
+        released: Quantity,
+        seller_id: Id,
+        buyer_id: Id
+    ) -> PostUsers {
+        USERS.settle_trade(exchange, quantity, exchange_price, released, seller_id, buyer_id)
+    }
+    fn check_risk(
+        &self,
+        user_id: Id,
+        exchange: &Exchange,
+        exposure: Exposure,
+        notional: Quantity,
+        timestamp: u64
+    ) -> Result<(), MatchingEngineErrors> {
+        USERS.check_risk(user_id, exchange, exposure, notional, timestamp)
+    }
+    fn set_open_notional(&self, exchange: &Exchange, notional: HashMap<Id, Quantity>) {
+        USERS.set_open_notional(exchange, notional)
+    }
+    fn set_mark_price(&self, asset: &Asset, price: Price) {
+        USERS.set_mark_price(asset, price)
+    }
+    fn reducible(
+        &self,
+        user_id: Id,
+        symbol: &Symbol,
+        order_side: &OrderSide,
+        quantity: Quantity
+    ) -> Quantity {
+        USERS.reducible(user_id, symbol, order_side, quantity)
+    }
+    fn settle_perp_trade(
+        &self,
+        exchange: &Exchange,
+        quantity: Quantity,
+        exchange_price: Price,
+        seller: &PerpFill,
+        buyer: &PerpFill
+    ) -> (PostUsers, Vec<PerpLeg>) {
+        USERS.settle_perp_trade(exchange, quantity, exchange_price, seller, buyer)
+    }
+    fn set_perp_price(&self, symbol: &Symbol, price: Price) {
+        USERS.set_perp_price(symbol, price)
+    }
+    fn take_moved_positions(&self) -> Vec<PersistPositions> {
+        USERS.take_moved_positions()
+    }
+}
 pub fn event_emitter(mut rx: UnboundedReceiver<Vec<RedisEmit>>) -> impl FnMut() {
     move || {
         let mut con = connect_redis("redis://127.0.0.1:6379");
@@ -150,6 +484,7 @@ pub fn persist_requests(mut rx: UnboundedReceiver<PersistOrderRequest>) -> impl
             loop {
                 if let Some(order) = rx.recv().await {
                     tokio::spawn(async move {
+                        let entries = order.ledger();
                         match order {
                             PersistOrderRequest::Save(s_order) =>
                                 new_order(
@@ -162,7 +497,27 @@ pub fn persist_requests(mut rx: UnboundedReceiver<PersistOrderRequest>) -> impl
                                 persist_order_cancel(&SESSION, c_order).await,
                             PersistOrderRequest::CancelAll(c_all) =>
                                 persist_order_cancel_all(&SESSION, c_all).await,
+                            PersistOrderRequest::Unlock(unlock) =>
+                                persist_unlock(&SESSION, unlock).await,
+                            PersistOrderRequest::Withdrawal(persist) =>
+                                persist_withdrawal(&SESSION, persist).await,
+                            PersistOrderRequest::Deposit(persist) =>
+                                persist_deposit(&SESSION, persist).await,
+                            PersistOrderRequest::SubAccount(persist) =>
+                                persist_sub_account(&SESSION, persist).await,
+                            PersistOrderRequest::Transfer(persist) =>
+                                persist_transfer(&SESSION, persist).await,
+                            PersistOrderRequest::Adjustment(persist) =>
+                                persist_adjustment(&SESSION, persist).await,
+                            PersistOrderRequest::Margin(persist) =>
+                                persist_margin(&SESSION, persist).await,
+                            PersistOrderRequest::Positions(persist) =>
+                                persist_positions(&SESSION, persist).await,
+                            PersistOrderRequest::Portfolio(persist) =>
+                                persist_portfolio(&SESSION, persist).await,
+                            PersistOrderRequest::Ledger(_) => {}
                         }
+                        persist_ledger(&SESSION, entries).await;
                     });
                 }
             }
@@ -174,6 +529,37 @@ pub enum PersistOrderRequest {
     Save(SaveOrder),
     Cancel(PersistCancel),
     CancelAll(PersistCancelAll),
+    Unlock(PersistUnlock),
+    Withdrawal(PersistWithdrawal),
+    Deposit(PersistDeposit),
+    SubAccount(PersistSubAccount),
+    Transfer(PersistTransfer),
+    Adjustment(PersistAdjustment),
+    Margin(PersistMargin),
+    Positions(PersistPositions),
+    Portfolio(PersistPortfolio),
+    // Balance movements of requests that persist nothing else, like manual deposits
+    Ledger(Vec<LedgerEntry>),
+}
+impl PersistOrderRequest {
+    // Ledger entries are written once whatever they belong to is persisted
+    pub fn ledger(&self) -> Vec<LedgerEntry> {
+        match self {
+            PersistOrderRequest::Save(s_order) => s_order.ledger.clone(),
+            PersistOrderRequest::Cancel(c_order) => c_order.ledger.clone(),
+            PersistOrderRequest::CancelAll(c_all) => c_all.ledger.clone(),
+            PersistOrderRequest::Unlock(unlock) => unlock.ledger.clone(),
+            PersistOrderRequest::Withdrawal(persist) => persist.ledger.clone(),
+            PersistOrderRequest::Deposit(persist) => persist.ledger.clone(),
+            PersistOrderRequest::SubAccount(_) => Vec::new(),
+            PersistOrderRequest::Transfer(persist) => persist.ledger.clone(),
+            PersistOrderRequest::Adjustment(persist) => persist.ledger.clone(),
+            PersistOrderRequest::Margin(persist) => persist.ledger.clone(),
+            PersistOrderRequest::Positions(persist) => persist.ledger.clone(),
+            PersistOrderRequest::Portfolio(_) => Vec::new(),
+            PersistOrderRequest::Ledger(entries) => entries.clone(),
+        }
+    }
 }
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PersistCancel {
@@ -183,8 +569,79 @@ pub struct PersistCancel {
     pub price: Price,
     pub order_side: OrderSide,
     pub asset: Asset,
-    pub updated_locked_balance: Quantity,
+    pub updated_locked_balance: Locked,
     pub timestamp: i64,
+    pub ledger: Vec<LedgerEntry>,
+}
+// Lock released after an order was processed, like the unspent quote of a market bid
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PersistUnlock {
+    pub user_id: Id,
+    pub asset: Asset,
+    pub updated_locked_balance: Locked,
+    pub ledger: Vec<LedgerEntry>,
+}
+// A withdrawal moved on, with the balances of its user after the move
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PersistWithdrawal {
+    pub withdrawal: Withdrawal,
+    pub user: User,
+    // The user's total of the asset counted against the daily limit
+    pub daily: DailyTotal,
+    pub last_withdrawal_id: Id,
+    pub stamp: i64,
+    pub ledger: Vec<LedgerEntry>,
+}
+// A deposit the custody side reported, `updated_balance` is set once it is credited
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PersistDeposit {
+    pub deposit: CustodyDeposit,
+    pub updated_balance: Option<Quantity>,
+    pub stamp: i64,
+    pub ledger: Vec<LedgerEntry>,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PersistSubAccount {
+    pub master_id: Id,
+    pub sub_account: User,
+}
+// An internal transfer with the balances of both accounts after it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PersistTransfer {
+    pub transfer: InternalTransfer,
+    pub from_balance: Quantity,
+    pub to_balance: Quantity,
+    pub stamp: i64,
+    pub ledger: Vec<LedgerEntry>,
+}
+// A balance adjustment with the balance it left
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PersistAdjustment {
+    pub adjustment: Adjustment,
+    pub balance: Quantity,
+    pub stamp: i64,
+    pub ledger: Vec<LedgerEntry>,
+}
+// Debt of a margin account after a loan, repayment or interest, `asset` is the balance that moved
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PersistMargin {
+    pub user: User,
+    pub asset: Option<Asset>,
+    pub stamp: i64,
+    pub ledger: Vec<LedgerEntry>,
+}
+// Positions of an account after fills or funding moved them, the engine is their only writer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PersistPositions {
+    pub user: User,
+    pub stamp: i64,
+    pub ledger: Vec<LedgerEntry>,
+}
+// Every account's value on a day
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PersistPortfolio {
+    pub day: u64,
+    pub valuations: Vec<Valuation>,
 }
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct PersistCancelAll {
@@ -192,7 +649,10 @@ pub struct PersistCancelAll {
     symbol: Symbol,
     timestamp: i64,
     data: Vec<OrderCancelInfo>,
-    locked_balances: HashMap<String, String>,
+    locked_balances: HashMap<String, Numeric>,
+    // Balance stamp the locked balances were read at
+    stamp: i64,
+    ledger: Vec<LedgerEntry>,
 }
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct OrderCancelInfo {
@@ -202,7 +662,8 @@ pub struct OrderCancelInfo {
 }
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct SaveOrder {
-    pub locked_balance: Quantity,
+    pub locked_balance: Locked,
     pub asset: Asset,
     pub recieved_order: RecievedOrder,
+    pub ledger: Vec<LedgerEntry>,
 }
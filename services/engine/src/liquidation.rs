@@ -0,0 +1,130 @@
+use std::{ thread, time::{ Duration, Instant } };
+
+use redis::{ Connection, Value };
+use rust_decimal_macros::dec;
+use serde_json::to_string;
+
+use crate::{
+    connect_redis,
+    handle_order_request::{ CancelAll, EngineRequests },
+    handle_user_requests::{ LoanRequest, UserRequests },
+    ledger,
+    margin::LiquidationOrder,
+    Id,
+    OrderStatus,
+    OrderType,
+    RecievedOrder,
+    USERS,
+};
+
+pub const MARGIN_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const RESPONSE_POLL_INTERVAL: Duration = Duration::from_millis(1);
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Accounts below the maintenance level are closed out through the same queues as everyone
+// else, so every step is journaled by the thread that applies it. Resting orders are canceled
+// to free their locks, what can be repaid is, then the rest is bought or sold at market.
+// Perpetual positions are closed on their own, each in its market.
+pub fn monitor_margin(interval: Duration) -> impl FnMut() {
+    move || {
+        let mut con = connect_redis("redis://127.0.0.1:6379");
+        loop {
+            let accounts = USERS.lock().unwrap().below_maintenance();
+            for user_id in accounts {
+                println!("Liquidating margin account {}", user_id);
+                if let Err(err) = liquidate(&mut con, user_id) {
+                    eprintln!("Could not liquidate margin account {}: {}", user_id, err);
+                }
+            }
+            let positions = USERS.lock().unwrap().positions_below_maintenance();
+            for (user_id, order) in positions {
+                println!("Liquidating position of {} in {}", user_id, order.symbol);
+                if let Err(err) = liquidate_position(&mut con, user_id, &order) {
+                    eprintln!("Could not liquidate position of {}: {}", user_id, err);
+                }
+            }
+            thread::sleep(interval);
+        }
+    }
+}
+fn liquidate(con: &mut Connection, user_id: Id) -> Result<(), String> {
+    let markets = USERS.lock().unwrap().open_markets(user_id);
+    for symbol in markets {
+        let sub_id = new_sub_id();
+        let request = EngineRequests::CancelAll(CancelAll::new(user_id, symbol.clone(), sub_id));
+        let queue = format!("queues:{}", symbol);
+        request_and_wait(con, &queue, sub_id, &to_string(&request).unwrap())?;
+    }
+    repay_available(con, user_id)?;
+    let orders = USERS.lock().unwrap().liquidation_orders(user_id);
+    for order in orders {
+        let sub_id = new_sub_id();
+        let request = EngineRequests::Liquidate(market_order(user_id, &order, sub_id));
+        let queue = format!("queues:{}", order.symbol);
+        let response = request_and_wait(con, &queue, sub_id, &to_string(&request).unwrap())?;
+        println!("Liquidation order of {} in {}: {}", user_id, order.symbol, response);
+    }
+    repay_available(con, user_id)
+}
+fn liquidate_position(
+    con: &mut Connection,
+    user_id: Id,
+    order: &LiquidationOrder
+) -> Result<(), String> {
+    let queue = format!("queues:{}", order.symbol);
+    let sub_id = new_sub_id();
+    let request = EngineRequests::CancelAll(CancelAll::new(user_id, order.symbol.clone(), sub_id));
+    request_and_wait(con, &queue, sub_id, &to_string(&request).unwrap())?;
+    let sub_id = new_sub_id();
+    let request = EngineRequests::Liquidate(market_order(user_id, order, sub_id));
+    let response = request_and_wait(con, &queue, sub_id, &to_string(&request).unwrap())?;
+    println!("Liquidation order of {} in {}: {}", user_id, order.symbol, response);
+    Ok(())
+}
+fn repay_available(con: &mut Connection, user_id: Id) -> Result<(), String> {
+    let repayments = USERS.lock().unwrap().repayments(user_id);
+    for (asset, quantity) in repayments {
+        let sub_id = new_sub_id();
+        let request = UserRequests::Repay(LoanRequest::new(user_id, asset, quantity, sub_id));
+        request_and_wait(con, "queues:user", sub_id, &to_string(&request).unwrap())?;
+    }
+    Ok(())
+}
+fn market_order(user_id: Id, order: &LiquidationOrder, sub_id: i64) -> RecievedOrder {
+    RecievedOrder {
+        id: sub_id,
+        user_id: user_id as i64,
+        symbol: order.symbol.clone(),
+        price: dec!(0),
+        initial_quantity: order.quantity,
+        filled_quantity: dec!(0),
+        quote_quantity: dec!(0),
+        filled_quote_quantity: dec!(0),
+        order_type: OrderType::Market,
+        order_side: order.order_side.clone(),
+        order_status: OrderStatus::InProgress,
+        timestamp: ledger::now() as i64,
+    }
+}
+// Microseconds are unique enough here, the monitor only ever waits on one response at a time
+fn new_sub_id() -> i64 {
+    ledger::now() as i64
+}
+// A market that does not answer in time is left for the next round, the account is still below
+// maintenance then and whatever was applied meanwhile is read again
+fn request_and_wait(
+    con: &mut Connection,
+    queue: &str,
+    sub_id: i64,
+    request: &str
+) -> Result<String, String> {
+    redis::cmd("LPUSH").arg(queue).arg(request).query::<Value>(con).map_err(|err| err.to_string())?;
+    let deadline = Instant::now() + RESPONSE_TIMEOUT;
+    while Instant::now() < deadline {
+        if let Ok(response) = redis::cmd("RPOP").arg(sub_id).query::<String>(con) {
+            return Ok(response);
+        }
+        thread::sleep(RESPONSE_POLL_INTERVAL);
+    }
+    Err(format!("no response from {} within {:?}", queue, RESPONSE_TIMEOUT))
+}
@@ -5,12 +5,24 @@ use std::thread;
 use std::time::Instant;
 use actix_web::web;
 use engine::connect_redis;
+use engine::recovery;
 use engine::matching_engine::engine::MatchingEngine;
 use engine::matching_engine::new_order;
 use engine::matching_engine::Exchange;
 use engine::matching_engine::RegisteredSymbols;
 use engine::process_order;
 use engine::process_user_request;
+use engine::watch_custody;
+use engine::charge_interest;
+use engine::INTEREST_CHECK_INTERVAL;
+use engine::charge_funding;
+use engine::FUNDING_CHECK_INTERVAL;
+use engine::snapshot_portfolios;
+use engine::PORTFOLIO_CHECK_INTERVAL;
+use engine::liquidation::{ monitor_margin, MARGIN_CHECK_INTERVAL };
+use engine::CUSTODY_POLL_INTERVAL;
+use engine::matching_engine::custody::CustodyConfig;
+use engine::snapshot::{ CHECKPOINTER, SNAPSHOT_INTERVAL };
 use engine::AppState;
 use engine::TOKIO_RUNTIME;
 use once_cell::sync::Lazy;
@@ -26,7 +38,8 @@ fn main() {
     ).unwrap();
     let mut matching_engine = MatchingEngine::init();
     // Block and recover orderbooks on restart
-    TOKIO_RUNTIME.block_on(matching_engine.recover_all_orderbooks(&session));
+    TOKIO_RUNTIME.block_on(recovery::recover_all_orderbooks(&mut matching_engine, &session))
+        .unwrap_or_else(|err| panic!("Could not recover from scylla: {}", err));
     // Running registered orderbooks engines parallely
     RegisteredSymbols::iter().for_each(|symbol| {
         let exchange = Exchange::from_symbol(symbol.to_string()).unwrap();
@@ -35,6 +48,22 @@ fn main() {
     });
     // process captial request parallely, like deposit withdrawl
     thread::spawn(process_user_request());
+    // Deposits are credited from what the configured custody reports
+    match CustodyConfig::load() {
+        Some(custody) => {
+            thread::spawn(watch_custody(custody.adapter(), CUSTODY_POLL_INTERVAL));
+        }
+        None => println!("No custody configured, deposits are not watched"),
+    }
+    // Margin accounts are charged interest hourly and liquidated below the maintenance level
+    thread::spawn(charge_interest(INTEREST_CHECK_INTERVAL));
+    thread::spawn(monitor_margin(MARGIN_CHECK_INTERVAL));
+    // Perpetual positions pay or receive funding every funding interval
+    thread::spawn(charge_funding(FUNDING_CHECK_INTERVAL));
+    // Every account's value is stored once a day for the portfolio history
+    thread::spawn(snapshot_portfolios(PORTFOLIO_CHECK_INTERVAL));
+    // Periodically pause the threads above to snapshot orderbooks and user balances
+    thread::spawn(CHECKPOINTER.run(SNAPSHOT_INTERVAL));
     loop {
     }
 }
@@ -0,0 +1,199 @@
+use common::{ ledger::LedgerEntry, numeric::Numeric };
+use rust_decimal_macros::dec;
+use scylla::{ batch::Batch, Session };
+use serde::{ Deserialize, Serialize };
+
+use crate::{ PersistSubAccount, PersistTransfer };
+
+use super::{
+    error::MatchingEngineErrors,
+    ledger,
+    Asset,
+    Id,
+    Quantity,
+    User,
+    Users,
+    SET_LAST_ID,
+    TRANSFER_ID,
+};
+
+// Sub-accounts are users of their own, with their own balances and orders, that belong to a
+// master account. Funds move between any two accounts with transfers, which are applied right
+// away by the user thread, in between the locks the markets take.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct InternalTransfer {
+    pub id: Id,
+    pub from: Id,
+    pub to: Id,
+    pub asset: Asset,
+    pub quantity: Quantity,
+    pub timestamp: u64,
+}
+
+impl Users {
+    pub fn new_sub_account(
+        &mut self,
+        master_id: Id,
+        id: Id
+    ) -> Result<&User, MatchingEngineErrors> {
+        let master = self.users.get(&master_id).ok_or(MatchingEngineErrors::UserNotFound)?;
+        if master.master_id.is_some() {
+            return Err(MatchingEngineErrors::InvalidMasterAccount);
+        }
+        self.new_user(id);
+        let sub_account = self.users.get_mut(&id).unwrap();
+        sub_account.master_id = Some(master_id);
+        Ok(sub_account)
+    }
+    // Only what is available moves, locked funds stay with the orders holding them
+    pub fn transfer(
+        &mut self,
+        transfer: &InternalTransfer
+    ) -> Result<Vec<LedgerEntry>, MatchingEngineErrors> {
+        let InternalTransfer { id, from, to, asset, quantity, timestamp } = *transfer;
+        if quantity <= dec!(0) || from == to {
+            return Err(MatchingEngineErrors::InvalidTransfer);
+        }
+        if !self.does_exist(to) {
+            return Err(MatchingEngineErrors::UserNotFound);
+        }
+        if self.is_frozen(from) {
+            return Err(MatchingEngineErrors::AccountFrozen);
+        }
+        if self.available_balance(&asset, from)? < quantity {
+            return Err(MatchingEngineErrors::InsufficientBalance);
+        }
+        self.check_margin_withdrawal(from, &asset, quantity)?;
+        self.withdraw(&asset, quantity, from)?;
+        self.deposit(&asset, quantity, to)?;
+        Ok(ledger::transfer(from, to, &asset, quantity, id, timestamp))
+    }
+    pub fn next_transfer_id(&mut self) -> Id {
+        self.last_transfer_id += 1;
+        self.last_transfer_id
+    }
+}
+
+pub async fn persist_sub_account(session: &Session, persist: PersistSubAccount) {
+    let new_user =
+        r#"
+        INSERT INTO keyspace_1.user_table (
+            id,
+            balance,
+            locked_balance
+        ) VALUES (?, ?, ?);
+    "#;
+    let new_sub_account =
+        r#"
+        INSERT INTO keyspace_1.sub_account_table (
+            master_id,
+            id
+        ) VALUES (?, ?);
+    "#;
+    let mut batch: Batch = Default::default();
+    batch.append_statement(new_user);
+    batch.append_statement(new_sub_account);
+    let prepared_batch: Batch = session.prepare_batch(&batch).await.unwrap();
+    let user = persist.sub_account.to_scylla_user();
+    let master_id = persist.master_id as i64;
+    session.batch(&prepared_batch, (user.clone(), (master_id, user.id))).await.unwrap();
+}
+// Both sides of the transfer, both balances and the last transfer id are written in one logged
+// batch at the balance stamp, so either both accounts show it or neither does and a later
+// balance is never overwritten
+pub async fn persist_transfer(session: &Session, persist: PersistTransfer) {
+    let new_transfer =
+        r#"
+        INSERT INTO keyspace_1.transfer_table (
+            user_id,
+            id,
+            counterparty_id,
+            asset,
+            amount,
+            timestamp
+        ) VALUES (?, ?, ?, ?, ?, ?)
+        USING TIMESTAMP ?;
+    "#;
+    let update_balance =
+        r#"
+        UPDATE keyspace_1.user_table USING TIMESTAMP ?
+        SET
+            balance[?] = ?
+        WHERE id = ?;
+    "#;
+    let mut batch: Batch = Default::default();
+    batch.append_statement(new_transfer);
+    batch.append_statement(new_transfer);
+    batch.append_statement(update_balance);
+    batch.append_statement(update_balance);
+    batch.append_statement(SET_LAST_ID);
+    let InternalTransfer { id, from, to, asset, quantity, timestamp } = persist.transfer;
+    let (id, from, to, timestamp) = (id as i64, from as i64, to as i64, timestamp as i64);
+    let asset = asset.to_string();
+    let stamp = persist.stamp;
+    let values = (
+        (from, id, to, asset.clone(), Numeric(-quantity), timestamp, stamp),
+        (to, id, from, asset.clone(), Numeric(quantity), timestamp, stamp),
+        (stamp, asset.clone(), Numeric(persist.from_balance), from),
+        (stamp, asset, Numeric(persist.to_balance), to),
+        (stamp, id, TRANSFER_ID),
+    );
+    let res = match session.prepare_batch(&batch).await {
+        Ok(prepared_batch) => session.batch(&prepared_batch, values).await,
+        Err(err) => Err(err),
+    };
+    if let Err(err) = res {
+        eprintln!("Could not persist transfer {}: {}", id, err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transfer(id: Id, from: Id, to: Id, quantity: Quantity) -> InternalTransfer {
+        InternalTransfer { id, from, to, asset: Asset::USDT, quantity, timestamp: 0 }
+    }
+
+    #[test]
+    fn sub_accounts_belong_to_one_master() {
+        let mut users = Users::default();
+        users.new_user(1);
+        users.new_sub_account(1, 2).unwrap();
+        users.new_sub_account(1, 3).unwrap();
+        assert_eq!(users.users[&2].master_id, Some(1));
+        assert_eq!(users.users[&3].master_id, Some(1));
+        assert!(matches!(
+            users.new_sub_account(2, 4),
+            Err(MatchingEngineErrors::InvalidMasterAccount)
+        ));
+        assert!(matches!(users.new_sub_account(9, 4), Err(MatchingEngineErrors::UserNotFound)));
+    }
+    #[test]
+    fn transfers_move_only_what_is_available() {
+        let mut users = Users::default();
+        users.new_user(1);
+        users.new_sub_account(1, 2).unwrap();
+        users.deposit(&Asset::USDT, dec!(100), 1).unwrap();
+        users.lock_amount(&Asset::USDT, 1, dec!(60));
+
+        assert!(matches!(
+            users.transfer(&transfer(1, 1, 2, dec!(50))),
+            Err(MatchingEngineErrors::InsufficientBalance)
+        ));
+        let entries = users.transfer(&transfer(1, 1, 2, dec!(40))).unwrap();
+        assert!(common::ledger::is_balanced(&entries));
+        assert_eq!(users.balance(&Asset::USDT, 1).unwrap(), &dec!(60));
+        assert_eq!(users.locked_balance(&Asset::USDT, 1).unwrap(), &dec!(60));
+        assert_eq!(users.balance(&Asset::USDT, 2).unwrap(), &dec!(40));
+
+        assert!(matches!(
+            users.transfer(&transfer(2, 2, 2, dec!(1))),
+            Err(MatchingEngineErrors::InvalidTransfer)
+        ));
+        assert!(matches!(
+            users.transfer(&transfer(2, 2, 9, dec!(1))),
+            Err(MatchingEngineErrors::UserNotFound)
+        ));
+    }
+}
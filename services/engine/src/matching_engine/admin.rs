@@ -0,0 +1,228 @@
+use common::{ ledger::LedgerEntry, numeric::Numeric };
+use enum_stringify::EnumStringify;
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use scylla::{ batch::Batch, Session };
+use serde::{ Deserialize, Serialize };
+
+use crate::PersistAdjustment;
+
+use super::{
+    error::MatchingEngineErrors,
+    ledger,
+    orderbook::Orderbook,
+    Asset,
+    Id,
+    Price,
+    Quantity,
+    User,
+    Users,
+    ADJUSTMENT_ID,
+    SET_LAST_ID,
+};
+
+// Operators change balances by hand only for one of these reasons
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, EnumStringify)]
+pub enum AdjustmentReason {
+    DepositCorrection,
+    WithdrawalCorrection,
+    TradeCorrection,
+    Compensation,
+    Other,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Adjustment {
+    pub id: Id,
+    pub user_id: Id,
+    pub asset: Asset,
+    // Signed, positive amounts are credited
+    pub amount: Quantity,
+    pub reason: AdjustmentReason,
+    pub timestamp: u64,
+}
+
+impl Users {
+    // Debits only come out of what is available, locked funds stay with the orders holding them
+    pub fn adjust_balance(
+        &mut self,
+        adjustment: &Adjustment
+    ) -> Result<Vec<LedgerEntry>, MatchingEngineErrors> {
+        let Adjustment { id, user_id, asset, amount, timestamp, .. } = *adjustment;
+        if amount == dec!(0) {
+            return Err(MatchingEngineErrors::InvalidAdjustment);
+        }
+        match amount > dec!(0) {
+            true => {
+                self.deposit(&asset, amount, user_id)?;
+            }
+            false => {
+                if self.available_balance(&asset, user_id)? < -amount {
+                    return Err(MatchingEngineErrors::InsufficientBalance);
+                }
+                self.withdraw(&asset, -amount, user_id)?;
+            }
+        }
+        Ok(ledger::adjustment(user_id, &asset, amount, id, timestamp))
+    }
+    pub fn next_adjustment_id(&mut self) -> Id {
+        self.last_adjustment_id += 1;
+        self.last_adjustment_id
+    }
+    // Frozen accounts keep their balances and orders but cannot lock, withdraw or transfer
+    pub fn set_frozen(&mut self, user_id: Id, frozen: bool) -> Result<&User, MatchingEngineErrors> {
+        let user = self.users.get_mut(&user_id).ok_or(MatchingEngineErrors::UserNotFound)?;
+        user.frozen = frozen;
+        Ok(user)
+    }
+    pub fn is_frozen(&self, user_id: Id) -> bool {
+        self.users.get(&user_id).is_some_and(|user| user.frozen)
+    }
+    pub fn user(&self, user_id: Id) -> Result<&User, MatchingEngineErrors> {
+        self.users.get(&user_id).ok_or(MatchingEngineErrors::UserNotFound)
+    }
+}
+
+// What operators see of a market
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MarketSummary {
+    pub symbol: String,
+    pub halted: bool,
+    pub last_price: Option<Price>,
+    pub max_price_deviation: Option<Decimal>,
+    pub bid_levels: usize,
+    pub ask_levels: usize,
+    pub open_orders: usize,
+    pub last_order_id: Id,
+    pub last_trade_id: Id,
+}
+impl Orderbook {
+    pub fn summary(&self) -> MarketSummary {
+        let open_orders = self.bids
+            .values()
+            .chain(self.asks.values())
+            .map(|limit| limit.orders.len())
+            .sum();
+        MarketSummary {
+            symbol: self.exchange.symbol.clone(),
+            halted: self.halted,
+            last_price: self.last_price,
+            max_price_deviation: self.max_price_deviation,
+            bid_levels: self.bids.len(),
+            ask_levels: self.asks.len(),
+            open_orders,
+            last_order_id: self.order_id,
+            last_trade_id: self.trade_id,
+        }
+    }
+}
+
+// The adjustment, the balance it left and the last adjustment id are written together at the
+// balance stamp, so a later balance is never overwritten
+pub async fn persist_adjustment(session: &Session, persist: PersistAdjustment) {
+    let new_adjustment =
+        r#"
+        INSERT INTO keyspace_1.adjustment_table (
+            user_id,
+            id,
+            asset,
+            amount,
+            reason,
+            timestamp
+        ) VALUES (?, ?, ?, ?, ?, ?)
+        USING TIMESTAMP ?;
+    "#;
+    let update_balance =
+        r#"
+        UPDATE keyspace_1.user_table USING TIMESTAMP ?
+        SET
+            balance[?] = ?
+        WHERE id = ?;
+    "#;
+    let mut batch: Batch = Default::default();
+    batch.append_statement(new_adjustment);
+    batch.append_statement(update_balance);
+    batch.append_statement(SET_LAST_ID);
+    let Adjustment { id, user_id, asset, amount, reason, timestamp } = persist.adjustment;
+    let (id, user_id, timestamp) = (id as i64, user_id as i64, timestamp as i64);
+    let asset = asset.to_string();
+    let stamp = persist.stamp;
+    let values = (
+        (user_id, id, asset.clone(), Numeric(amount), reason.to_string(), timestamp, stamp),
+        (stamp, asset, Numeric(persist.balance), user_id),
+        (stamp, id, ADJUSTMENT_ID),
+    );
+    let res = match session.prepare_batch(&batch).await {
+        Ok(prepared_batch) => session.batch(&prepared_batch, values).await,
+        Err(err) => Err(err),
+    };
+    if let Err(err) = res {
+        eprintln!("Could not persist adjustment {}: {}", id, err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accounts::InternalTransfer;
+
+    fn adjustment(id: Id, amount: Quantity) -> Adjustment {
+        Adjustment {
+            id,
+            user_id: 1,
+            asset: Asset::USDT,
+            amount,
+            reason: AdjustmentReason::Compensation,
+            timestamp: 0,
+        }
+    }
+
+    #[test]
+    fn adjustments_debit_only_what_is_available() {
+        let mut users = Users::default();
+        users.new_user(1);
+        let entries = users.adjust_balance(&adjustment(1, dec!(100))).unwrap();
+        assert!(common::ledger::is_balanced(&entries));
+        users.lock_amount(&Asset::USDT, 1, dec!(60));
+
+        assert!(matches!(
+            users.adjust_balance(&adjustment(2, dec!(-50))),
+            Err(MatchingEngineErrors::InsufficientBalance)
+        ));
+        users.adjust_balance(&adjustment(2, dec!(-40))).unwrap();
+        assert_eq!(users.balance(&Asset::USDT, 1).unwrap(), &dec!(60));
+        assert!(matches!(
+            users.adjust_balance(&adjustment(3, dec!(0))),
+            Err(MatchingEngineErrors::InvalidAdjustment)
+        ));
+    }
+    #[test]
+    fn frozen_accounts_cannot_lock_or_transfer() {
+        let mut users = Users::default();
+        users.new_user(1);
+        users.new_user(2);
+        users.deposit(&Asset::USDT, dec!(100), 1).unwrap();
+        users.set_frozen(1, true).unwrap();
+        assert!(matches!(
+            users.validate_and_lock(&Asset::USDT, 1, dec!(10)),
+            Err(MatchingEngineErrors::AccountFrozen)
+        ));
+        let transfer = InternalTransfer {
+            id: 1,
+            from: 1,
+            to: 2,
+            asset: Asset::USDT,
+            quantity: dec!(10),
+            timestamp: 0,
+        };
+        assert!(matches!(users.transfer(&transfer), Err(MatchingEngineErrors::AccountFrozen)));
+        users.set_frozen(1, false).unwrap();
+        users.validate_and_lock(&Asset::USDT, 1, dec!(10)).unwrap();
+    }
+    #[test]
+    fn unknown_users_are_not_found() {
+        let mut users = Users::default();
+        users.new_user(1);
+        assert_eq!(users.user(1).unwrap().id, 1);
+        assert!(matches!(users.user(2), Err(MatchingEngineErrors::UserNotFound)));
+    }
+}
@@ -0,0 +1,284 @@
+use std::{ collections::HashMap, fmt::Debug, sync::Mutex };
+
+use serde::{ Deserialize, Serialize };
+
+use crate::{ journal, PersistPositions };
+
+use super::{
+    error::MatchingEngineErrors,
+    perpetual::{ PerpFill, PerpLeg },
+    risk::Exposure,
+    Asset,
+    Exchange,
+    Id,
+    OrderSide,
+    PostUsers,
+    Price,
+    Quantity,
+    Symbol,
+    Users,
+};
+
+// What a user has locked of an asset after a change, with the balance stamp of the change.
+// Locks of orders also carry the journal stamp taken with them, unlocks leave it at 0.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Locked {
+    pub total: Quantity,
+    pub stamp: i64,
+    #[serde(default)]
+    pub journal_stamp: u64,
+}
+
+// Where the orderbook settles the balances of its trades and cancels. The engine backs it with
+// the global USERS, simulations and tests can hand a book its own `Mutex<Users>`.
+pub trait BalanceStore: Debug + Send + Sync {
+    fn lock_amount(&self, asset: &Asset, user_id: Id, quantity: Quantity);
+    // Locks only if the user has that much available, returns what is locked of the asset
+    fn validate_and_lock(
+        &self,
+        asset: &Asset,
+        user_id: Id,
+        quantity: Quantity
+    ) -> Result<Locked, MatchingEngineErrors>;
+    // Same for liquidations, which lock out of frozen accounts too
+    fn lock_available(
+        &self,
+        asset: &Asset,
+        user_id: Id,
+        quantity: Quantity
+    ) -> Result<Locked, MatchingEngineErrors>;
+    // Returns what is still locked of the asset
+    fn unlock_amount(&self, asset: &Asset, user_id: Id, quantity: Quantity) -> Locked;
+    // Every locked total of the user, with the stamp they were read at
+    fn locked_balances(&self, user_id: Id) -> (HashMap<Asset, Quantity>, i64);
+    // Base moves from the seller to the buyer and quote the other way, out of their locks.
+    // `released` is quote the buyer locked above the trade price and gets back with the trade.
+    fn settle_trade(
+        &self,
+        exchange: &Exchange,
+        quantity: Quantity,
+        exchange_price: Price,
+        released: Quantity,
+        seller_id: Id,
+        buyer_id: Id
+    ) -> PostUsers;
+    // Account risk is kept with the balances since it spans every market
+    fn check_risk(
+        &self,
+        user_id: Id,
+        exchange: &Exchange,
+        exposure: Exposure,
+        notional: Quantity,
+        timestamp: u64
+    ) -> Result<(), MatchingEngineErrors>;
+    // What the accounts now have resting in the market, zero for nothing
+    fn set_open_notional(&self, exchange: &Exchange, notional: HashMap<Id, Quantity>);
+    // Margin levels are valued at the reference price of the asset's market
+    fn set_mark_price(&self, asset: &Asset, price: Price);
+    // What of an order on the side closes the user's position in a perpetual market
+    fn reducible(
+        &self,
+        user_id: Id,
+        symbol: &Symbol,
+        order_side: &OrderSide,
+        quantity: Quantity
+    ) -> Quantity;
+    // Both sides open or close their positions out of their locks, `user` of the returned
+    // users is still the seller. Returns the ledger legs of both.
+    fn settle_perp_trade(
+        &self,
+        exchange: &Exchange,
+        quantity: Quantity,
+        exchange_price: Price,
+        seller: &PerpFill,
+        buyer: &PerpFill
+    ) -> (PostUsers, Vec<PerpLeg>);
+    fn set_perp_price(&self, symbol: &Symbol, price: Price);
+    // Accounts whose positions fills moved since they were last taken
+    fn take_moved_positions(&self) -> Vec<PersistPositions>;
+}
+
+impl BalanceStore for Mutex<Users> {
+    fn lock_amount(&self, asset: &Asset, user_id: Id, quantity: Quantity) {
+        self.lock().unwrap().lock_amount(asset, user_id, quantity);
+    }
+    fn validate_and_lock(
+        &self,
+        asset: &Asset,
+        user_id: Id,
+        quantity: Quantity
+    ) -> Result<Locked, MatchingEngineErrors> {
+        let mut users = self.lock().unwrap();
+        let total = users.validate_and_lock(asset, user_id, quantity)?;
+        Ok(Locked { total, stamp: users.stamp(), journal_stamp: journal::next_stamp() })
+    }
+    fn lock_available(
+        &self,
+        asset: &Asset,
+        user_id: Id,
+        quantity: Quantity
+    ) -> Result<Locked, MatchingEngineErrors> {
+        let mut users = self.lock().unwrap();
+        let total = users.lock_available(asset, user_id, quantity)?;
+        Ok(Locked { total, stamp: users.stamp(), journal_stamp: journal::next_stamp() })
+    }
+    fn unlock_amount(&self, asset: &Asset, user_id: Id, quantity: Quantity) -> Locked {
+        let mut users = self.lock().unwrap();
+        let total = users.unlock_amount(asset, user_id, quantity).locked_balance[asset];
+        Locked { total, stamp: users.stamp(), journal_stamp: 0 }
+    }
+    fn locked_balances(&self, user_id: Id) -> (HashMap<Asset, Quantity>, i64) {
+        let mut users = self.lock().unwrap();
+        let locked_balance = users.users.get(&user_id).unwrap().locked_balance.clone();
+        (locked_balance, users.stamp())
+    }
+    fn settle_trade(
+        &self,
+        exchange: &Exchange,
+        quantity: Quantity,
+        exchange_price: Price,
+        released: Quantity,
+        seller_id: Id,
+        buyer_id: Id
+    ) -> PostUsers {
+        let mut users = self.lock().unwrap();
+        users.unlock_amount(&exchange.base, seller_id, quantity);
+        users.withdraw(&exchange.base, quantity, seller_id);
+        users.deposit(&exchange.base, quantity, buyer_id);
+
+        users.unlock_amount(&exchange.quote, buyer_id, quantity * exchange_price + released);
+        users.withdraw(&exchange.quote, quantity * exchange_price, buyer_id);
+        users.deposit(&exchange.quote, quantity * exchange_price, seller_id);
+
+        let user = users.users.get(&seller_id).unwrap().clone();
+        let client = users.users.get(&buyer_id).unwrap().clone();
+        PostUsers {
+            client,
+            user,
+            stamp: users.stamp(),
+        }
+    }
+    fn check_risk(
+        &self,
+        user_id: Id,
+        exchange: &Exchange,
+        exposure: Exposure,
+        notional: Quantity,
+        timestamp: u64
+    ) -> Result<(), MatchingEngineErrors> {
+        self.lock().unwrap().check_risk(user_id, exchange, exposure, notional, timestamp)
+    }
+    fn set_open_notional(&self, exchange: &Exchange, notional: HashMap<Id, Quantity>) {
+        self.lock().unwrap().set_open_notional(exchange, notional);
+    }
+    fn set_mark_price(&self, asset: &Asset, price: Price) {
+        self.lock().unwrap().set_mark_price(asset, price);
+    }
+    fn reducible(
+        &self,
+        user_id: Id,
+        symbol: &Symbol,
+        order_side: &OrderSide,
+        quantity: Quantity
+    ) -> Quantity {
+        self.lock().unwrap().reducible(user_id, symbol, order_side, quantity)
+    }
+    fn settle_perp_trade(
+        &self,
+        exchange: &Exchange,
+        quantity: Quantity,
+        exchange_price: Price,
+        seller: &PerpFill,
+        buyer: &PerpFill
+    ) -> (PostUsers, Vec<PerpLeg>) {
+        let mut users = self.lock().unwrap();
+        let mut legs = users.settle_perp_fill(exchange, seller, quantity, exchange_price);
+        legs.extend(users.settle_perp_fill(exchange, buyer, quantity, exchange_price));
+        let user = users.users.get(&seller.user_id).unwrap().clone();
+        let client = users.users.get(&buyer.user_id).unwrap().clone();
+        (PostUsers { client, user, stamp: users.stamp() }, legs)
+    }
+    fn set_perp_price(&self, symbol: &Symbol, price: Price) {
+        self.lock().unwrap().set_perp_price(symbol, price);
+    }
+    fn take_moved_positions(&self) -> Vec<PersistPositions> {
+        self.lock().unwrap().take_moved_positions()
+    }
+}
+
+// Books read back from a snapshot have nothing attached until the engine hands them a store
+#[derive(Debug)]
+pub struct Detached;
+impl BalanceStore for Detached {
+    fn lock_amount(&self, _: &Asset, _: Id, _: Quantity) {
+        panic!("Orderbook has no balance store attached")
+    }
+    fn validate_and_lock(
+        &self,
+        _: &Asset,
+        _: Id,
+        _: Quantity
+    ) -> Result<Locked, MatchingEngineErrors> {
+        panic!("Orderbook has no balance store attached")
+    }
+    fn lock_available(
+        &self,
+        _: &Asset,
+        _: Id,
+        _: Quantity
+    ) -> Result<Locked, MatchingEngineErrors> {
+        panic!("Orderbook has no balance store attached")
+    }
+    fn unlock_amount(&self, _: &Asset, _: Id, _: Quantity) -> Locked {
+        panic!("Orderbook has no balance store attached")
+    }
+    fn locked_balances(&self, _: Id) -> (HashMap<Asset, Quantity>, i64) {
+        panic!("Orderbook has no balance store attached")
+    }
+    fn settle_trade(
+        &self,
+        _: &Exchange,
+        _: Quantity,
+        _: Price,
+        _: Quantity,
+        _: Id,
+        _: Id
+    ) -> PostUsers {
+        panic!("Orderbook has no balance store attached")
+    }
+    fn check_risk(
+        &self,
+        _: Id,
+        _: &Exchange,
+        _: Exposure,
+        _: Quantity,
+        _: u64
+    ) -> Result<(), MatchingEngineErrors> {
+        panic!("Orderbook has no balance store attached")
+    }
+    fn set_open_notional(&self, _: &Exchange, _: HashMap<Id, Quantity>) {
+        panic!("Orderbook has no balance store attached")
+    }
+    fn set_mark_price(&self, _: &Asset, _: Price) {
+        panic!("Orderbook has no balance store attached")
+    }
+    fn reducible(&self, _: Id, _: &Symbol, _: &OrderSide, _: Quantity) -> Quantity {
+        panic!("Orderbook has no balance store attached")
+    }
+    fn settle_perp_trade(
+        &self,
+        _: &Exchange,
+        _: Quantity,
+        _: Price,
+        _: &PerpFill,
+        _: &PerpFill
+    ) -> (PostUsers, Vec<PerpLeg>) {
+        panic!("Orderbook has no balance store attached")
+    }
+    fn set_perp_price(&self, _: &Symbol, _: Price) {
+        panic!("Orderbook has no balance store attached")
+    }
+    fn take_moved_positions(&self) -> Vec<PersistPositions> {
+        panic!("Orderbook has no balance store attached")
+    }
+}
@@ -0,0 +1,395 @@
+use std::{ collections::{ BTreeMap, HashMap }, fs, io::ErrorKind, path::PathBuf, str::FromStr };
+
+use common::{ ledger::LedgerEntry, numeric::Numeric };
+use config::{ Config, ConfigError, File };
+use enum_stringify::EnumStringify;
+use rust_decimal_macros::dec;
+use scylla::{ batch::Batch, transport::errors::QueryError, FromRow, SerializeRow, Session };
+use serde::{ Deserialize, Serialize };
+
+use crate::PersistDeposit;
+
+use super::{ error::MatchingEngineErrors, invalid_value, ledger, Asset, Id, Quantity, Users };
+
+// A transfer to one of a user's deposit addresses as the custody side sees it. It is reported
+// again as it gathers confirmations.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IncomingTransfer {
+    pub tx_id: String,
+    pub user_id: Id,
+    pub asset: Asset,
+    pub amount: Quantity,
+    pub confirmations: u32,
+}
+
+// Whatever watches the chains for deposits, polled for the transfers it knows of
+pub trait CustodyAdapter: Send {
+    fn incoming(&mut self) -> Vec<IncomingTransfer>;
+}
+impl<A: CustodyAdapter + ?Sized> CustodyAdapter for Box<A> {
+    fn incoming(&mut self) -> Vec<IncomingTransfer> {
+        (**self).incoming()
+    }
+}
+
+// Which custody deposits come from, `custody` in config/base.yaml
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "adapter", rename_all = "snake_case")]
+pub enum CustodyConfig {
+    File {
+        path: PathBuf,
+    },
+}
+impl CustodyConfig {
+    // None when no custody is configured, an invalid one stops the engine from starting
+    pub fn load() -> Option<CustodyConfig> {
+        let custody = Config::builder()
+            .add_source(File::with_name("config/base").required(false))
+            .build()
+            .and_then(|config| config.get::<CustodyConfig>("custody"));
+        match custody {
+            Ok(custody) => Some(custody),
+            Err(ConfigError::NotFound(_)) => None,
+            Err(err) => panic!("Invalid custody config: {}", err),
+        }
+    }
+    pub fn adapter(&self) -> Box<dyn CustodyAdapter> {
+        match self {
+            CustodyConfig::File { path } => Box::new(FileCustody::new(path.clone())),
+        }
+    }
+}
+
+// Reads the transfers from a JSON file that is edited by hand or by a test, for local runs
+pub struct FileCustody {
+    path: PathBuf,
+}
+impl FileCustody {
+    pub fn new(path: PathBuf) -> FileCustody {
+        FileCustody { path }
+    }
+}
+impl CustodyAdapter for FileCustody {
+    fn incoming(&mut self) -> Vec<IncomingTransfer> {
+        match fs::read(&self.path) {
+            Ok(bytes) =>
+                serde_json::from_slice(&bytes).unwrap_or_else(|err| {
+                    eprintln!("Could not read custody transfers: {}", err);
+                    Vec::new()
+                }),
+            Err(err) if err.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(err) => {
+                eprintln!("Could not read custody transfers: {}", err);
+                Vec::new()
+            }
+        }
+    }
+}
+
+// Confirmations a transfer needs before it is credited
+pub fn required_confirmations(asset: &Asset) -> u32 {
+    match asset {
+        Asset::USDT => 12,
+        Asset::BTC => 3,
+        Asset::SOL => 32,
+        Asset::ETH => 12,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, EnumStringify)]
+pub enum DepositState {
+    Pending,
+    Credited,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CustodyDeposit {
+    pub id: Id,
+    pub tx_id: String,
+    pub user_id: Id,
+    pub asset: Asset,
+    pub amount: Quantity,
+    pub confirmations: u32,
+    pub state: DepositState,
+    pub seen_at: u64,
+    pub updated_at: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Deposits {
+    // By tx id, a transfer is only ever credited once
+    pub deposits: BTreeMap<String, CustodyDeposit>,
+    // Last id handed out, recovered from the snapshot and the users journal
+    pub last_deposit_id: Id,
+}
+impl Deposits {
+    // A transfer seen before keeps its id
+    pub fn deposit_id(&mut self, tx_id: &str) -> Id {
+        match self.deposits.get(tx_id) {
+            Some(deposit) => deposit.id,
+            None => self.next_deposit_id(),
+        }
+    }
+    // Manual deposits share the ids of custody deposits
+    pub fn next_deposit_id(&mut self) -> Id {
+        self.last_deposit_id += 1;
+        self.last_deposit_id
+    }
+}
+
+impl Users {
+    // Records what the custody side reported and credits the deposit once it has enough
+    // confirmations. Returns the deposit and the ledger entries of the credit, if it was credited.
+    pub fn observe_transfer(
+        &mut self,
+        id: Id,
+        transfer: IncomingTransfer,
+        timestamp: u64
+    ) -> Result<(CustodyDeposit, Vec<LedgerEntry>), MatchingEngineErrors> {
+        if transfer.amount <= dec!(0) {
+            return Err(MatchingEngineErrors::InvalidDeposit);
+        }
+        if !self.does_exist(transfer.user_id) {
+            return Err(MatchingEngineErrors::UserNotFound);
+        }
+        let deposit = self.deposits.deposits
+            .entry(transfer.tx_id.clone())
+            .or_insert_with(|| CustodyDeposit {
+                id,
+                tx_id: transfer.tx_id.clone(),
+                user_id: transfer.user_id,
+                asset: transfer.asset,
+                amount: transfer.amount,
+                confirmations: 0,
+                state: DepositState::Pending,
+                seen_at: timestamp,
+                updated_at: timestamp,
+            });
+        // The first report of a tx decides what it deposits
+        let same_transfer =
+            deposit.user_id == transfer.user_id &&
+            deposit.asset == transfer.asset &&
+            deposit.amount == transfer.amount;
+        if !same_transfer {
+            return Err(MatchingEngineErrors::DepositMismatch);
+        }
+        if deposit.state == DepositState::Credited {
+            return Err(MatchingEngineErrors::DuplicateDeposit);
+        }
+        deposit.confirmations = deposit.confirmations.max(transfer.confirmations);
+        deposit.updated_at = timestamp;
+        if deposit.confirmations < required_confirmations(&deposit.asset) {
+            return Ok((deposit.clone(), Vec::new()));
+        }
+        deposit.state = DepositState::Credited;
+        let deposit = deposit.clone();
+        self.deposit(&deposit.asset, deposit.amount, deposit.user_id)?;
+        let entries = ledger::deposit(deposit.user_id, &deposit.asset, deposit.amount, deposit.id);
+        Ok((deposit, entries))
+    }
+}
+
+// Reports only what changed since the last poll, transfers that are credited by then are not
+// reported again until a restart
+pub struct CustodyWatcher<A: CustodyAdapter> {
+    adapter: A,
+    reported: HashMap<String, u32>,
+}
+impl<A: CustodyAdapter> CustodyWatcher<A> {
+    pub fn new(adapter: A) -> CustodyWatcher<A> {
+        CustodyWatcher { adapter, reported: HashMap::new() }
+    }
+    pub fn poll(&mut self) -> Vec<IncomingTransfer> {
+        let mut changed = Vec::new();
+        for transfer in self.adapter.incoming() {
+            let required = required_confirmations(&transfer.asset);
+            let reported = self.reported.get(&transfer.tx_id).copied();
+            let newly_confirmed = match reported {
+                None => true,
+                Some(confirmations) =>
+                    confirmations < required && transfer.confirmations > confirmations,
+            };
+            if newly_confirmed {
+                self.reported.insert(transfer.tx_id.clone(), transfer.confirmations);
+                changed.push(transfer);
+            }
+        }
+        changed
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, SerializeRow, FromRow)]
+pub struct ScyllaDeposit {
+    pub id: i64,
+    pub tx_id: String,
+    pub user_id: i64,
+    pub asset: String,
+    pub amount: Numeric,
+    pub confirmations: i32,
+    pub state: String,
+    pub seen_at: i64,
+    pub updated_at: i64,
+}
+impl ScyllaDeposit {
+    pub fn from_scylla_deposit(&self) -> Result<CustodyDeposit, QueryError> {
+        let invalid = |column: &str, value: &str| invalid_value("deposit_table", column, value);
+        Ok(CustodyDeposit {
+            id: self.id as u64,
+            tx_id: self.tx_id.clone(),
+            user_id: self.user_id as u64,
+            asset: Asset::from_str(&self.asset).ok_or_else(|| invalid("asset", &self.asset))?,
+            amount: self.amount.0,
+            confirmations: self.confirmations as u32,
+            state: DepositState::from_str(&self.state).map_err(|_| invalid("state", &self.state))?,
+            seen_at: self.seen_at as u64,
+            updated_at: self.updated_at as u64,
+        })
+    }
+}
+impl CustodyDeposit {
+    fn to_scylla_deposit(&self) -> ScyllaDeposit {
+        ScyllaDeposit {
+            id: self.id as i64,
+            tx_id: self.tx_id.clone(),
+            user_id: self.user_id as i64,
+            asset: self.asset.to_string(),
+            amount: Numeric(self.amount),
+            confirmations: self.confirmations as i32,
+            state: self.state.to_string(),
+            seen_at: self.seen_at as i64,
+            updated_at: self.updated_at as i64,
+        }
+    }
+}
+// Upserts the deposit, and the balances of its user once it is credited. Both are written at
+// the balance stamp of the report so a later state of either is never overwritten.
+pub async fn persist_deposit(session: &Session, persist: PersistDeposit) {
+    let upsert_deposit =
+        r#"
+        INSERT INTO keyspace_1.deposit_table (
+            id,
+            tx_id,
+            user_id,
+            asset,
+            amount,
+            confirmations,
+            state,
+            seen_at,
+            updated_at
+        ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+        USING TIMESTAMP ?;
+    "#;
+    let update_balance =
+        r#"
+        UPDATE keyspace_1.user_table USING TIMESTAMP ?
+        SET
+            balance[?] = ?
+        WHERE id = ?;
+    "#;
+    let stamp = persist.stamp;
+    let deposit = persist.deposit.to_scylla_deposit();
+    let deposit_values = (
+        deposit.id,
+        deposit.tx_id,
+        deposit.user_id,
+        deposit.asset.clone(),
+        deposit.amount,
+        deposit.confirmations,
+        deposit.state,
+        deposit.seen_at,
+        deposit.updated_at,
+        stamp,
+    );
+    let res = match persist.updated_balance {
+        Some(balance) => {
+            let mut batch: Batch = Default::default();
+            batch.append_statement(upsert_deposit);
+            batch.append_statement(update_balance);
+            let user_value = (stamp, deposit.asset, Numeric(balance), deposit.user_id);
+            match session.prepare_batch(&batch).await {
+                Ok(prepared_batch) =>
+                    session.batch(&prepared_batch, (deposit_values, user_value)).await,
+                Err(err) => Err(err),
+            }
+        }
+        None => session.query(upsert_deposit, deposit_values).await,
+    };
+    if let Err(err) = res {
+        eprintln!("Could not persist deposit {}: {}", persist.deposit.tx_id, err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transfer(tx_id: &str, amount: Quantity, confirmations: u32) -> IncomingTransfer {
+        IncomingTransfer {
+            tx_id: tx_id.to_string(),
+            user_id: 1,
+            asset: Asset::BTC,
+            amount,
+            confirmations,
+        }
+    }
+    fn observe(
+        users: &mut Users,
+        transfer: IncomingTransfer
+    ) -> Result<CustodyDeposit, MatchingEngineErrors> {
+        let id = users.deposits.deposit_id(&transfer.tx_id);
+        users.observe_transfer(id, transfer, 0).map(|(deposit, _)| deposit)
+    }
+
+    #[test]
+    fn credits_once_confirmed_and_only_once() {
+        let mut users = Users::default();
+        users.new_user(1);
+        let pending = observe(&mut users, transfer("tx-1", dec!(0.5), 1)).unwrap();
+        assert_eq!(pending.state, DepositState::Pending);
+        assert_eq!(users.balance(&Asset::BTC, 1).unwrap(), &dec!(0));
+
+        let credited = observe(&mut users, transfer("tx-1", dec!(0.5), 3)).unwrap();
+        assert_eq!((credited.id, credited.state), (pending.id, DepositState::Credited));
+        assert_eq!(users.balance(&Asset::BTC, 1).unwrap(), &dec!(0.5));
+
+        assert!(matches!(
+            observe(&mut users, transfer("tx-1", dec!(0.5), 4)),
+            Err(MatchingEngineErrors::DuplicateDeposit)
+        ));
+        assert_eq!(users.balance(&Asset::BTC, 1).unwrap(), &dec!(0.5));
+        assert_eq!(observe(&mut users, transfer("tx-2", dec!(1), 0)).unwrap().id, 2);
+    }
+    #[test]
+    fn reports_of_a_tx_cannot_change_what_it_deposits() {
+        let mut users = Users::default();
+        users.new_user(1);
+        observe(&mut users, transfer("tx-1", dec!(0.5), 1)).unwrap();
+        assert!(matches!(
+            observe(&mut users, transfer("tx-1", dec!(5), 3)),
+            Err(MatchingEngineErrors::DepositMismatch)
+        ));
+        assert_eq!(users.balance(&Asset::BTC, 1).unwrap(), &dec!(0));
+    }
+    #[test]
+    fn watcher_reports_changes_until_confirmed() {
+        struct Script(Vec<Vec<IncomingTransfer>>);
+        impl CustodyAdapter for Script {
+            fn incoming(&mut self) -> Vec<IncomingTransfer> {
+                self.0.remove(0)
+            }
+        }
+        let mut watcher = CustodyWatcher::new(
+            Script(
+                vec![
+                    vec![transfer("tx-1", dec!(1), 1)],
+                    vec![transfer("tx-1", dec!(1), 1)],
+                    vec![transfer("tx-1", dec!(1), 3)],
+                    vec![transfer("tx-1", dec!(1), 4)]
+                ]
+            )
+        );
+        assert_eq!(watcher.poll().len(), 1);
+        assert_eq!(watcher.poll().len(), 0);
+        assert_eq!(watcher.poll()[0].confirmations, 3);
+        assert_eq!(watcher.poll().len(), 0);
+    }
+}
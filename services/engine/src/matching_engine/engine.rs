@@ -2,14 +2,14 @@ use enum_stringify::EnumStringify;
 use redis::{ Commands, Connection, FromRedisValue, Value };
 use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
-use scylla::Session;
 use serde::{ Deserialize, Serialize };
-use serde_json::from_str;
+use serde_json::{ from_str, to_string };
 use strum::IntoEnumIterator;
 use strum_macros::{ EnumIter, FromRepr };
 use crate::matching_engine::Symbol;
 
 use super::*;
+use super::events::NoEvents;
 use super::orderbook::{ Limit, Order, Orderbook };
 use super::error::MatchingEngineErrors;
 use super::{ Asset, Id, OrderId, Quantity, RegisteredSymbols };
@@ -29,29 +29,6 @@ impl MatchingEngine {
             orderbooks: HashMap::new(),
         }
     }
-    pub async fn recover_all_orderbooks(
-        &mut self,
-        session: &Session,
-    ) {
-        let symbols = self.registered_exchanges();
-        let mut orderbooks = &mut self.orderbooks;
-        println!("Recovering users...");
-        let res = session.query("SELECT * FROM keyspace_1.user_table", &[]).await.unwrap();
-        let mut users = res.rows_typed::<ScyllaUser>().unwrap();
-        let users: Vec<User> = users.map(|user| { user.unwrap().from_scylla_user() }).collect();
-        let mut users_global = USERS.lock().unwrap();
-        for user in users {
-            users_global.users.insert(user.id, user);
-        }
-        for symbol in symbols {
-            println!("Recovering {:?} orderbook...", symbol);
-            let exchange = Exchange::from_symbol(symbol.to_string()).unwrap();
-            let mut orderbook = Orderbook::new(exchange.clone());
-            orderbook.recover_orderbook(session).await;
-            orderbooks.insert(exchange, orderbook);
-        }
-        println!("\nOrderbook recovering complete.")
-    }
     pub fn registered_exchanges(&self) -> Vec<Symbol> {
         let exchanges: Vec<Symbol> = RegisteredSymbols::iter()
             .map(|s| s.to_string())
@@ -93,21 +70,27 @@ impl MatchingEngine {
         Orderbook::bid_limits(&mut orderbook.bids)
     }
 }
-fn setup_engine_and_users() -> (MatchingEngine, Exchange, Orderbook, Vec<Id>, Connection) {
+fn setup_engine_and_users() -> (MatchingEngine, Exchange, Orderbook, Vec<Id>) {
     let mut engine = MatchingEngine::init();
     let exchange = Exchange::new(Asset::SOL, Asset::USDT);
     let mut orderbook = Orderbook::new(exchange.clone());
     engine.add_new_market(exchange.clone());
-    let client = redis::Client::open("redis://127.0.0.1:6379").expect("Could not create client.");
-    let mut connection = client.get_connection().expect("Could not connect to the client");
     let ids: Vec<_> = [1, 2, 3, 4, 5, 6, 7, 8].to_vec();
-    (engine, exchange, orderbook, ids, connection)
+    let mut users = Users::default();
+    for id in ids.iter() {
+        users.new_user(*id);
+    }
+    orderbook.attach(Arc::new(Mutex::new(users)), Arc::new(NoEvents));
+    (engine, exchange, orderbook, ids)
 }
 
 #[cfg(test)]
 pub mod tests {
     use std::sync::atomic::Ordering;
+    use common::{ ids, ledger::{ self, Account, LedgerEntry, LedgerKind } };
     use rust_decimal_macros::dec;
+    use crate::{ handle_order_request::{ CancelOrder, EngineRequests }, journal::JournalEntry };
+    use crate::matching_engine::events::RecordedEvents;
     use super::*;
     #[test]
     fn is_sorting_working() {
@@ -157,8 +140,315 @@ pub mod tests {
         assert_eq!(orderbook.bids.get(&dec!(88)).unwrap().price, worst_bid_price);
     }
     #[test]
+    fn only_the_owner_cancels_an_order() {
+        let mut orderbook = Orderbook::new(Exchange::new(Asset::SOL, Asset::USDT));
+        let (owner, other) = (1, 2);
+        orderbook.add_limit_order(
+            dec!(100),
+            Order::new(7, 1, OrderSide::Ask, dec!(3), OrderType::Limit, owner)
+        );
+        let result = orderbook.cancel_order(7, other, &OrderSide::Ask, &dec!(100));
+        assert!(matches!(result, Err(MatchingEngineErrors::InvalidOrderId)));
+        assert_eq!(orderbook.asks.get(&dec!(100)).unwrap().orders.len(), 1);
+        assert_eq!(orderbook.exposure(owner).open_orders, 1);
+
+        let order = orderbook.cancel_order(7, owner, &OrderSide::Ask, &dec!(100)).unwrap();
+        assert_eq!(order.user_id, owner);
+        assert!(orderbook.asks.get(&dec!(100)).unwrap().orders.is_empty());
+    }
+    #[test]
+    fn depth_lists_the_best_levels_first() {
+        let mut orderbook = Orderbook::new(Exchange::new(Asset::SOL, Asset::USDT));
+        for (id, price, order_side) in [
+            (1, dec!(101), OrderSide::Ask),
+            (2, dec!(102), OrderSide::Ask),
+            (3, dec!(103), OrderSide::Ask),
+            (4, dec!(101), OrderSide::Ask),
+            (5, dec!(99), OrderSide::Bid),
+            (6, dec!(98), OrderSide::Bid),
+        ] {
+            let order = Order::new(id, id, order_side, dec!(2), OrderType::Limit, id);
+            orderbook.add_limit_order(price, order);
+        }
+        orderbook.cancel_order(6, 6, &OrderSide::Bid, &dec!(98)).unwrap();
+
+        let depth = orderbook.depth(2);
+        assert_eq!(depth.symbol, "SOL_USDT");
+        assert_eq!(depth.asks, vec![(dec!(101), dec!(4)), (dec!(102), dec!(2))]);
+        // a price whose orders were all cancelled is not a level
+        assert_eq!(depth.bids, vec![(dec!(99), dec!(2))]);
+    }
+    #[test]
+    fn markets_have_their_own_id_namespace() {
+        let namespaces: Vec<u16> = RegisteredSymbols::iter()
+            .map(|symbol| symbol.id_namespace())
+            .collect();
+        let mut unique = namespaces.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), namespaces.len());
+        assert!(!namespaces.contains(&ids::USERS_NAMESPACE));
+        assert!(namespaces.iter().all(|namespace| *namespace <= ids::MAX_NAMESPACE));
+        // taken from the constant of the symbol, not from where it is declared
+        assert_eq!(Exchange::new(Asset::SOL, Asset::USDT).id_namespace(), 1);
+        assert_eq!(RegisteredSymbols::BTC_USDT_PERP.id_namespace(), 4);
+    }
+    #[test]
+    fn replays_journal_into_the_same_book() {
+        let exchange = Exchange::new(Asset::SOL, Asset::USDT);
+        let order_id = |sequence: u64| ids::compose(exchange.id_namespace(), sequence);
+        let limit_order = |
+            id: OrderId,
+            user_id: i64,
+            order_side: OrderSide,
+            price: Price,
+            initial_quantity: Quantity
+        | {
+            EngineRequests::ExecuteOrder(RecievedOrder {
+                id: id as i64,
+                user_id,
+                symbol: exchange.symbol.clone(),
+                price,
+                initial_quantity,
+                filled_quantity: dec!(0),
+                quote_quantity: initial_quantity * price,
+                filled_quote_quantity: dec!(0),
+                order_type: OrderType::Limit,
+                order_side,
+                order_status: OrderStatus::InProgress,
+                timestamp: ids::sequence(id) as i64,
+            })
+        };
+        let requests = [
+            limit_order(order_id(1), 1, OrderSide::Ask, dec!(100), dec!(10)),
+            limit_order(order_id(2), 2, OrderSide::Ask, dec!(101), dec!(10)),
+            limit_order(order_id(3), 3, OrderSide::Bid, dec!(99), dec!(10)),
+            EngineRequests::CancelOrder(CancelOrder {
+                id: order_id(2),
+                user_id: 2,
+                symbol: exchange.symbol.clone(),
+                price: dec!(101),
+                order_side: OrderSide::Ask,
+                sub_id: 0,
+                timestamp: 4,
+            }),
+            // cancelling an order that is already gone is a no-op on replay
+            EngineRequests::CancelOrder(CancelOrder {
+                id: order_id(2),
+                user_id: 2,
+                symbol: exchange.symbol.clone(),
+                price: dec!(101),
+                order_side: OrderSide::Ask,
+                sub_id: 0,
+                timestamp: 5,
+            }),
+            // crosses the resting ask at 100 and takes 4 of its 10
+            limit_order(order_id(6), 4, OrderSide::Bid, dec!(100), dec!(4))
+        ];
+        let entries: Vec<JournalEntry> = requests
+            .iter()
+            .enumerate()
+            .map(|(index, request)| JournalEntry {
+                seq: (index as u64) + 1,
+                stamp: (index as u64) + 1,
+                payload: serde_json::to_vec(request).unwrap(),
+            })
+            .collect();
+
+        let mut orderbook = Orderbook::new(exchange.clone());
+        orderbook.replay_journal(entries, false);
+        assert_eq!(orderbook.order_id, order_id(6));
+        assert_eq!(orderbook.trade_id, ids::compose(exchange.id_namespace(), 1));
+        let ask = &orderbook.asks.get(&dec!(100)).unwrap().orders[0];
+        assert_eq!((ask.id, ask.quantity), (order_id(1), dec!(6)));
+        assert!(orderbook.asks.get(&dec!(101)).unwrap().orders.is_empty());
+        let bid = &orderbook.bids.get(&dec!(99)).unwrap().orders[0];
+        assert_eq!((bid.id, bid.quantity), (order_id(3), dec!(10)));
+        // the crossing bid filled completely and never rested
+        assert!(!orderbook.bids.contains_key(&dec!(100)));
+
+        let depth = orderbook.depth(10);
+        assert_eq!(depth.asks, vec![(dec!(100), dec!(6))]);
+        assert_eq!(depth.bids, vec![(dec!(99), dec!(10))]);
+    }
+    #[test]
+    fn replays_balances_on_top_of_a_snapshot() {
+        let exchange = Exchange::new(Asset::SOL, Asset::USDT);
+        let (seller, buyer) = (1, 2);
+        let mut users = Users::default();
+        users.new_user(seller);
+        users.new_user(buyer);
+        users.deposit(&Asset::SOL, dec!(10), seller).unwrap();
+        users.deposit(&Asset::USDT, dec!(1000), buyer).unwrap();
+        users.lock_amount(&Asset::SOL, seller, dec!(5));
+        let users = Arc::new(Mutex::new(users));
+        let id = |sequence: u64| ids::compose(exchange.id_namespace(), sequence);
+        // state at the snapshot, the seller's ask is resting and locked
+        let mut orderbook = Orderbook::new(exchange.clone());
+        orderbook.attach(users.clone(), Arc::new(NoEvents));
+        orderbook.order_id = id(1);
+        orderbook.add_limit_order(
+            dec!(100),
+            Order::new(id(1), 1, OrderSide::Ask, dec!(5), OrderType::Limit, seller)
+        );
+        let requests = [
+            EngineRequests::ExecuteOrder(RecievedOrder {
+                id: id(2) as i64,
+                user_id: buyer as i64,
+                symbol: exchange.symbol.clone(),
+                price: dec!(100),
+                initial_quantity: dec!(3),
+                filled_quantity: dec!(0),
+                quote_quantity: dec!(300),
+                filled_quote_quantity: dec!(0),
+                order_type: OrderType::Limit,
+                order_side: OrderSide::Bid,
+                order_status: OrderStatus::InProgress,
+                timestamp: 2,
+            }),
+            EngineRequests::CancelOrder(CancelOrder {
+                id: id(1),
+                user_id: seller,
+                symbol: exchange.symbol.clone(),
+                price: dec!(100),
+                order_side: OrderSide::Ask,
+                sub_id: 0,
+                timestamp: 3,
+            })
+        ];
+        let entries = requests
+            .iter()
+            .enumerate()
+            .map(|(index, request)| JournalEntry {
+                seq: (index as u64) + 8,
+                stamp: (index as u64) + 8,
+                payload: serde_json::to_vec(request).unwrap(),
+            })
+            .collect();
+        orderbook.replay_journal(entries, true);
+
+        assert_eq!(orderbook.trade_id, id(1));
+        assert_eq!(orderbook.order_id, id(2));
+        assert!(orderbook.asks.get(&dec!(100)).unwrap().orders.is_empty());
+        let users = users.lock().unwrap();
+        assert_eq!(users.balance(&Asset::SOL, seller).unwrap(), &dec!(7));
+        assert_eq!(users.locked_balance(&Asset::SOL, seller).unwrap(), &dec!(0));
+        assert_eq!(users.balance(&Asset::USDT, seller).unwrap(), &dec!(300));
+        assert_eq!(users.balance(&Asset::SOL, buyer).unwrap(), &dec!(3));
+        assert_eq!(users.balance(&Asset::USDT, buyer).unwrap(), &dec!(700));
+        assert_eq!(users.locked_balance(&Asset::USDT, buyer).unwrap(), &dec!(0));
+    }
+    #[test]
+    fn settles_trades_through_the_attached_store_and_sink() {
+        let exchange = Exchange::new(Asset::SOL, Asset::USDT);
+        let mut orderbook = Orderbook::new(exchange.clone());
+        let (seller, buyer) = (1, 2);
+        let users = Arc::new(Mutex::new(Users::default()));
+        {
+            let mut users = users.lock().unwrap();
+            users.new_user(seller);
+            users.new_user(buyer);
+            users.deposit(&Asset::SOL, dec!(10), seller).unwrap();
+            users.deposit(&Asset::USDT, dec!(1000), buyer).unwrap();
+            users.lock_amount(&Asset::SOL, seller, dec!(4));
+            users.lock_amount(&Asset::USDT, buyer, dec!(300));
+        }
+        let events = Arc::new(RecordedEvents::default());
+        orderbook.attach(users.clone(), events.clone());
+        orderbook.add_limit_order(
+            dec!(100),
+            Order::new(1, 1, OrderSide::Ask, dec!(4), OrderType::Limit, seller)
+        );
+        let bid = RecievedOrder {
+            id: 0,
+            user_id: buyer as i64,
+            symbol: exchange.symbol.clone(),
+            price: dec!(100),
+            initial_quantity: dec!(3),
+            filled_quantity: dec!(0),
+            quote_quantity: dec!(300),
+            filled_quote_quantity: dec!(0),
+            order_type: OrderType::Limit,
+            order_side: OrderSide::Bid,
+            order_status: OrderStatus::InProgress,
+            timestamp: 2,
+        };
+        let order_id = orderbook.increment_order_id();
+        let (filled, filled_quote, status) = orderbook.process_order(bid, order_id);
+        assert_eq!((filled, filled_quote, status), (dec!(3), dec!(300), OrderStatus::Filled));
+
+        let users = users.lock().unwrap();
+        assert_eq!(users.balance(&Asset::SOL, seller).unwrap(), &dec!(7));
+        assert_eq!(users.locked_balance(&Asset::SOL, seller).unwrap(), &dec!(1));
+        assert_eq!(users.balance(&Asset::USDT, seller).unwrap(), &dec!(300));
+        assert_eq!(users.balance(&Asset::SOL, buyer).unwrap(), &dec!(3));
+        assert_eq!(users.locked_balance(&Asset::USDT, buyer).unwrap(), &dec!(0));
+        let events = events.0.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].trade.quantity, dec!(3));
+        assert_eq!(events[0].order_update.user_id, buyer);
+        assert_eq!(events[0].client_order_update.user_id, seller);
+    }
+    #[test]
+    fn releases_what_a_bid_locked_above_the_trade_price() {
+        let exchange = Exchange::new(Asset::SOL, Asset::USDT);
+        let mut orderbook = Orderbook::new(exchange.clone());
+        let (seller, buyer) = (1, 2);
+        let users = Arc::new(Mutex::new(Users::default()));
+        {
+            let mut users = users.lock().unwrap();
+            users.new_user(seller);
+            users.new_user(buyer);
+            users.deposit(&Asset::SOL, dec!(10), seller).unwrap();
+            users.deposit(&Asset::USDT, dec!(1000), buyer).unwrap();
+            users.lock_amount(&Asset::SOL, seller, dec!(4));
+            users.lock_amount(&Asset::USDT, buyer, dec!(315));
+        }
+        let events = Arc::new(RecordedEvents::default());
+        orderbook.attach(users.clone(), events.clone());
+        orderbook.add_limit_order(
+            dec!(100),
+            Order::new(1, 1, OrderSide::Ask, dec!(4), OrderType::Limit, seller)
+        );
+        let bid = RecievedOrder {
+            id: 0,
+            user_id: buyer as i64,
+            symbol: exchange.symbol.clone(),
+            price: dec!(105),
+            initial_quantity: dec!(3),
+            filled_quantity: dec!(0),
+            quote_quantity: dec!(315),
+            filled_quote_quantity: dec!(0),
+            order_type: OrderType::Limit,
+            order_side: OrderSide::Bid,
+            order_status: OrderStatus::InProgress,
+            timestamp: 2,
+        };
+        let order_id = orderbook.increment_order_id();
+        let (_, filled_quote, _) = orderbook.process_order(bid, order_id);
+        assert_eq!(filled_quote, dec!(300));
+
+        let users = users.lock().unwrap();
+        assert_eq!(users.balance(&Asset::USDT, buyer).unwrap(), &dec!(700));
+        assert_eq!(users.locked_balance(&Asset::USDT, buyer).unwrap(), &dec!(0));
+        // the filler persists the buyer with the improvement already released
+        let events = events.0.lock().unwrap();
+        let persisted_buyer = &events[0].filler.post_users.client;
+        assert_eq!(persisted_buyer.locked_balance[&Asset::USDT], dec!(0));
+        // four trade legs and the release of the improvement, which moves nothing out of the book
+        let entries = &events[0].filler.ledger;
+        assert!(ledger::is_balanced(entries));
+        let released: Vec<&LedgerEntry> = entries
+            .iter()
+            .filter(|entry| entry.kind == LedgerKind::Unlock)
+            .collect();
+        assert_eq!(entries.len(), 6);
+        assert_eq!(released[1].account, Account::Available);
+        assert_eq!((released[1].user_id, released[1].amount), (buyer, dec!(15)));
+    }
+    #[test]
     fn adds_to_orderbook_if_didnot_match() {
-        let (mut engine, exchange, mut orderbook, ids, mut rc) = setup_engine_and_users();
+        let (mut engine, exchange, mut orderbook, ids) = setup_engine_and_users();
         // dummy limit orders in orderbook
         orderbook.add_limit_order(
             dec!(110),
@@ -194,17 +484,17 @@ pub mod tests {
         );
 
         let bob_order = Order::new(9, 9, OrderSide::Bid, dec!(10), OrderType::Limit, ids[4]);
-        orderbook.fill_limit_order(dec!(50), bob_order, false, None);
+        orderbook.fill_limit_order(dec!(50), bob_order, false, false);
         assert_eq!(orderbook.bids.contains_key(&dec!(50)), true); // failed to match at best ask(88) so it should be added to orderbook
 
         let alice_order = Order::new(10, 10, OrderSide::Ask, dec!(10), OrderType::Limit, ids[5]);
-        orderbook.fill_limit_order(dec!(201), alice_order, false, None);
+        orderbook.fill_limit_order(dec!(201), alice_order, false, false);
         assert_eq!(orderbook.asks.contains_key(&dec!(201)), true); // failed to match at best bid(101) so it should be added to orderbook
     }
 
     #[test]
     fn if_matched_but_not_filled_bid_order() {
-        let (mut engine, exchange, mut orderbook, ids, mut rc) = setup_engine_and_users();
+        let (mut engine, exchange, mut orderbook, ids) = setup_engine_and_users();
 
         let ask_price_limit_1 = dec!(200);
         let ask_price_limit_2 = dec!(400);
@@ -229,7 +519,7 @@ pub mod tests {
 
         let bid_order = Order::new(5, 5, OrderSide::Bid, dec!(40), OrderType::Limit, ids[5]);
         let bid_price_limit_1 = dec!(500);
-        orderbook.fill_limit_order(bid_price_limit_1, bid_order, false, None);
+        orderbook.fill_limit_order(bid_price_limit_1, bid_order, false, false);
         // For the Remaining Quantity a new order should be added for the price limit made by the order
         assert_eq!(
             orderbook.bids.get(&bid_price_limit_1).unwrap().orders.get(0).unwrap().quantity,
@@ -238,7 +528,7 @@ pub mod tests {
     }
     #[test]
     fn if_matched_but_not_filled_ask_order() {
-        let (mut engine, exchange, mut orderbook, ids, mut rc) = setup_engine_and_users();
+        let (mut engine, exchange, mut orderbook, ids) = setup_engine_and_users();
 
         let bid_price_limit_1 = dec!(500);
         let bid_price_limit_2 = dec!(400);
@@ -263,7 +553,7 @@ pub mod tests {
 
         let ask_order = Order::new(5, 5, OrderSide::Ask, dec!(40), OrderType::Limit, ids[5]);
         let ask_price_limit_1 = dec!(300);
-        orderbook.fill_limit_order(ask_price_limit_1, ask_order, false, None);
+        orderbook.fill_limit_order(ask_price_limit_1, ask_order, false, false);
         // Checkk all orders for that partically price limit is filled
         // println!("{:?}", orderbook.bids.get(&bid_price_limit_1).unwrap().orders);
         assert_eq!(
@@ -282,7 +572,7 @@ pub mod tests {
     }
     #[test]
     fn fill_market_order() {
-        let (mut engine, exchange, mut orderbook, ids, mut rc) = setup_engine_and_users();
+        let (mut engine, exchange, mut orderbook, ids) = setup_engine_and_users();
 
         let ask_price_limit_1 = dec!(200);
         let ask_price_limit_2 = dec!(400);
@@ -306,7 +596,7 @@ pub mod tests {
         );
 
         let market_order = Order::new(5, 5, OrderSide::Bid, dec!(40), OrderType::Market, ids[5]);
-        orderbook.fill_market_order(market_order, false, None);
+        orderbook.fill_market_order(market_order, false, false);
         dbg!(&orderbook.asks);
         assert_eq!(
             orderbook.asks.get(&ask_price_limit_3).unwrap().orders.get(0).unwrap().quantity,
@@ -1,7 +1,7 @@
 use enum_stringify::EnumStringify;
 use serde::{ Deserialize, Serialize };
 
-#[derive(Debug, Serialize, EnumStringify)]
+#[derive(Debug, PartialEq, Serialize, Deserialize, EnumStringify)]
 pub enum MatchingEngineErrors {
     ExchangeAlreadyExist,
     AskedMoreThanTradeable,
@@ -9,5 +9,33 @@ pub enum MatchingEngineErrors {
     OverWithdrawl,
     InsufficientBalance,
     InvalidOrderId,
-    InvalidPriceLimitOrOrderSide
+    InvalidPriceLimitOrOrderSide,
+    InvalidQuantity,
+    JournalUnavailable,
+    InvalidWithdrawal,
+    WithdrawalLimitExceeded,
+    WithdrawalNotFound,
+    InvalidWithdrawalState,
+    InvalidDeposit,
+    DuplicateDeposit,
+    DepositMismatch,
+    InvalidMasterAccount,
+    InvalidTransfer,
+    InvalidAdjustment,
+    AccountFrozen,
+    MarketHalted,
+    OrderRateLimitExceeded,
+    OpenOrdersLimitExceeded,
+    OrderNotionalLimitExceeded,
+    OpenNotionalLimitExceeded,
+    PriceOutsideBand,
+    InvalidPriceBand,
+    MarginNotEnabled,
+    InvalidMarginMode,
+    MarginAccountIsolated,
+    MarginLevelTooLow,
+    MarkPriceUnavailable,
+    OutstandingDebt,
+    InvalidLoan,
+    NothingToRepay,
 }
@@ -0,0 +1,47 @@
+use std::{ fmt::Debug, sync::Mutex };
+
+use common::events::{ Depth, OrderUpdate, Ticker, Trade };
+
+use super::{ balances::Detached, Filler };
+
+// Everything a single match produces, the private updates of both orders, the public trade
+// and the filler entry that persists the post trade balances
+#[derive(Debug)]
+pub struct TradeEvents {
+    pub order_update: OrderUpdate,
+    pub client_order_update: OrderUpdate,
+    pub trade: Trade,
+    pub filler: Filler,
+}
+
+// Where the orderbook sends the events of its trades, the engine forwards them to redis
+pub trait EventSink: Debug + Send + Sync {
+    fn trade(&self, events: TradeEvents);
+    // The book after a request changed it, sinks that only look at trades ignore it
+    fn depth(&self, _: Depth) {}
+    fn ticker(&self, _: Ticker) {}
+}
+
+// For simulations that only look at the book and balances
+#[derive(Debug)]
+pub struct NoEvents;
+impl EventSink for NoEvents {
+    fn trade(&self, _: TradeEvents) {}
+}
+
+// Keeps the trades for tests to look at
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct RecordedEvents(pub Mutex<Vec<TradeEvents>>);
+#[cfg(test)]
+impl EventSink for RecordedEvents {
+    fn trade(&self, events: TradeEvents) {
+        self.0.lock().unwrap().push(events);
+    }
+}
+
+impl EventSink for Detached {
+    fn trade(&self, _: TradeEvents) {
+        panic!("Orderbook has no event sink attached")
+    }
+}
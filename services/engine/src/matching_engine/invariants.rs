@@ -0,0 +1,225 @@
+// Throws random sequences of orders and cancels at an orderbook settling against its own users,
+// and checks after every step that the book and balances are still consistent
+use std::{ collections::HashMap, sync::{ Arc, Mutex } };
+
+use proptest::prelude::*;
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use strum::IntoEnumIterator;
+
+use crate::replay::locked_by_orders;
+use super::{
+    events::NoEvents,
+    orderbook::Orderbook,
+    Asset,
+    Exchange,
+    Id,
+    OrderSide,
+    OrderStatus,
+    OrderType,
+    Quantity,
+    RecievedOrder,
+    Users,
+};
+
+const USERS_COUNT: u64 = 4;
+
+#[derive(Debug, Clone)]
+enum Action {
+    Limit {
+        user_id: Id,
+        order_side: OrderSide,
+        price: Decimal,
+        quantity: Quantity,
+    },
+    Market {
+        user_id: Id,
+        order_side: OrderSide,
+        quantity: Quantity,
+    },
+    Cancel {
+        pick: usize,
+    },
+    CancelAll {
+        user_id: Id,
+    },
+}
+
+fn order_side() -> impl Strategy<Value = OrderSide> {
+    prop_oneof![Just(OrderSide::Bid), Just(OrderSide::Ask)]
+}
+// Prices and quantities with one decimal place, few enough price levels that orders cross often
+fn action() -> impl Strategy<Value = Action> {
+    let user_id = 1..=USERS_COUNT;
+    let price = (90u32..=110).prop_map(|price| Decimal::new(price as i64, 0));
+    let quantity = (1u32..=50).prop_map(|quantity| Decimal::new(quantity as i64, 1));
+    prop_oneof![
+        6 => (user_id.clone(), order_side(), price, quantity.clone()).prop_map(
+            |(user_id, order_side, price, quantity)| Action::Limit {
+                user_id,
+                order_side,
+                price,
+                quantity,
+            }
+        ),
+        2 => (user_id.clone(), order_side(), quantity).prop_map(
+            |(user_id, order_side, quantity)| Action::Market { user_id, order_side, quantity }
+        ),
+        2 => any::<usize>().prop_map(|pick| Action::Cancel { pick }),
+        1 => user_id.prop_map(|user_id| Action::CancelAll { user_id })
+    ]
+}
+
+fn setup() -> (Orderbook, Arc<Mutex<Users>>) {
+    let mut users = Users::default();
+    for user_id in 1..=USERS_COUNT {
+        users.new_user(user_id);
+        users.deposit(&Asset::SOL, dec!(100), user_id).unwrap();
+        users.deposit(&Asset::USDT, dec!(10000), user_id).unwrap();
+    }
+    let users = Arc::new(Mutex::new(users));
+    let mut orderbook = Orderbook::new(Exchange::new(Asset::SOL, Asset::USDT));
+    orderbook.attach(users.clone(), Arc::new(NoEvents));
+    (orderbook, users)
+}
+fn supply(users: &Users) -> HashMap<Asset, Quantity> {
+    Asset::iter()
+        .map(|asset| {
+            let total = users.users
+                .keys()
+                .map(|user_id| *users.balance(&asset, *user_id).unwrap())
+                .sum();
+            (asset, total)
+        })
+        .collect()
+}
+
+// Locks and fills the way the engine does, returns the id and what was filled of accepted orders
+fn apply(orderbook: &mut Orderbook, action: Action) -> Option<(u64, Quantity)> {
+    let exchange = orderbook.exchange.clone();
+    let (user_id, order_side, price, quantity, order_type) = match action {
+        Action::Limit { user_id, order_side, price, quantity } =>
+            (user_id, order_side, price, quantity, OrderType::Limit),
+        Action::Market { user_id, order_side, quantity } =>
+            (user_id, order_side, dec!(0), quantity, OrderType::Market),
+        Action::Cancel { pick } => {
+            let open_orders: Vec<(Decimal, Id, OrderSide, u64)> = (1..=USERS_COUNT)
+                .flat_map(|user_id| {
+                    orderbook
+                        .get_open_orders(user_id)
+                        .into_iter()
+                        .map(|(price, order)| {
+                            (price, order.user_id, order.order_side.clone(), order.id)
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .collect();
+            if open_orders.is_empty() {
+                return None;
+            }
+            let (price, user_id, order_side, order_id) = open_orders
+                [pick % open_orders.len()].clone();
+            let order = orderbook.cancel_order(order_id, user_id, &order_side, &price).unwrap();
+            orderbook.unlock_cancelled(&order, &price);
+            return None;
+        }
+        Action::CancelAll { user_id } => {
+            orderbook.cancel_all_orders(user_id);
+            return None;
+        }
+    };
+    let mut order = RecievedOrder {
+        id: 0,
+        user_id: user_id as i64,
+        symbol: exchange.symbol.clone(),
+        price,
+        initial_quantity: quantity,
+        filled_quantity: dec!(0),
+        quote_quantity: price * quantity,
+        filled_quote_quantity: dec!(0),
+        order_type,
+        order_side,
+        order_status: OrderStatus::InProgress,
+        timestamp: 0,
+    };
+    let (_, locked_amount, _) = orderbook.lock_order(&order).ok()?;
+    let order_id = orderbook.increment_order_id();
+    order.id = order_id as i64;
+    let (filled_quantity, filled_quote_quantity, _) = orderbook.process_order(
+        order.clone(),
+        order_id
+    );
+    orderbook.release_unspent(&order, locked_amount, filled_quote_quantity);
+    Some((order_id, filled_quantity))
+}
+
+fn check_invariants(
+    orderbook: &Orderbook,
+    users: &Users,
+    initial_supply: &HashMap<Asset, Quantity>
+) {
+    let best_bid = orderbook.bids
+        .values()
+        .filter(|limit| !limit.orders.is_empty())
+        .map(|limit| limit.price)
+        .max();
+    let best_ask = orderbook.asks
+        .values()
+        .filter(|limit| !limit.orders.is_empty())
+        .map(|limit| limit.price)
+        .min();
+    if let (Some(best_bid), Some(best_ask)) = (best_bid, best_ask) {
+        assert!(best_bid < best_ask, "Book is crossed, bid {} ask {}", best_bid, best_ask);
+    }
+    for limit in orderbook.asks.values().chain(orderbook.bids.values()) {
+        for order in limit.orders.iter() {
+            assert!(order.quantity > dec!(0), "Order {} rests with nothing left", order.id);
+            assert!(order.quantity <= order.initial_quantity, "Order {} grew", order.id);
+        }
+    }
+
+    assert_eq!(&supply(users), initial_supply, "Total supply changed");
+    let locked_by_orders = locked_by_orders(orderbook);
+    for (user_id, user) in users.users.iter() {
+        for asset in Asset::iter() {
+            let balance = user.balance[&asset];
+            let locked = user.locked_balance[&asset];
+            assert!(locked >= dec!(0), "User {} has negative locked {}", user_id, asset);
+            assert!(locked <= balance, "User {} has more {} locked than owned", user_id, asset);
+        }
+        // Locks are reconciled after every fill, so they are exactly what rests in the book
+        let (base, quote) = locked_by_orders.get(user_id).copied().unwrap_or_default();
+        assert_eq!(user.locked_balance[&orderbook.exchange.base], base, "User {} base", user_id);
+        assert_eq!(user.locked_balance[&orderbook.exchange.quote], quote, "User {} quote", user_id);
+    }
+}
+
+proptest! {
+    #[test]
+    fn orderbook_and_balances_stay_consistent(actions in prop::collection::vec(action(), 1..60)) {
+        let (mut orderbook, users) = setup();
+        let initial_supply = supply(&users.lock().unwrap());
+        for action in actions {
+            let initial_quantity = match &action {
+                Action::Limit { quantity, .. } | Action::Market { quantity, .. } => *quantity,
+                _ => dec!(0),
+            };
+            let is_market = matches!(action, Action::Market { .. });
+            if let Some((order_id, filled_quantity)) = apply(&mut orderbook, action) {
+                let remaining: Quantity = orderbook.asks
+                    .values()
+                    .chain(orderbook.bids.values())
+                    .flat_map(|limit| limit.orders.iter())
+                    .filter(|order| order.id == order_id)
+                    .map(|order| order.quantity)
+                    .sum();
+                prop_assert_eq!(filled_quantity + remaining, initial_quantity);
+                if is_market {
+                    // market orders are only accepted when the book can fill them completely
+                    prop_assert_eq!(filled_quantity, initial_quantity);
+                }
+            }
+            check_invariants(&orderbook, &users.lock().unwrap(), &initial_supply);
+        }
+    }
+}
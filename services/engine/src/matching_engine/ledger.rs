@@ -0,0 +1,199 @@
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+use common::ledger::{ insert_entries, Account, LedgerEntry, LedgerKind, Movement };
+use rust_decimal_macros::dec;
+use scylla::Session;
+
+use super::{ perpetual::PerpLeg, Asset, Exchange, Id, OrderId, Price, Quantity, TradeId };
+
+// Ledger entries of the balance movements the engine makes, built where the request, order or
+// trade they belong to is known. Movements of nothing are not recorded.
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros() as u64
+}
+
+pub fn deposit(user_id: Id, asset: &Asset, amount: Quantity, request_id: u64) -> Vec<LedgerEntry> {
+    Movement::new(LedgerKind::Deposit, request_id, now()).transfer(
+        user_id,
+        asset.to_string(),
+        Account::External,
+        Account::Available,
+        amount
+    )
+}
+// A withdrawal locks its amount when requested, then it either leaves for External or goes
+// back to Available
+pub fn withdrawal(
+    user_id: Id,
+    asset: &Asset,
+    amount: Quantity,
+    withdrawal_id: Id,
+    from: Account,
+    to: Account
+) -> Vec<LedgerEntry> {
+    Movement::new(LedgerKind::Withdrawal, withdrawal_id, now()).transfer(
+        user_id,
+        asset.to_string(),
+        from,
+        to,
+        amount
+    )
+}
+pub fn lock(user_id: Id, asset: &Asset, amount: Quantity, order_id: OrderId) -> Vec<LedgerEntry> {
+    if amount <= dec!(0) {
+        return Vec::new();
+    }
+    Movement::new(LedgerKind::Lock, order_id, now()).transfer(
+        user_id,
+        asset.to_string(),
+        Account::Available,
+        Account::Locked,
+        amount
+    )
+}
+pub fn unlock(
+    user_id: Id,
+    asset: &Asset,
+    amount: Quantity,
+    order_id: OrderId,
+    timestamp: u64
+) -> Vec<LedgerEntry> {
+    if amount <= dec!(0) {
+        return Vec::new();
+    }
+    Movement::new(LedgerKind::Unlock, order_id, timestamp).transfer(
+        user_id,
+        asset.to_string(),
+        Account::Locked,
+        Account::Available,
+        amount
+    )
+}
+// Base leaves the seller's lock for the buyer and quote the buyer's lock for the seller
+pub fn trade(
+    exchange: &Exchange,
+    quantity: Quantity,
+    exchange_price: Price,
+    seller_id: Id,
+    buyer_id: Id,
+    trade_id: TradeId,
+    timestamp: u64
+) -> Vec<LedgerEntry> {
+    let base = exchange.base.to_string();
+    let quote = exchange.quote.to_string();
+    let quote_quantity = quantity * exchange_price;
+    Movement::new(LedgerKind::Trade, trade_id, timestamp).entries(
+        vec![
+            (seller_id, Account::Locked, base.clone(), -quantity),
+            (buyer_id, Account::Available, base, quantity),
+            (buyer_id, Account::Locked, quote.clone(), -quote_quantity),
+            (seller_id, Account::Available, quote, quote_quantity)
+        ]
+    )
+}
+pub fn transfer(
+    from: Id,
+    to: Id,
+    asset: &Asset,
+    amount: Quantity,
+    transfer_id: Id,
+    timestamp: u64
+) -> Vec<LedgerEntry> {
+    let asset = asset.to_string();
+    Movement::new(LedgerKind::Transfer, transfer_id, timestamp).entries(
+        vec![
+            (from, Account::Available, asset.clone(), -amount),
+            (to, Account::Available, asset, amount)
+        ]
+    )
+}
+// Positive amounts come in from External, negative ones go out to it
+pub fn adjustment(
+    user_id: Id,
+    asset: &Asset,
+    amount: Quantity,
+    adjustment_id: Id,
+    timestamp: u64
+) -> Vec<LedgerEntry> {
+    Movement::new(LedgerKind::Adjustment, adjustment_id, timestamp).transfer(
+        user_id,
+        asset.to_string(),
+        Account::External,
+        Account::Available,
+        amount
+    )
+}
+// Loans are drawn from Borrowed and repaid into it, interest adds to what is owed there
+pub fn borrow(
+    user_id: Id,
+    asset: &Asset,
+    amount: Quantity,
+    loan_id: Id,
+    timestamp: u64
+) -> Vec<LedgerEntry> {
+    Movement::new(LedgerKind::Borrow, loan_id, timestamp).transfer(
+        user_id,
+        asset.to_string(),
+        Account::Borrowed,
+        Account::Available,
+        amount
+    )
+}
+pub fn repay(
+    user_id: Id,
+    asset: &Asset,
+    amount: Quantity,
+    loan_id: Id,
+    timestamp: u64
+) -> Vec<LedgerEntry> {
+    Movement::new(LedgerKind::Repay, loan_id, timestamp).transfer(
+        user_id,
+        asset.to_string(),
+        Account::Available,
+        Account::Borrowed,
+        amount
+    )
+}
+pub fn interest(
+    user_id: Id,
+    asset: &Asset,
+    amount: Quantity,
+    hour: u64,
+    timestamp: u64
+) -> Vec<LedgerEntry> {
+    Movement::new(LedgerKind::Interest, hour, timestamp).transfer(
+        user_id,
+        asset.to_string(),
+        Account::Borrowed,
+        Account::Fees,
+        amount
+    )
+}
+// Perpetual fills move quote between the locks, position margins and balances of both sides,
+// the legs are built by the side that settles them
+pub fn perp_trade(legs: Vec<PerpLeg>, trade_id: TradeId, timestamp: u64) -> Vec<LedgerEntry> {
+    Movement::new(LedgerKind::Trade, trade_id, timestamp).entries(legs)
+}
+// Positive amounts are paid out of the position's margin, negative ones paid into it
+pub fn funding(
+    user_id: Id,
+    asset: &Asset,
+    amount: Quantity,
+    interval: u64,
+    timestamp: u64
+) -> Vec<LedgerEntry> {
+    Movement::new(LedgerKind::Funding, interval, timestamp).transfer(
+        user_id,
+        asset.to_string(),
+        Account::Position,
+        Account::Settlement,
+        amount
+    )
+}
+
+pub async fn persist_ledger(session: &Session, entries: Vec<LedgerEntry>) {
+    if let Err(err) = insert_entries(session, &entries).await {
+        eprintln!("Could not write {} ledger entries: {}", entries.len(), err);
+    }
+}
@@ -0,0 +1,600 @@
+use std::collections::HashMap;
+
+use common::{ ledger::LedgerEntry, numeric::Numeric };
+use rust_decimal::{ Decimal, RoundingStrategy };
+use rust_decimal_macros::dec;
+use scylla::{ transport::errors::QueryError, FromRow, Session };
+use serde::{ Deserialize, Serialize };
+use serde_json::{ from_str, to_string };
+use strum::IntoEnumIterator;
+
+use crate::PersistMargin;
+
+use super::{
+    error::MatchingEngineErrors,
+    invalid_value,
+    ledger,
+    orderbook::Orderbook,
+    Asset,
+    Exchange,
+    Id,
+    OrderSide,
+    Price,
+    Quantity,
+    RegisteredSymbols,
+    Symbol,
+    User,
+    Users,
+};
+
+// Every market is quoted in it, margin levels are valued in it
+pub const VALUATION_ASSET: Asset = Asset::USDT;
+pub const HOUR: u64 = 3_600_000_000;
+// Liquidation orders are sized for fills this far from the mark price
+const LIQUIDATION_SLIPPAGE: Decimal = dec!(0.02);
+
+// Cross margin backs loans with everything the account holds. Isolated margin backs them with
+// the base and quote of one market only, and the account trades nowhere else, positions are
+// kept apart by isolating them on sub-accounts.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MarginMode {
+    Cross,
+    Isolated(Symbol),
+}
+
+// A margin level is what an account holds over what it owes, both valued in VALUATION_ASSET
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MarginParams {
+    // Loans and withdrawals may not leave an account below it
+    pub initial_level: Decimal,
+    // Accounts below it are liquidated
+    pub maintenance_level: Decimal,
+    // Charged on what is borrowed of an asset at every hour
+    pub hourly_rates: HashMap<Asset, Decimal>,
+}
+impl Default for MarginParams {
+    fn default() -> MarginParams {
+        MarginParams {
+            initial_level: dec!(1.5),
+            maintenance_level: dec!(1.1),
+            hourly_rates: HashMap::from([
+                (Asset::USDT, dec!(0.000005)),
+                (Asset::BTC, dec!(0.000002)),
+                (Asset::ETH, dec!(0.000002)),
+                (Asset::SOL, dec!(0.00001)),
+            ]),
+        }
+    }
+}
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Margin {
+    #[serde(default)]
+    pub params: MarginParams,
+    // Every borrow and repayment is given one, the reference of its ledger entries
+    #[serde(default)]
+    pub last_loan_id: Id,
+    // Hours since the epoch, the last one interest was charged at
+    #[serde(default)]
+    pub last_accrual: u64,
+    // Rebuilt like open notionals, every market publishes its reference price when it starts
+    // and after each request that changes its book
+    #[serde(skip)]
+    pub prices: HashMap<Asset, Price>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Loan {
+    pub id: Id,
+    pub user_id: Id,
+    pub asset: Asset,
+    pub quantity: Quantity,
+    pub timestamp: u64,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MarginAccount {
+    pub user_id: Id,
+    pub mode: Option<MarginMode>,
+    // Values are unknown until every market the account holds has a mark price, and there is
+    // no level while nothing is owed
+    pub assets: Option<Quantity>,
+    pub liabilities: Option<Quantity>,
+    pub level: Option<Decimal>,
+    pub borrowed: HashMap<Asset, Quantity>,
+    pub interest: HashMap<Asset, Quantity>,
+}
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LiquidationOrder {
+    pub symbol: Symbol,
+    pub order_side: OrderSide,
+    pub quantity: Quantity,
+}
+
+fn margin_level(assets: Quantity, liabilities: Quantity) -> Option<Decimal> {
+    match liabilities > dec!(0) {
+        true => Some(assets / liabilities),
+        false => None,
+    }
+}
+// The market an asset is bought and sold against the valuation asset in
+fn valuation_market(asset: &Asset) -> Option<Symbol> {
+    let symbol = Exchange::new(*asset, VALUATION_ASSET).symbol;
+    RegisteredSymbols::iter()
+        .any(|registered| registered.to_string() == symbol)
+        .then_some(symbol)
+}
+
+impl User {
+    // Interest is owed on top of what was borrowed
+    pub fn debt(&self, asset: &Asset) -> Quantity {
+        let borrowed = self.borrowed.get(asset).copied().unwrap_or_default();
+        borrowed + self.interest.get(asset).copied().unwrap_or_default()
+    }
+    pub fn has_debt(&self) -> bool {
+        Asset::iter().any(|asset| self.debt(&asset) > dec!(0))
+    }
+    // What backs the account's loans, and what it may borrow
+    pub fn margin_assets(&self) -> Vec<Asset> {
+        match &self.margin {
+            Some(MarginMode::Isolated(symbol)) =>
+                Exchange::from_symbol(symbol.clone())
+                    .map(|exchange| vec![exchange.base, exchange.quote])
+                    .unwrap_or_default(),
+            _ => Asset::iter().collect(),
+        }
+    }
+}
+
+impl Users {
+    pub fn mark_price(&self, asset: &Asset) -> Option<Price> {
+        match *asset == VALUATION_ASSET {
+            true => Some(dec!(1)),
+            false => self.margin.prices.get(asset).copied(),
+        }
+    }
+    pub fn set_mark_price(&mut self, asset: &Asset, price: Price) {
+        self.margin.prices.insert(*asset, price);
+    }
+    pub fn next_loan_id(&mut self) -> Id {
+        self.margin.last_loan_id += 1;
+        self.margin.last_loan_id
+    }
+    // What the account holds and what it owes, locked funds included
+    fn margin_value(&self, user: &User) -> Result<(Quantity, Quantity), MatchingEngineErrors> {
+        let (mut assets, mut liabilities) = (dec!(0), dec!(0));
+        for asset in user.margin_assets() {
+            let held = user.balance.get(&asset).copied().unwrap_or_default();
+            let owed = user.debt(&asset);
+            if held.is_zero() && owed.is_zero() {
+                continue;
+            }
+            let price = self.mark_price(&asset).ok_or(MatchingEngineErrors::MarkPriceUnavailable)?;
+            assets += held * price;
+            liabilities += owed * price;
+        }
+        Ok((assets, liabilities))
+    }
+    // The level the account is left at once `held` and `owed` of the asset are added
+    fn level_after(
+        &self,
+        user: &User,
+        asset: &Asset,
+        held: Quantity,
+        owed: Quantity
+    ) -> Result<Option<Decimal>, MatchingEngineErrors> {
+        let (assets, liabilities) = self.margin_value(user)?;
+        let price = self.mark_price(asset).ok_or(MatchingEngineErrors::MarkPriceUnavailable)?;
+        Ok(margin_level(assets + held * price, liabilities + owed * price))
+    }
+    pub fn margin_account(&self, user_id: Id) -> Result<MarginAccount, MatchingEngineErrors> {
+        let user = self.users.get(&user_id).ok_or(MatchingEngineErrors::UserNotFound)?;
+        let value = self.margin_value(user).ok();
+        Ok(MarginAccount {
+            user_id,
+            mode: user.margin.clone(),
+            assets: value.map(|(assets, _)| assets),
+            liabilities: value.map(|(_, liabilities)| liabilities),
+            level: value.and_then(|(assets, liabilities)| margin_level(assets, liabilities)),
+            borrowed: user.borrowed.clone(),
+            interest: user.interest.clone(),
+        })
+    }
+    // Without a mode margin is turned off, the mode only changes while nothing is owed
+    pub fn set_margin_mode(
+        &mut self,
+        user_id: Id,
+        mode: Option<MarginMode>
+    ) -> Result<MarginAccount, MatchingEngineErrors> {
+        if let Some(MarginMode::Isolated(symbol)) = &mode {
+            if !RegisteredSymbols::iter().any(|registered| registered.to_string() == *symbol) {
+                return Err(MatchingEngineErrors::InvalidMarginMode);
+            }
+            // perpetual positions are margined on their own
+            if Exchange::from_symbol(symbol.clone()).is_ok_and(|exchange| exchange.is_perpetual()) {
+                return Err(MatchingEngineErrors::InvalidMarginMode);
+            }
+        }
+        let user = self.users.get_mut(&user_id).ok_or(MatchingEngineErrors::UserNotFound)?;
+        if user.has_debt() {
+            return Err(MatchingEngineErrors::OutstandingDebt);
+        }
+        user.margin = mode;
+        self.margin_account(user_id)
+    }
+    pub fn borrow(&mut self, loan: &Loan) -> Result<Vec<LedgerEntry>, MatchingEngineErrors> {
+        let Loan { id, user_id, asset, quantity, timestamp } = *loan;
+        if quantity <= dec!(0) {
+            return Err(MatchingEngineErrors::InvalidLoan);
+        }
+        let user = self.users.get(&user_id).ok_or(MatchingEngineErrors::UserNotFound)?;
+        if user.frozen {
+            return Err(MatchingEngineErrors::AccountFrozen);
+        }
+        if user.margin.is_none() {
+            return Err(MatchingEngineErrors::MarginNotEnabled);
+        }
+        if !user.margin_assets().contains(&asset) {
+            return Err(MatchingEngineErrors::InvalidLoan);
+        }
+        let level = self.level_after(user, &asset, quantity, quantity)?;
+        if level.is_some_and(|level| level < self.margin.params.initial_level) {
+            return Err(MatchingEngineErrors::MarginLevelTooLow);
+        }
+        let user = self.users.get_mut(&user_id).unwrap();
+        *user.balance.entry(asset).or_default() += quantity;
+        *user.borrowed.entry(asset).or_default() += quantity;
+        Ok(ledger::borrow(user_id, &asset, quantity, id, timestamp))
+    }
+    // Interest is paid off before what was borrowed, and no more than is owed is repaid
+    pub fn repay(&mut self, loan: &Loan) -> Result<Vec<LedgerEntry>, MatchingEngineErrors> {
+        let Loan { id, user_id, asset, quantity, timestamp } = *loan;
+        if quantity <= dec!(0) {
+            return Err(MatchingEngineErrors::InvalidLoan);
+        }
+        let available = self.available_balance(&asset, user_id)?;
+        let user = self.users.get_mut(&user_id).unwrap();
+        let owed = user.debt(&asset);
+        if owed.is_zero() {
+            return Err(MatchingEngineErrors::NothingToRepay);
+        }
+        let amount = quantity.min(owed);
+        if available < amount {
+            return Err(MatchingEngineErrors::InsufficientBalance);
+        }
+        let interest = user.interest.entry(asset).or_default();
+        let paid_interest = amount.min(*interest);
+        *interest -= paid_interest;
+        *user.borrowed.entry(asset).or_default() -= amount - paid_interest;
+        *user.balance.entry(asset).or_default() -= amount;
+        Ok(ledger::repay(user_id, &asset, amount, id, timestamp))
+    }
+    // Charged once at every hour on what is borrowed then, an hour that was charged is not
+    // charged again. Hours missed while the engine was down are charged on what was borrowed
+    // through them, each with its own entry. Returns the entries of every account charged.
+    pub fn accrue_interest(&mut self, hour: u64, timestamp: u64) -> Vec<(Id, Vec<LedgerEntry>)> {
+        if hour <= self.margin.last_accrual {
+            return Vec::new();
+        }
+        // nothing was ever charged before the first hour
+        let first_hour = match self.margin.last_accrual {
+            0 => hour,
+            last_accrual => last_accrual + 1,
+        };
+        self.margin.last_accrual = hour;
+        let rates = &self.margin.params.hourly_rates;
+        let mut charged = Vec::new();
+        for user in self.users.values_mut() {
+            let mut entries = Vec::new();
+            for (asset, borrowed) in user.borrowed.iter() {
+                let amount = borrowed * rates.get(asset).copied().unwrap_or_default();
+                if amount <= dec!(0) {
+                    continue;
+                }
+                for charged in first_hour..=hour {
+                    *user.interest.entry(*asset).or_default() += amount;
+                    entries.extend(ledger::interest(user.id, asset, amount, charged, timestamp));
+                }
+            }
+            if !entries.is_empty() {
+                charged.push((user.id, entries));
+            }
+        }
+        charged
+    }
+    // Withdrawals and transfers out of an account that owes may not leave it below the
+    // initial level
+    pub fn check_margin_withdrawal(
+        &self,
+        user_id: Id,
+        asset: &Asset,
+        quantity: Quantity
+    ) -> Result<(), MatchingEngineErrors> {
+        let Some(user) = self.users.get(&user_id) else {
+            return Ok(());
+        };
+        if !user.has_debt() || !user.margin_assets().contains(asset) {
+            return Ok(());
+        }
+        let level = self.level_after(user, asset, -quantity, dec!(0))?;
+        match level.is_some_and(|level| level < self.margin.params.initial_level) {
+            true => Err(MatchingEngineErrors::MarginLevelTooLow),
+            false => Ok(()),
+        }
+    }
+    pub fn check_margin_market(
+        &self,
+        user_id: Id,
+        symbol: &Symbol
+    ) -> Result<(), MatchingEngineErrors> {
+        match self.users.get(&user_id).and_then(|user| user.margin.as_ref()) {
+            Some(MarginMode::Isolated(isolated)) if isolated != symbol => {
+                Err(MatchingEngineErrors::MarginAccountIsolated)
+            }
+            _ => Ok(()),
+        }
+    }
+    // Accounts holding something without a mark price are left until it has one
+    pub fn below_maintenance(&self) -> Vec<Id> {
+        let maintenance = self.margin.params.maintenance_level;
+        let mut accounts: Vec<Id> = self.users
+            .values()
+            .filter(|user| user.has_debt())
+            .filter(|user| {
+                self.margin_value(user)
+                    .ok()
+                    .and_then(|(assets, liabilities)| margin_level(assets, liabilities))
+                    .is_some_and(|level| level < maintenance)
+            })
+            .map(|user| user.id)
+            .collect();
+        accounts.sort();
+        accounts
+    }
+    // What can be repaid right away out of what is available
+    pub fn repayments(&self, user_id: Id) -> Vec<(Asset, Quantity)> {
+        let Some(user) = self.users.get(&user_id) else {
+            return Vec::new();
+        };
+        Asset::iter()
+            .filter_map(|asset| {
+                let available = self.available_balance(&asset, user_id).unwrap_or_default();
+                let amount = user.debt(&asset).min(available);
+                (amount > dec!(0)).then_some((asset, amount))
+            })
+            .collect()
+    }
+    // Market orders bringing in what is still owed once what is available is repaid. Assets
+    // that are owed are bought with the valuation asset, and assets that are not are sold for
+    // what is owed of it and what the buys cost. A buy with nothing to pay for it yet waits for
+    // the sells, the monitor comes back to the account until it is above maintenance.
+    pub fn liquidation_orders(&self, user_id: Id) -> Vec<LiquidationOrder> {
+        let Some(user) = self.users.get(&user_id) else {
+            return Vec::new();
+        };
+        let available = |asset: &Asset| self.available_balance(asset, user_id).unwrap_or_default();
+        let owed = |asset: &Asset| (user.debt(asset) - available(asset)).max(dec!(0));
+        let spare = |asset: &Asset| (available(asset) - user.debt(asset)).max(dec!(0));
+        let priced = |asset: &Asset| {
+            let symbol = match &user.margin {
+                Some(MarginMode::Isolated(symbol)) => Some(symbol.clone()),
+                _ => valuation_market(asset),
+            };
+            symbol.zip(self.mark_price(asset))
+        };
+        let others: Vec<Asset> = user
+            .margin_assets()
+            .into_iter()
+            .filter(|asset| *asset != VALUATION_ASSET)
+            .collect();
+        let mut orders = Vec::new();
+        let mut to_spend = spare(&VALUATION_ASSET);
+        let mut to_raise = owed(&VALUATION_ASSET);
+        for asset in others.iter() {
+            let Some((symbol, price)) = priced(asset) else {
+                continue;
+            };
+            let cost = price * (dec!(1) + LIQUIDATION_SLIPPAGE);
+            let owed = owed(asset);
+            let quantity = owed
+                .min(to_spend / cost)
+                .round_dp_with_strategy(8, RoundingStrategy::ToZero);
+            to_spend -= quantity * cost;
+            to_raise += (owed - quantity) * cost;
+            if quantity > dec!(0) {
+                orders.push(LiquidationOrder { symbol, order_side: OrderSide::Bid, quantity });
+            }
+        }
+        for asset in others.iter() {
+            let Some((symbol, price)) = priced(asset) else {
+                continue;
+            };
+            if to_raise <= dec!(0) {
+                break;
+            }
+            let proceeds = price * (dec!(1) - LIQUIDATION_SLIPPAGE);
+            let quantity = (to_raise / proceeds)
+                .round_dp_with_strategy(8, RoundingStrategy::AwayFromZero)
+                .min(spare(asset));
+            to_raise -= quantity * proceeds;
+            if quantity > dec!(0) {
+                orders.push(LiquidationOrder { symbol, order_side: OrderSide::Ask, quantity });
+            }
+        }
+        orders
+    }
+}
+
+impl Orderbook {
+    // Perpetual books only publish their own price, funding compares it to the spot mark
+    pub fn publish_mark_price(&self) {
+        let Some(price) = self.reference_price() else {
+            return;
+        };
+        match self.exchange.is_perpetual() {
+            true => self.balances.set_perp_price(&self.exchange.symbol, price),
+            false if self.exchange.quote == VALUATION_ASSET => {
+                self.balances.set_mark_price(&self.exchange.base, price);
+            }
+            false => {}
+        }
+    }
+}
+
+// The margin columns of user_table, a user who never used margin has none of them
+#[derive(Debug, Clone, FromRow)]
+pub struct ScyllaMargin {
+    pub id: i64,
+    pub borrowed: Option<HashMap<String, Numeric>>,
+    pub interest: Option<HashMap<String, Numeric>>,
+    pub margin_mode: Option<String>,
+}
+impl ScyllaMargin {
+    pub fn recover(self, users: &mut Users) -> Result<(), QueryError> {
+        let Some(user) = users.users.get_mut(&(self.id as u64)) else {
+            return Ok(());
+        };
+        let from_numerics = |column: &str, quantities: Option<HashMap<String, Numeric>>| {
+            quantities
+                .unwrap_or_default()
+                .iter()
+                .map(|(asset, quantity)| {
+                    let asset = Asset::from_str(asset).ok_or_else(|| {
+                        invalid_value("user_table", column, asset)
+                    })?;
+                    Ok((asset, quantity.0))
+                })
+                .collect::<Result<_, QueryError>>()
+        };
+        user.borrowed = from_numerics("borrowed", self.borrowed)?;
+        user.interest = from_numerics("interest", self.interest)?;
+        user.margin = self.margin_mode.and_then(|mode| from_str(&mode).ok());
+        Ok(())
+    }
+}
+// Writes what the account owes and its mode, with the balance of the asset a loan moved
+pub async fn persist_margin(session: &Session, persist: PersistMargin) {
+    let update_margin =
+        r#"
+        UPDATE keyspace_1.user_table USING TIMESTAMP ?
+        SET
+            borrowed = ?,
+            interest = ?,
+            margin_mode = ?
+        WHERE id = ?;
+    "#;
+    let update_balance =
+        r#"
+        UPDATE keyspace_1.user_table USING TIMESTAMP ?
+        SET
+            balance[?] = ?
+        WHERE id = ?;
+    "#;
+    let user = persist.user;
+    let to_numerics = |quantities: &HashMap<Asset, Quantity>| -> HashMap<String, Numeric> {
+        quantities
+            .iter()
+            .map(|(asset, quantity)| (asset.to_string(), Numeric(*quantity)))
+            .collect()
+    };
+    let mode = user.margin.as_ref().map(|mode| to_string(mode).unwrap());
+    let (user_id, stamp) = (user.id as i64, persist.stamp);
+    let (borrowed, interest) = (to_numerics(&user.borrowed), to_numerics(&user.interest));
+    let res = session.query(update_margin, (stamp, borrowed, interest, mode, user_id)).await;
+    if let Err(err) = res {
+        eprintln!("Could not persist margin of {}: {}", user_id, err);
+    }
+    if let Some(asset) = persist.asset {
+        let balance = Numeric(user.balance.get(&asset).copied().unwrap_or_default());
+        let res = session.query(update_balance, (stamp, asset.to_string(), balance, user_id)).await;
+        if let Err(err) = res {
+            eprintln!("Could not persist balance of {}: {}", user_id, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn loan(id: Id, asset: Asset, quantity: Quantity) -> Loan {
+        Loan { id, user_id: 1, asset, quantity, timestamp: 0 }
+    }
+    fn margin_user(mode: MarginMode) -> Users {
+        let mut users = Users::default();
+        users.new_user(1);
+        users.deposit(&Asset::USDT, dec!(1000), 1).unwrap();
+        users.set_margin_mode(1, Some(mode)).unwrap();
+        users.set_mark_price(&Asset::SOL, dec!(100));
+        users
+    }
+
+    #[test]
+    fn loans_keep_accounts_above_the_initial_level() {
+        let mut users = margin_user(MarginMode::Cross);
+        assert!(matches!(
+            users.borrow(&loan(1, Asset::SOL, dec!(21))),
+            Err(MatchingEngineErrors::MarginLevelTooLow)
+        ));
+        users.borrow(&loan(1, Asset::SOL, dec!(20))).unwrap();
+        assert_eq!(users.margin_account(1).unwrap().level, Some(dec!(1.5)));
+        assert!(matches!(
+            users.check_margin_withdrawal(1, &Asset::USDT, dec!(1)),
+            Err(MatchingEngineErrors::MarginLevelTooLow)
+        ));
+        assert!(matches!(
+            users.set_margin_mode(1, None),
+            Err(MatchingEngineErrors::OutstandingDebt)
+        ));
+
+        users.accrue_interest(1, 0);
+        users.accrue_interest(1, 0);
+        assert_eq!(users.users[&1].interest[&Asset::SOL], dec!(0.0002));
+        users.repay(&loan(2, Asset::SOL, dec!(0.0001))).unwrap();
+        assert_eq!(users.users[&1].interest[&Asset::SOL], dec!(0.0001));
+        assert!(matches!(
+            users.repay(&loan(3, Asset::SOL, dec!(50))),
+            Err(MatchingEngineErrors::InsufficientBalance)
+        ));
+        users.deposit(&Asset::SOL, dec!(1), 1).unwrap();
+        users.repay(&loan(3, Asset::SOL, dec!(50))).unwrap();
+        assert!(!users.users[&1].has_debt());
+        assert_eq!(users.balance(&Asset::SOL, 1).unwrap(), &dec!(0.9998));
+    }
+    #[test]
+    fn hours_missed_are_charged_when_the_engine_is_back() {
+        let mut users = margin_user(MarginMode::Cross);
+        users.borrow(&loan(1, Asset::SOL, dec!(20))).unwrap();
+        users.accrue_interest(10, 0);
+        users.accrue_interest(13, 0);
+        let charged = users.accrue_interest(15, 0);
+        assert_eq!(users.users[&1].interest[&Asset::SOL], dec!(0.0012));
+        let hours: Vec<u64> = charged[0].1
+            .iter()
+            .map(|entry| entry.reference)
+            .collect();
+        assert!(hours.contains(&14) && hours.contains(&15));
+        assert!(users.accrue_interest(15, 0).is_empty());
+    }
+    #[test]
+    fn accounts_below_maintenance_are_liquidated() {
+        let mut users = margin_user(MarginMode::Isolated("SOL_USDT".to_string()));
+        users.borrow(&loan(1, Asset::USDT, dec!(1000))).unwrap();
+        // spent on 20 SOL at 100
+        users.withdraw(&Asset::USDT, dec!(2000), 1).unwrap();
+        users.deposit(&Asset::SOL, dec!(20), 1).unwrap();
+        assert!(users.below_maintenance().is_empty());
+
+        users.set_mark_price(&Asset::SOL, dec!(54));
+        assert_eq!(users.below_maintenance(), vec![1]);
+        assert!(users.repayments(1).is_empty());
+        let orders = users.liquidation_orders(1);
+        assert_eq!(orders, vec![LiquidationOrder {
+            symbol: "SOL_USDT".to_string(),
+            order_side: OrderSide::Ask,
+            quantity: dec!(18.89644747),
+        }]);
+        assert!(matches!(
+            users.check_margin_market(1, &"BTC_USDT".to_string()),
+            Err(MatchingEngineErrors::MarginAccountIsolated)
+        ));
+    }
+}
@@ -6,6 +6,18 @@ use std::{
     sync::{ atomic::{ AtomicU64, Ordering }, Arc, Mutex },
 };
 use enum_stringify::EnumStringify;
+use common::{
+    buckets::bucket,
+    ids,
+    ledger::LedgerEntry,
+    numeric::Numeric,
+    query_tables::{
+        CANCEL_ORDER_BY_MARKET_STATEMENT,
+        ORDER_BY_MARKET_STATEMENT,
+        ORDER_BY_USER_STATEMENT,
+        USER_SYMBOL_STATEMENT,
+    },
+};
 use once_cell::sync::Lazy;
 use rust_decimal::Decimal;
 use scylla::{ batch::Batch, transport::errors::QueryError, FromRow, SerializeRow, Session };
@@ -13,31 +25,132 @@ use serde::{ Deserialize, Serialize };
 use strum::IntoEnumIterator;
 use strum_macros::{ EnumIter, EnumString };
 
-use crate::{ handle_order_request::CancelOrder, PersistCancel, PersistCancelAll };
+use balances::Locked;
+use custody::Deposits;
+use margin::{ Margin, MarginMode };
+use perpetual::{ Perpetuals, Position, INITIAL_MARGIN };
+use risk::{ RiskLimits, RiskState };
+use withdrawal::Withdrawals;
+
+use crate::{ handle_order_request::CancelOrder, PersistCancel, PersistCancelAll, PersistUnlock };
+pub use common::{ OrderSide, OrderStatus };
 pub mod orderbook;
 pub mod engine;
 pub mod error;
 pub mod user;
+pub mod balances;
+pub mod events;
+pub mod ledger;
+pub mod withdrawal;
+pub mod custody;
+pub mod accounts;
+pub mod admin;
+pub mod risk;
+pub mod price_band;
+pub mod margin;
+pub mod perpetual;
+pub mod portfolio;
+pub mod ticker;
+#[cfg(test)]
+mod invariants;
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct User {
     pub id: Id,
     pub balance: HashMap<Asset, Quantity>,
     pub locked_balance: HashMap<Asset, Quantity>,
+    // Set on sub-accounts, the account they belong to
+    #[serde(default)]
+    pub master_id: Option<Id>,
+    #[serde(default)]
+    pub frozen: bool,
+    // Set once the account trades on margin
+    #[serde(default)]
+    pub margin: Option<MarginMode>,
+    #[serde(default)]
+    pub borrowed: HashMap<Asset, Quantity>,
+    // Charged on what is borrowed and not yet repaid
+    #[serde(default)]
+    pub interest: HashMap<Asset, Quantity>,
+    // Open positions in perpetual markets, by symbol
+    #[serde(default)]
+    pub positions: HashMap<Symbol, Position>,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct Users {
     pub users: HashMap<Id, User>,
+    // Last id handed out, recovered from the snapshot and the users journal
+    #[serde(default)]
+    pub last_user_id: Id,
+    #[serde(default)]
+    pub withdrawals: Withdrawals,
+    #[serde(default)]
+    pub deposits: Deposits,
+    #[serde(default)]
+    pub last_transfer_id: Id,
+    #[serde(default)]
+    pub last_adjustment_id: Id,
+    // Only accounts whose limits were changed from the defaults
+    #[serde(default)]
+    pub risk_limits: HashMap<Id, RiskLimits>,
+    #[serde(skip)]
+    pub risk: RiskState,
+    #[serde(default)]
+    pub margin: Margin,
+    #[serde(default)]
+    pub perpetuals: Perpetuals,
+    // Last stamp handed to a balance write, see `Users::stamp`
+    #[serde(skip)]
+    pub balance_clock: i64,
+}
+
+// Counters of the user thread are written along with what they count, recovery without a
+// snapshot reads them back
+pub const SET_LAST_ID: &str =
+    r#"
+    UPDATE keyspace_1.last_id_table USING TIMESTAMP ? SET id = ? WHERE name = ?;
+"#;
+pub const WITHDRAWAL_ID: &str = "withdrawal";
+pub const TRANSFER_ID: &str = "transfer";
+pub const ADJUSTMENT_ID: &str = "adjustment";
+
+// The error of a text column that holds none of the values it can take, recovery stops on it
+// instead of panicking
+pub fn invalid_value(table: &str, column: &str, value: &str) -> QueryError {
+    QueryError::InvalidMessage(format!("{}.{} holds an invalid value {:?}", table, column, value))
 }
 
 pub static USERS: Lazy<Mutex<Users>> = Lazy::new(|| {
     Mutex::new(Users {
         users: HashMap::new(),
+        last_user_id: ids::base(ids::USERS_NAMESPACE),
+        withdrawals: Withdrawals::default(),
+        deposits: Deposits::default(),
+        last_transfer_id: 0,
+        last_adjustment_id: 0,
+        risk_limits: HashMap::new(),
+        risk: RiskState::default(),
+        margin: Margin::default(),
+        perpetuals: Perpetuals::default(),
+        balance_clock: 0,
     })
 });
 
-#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, EnumIter, Serialize, Deserialize, EnumStringify)]
+#[derive(
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Clone,
+    Copy,
+    EnumIter,
+    Serialize,
+    Deserialize,
+    EnumStringify
+)]
 pub enum Asset {
     USDT,
     BTC,
@@ -78,33 +191,41 @@ impl OrderType {
         Err(())
     }
 }
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, EnumStringify)]
-pub enum OrderSide {
-    Bid,
-    Ask,
-}
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, EnumIter, Serialize, Deserialize, EnumStringify)]
 pub enum RegisteredSymbols {
     SOL_USDT,
     BTC_USDT,
     ETH_USDT,
+    BTC_USDT_PERP,
+}
+impl RegisteredSymbols {
+    // The ids of a market's orders and trades are built from its namespace, so it is fixed for
+    // good once the market traded. A new market takes the next unused one, never a reused one.
+    pub fn id_namespace(&self) -> u16 {
+        match self {
+            RegisteredSymbols::SOL_USDT => 1,
+            RegisteredSymbols::BTC_USDT => 2,
+            RegisteredSymbols::ETH_USDT => 3,
+            RegisteredSymbols::BTC_USDT_PERP => 4,
+        }
+    }
 }
 #[derive(Debug, Clone, Deserialize, Serialize, SerializeRow, FromRow)]
 pub struct ScyllaUser {
     pub id: i64,
-    pub balance: HashMap<String, String>,
-    pub locked_balance: HashMap<String, String>,
+    pub balance: HashMap<String, Numeric>,
+    pub locked_balance: HashMap<String, Numeric>,
 }
 #[derive(Debug, Deserialize, Serialize, SerializeRow, FromRow)]
 pub struct ScyllaOrder {
     pub id: i64,
     pub user_id: i64,
     pub symbol: String,
-    pub price: String,
-    pub initial_quantity: String,
-    pub filled_quantity: String,
-    pub quote_quantity: String,
-    pub filled_quote_quantity: String,
+    pub price: Numeric,
+    pub initial_quantity: Numeric,
+    pub filled_quantity: Numeric,
+    pub quote_quantity: Numeric,
+    pub filled_quote_quantity: Numeric,
     pub order_type: String,
     pub order_side: String,
     pub order_status: String,
@@ -116,7 +237,7 @@ pub struct ScyllaCancelOrder {
     pub user_id: i64,
     pub order_side: String,
     pub symbol: String,
-    pub price: String,
+    pub price: Numeric,
     pub timestamp: i64,
 }
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -135,25 +256,6 @@ pub struct RecievedOrder {
     pub timestamp: i64,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize, EnumIter, EnumStringify)]
-pub enum OrderStatus {
-    InProgress,
-    Filled,
-    PartiallyFilled,
-    Cancelled,
-}
-impl OrderStatus {
-    pub fn from_str(asset_to_match: &str) -> Result<Self, ()> {
-        for asset in OrderStatus::iter() {
-            let current_asset = asset.to_string();
-            if asset_to_match.to_string() == current_asset {
-                return Ok(asset);
-            }
-        }
-        Err(())
-    }
-}
-
 impl RecievedOrder {
     fn to_scylla_order(&self) -> ScyllaOrder {
         ScyllaOrder {
@@ -161,11 +263,11 @@ impl RecievedOrder {
             timestamp: self.timestamp,
             user_id: self.user_id,
             symbol: self.symbol.to_string(),
-            filled_quantity: self.filled_quantity.to_string(),
-            filled_quote_quantity: self.filled_quote_quantity.to_string(),
-            quote_quantity: self.quote_quantity.to_string(),
-            price: self.price.to_string(),
-            initial_quantity: self.initial_quantity.to_string(),
+            filled_quantity: self.filled_quantity.into(),
+            filled_quote_quantity: self.filled_quote_quantity.into(),
+            quote_quantity: self.quote_quantity.into(),
+            price: self.price.into(),
+            initial_quantity: self.initial_quantity.into(),
             order_side: self.order_side.to_string(),
             order_status: self.order_status.to_string(),
             order_type: self.order_type.to_string(),
@@ -173,20 +275,9 @@ impl RecievedOrder {
     }
 }
 pub async fn persist_order_cancel_all(session: &Session, cancel_order: PersistCancelAll) {
-    let new_cance_order =
-        r#"
-        INSERT INTO keyspace_1.cancel_order_table (
-            id,
-            user_id,
-            order_side,
-            symbol,
-            price,
-            timestamp
-        ) VALUES (?, ?, ?, ?, ?, ?);
-        "#;
     let unlock_balance =
         r#"
-        UPDATE keyspace_1.user_table 
+        UPDATE keyspace_1.user_table USING TIMESTAMP ?
         SET
             locked_balance = ?
         WHERE id = ?;
@@ -200,13 +291,14 @@ pub async fn persist_order_cancel_all(session: &Session, cancel_order: PersistCa
         "#;
     for data in cancel_order.data {
         session
-            .query(new_cance_order, (
+            .query(CANCEL_ORDER_BY_MARKET_STATEMENT, (
                 data.id as i64,
                 cancel_order.user_id as i64,
                 data.order_side.to_string(),
                 cancel_order.symbol.clone(),
-                data.price.to_string(),
+                Numeric(data.price),
                 cancel_order.timestamp,
+                bucket(cancel_order.timestamp),
             )).await
             .unwrap();
         session
@@ -218,24 +310,17 @@ pub async fn persist_order_cancel_all(session: &Session, cancel_order: PersistCa
             .unwrap();
     }
     session
-        .query(unlock_balance, (cancel_order.locked_balances, cancel_order.user_id as i64)).await
+        .query(unlock_balance, (
+            cancel_order.stamp,
+            cancel_order.locked_balances,
+            cancel_order.user_id as i64,
+        )).await
         .unwrap();
 }
 pub async fn persist_order_cancel(session: &Session, cancel_order: PersistCancel) {
-    let new_cance_order =
-        r#"
-        INSERT INTO keyspace_1.cancel_order_table (
-            id,
-            user_id,
-            order_side,
-            symbol,
-            price,
-            timestamp
-        ) VALUES (?, ?, ?, ?, ?, ?);
-        "#;
     let unlock_balance =
         r#"
-        UPDATE keyspace_1.user_table 
+        UPDATE keyspace_1.user_table USING TIMESTAMP ?
         SET
             locked_balance[?] = ?
         WHERE id = ?;
@@ -248,7 +333,7 @@ pub async fn persist_order_cancel(session: &Session, cancel_order: PersistCancel
         WHERE id = ? AND symbol = ?;
         "#;
     let mut batch: Batch = Default::default();
-    batch.append_statement(new_cance_order);
+    batch.append_statement(CANCEL_ORDER_BY_MARKET_STATEMENT);
     batch.append_statement(unlock_balance);
     batch.append_statement(update_order_status);
     let prepared_batch: Batch = session.prepare_batch(&batch).await.unwrap();
@@ -258,23 +343,42 @@ pub async fn persist_order_cancel(session: &Session, cancel_order: PersistCancel
                 cancel_order.id as i64,
                 cancel_order.user_id as i64,
                 cancel_order.order_side.to_string(),
-                cancel_order.symbol,
-                cancel_order.price.to_string(),
+                cancel_order.symbol.clone(),
+                Numeric(cancel_order.price),
                 cancel_order.timestamp,
+                bucket(cancel_order.timestamp),
             ),
             (
+                cancel_order.updated_locked_balance.stamp,
                 cancel_order.asset.to_string(),
-                cancel_order.updated_locked_balance.to_string(),
+                Numeric(cancel_order.updated_locked_balance.total),
                 cancel_order.user_id as i64,
             ),
-            (OrderStatus::Cancelled.to_string(), cancel_order.id as i64),
+            (OrderStatus::Cancelled.to_string(), cancel_order.id as i64, cancel_order.symbol),
+        )).await
+        .unwrap();
+}
+pub async fn persist_unlock(session: &Session, unlock: PersistUnlock) {
+    let unlock_balance =
+        r#"
+        UPDATE keyspace_1.user_table USING TIMESTAMP ?
+        SET
+            locked_balance[?] = ?
+        WHERE id = ?;
+        "#;
+    session
+        .query(unlock_balance, (
+            unlock.updated_locked_balance.stamp,
+            unlock.asset.to_string(),
+            Numeric(unlock.updated_locked_balance.total),
+            unlock.user_id as i64,
         )).await
         .unwrap();
 }
 pub async fn new_order(
     session: &Session,
     order: RecievedOrder,
-    locked_balance: Quantity,
+    locked_balance: Locked,
     lock_asset: Asset
 ) {
     let new_order =
@@ -296,7 +400,7 @@ pub async fn new_order(
     "#;
     let lock_balance =
         r#"
-        UPDATE keyspace_1.user_table 
+        UPDATE keyspace_1.user_table USING TIMESTAMP ?
         SET
             locked_balance[?] = ?
         WHERE id = ?;
@@ -304,23 +408,57 @@ pub async fn new_order(
     let mut batch: Batch = Default::default();
     batch.append_statement(new_order);
     batch.append_statement(lock_balance);
+    // The order is also found by its user and by its market's day, as placed
+    batch.append_statement(ORDER_BY_USER_STATEMENT);
+    batch.append_statement(USER_SYMBOL_STATEMENT);
+    batch.append_statement(ORDER_BY_MARKET_STATEMENT);
     let prepared_batch: Batch = session.prepare_batch(&batch).await.unwrap();
     let order_value = order.to_scylla_order();
-    let user_value = (lock_asset.to_string(), locked_balance.to_string(), order.user_id as i64);
-    session.batch(&prepared_batch, (order_value, user_value)).await.unwrap();
+    let user_value = (
+        locked_balance.stamp,
+        lock_asset.to_string(),
+        Numeric(locked_balance.total),
+        order.user_id as i64,
+    );
+    let by_user_value = (order_value.user_id, &order_value.symbol, order.timestamp, order_value.id);
+    let user_symbol_value = (order_value.user_id, &order_value.symbol);
+    let by_market_value = (
+        order_value.id,
+        order_value.user_id,
+        &order_value.symbol,
+        &order_value.price,
+        &order_value.initial_quantity,
+        &order_value.filled_quantity,
+        &order_value.quote_quantity,
+        &order_value.filled_quote_quantity,
+        &order_value.order_type,
+        &order_value.order_side,
+        &order_value.order_status,
+        order_value.timestamp,
+        bucket(order_value.timestamp),
+    );
+    session
+        .batch(&prepared_batch, (
+            &order_value,
+            user_value,
+            by_user_value,
+            user_symbol_value,
+            by_market_value,
+        )).await
+        .unwrap();
 }
 impl ScyllaOrder {
-    fn from_scylla_order(&self) -> RecievedOrder {
+    pub fn from_scylla_order(&self) -> RecievedOrder {
         RecievedOrder {
             id: self.id,
             timestamp: self.timestamp,
             user_id: self.user_id,
             symbol: self.symbol.to_string(),
-            filled_quote_quantity: Decimal::from_str(&self.filled_quote_quantity).unwrap(),
-            quote_quantity: Decimal::from_str(&self.quote_quantity).unwrap(),
-            filled_quantity: Decimal::from_str(&self.filled_quantity).unwrap(),
-            price: Decimal::from_str(&self.price).unwrap(),
-            initial_quantity: Decimal::from_str(&self.initial_quantity).unwrap(),
+            filled_quote_quantity: self.filled_quote_quantity.into(),
+            quote_quantity: self.quote_quantity.into(),
+            filled_quantity: self.filled_quantity.into(),
+            price: self.price.into(),
+            initial_quantity: self.initial_quantity.into(),
             order_side: OrderSide::from_str(&self.order_side).unwrap(),
             order_status: OrderStatus::from_str(&self.order_status).unwrap(),
             order_type: OrderType::from_str(&self.order_type).unwrap(),
@@ -341,17 +479,31 @@ pub struct Filler {
     order_id: OrderId,
     client_order_id: OrderId,
     timestamp: u128,
+    // Trade legs, and the release of what an incoming bid locked above the trade price
+    ledger: Vec<LedgerEntry>,
 }
 #[derive(Debug, Serialize)]
 pub struct PostUsers {
     pub user: User,
     pub client: User,
+    // Balance stamp of both users' state, written as its Scylla write time
+    pub stamp: i64,
+}
+// Spot fills swap base for quote, perpetual fills open and close positions in the base that
+// are margined and settled in the quote
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone, Copy, Deserialize, Serialize)]
+pub enum MarketKind {
+    #[default]
+    Spot,
+    Perpetual,
 }
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Deserialize, Serialize)]
 pub struct Exchange {
     pub base: Asset,
     pub quote: Asset,
     pub symbol: String,
+    #[serde(default)]
+    pub kind: MarketKind,
 }
 #[derive(Debug, Serialize)]
 pub enum SymbolError {
@@ -366,6 +518,15 @@ impl Exchange {
             base,
             quote,
             symbol,
+            kind: MarketKind::Spot,
+        }
+    }
+    pub fn perpetual(base: Asset, quote: Asset) -> Exchange {
+        Exchange {
+            base,
+            quote,
+            symbol: format!("{}_{}_PERP", base, quote),
+            kind: MarketKind::Perpetual,
         }
     }
     pub fn from_symbol(symbol: Symbol) -> Result<Exchange, SymbolError> {
@@ -374,32 +535,36 @@ impl Exchange {
         let quote_str = symbols.get(1).ok_or(SymbolError::InvalidSymbol)?;
         let base = Asset::from_str(&base_str).ok_or(SymbolError::InvalidSymbol)?;
         let quote = Asset::from_str(&quote_str).ok_or(SymbolError::InvalidSymbol)?;
-        let exchange = Exchange::new(base, quote);
+        let exchange = match symbols.get(2) {
+            None => Exchange::new(base, quote),
+            Some(&"PERP") => Exchange::perpetual(base, quote),
+            Some(_) => {
+                return Err(SymbolError::InvalidSymbol);
+            }
+        };
         Ok(exchange)
     }
-}
-
-#[derive(Serialize, Deserialize)]
-pub struct OrderUpdate {
-    order_id: u64,
-    client_order_id: u64,
-    trade_id: u64,
-    user_id: u64,
-    trade_timestamp: u128,
-    order_side: OrderSide,
-    order_status: OrderStatus,
-    symbol: String,
-    price: Decimal,
-    executed_quantity: Decimal,
-    executed_quote_quantity: Decimal,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Trade {
-    id: Id,
-    quantity: Quantity,
-    quote_quantity: Quantity,
-    is_buyer_maker: bool,
-    timestamp: u128,
-    price: Price,
+    pub fn is_perpetual(&self) -> bool {
+        self.kind == MarketKind::Perpetual
+    }
+    // What a resting order locks, the quantity for spot asks and the quote at its price for
+    // spot bids. Either side of a perpetual locks the initial margin of that quote.
+    pub fn order_lock(
+        &self,
+        order_side: &OrderSide,
+        price: Price,
+        quantity: Quantity
+    ) -> (Asset, Quantity) {
+        match (self.kind, order_side) {
+            (MarketKind::Perpetual, _) => (self.quote, price * quantity * INITIAL_MARGIN),
+            (MarketKind::Spot, OrderSide::Ask) => (self.base, quantity),
+            (MarketKind::Spot, OrderSide::Bid) => (self.quote, price * quantity),
+        }
+    }
+    pub fn id_namespace(&self) -> u16 {
+        RegisteredSymbols::iter()
+            .find(|symbol| symbol.to_string() == self.symbol)
+            .expect("Market is not registered")
+            .id_namespace()
+    }
 }
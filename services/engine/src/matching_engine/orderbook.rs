@@ -7,28 +7,26 @@ use std::{
 };
 use enum_stringify::EnumStringify;
 use error::MatchingEngineErrors;
-use futures::SinkExt;
-use redis::{ Connection, PubSub, Value };
 use rust_decimal_macros::dec;
 use rust_decimal::prelude::*;
-use scylla::{
-    frame::Compression,
-    load_balancing,
-    ExecutionProfile,
-    FromRow,
-    SerializeRow,
-    Session,
-    SessionBuilder,
-};
 use serde::{ Deserialize, Serialize };
-use serde_json::to_string;
 use strum::IntoEnumIterator;
 use strum_macros::EnumIter;
-use tokio::sync::mpsc::UnboundedSender;
 use std::{ clone, collections::HashMap };
-use crate::{ EventTranmitter, RedisEmit };
+use common::{ events::{ Depth, OrderUpdate, Trade }, ids };
+use crate::{ handle_order_request::EngineRequests, journal::JournalEntry };
 
 use super::*;
+use super::{
+    balances::{ BalanceStore, Detached, Locked },
+    events::{ EventSink, TradeEvents },
+    risk::Exposures,
+};
+use super::price_band::default_max_price_deviation;
+use super::perpetual::PerpFill;
+use super::ticker::TickerWindow;
+// The matching core, balances and events of its trades go through the store and sink it was
+// attached to so it runs the same inside the engine, the replay tool and tests
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Orderbook {
     pub trade_id: u64,
@@ -36,142 +34,224 @@ pub struct Orderbook {
     pub exchange: Exchange,
     pub asks: HashMap<Price, Limit>,
     pub bids: HashMap<Price, Limit>,
+    // Halted markets take no new orders, cancels still go through
+    #[serde(default)]
+    pub halted: bool,
+    #[serde(default)]
+    pub last_price: Option<Price>,
+    #[serde(default)]
+    pub ticker: TickerWindow,
+    // How far in percent a limit price or a market order's average price may be from the
+    // reference price, without it orders are not checked
+    #[serde(default = "default_max_price_deviation")]
+    pub max_price_deviation: Option<Decimal>,
+    #[serde(skip)]
+    pub exposures: Exposures,
+    #[serde(skip, default = "detached_balances")]
+    pub balances: Arc<dyn BalanceStore>,
+    #[serde(skip, default = "detached_events")]
+    pub events: Arc<dyn EventSink>,
+}
+// Levels on each side of the depth published after the book changes
+pub const DEPTH_LEVELS: usize = 20;
+fn detached_balances() -> Arc<dyn BalanceStore> {
+    Arc::new(Detached)
+}
+fn detached_events() -> Arc<dyn EventSink> {
+    Arc::new(Detached)
 }
 impl Orderbook {
     pub fn new(exchange: Exchange) -> Orderbook {
+        let namespace = exchange.id_namespace();
         Orderbook {
-            trade_id: 0,
-            order_id: 0,
+            trade_id: ids::base(namespace),
+            order_id: ids::base(namespace),
             exchange,
             asks: HashMap::new(),
             bids: HashMap::new(),
+            halted: false,
+            last_price: None,
+            ticker: TickerWindow::default(),
+            max_price_deviation: default_max_price_deviation(),
+            exposures: Exposures::default(),
+            balances: detached_balances(),
+            events: detached_events(),
         }
     }
-    pub async fn recover_orderbook(&mut self, session: &Session) {
-        self.recover_trade_id(&session).await;
-        self.recover_order_id(&session).await;
-        self.replay_orders(&session).await;
+    pub fn attach(&mut self, balances: Arc<dyn BalanceStore>, events: Arc<dyn EventSink>) {
+        self.balances = balances;
+        self.events = events;
+        self.rebuild_exposures();
     }
-    async fn recover_trade_id(&mut self, session: &Session) {
-        let s = r#"
-            SELECT COUNT(*) FROM keyspace_1.trade_table;
-                "#;
-        let res = session.query(s, &[]).await.unwrap();
-        let mut res = res.rows_typed::<(i64,)>().unwrap();
-        let trade_id = res.next().transpose().unwrap().unwrap().0;
-        self.trade_id = trade_id as u64;
-    }
-    async fn recover_order_id(&mut self, session: &Session) {
-        let s = r#"
-            SELECT COUNT(*) FROM keyspace_1.order_table;
-                "#;
-        let res = session.query(s, &[]).await.unwrap();
-        let mut res = res.rows_typed::<(i64,)>().unwrap();
-        let order_id = res.next().transpose().unwrap().unwrap().0;
-        self.order_id = order_id as u64;
-    }
-    async fn replay_orders(&mut self, session: &Session) {
-        let current_time = get_epoch_ms() as i64;
-        let since = 1000 * 60 * 60 * 24; // 24 hours in millis
-        let from_time = current_time - since;
-        let canceled_order_s =
-            r#"
-        SELECT 
-            id,
-            user_id,
-            order_side,
-            symbol,
-            price,
-            timestamp
-        FROM keyspace_1.cancel_order_table
-        WHERE timestamp > ? AND symbol = ? ALLOW FILTERING;
-            "#;
-        let normal_order_s =
-            r#"
-        SELECT 
-            id,
-            user_id,
-            symbol,
-            price,
-            initial_quantity,
-            filled_quantity, 
-            quote_quantity,
-            filled_quote_quantity,
-            order_type,
-            order_side,
-            order_status,
-            timestamp
-        FROM keyspace_1.order_table
-        WHERE timestamp > ? AND symbol = ? ALLOW FILTERING;
-            "#;
-        enum OrderRequest {
-            Cancel(ScyllaCancelOrder),
-            Normal(RecievedOrder),
-        }
-        let symbol = &self.exchange.symbol;
-        let res = session.query(normal_order_s, (from_time, symbol)).await.unwrap();
-        let cancel_res = session.query(canceled_order_s, (from_time, symbol)).await.unwrap();
-        let mut orders = res.rows_typed::<ScyllaOrder>().unwrap();
-        let mut canceled_orders = cancel_res.rows_typed::<ScyllaCancelOrder>().unwrap();
-        let mut replay_orders: Vec<OrderRequest> = orders
-            .map(|order| {
-                let order = order.unwrap().from_scylla_order();
-                OrderRequest::Normal(order)
-            })
-            .collect();
-        let mut canceled_orders: Vec<OrderRequest> = canceled_orders
-            .map(|order| {
-                let order = order.unwrap();
-                OrderRequest::Cancel(order)
-            })
-            .collect();
-        replay_orders.extend(canceled_orders);
-        replay_orders.sort_by(|r1, r2| {
-            let r1_timestamp = match r1 {
-                OrderRequest::Cancel(c_order) => c_order.timestamp,
-                OrderRequest::Normal(n_order) => n_order.timestamp,
-            };
-            let r2_timestamp = match r2 {
-                OrderRequest::Cancel(c_order) => c_order.timestamp,
-                OrderRequest::Normal(n_order) => n_order.timestamp,
-            };
-            r1_timestamp.cmp(&r2_timestamp)
-        });
-        for replay_order in replay_orders {
-            match replay_order {
-                OrderRequest::Cancel(c_order) => {
-                    self.cancel_order(
-                        c_order.id as u64,
-                        c_order.user_id as u64,
-                        &OrderSide::from_str(&c_order.order_side).unwrap(),
-                        &Decimal::from_str(&c_order.price).unwrap()
-                    ).unwrap();
-                    println!("Cancelled an {} Open order", c_order.order_side);
-                }
-                OrderRequest::Normal(replay_order) => {
+    // Without `apply_balances` only the book is rebuilt, for when balances were loaded from
+    // scylla which already holds the effect of every journaled request. On top of a snapshot
+    // the locks, trades and unlocks of each request are applied to the balance store again.
+    pub fn replay_journal(&mut self, entries: Vec<JournalEntry>, apply_balances: bool) {
+        self.rebuild_exposures();
+        for entry in entries {
+            let request: EngineRequests = serde_json
+                ::from_slice(&entry.payload)
+                .expect("Journal entry is not an engine request");
+            match request {
+                EngineRequests::ExecuteOrder(replay_order) |
+                EngineRequests::Liquidate(replay_order) => {
+                    self.order_id = self.order_id.max(replay_order.id as u64);
+                    let locked_amount = match apply_balances {
+                        true => self.replay_lock(&replay_order),
+                        false => dec!(0),
+                    };
                     let order = Order::new(
                         replay_order.id as u64,
                         replay_order.timestamp as u64,
-                        replay_order.order_side,
+                        replay_order.order_side.clone(),
                         replay_order.initial_quantity,
                         replay_order.order_type.clone(),
                         replay_order.user_id as u64
                     );
-                    let _ = match replay_order.order_type {
-                        OrderType::Market => self.fill_market_order(order, false, None),
+                    let (_, filled_quote_quantity, _) = match replay_order.order_type {
+                        OrderType::Market =>
+                            self.fill_market_order(order, apply_balances, false),
                         OrderType::Limit =>
-                            self.fill_limit_order(replay_order.price, order, false, None),
+                            self.fill_limit_order(replay_order.price, order, apply_balances, false),
                     };
+                    if apply_balances {
+                        self.release_unspent(&replay_order, locked_amount, filled_quote_quantity);
+                    }
                 }
+                EngineRequests::CancelOrder(cancel_order) => {
+                    let result = self.cancel_order(
+                        cancel_order.id,
+                        cancel_order.user_id,
+                        &cancel_order.order_side,
+                        &cancel_order.price
+                    );
+                    if let (Ok(order), true) = (result, apply_balances) {
+                        self.unlock_cancelled(&order, &cancel_order.price);
+                    }
+                }
+                EngineRequests::CancelAll(cancel_all) => {
+                    match apply_balances {
+                        true => {
+                            self.cancel_all_orders(cancel_all.user_id);
+                        }
+                        false => self.remove_user_orders(cancel_all.user_id),
+                    }
+                }
+                EngineRequests::Halt(_) => {
+                    self.halted = true;
+                }
+                EngineRequests::Resume(_) => {
+                    self.halted = false;
+                }
+                EngineRequests::SetPriceBand(price_band) => {
+                    self.max_price_deviation = price_band.max_deviation;
+                }
+                EngineRequests::OpenOrders(_) |
+                EngineRequests::OpenOrder(_) |
+                EngineRequests::MarketState(_) => {}
             }
         }
     }
-
+    // Journaled orders were already validated, so the lock is taken again unconditionally
+    fn replay_lock(&mut self, order: &RecievedOrder) -> Quantity {
+        let user_id = order.user_id as u64;
+        let (asset, quantity) = match (&order.order_type, &order.order_side) {
+            (OrderType::Market, _) if self.exchange.is_perpetual() =>
+                (self.exchange.quote, self.perp_market_lock(order).unwrap_or(dec!(0))),
+            (OrderType::Limit, _) | (_, OrderSide::Ask) =>
+                self.exchange.order_lock(&order.order_side, order.price, order.initial_quantity),
+            (OrderType::Market, OrderSide::Bid) =>
+                (
+                    self.exchange.quote,
+                    self.get_quote(&order.order_side, order.initial_quantity).unwrap_or(dec!(0)),
+                ),
+        };
+        self.balances.lock_amount(&asset, user_id, quantity);
+        quantity
+    }
+    // Validates the order against the market's price band, its user's risk limits and what they
+    // have available and locks what it can spend, the quantity for asks and the price, or the
+    // quote of the book for market bids, for bids. Returns the locked asset, the amount locked
+    // for this order and the user's locked total.
+    pub fn lock_order(
+        &mut self,
+        order: &RecievedOrder
+    ) -> Result<(Asset, Quantity, Locked), MatchingEngineErrors> {
+        let (asset, amount, quote) = self.order_amount(order)?;
+        self.check_price_band(&order.order_side, order.price, order.initial_quantity, quote)?;
+        let user_id = order.user_id as u64;
+        let notional = quote.unwrap_or(order.price * order.initial_quantity);
+        self.balances.check_risk(
+            user_id,
+            &self.exchange,
+            self.exposure(user_id),
+            notional,
+            order.timestamp as u64
+        )?;
+        let locked_balance = self.balances.validate_and_lock(&asset, order.user_id as u64, amount)?;
+        Ok((asset, amount, locked_balance))
+    }
+    // Liquidations close out what the engine decided to, so they skip the checks of orders users
+    // send, the price band, risk and rate limits and a frozen account, and only need the funds
+    pub fn lock_liquidation(
+        &mut self,
+        order: &RecievedOrder
+    ) -> Result<(Asset, Quantity, Locked), MatchingEngineErrors> {
+        let (asset, amount, _) = self.order_amount(order)?;
+        let locked_balance = self.balances.lock_available(&asset, order.user_id as u64, amount)?;
+        Ok((asset, amount, locked_balance))
+    }
+    // What the order locks of which asset, with the quote of the book for market orders
+    fn order_amount(
+        &mut self,
+        order: &RecievedOrder
+    ) -> Result<(Asset, Quantity, Option<Quantity>), MatchingEngineErrors> {
+        if order.initial_quantity <= dec!(0) {
+            return Err(MatchingEngineErrors::InvalidQuantity);
+        }
+        let quote = match order.order_type {
+            OrderType::Market => Some(self.get_quote(&order.order_side, order.initial_quantity)?),
+            OrderType::Limit => None,
+        };
+        let (asset, amount) = match (&order.order_side, quote) {
+            _ if self.exchange.is_perpetual() && quote.is_some() =>
+                (self.exchange.quote, self.perp_market_lock(order)?),
+            (OrderSide::Bid, Some(quote)) => (self.exchange.quote, quote),
+            _ => self.exchange.order_lock(&order.order_side, order.price, order.initial_quantity),
+        };
+        Ok((asset, amount, quote))
+    }
+    // Market bids lock the quote of the book before they fill, once processed whatever of it
+    // was not spent is released. Returns the asset, the released amount and the user's locked
+    // total when something was.
+    pub fn release_unspent(
+        &self,
+        order: &RecievedOrder,
+        locked_amount: Quantity,
+        filled_quote_quantity: Quantity
+    ) -> Option<(Asset, Quantity, Locked)> {
+        let unspent = match (&order.order_type, &order.order_side) {
+            _ if self.exchange.is_perpetual() => dec!(0),
+            (OrderType::Market, OrderSide::Bid) => locked_amount - filled_quote_quantity,
+            _ => dec!(0),
+        };
+        if unspent <= dec!(0) {
+            return None;
+        }
+        let asset = self.exchange.quote;
+        let locked_balance = self.balances.unlock_amount(&asset, order.user_id as u64, unspent);
+        Some((asset, unspent, locked_balance))
+    }
+    // Releases what a cancelled order still had locked, returns the asset, the released amount
+    // and the locked total
+    pub fn unlock_cancelled(&self, order: &Order, price: &Price) -> (Asset, Quantity, Locked) {
+        let (asset, quantity) = self.exchange.order_lock(&order.order_side, *price, order.quantity);
+        (asset, quantity, self.balances.unlock_amount(&asset, order.user_id, quantity))
+    }
     pub fn process_order(
         &mut self,
         recieved_order: RecievedOrder,
-        order_id: OrderId,
-        event_tx: EventTranmitter
+        order_id: OrderId
     ) -> (Decimal, Decimal, OrderStatus) {
         let order = Order::new(
             order_id as u64,
@@ -182,10 +262,8 @@ impl Orderbook {
             recieved_order.user_id as u64
         );
         match recieved_order.order_type {
-            OrderType::Market => { self.fill_market_order(order, true, Some(event_tx)) }
-            OrderType::Limit => {
-                self.fill_limit_order(recieved_order.price, order, true, Some(event_tx))
-            }
+            OrderType::Market => { self.fill_market_order(order, true, true) }
+            OrderType::Limit => { self.fill_limit_order(recieved_order.price, order, true, true) }
         }
     }
     pub fn increment_order_id(&mut self) -> OrderId {
@@ -218,8 +296,16 @@ impl Orderbook {
         &mut self,
         mut order: Order,
         should_exectute_trade: bool,
-        event_tx: Option<EventTranmitter>
+        emit_events: bool
     ) -> (Decimal, Decimal, OrderStatus) {
+        let balances = self.balances.clone();
+        let events = self.events.clone();
+        let settlement = should_exectute_trade.then_some(Settlement {
+            balances: balances.as_ref(),
+            events: emit_events.then_some(events.as_ref()),
+            limit_price: None,
+            resting_price: None,
+        });
         let sorted_orders = match order.order_side {
             OrderSide::Ask => Orderbook::bid_limits(&mut self.bids),
             OrderSide::Bid => Orderbook::ask_limits(&mut self.asks),
@@ -230,17 +316,25 @@ impl Orderbook {
         println!("Recived an {} Market order", order.order_side);
         for limit_order in sorted_orders {
             let price = limit_order.price.clone();
+            let quantity_before = order.quantity;
             order = limit_order.fill_order(
                 order,
                 &self.exchange,
                 price,
                 &mut self.trade_id,
-                should_exectute_trade,
-                event_tx.clone()
+                &mut self.exposures,
+                settlement.as_ref()
             );
-            let executed_quantity_limit = order.initial_quantity - order.quantity;
+            let executed_quantity_limit = quantity_before - order.quantity;
             executed_quantity += executed_quantity_limit;
             executed_quote_quantity += executed_quantity_limit * price;
+            Orderbook::trade_at(
+                &mut self.last_price,
+                &mut self.ticker,
+                order.timestamp,
+                executed_quantity_limit,
+                price
+            );
             order_status = order.order_status.clone();
             if order.is_filled() {
                 break;
@@ -248,13 +342,35 @@ impl Orderbook {
         }
         (executed_quantity, executed_quote_quantity, order_status)
     }
+    // A limit that traded moves the last price of the market, one that filled nothing does not.
+    // Takes the fields alone as the fill loops still hold the sides of the book.
+    fn trade_at(
+        last_price: &mut Option<Price>,
+        ticker: &mut TickerWindow,
+        timestamp: u64,
+        executed_quantity: Quantity,
+        price: Price
+    ) {
+        if executed_quantity > dec!(0) {
+            *last_price = Some(price);
+            ticker.record(timestamp, price, executed_quantity);
+        }
+    }
     pub fn fill_limit_order(
         &mut self,
         price: Price,
         mut order: Order,
         should_exectute_trade: bool,
-        event_tx: Option<EventTranmitter>
+        emit_events: bool
     ) -> (Decimal, Decimal, OrderStatus) {
+        let balances = self.balances.clone();
+        let events = self.events.clone();
+        let settlement = should_exectute_trade.then_some(Settlement {
+            balances: balances.as_ref(),
+            events: emit_events.then_some(events.as_ref()),
+            limit_price: Some(price),
+            resting_price: None,
+        });
         println!("Recived an {} Limit order", order.order_side);
         let mut executed_quantity = dec!(0);
         let mut executed_quote_quantity = dec!(0);
@@ -272,19 +388,33 @@ impl Orderbook {
                         self.add_limit_order(price, order);
                         break;
                     }
+                    // trades execute at the price of the resting order
+                    let limit_price = sorted_bids[i].price;
+                    let quantity_before = order.quantity;
                     order = sorted_bids[i].fill_order(
                         order,
                         &self.exchange,
-                        price,
+                        limit_price,
                         &mut self.trade_id,
-                        should_exectute_trade,
-                        event_tx.clone()
+                        &mut self.exposures,
+                        settlement.as_ref()
                     );
-                    let executed_quantity_limit = order.initial_quantity - order.quantity;
+                    let executed_quantity_limit = quantity_before - order.quantity;
                     executed_quantity += executed_quantity_limit;
-                    executed_quote_quantity += executed_quantity_limit * price;
+                    executed_quote_quantity += executed_quantity_limit * limit_price;
+                    Orderbook::trade_at(
+                        &mut self.last_price,
+                        &mut self.ticker,
+                        order.timestamp,
+                        executed_quantity_limit,
+                        limit_price
+                    );
                     order_status = order.order_status.clone();
-                    if order.quantity > dec!(0) && sorted_bids.get(i + 1).is_none() {
+                    // a filled order must not go on to rest in the book with nothing left
+                    if order.is_filled() {
+                        break;
+                    }
+                    if sorted_bids.get(i + 1).is_none() {
                         self.add_limit_order(price, order);
                         break;
                     }
@@ -303,20 +433,33 @@ impl Orderbook {
                         self.add_limit_order(price, order);
                         break;
                     }
-                    let price = sorted_asks[i].price.clone();
+                    // trades execute at the price of the resting order
+                    let limit_price = sorted_asks[i].price;
+                    let quantity_before = order.quantity;
                     order = sorted_asks[i].fill_order(
                         order,
                         &self.exchange,
-                        price,
+                        limit_price,
                         &mut self.trade_id,
-                        should_exectute_trade,
-                        event_tx.clone()
+                        &mut self.exposures,
+                        settlement.as_ref()
                     );
-                    let executed_quantity_limit = order.initial_quantity - order.quantity;
+                    let executed_quantity_limit = quantity_before - order.quantity;
                     executed_quantity += executed_quantity_limit;
-                    executed_quote_quantity += executed_quantity_limit * price;
+                    executed_quote_quantity += executed_quantity_limit * limit_price;
+                    Orderbook::trade_at(
+                        &mut self.last_price,
+                        &mut self.ticker,
+                        order.timestamp,
+                        executed_quantity_limit,
+                        limit_price
+                    );
                     order_status = order.order_status.clone();
-                    if order.quantity > dec!(0) && sorted_asks.get(i + 1).is_none() {
+                    // a filled order must not go on to rest in the book with nothing left
+                    if order.is_filled() {
+                        break;
+                    }
+                    if sorted_asks.get(i + 1).is_none() {
                         self.add_limit_order(price, order);
                         break;
                     }
@@ -342,26 +485,24 @@ impl Orderbook {
         open_orders.extend(Orderbook::users_orders(&mut self.bids, user_id));
         open_orders
     }
+    // Returns the cancelled orders and the user's locked totals after, with their stamp
     pub fn cancel_all_orders(
         &mut self,
         user_id: Id
-    ) -> (Vec<RecievedOrder>, HashMap<String, String>) {
-        let quote = self.exchange.quote;
-        let base = self.exchange.base;
+    ) -> (Vec<RecievedOrder>, HashMap<String, Numeric>, i64) {
         let symbol = self.exchange.symbol.clone();
+        let exchange = self.exchange.clone();
+        let balances = self.balances.clone();
         let mut open_orders = self.get_open_orders(user_id);
-        let mut users = USERS.lock().unwrap();
         let orders: Vec<RecievedOrder> = open_orders
             .iter()
             .map(|(price, order)| {
-                match order.order_side {
-                    OrderSide::Bid => {
-                        users.unlock_amount(&quote, user_id, order.quantity * price);
-                    }
-                    OrderSide::Ask => {
-                        users.unlock_amount(&base, user_id, order.quantity);
-                    }
-                }
+                let (asset, quantity) = exchange.order_lock(
+                    &order.order_side,
+                    *price,
+                    order.quantity
+                );
+                balances.unlock_amount(&asset, user_id, quantity);
                 RecievedOrder {
                     id: order.id as i64,
                     filled_quantity: order.initial_quantity - order.quantity,
@@ -378,21 +519,25 @@ impl Orderbook {
                 }
             })
             .collect();
+        self.remove_user_orders(user_id);
+        let (locked_balances, stamp) = balances.locked_balances(user_id);
+        let locked_balances: HashMap<String, Numeric> = locked_balances
+            .iter()
+            .map(|(asset, balance)| (asset.to_string(), Numeric(*balance)))
+            .collect();
+        (orders, locked_balances, stamp)
+    }
+    pub fn remove_user_orders(&mut self, user_id: Id) {
+        self.exposures.remove(user_id);
         self.asks
             .values_mut()
             .for_each(|limit| limit.orders.retain(|order| order.user_id != user_id));
         self.bids
             .values_mut()
             .for_each(|limit| limit.orders.retain(|order| order.user_id != user_id));
-        let locked_balances: &HashMap<String, String> = &users.users
-            .get(&user_id)
-            .unwrap()
-            .locked_balance.iter()
-            .map(|(asset, balance)| (asset.to_string(), balance.to_string()))
-            .collect();
-        (orders, locked_balances.clone())
     }
-    // More perfomant
+    // More perfomant. Only the user who placed an order can cancel it, an order of anyone else is
+    // as unknown as one that does not exist.
     pub fn cancel_order(
         &mut self,
         order_id: OrderId,
@@ -405,11 +550,14 @@ impl Orderbook {
                 let mut limit = self.bids.get_mut(price);
                 match limit {
                     Some(limit) => {
-                        let index = limit.orders.iter().position(|order| order.id == order_id);
+                        let index = limit.orders
+                            .iter()
+                            .position(|order| order.id == order_id && order.user_id == user_id);
                         match index {
                             Some(index) => {
-                                let order = limit.orders.get(index).unwrap().clone();
-                                limit.orders.remove(index);
+                                let order = limit.orders.remove(index);
+                                let notional = order.quantity * limit.price;
+                                self.exposures.change(order.user_id, -1, -notional);
                                 Ok(order)
                             }
                             None => { Err(MatchingEngineErrors::InvalidOrderId) }
@@ -422,11 +570,14 @@ impl Orderbook {
                 let mut limit = self.asks.get_mut(price);
                 match limit {
                     Some(limit) => {
-                        let index = limit.orders.iter().position(|order| order.id == order_id);
+                        let index = limit.orders
+                            .iter()
+                            .position(|order| order.id == order_id && order.user_id == user_id);
                         match index {
                             Some(index) => {
-                                let order = limit.orders.get(index).unwrap().clone();
-                                limit.orders.remove(index);
+                                let order = limit.orders.remove(index);
+                                let notional = order.quantity * limit.price;
+                                self.exposures.change(order.user_id, -1, -notional);
                                 Ok(order)
                             }
                             None => { Err(MatchingEngineErrors::InvalidOrderId) }
@@ -450,21 +601,30 @@ impl Orderbook {
         bids
     }
 
-    pub fn get_depth(&mut self) -> (HashMap<Price, Quantity>, HashMap<Price, Quantity>) {
-        let sorted_bids = Orderbook::bid_limits(&mut self.bids);
-        let sorted_asks = Orderbook::bid_limits(&mut self.asks);
-        let bids: HashMap<Price, Quantity> = sorted_bids
-            .iter()
-            .map(|limit| (limit.price, limit.total_volume()))
-            .collect();
-        let asks: HashMap<Price, Quantity> = sorted_asks
-            .iter()
-            .map(|limit| (limit.price, limit.total_volume()))
-            .collect();
-        return (bids, asks);
+    // The best `levels` prices of each side with what rests at them, best first
+    pub fn depth(&mut self, levels: usize) -> Depth {
+        let side = |limits: Vec<&mut Limit>| {
+            limits
+                .into_iter()
+                .map(|limit| (limit.price, limit.total_volume()))
+                .filter(|(_, quantity)| *quantity > dec!(0))
+                .take(levels)
+                .collect()
+        };
+        Depth {
+            symbol: self.exchange.symbol.clone(),
+            bids: side(Orderbook::bid_limits(&mut self.bids)),
+            asks: side(Orderbook::ask_limits(&mut self.asks)),
+            timestamp: get_epoch_micro() as u64,
+        }
+    }
+    pub fn publish_depth(&mut self) {
+        let depth = self.depth(DEPTH_LEVELS);
+        self.events.depth(depth);
     }
 
     pub fn add_limit_order(&mut self, price: Price, order: Order) {
+        self.exposures.change(order.user_id, 1, order.quantity * price);
         let order_side = &order.order_side.clone();
         match order_side {
             OrderSide::Bid => {
@@ -506,9 +666,6 @@ pub struct Order {
     pub timestamp: u64,
 }
 
-fn get_epoch_ms() -> u64 {
-    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
-}
 fn get_epoch_micro() -> u128 {
     SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros()
 }
@@ -560,9 +717,14 @@ impl Limit {
         exchange: &Exchange,
         exchange_price: Price,
         mut trade_id: &mut u64,
-        should_exectute_trade: bool,
-        event_tx: Option<EventTranmitter>
+        exposures: &mut Exposures,
+        settlement: Option<&Settlement>
     ) -> Order {
+        let settlement = settlement.map(|settlement| Settlement {
+            resting_price: Some(self.price),
+            ..*settlement
+        });
+        let settlement = settlement.as_ref();
         let mut remaining_quantity = order.quantity.clone();
         let mut i = 0;
         while i < self.orders.len() {
@@ -570,113 +732,25 @@ impl Limit {
                 break;
             }
             let limit_order = &mut self.orders[i];
-            let event_tx = event_tx.clone();
             match limit_order.quantity > remaining_quantity {
                 true => {
                     println!("\tAn order was matched");
                     limit_order.quantity -= remaining_quantity;
+                    exposures.change(limit_order.user_id, 0, -remaining_quantity * self.price);
                     order.quantity = dec!(0);
                     order.order_status = OrderStatus::Filled;
                     limit_order.order_status = OrderStatus::PartiallyFilled;
                     limit_order.filled_quote_quantity += exchange_price * remaining_quantity;
-                    if should_exectute_trade == true {
-                        *trade_id += 1;
-                        let timestamp = get_epoch_micro();
-                        let user_ids = match order.order_side {
-                            OrderSide::Bid => (limit_order.user_id, order.user_id),
-                            OrderSide::Ask => (order.user_id, limit_order.user_id),
-                        };
-                        let post_users = exchange_balance(
-                            &exchange,
+                    *trade_id += 1;
+                    if let Some(settlement) = settlement {
+                        execute_trade(
+                            &order,
+                            limit_order,
+                            exchange,
                             remaining_quantity,
                             exchange_price,
-                            user_ids.0,
-                            user_ids.1
-                        );
-                        let is_buyer_maker = if
-                            order.order_type == OrderType::Market &&
-                            order.order_side == OrderSide::Bid
-                        {
-                            true
-                        } else {
-                            false
-                        };
-                        let trade = Filler {
-                            trade_id: *trade_id,
-                            post_users,
-                            exchange: exchange.clone(),
-                            quantity: remaining_quantity,
-                            exchange_price,
-                            is_buyer_maker,
-                            order_status: order.order_status.clone(),
-                            client_order_status: limit_order.order_status.clone(),
-                            order_id: order.id,
-                            client_order_id: limit_order.id,
-                            timestamp,
-                        };
-
-                        let order_update_1 = OrderUpdate {
-                            order_id: trade.order_id,
-                            client_order_id: trade.client_order_id,
-                            executed_quantity: trade.quantity,
-                            executed_quote_quantity: trade.exchange_price * trade.quantity,
-                            order_side: order.order_side.clone(),
-                            order_status: trade.order_status.clone(),
-                            price: trade.exchange_price,
-                            symbol: trade.exchange.symbol.clone(),
-                            trade_id: trade.trade_id,
-                            trade_timestamp: timestamp,
-                            user_id: order.user_id,
-                        };
-                        let order_update_2 = OrderUpdate {
-                            order_id: trade.order_id,
-                            client_order_id: trade.client_order_id,
-                            executed_quantity: trade.quantity,
-                            executed_quote_quantity: trade.exchange_price * trade.quantity,
-                            order_side: limit_order.order_side.clone(),
-                            order_status: trade.order_status.clone(),
-                            price: trade.exchange_price,
-                            symbol: trade.exchange.symbol.clone(),
-                            trade_id: trade.trade_id,
-                            trade_timestamp: timestamp,
-                            user_id: limit_order.user_id,
-                        };
-                        let publish_trade = Trade {
-                            id: trade.trade_id,
-                            is_buyer_maker: trade.is_buyer_maker,
-                            price: trade.exchange_price,
-                            quantity: trade.quantity,
-                            quote_quantity: trade.exchange_price * trade.quantity,
-                            timestamp,
-                        };
-                        let serialized_filler = to_string(&trade).unwrap();
-                        let serialized_order_update_1 = to_string(&order_update_1).unwrap();
-                        let serialized_order_update_2 = to_string(&order_update_2).unwrap();
-                        let serialized_publish_trade = to_string(&publish_trade).unwrap();
-                        event_tx.unwrap().send(
-                            vec![
-                                RedisEmit {
-                                    cmd: "PUBLISH".to_string(),
-                                    arg_1: format!("order_update:{}", trade.exchange.symbol),
-                                    arg_2: serialized_order_update_1,
-                                },
-                                RedisEmit {
-                                    cmd: "PUBLISH".to_string(),
-                                    arg_1: format!("order_update:{}", trade.exchange.symbol),
-                                    arg_2: serialized_order_update_2,
-                                },
-
-                                RedisEmit {
-                                    cmd: "PUBLISH".to_string(),
-                                    arg_1: format!("trade:{}", trade.exchange.symbol),
-                                    arg_2: serialized_publish_trade,
-                                },
-                                RedisEmit {
-                                    cmd: "LPUSH".to_string(),
-                                    arg_1: "filler".to_string(),
-                                    arg_2: serialized_filler,
-                                }
-                            ]
+                            *trade_id,
+                            settlement
                         );
                     }
                 }
@@ -687,107 +761,21 @@ impl Limit {
                         false => OrderStatus::PartiallyFilled,
                     };
                     remaining_quantity -= limit_order.quantity;
+                    exposures.change(limit_order.user_id, -1, -limit_order.quantity * self.price);
                     order.quantity -= limit_order.quantity;
                     order.order_status = order_status;
                     limit_order.order_status = OrderStatus::Filled;
                     limit_order.filled_quote_quantity += exchange_price * limit_order.quantity;
-                    if should_exectute_trade == true {
-                        *trade_id += 1;
-                        let timestamp = get_epoch_micro();
-                        let user_ids = match order.order_side {
-                            OrderSide::Bid => (limit_order.user_id, order.user_id),
-                            OrderSide::Ask => (order.user_id, limit_order.user_id),
-                        };
-                        let post_users = exchange_balance(
-                            &exchange,
+                    *trade_id += 1;
+                    if let Some(settlement) = settlement {
+                        execute_trade(
+                            &order,
+                            limit_order,
+                            exchange,
                             limit_order.quantity,
                             exchange_price,
-                            user_ids.0,
-                            user_ids.1
-                        );
-                        let is_buyer_maker = if
-                            order.order_type == OrderType::Market &&
-                            order.order_side == OrderSide::Bid
-                        {
-                            true
-                        } else {
-                            false
-                        };
-                        let trade = Filler {
-                            trade_id: *trade_id,
-                            post_users,
-                            exchange: exchange.clone(),
-                            quantity: limit_order.quantity,
-                            exchange_price,
-                            is_buyer_maker,
-                            order_status: order.order_status.clone(),
-                            client_order_status: limit_order.order_status.clone(),
-                            order_id: order.id,
-                            client_order_id: limit_order.id,
-                            timestamp,
-                        };
-                        let order_update_1 = OrderUpdate {
-                            order_id: trade.order_id,
-                            client_order_id: trade.client_order_id,
-                            executed_quantity: trade.quantity,
-                            executed_quote_quantity: trade.exchange_price * trade.quantity,
-                            order_side: order.order_side.clone(),
-                            order_status: trade.order_status.clone(),
-                            price: trade.exchange_price,
-                            symbol: trade.exchange.symbol.clone(),
-                            trade_id: trade.trade_id,
-                            trade_timestamp: timestamp,
-                            user_id: order.user_id,
-                        };
-                        let order_update_2 = OrderUpdate {
-                            order_id: trade.client_order_id,
-                            client_order_id: trade.order_id,
-                            executed_quantity: trade.quantity,
-                            executed_quote_quantity: trade.exchange_price * trade.quantity,
-                            order_side: limit_order.order_side.clone(),
-                            order_status: trade.order_status.clone(),
-                            price: trade.exchange_price,
-                            symbol: trade.exchange.symbol.clone(),
-                            trade_id: trade.trade_id,
-                            trade_timestamp: timestamp,
-                            user_id: limit_order.user_id,
-                        };
-                        let publish_trade = Trade {
-                            id: trade.trade_id,
-                            is_buyer_maker: trade.is_buyer_maker,
-                            price: trade.exchange_price,
-                            quantity: trade.quantity,
-                            quote_quantity: trade.exchange_price * trade.quantity,
-                            timestamp: timestamp,
-                        };
-                        let serialized_filler = to_string(&trade).unwrap();
-                        let serialized_order_update_1 = to_string(&order_update_1).unwrap();
-                        let serialized_order_update_2 = to_string(&order_update_2).unwrap();
-                        let serialized_publish_trade = to_string(&publish_trade).unwrap();
-                        event_tx.unwrap().send(
-                            vec![
-                                RedisEmit {
-                                    cmd: "PUBLISH".to_string(),
-                                    arg_1: format!("order_update:{}", trade.exchange.symbol),
-                                    arg_2: serialized_order_update_1,
-                                },
-                                RedisEmit {
-                                    cmd: "PUBLISH".to_string(),
-                                    arg_1: format!("order_update:{}", trade.exchange.symbol),
-                                    arg_2: serialized_order_update_2,
-                                },
-
-                                RedisEmit {
-                                    cmd: "PUBLISH".to_string(),
-                                    arg_1: format!("trade:{}", trade.exchange.symbol),
-                                    arg_2: serialized_publish_trade,
-                                },
-                                RedisEmit {
-                                    cmd: "LPUSH".to_string(),
-                                    arg_1: "filler".to_string(),
-                                    arg_2: serialized_filler,
-                                }
-                            ]
+                            *trade_id,
+                            settlement
                         );
                     }
 
@@ -810,26 +798,150 @@ impl Limit {
             .unwrap_or(dec!(0))
     }
 }
-pub fn exchange_balance(
+// Where the trades of a fill go, book only replays pass none and replays on top of a snapshot
+// settle balances without events
+#[derive(Clone, Copy)]
+struct Settlement<'a> {
+    balances: &'a dyn BalanceStore,
+    events: Option<&'a dyn EventSink>,
+    // Price the incoming limit order locked at, bids filling below it get the difference back
+    limit_price: Option<Price>,
+    // Price of the level being filled, what its resting orders locked at
+    resting_price: Option<Price>,
+}
+// Settles a single match between the incoming order and a resting limit order, then emits
+// the private order updates for both sides, the public trade and the filler entry
+fn execute_trade(
+    order: &Order,
+    limit_order: &Order,
     exchange: &Exchange,
     quantity: Quantity,
     exchange_price: Price,
-    user_id: Id,
-    client_user_id: Id
-) -> PostUsers {
-    let mut users = USERS.lock().unwrap();
-    users.unlock_amount(&exchange.base, user_id, quantity);
-    users.withdraw(&exchange.base, quantity, user_id);
-    users.deposit(&exchange.base, quantity, client_user_id);
-
-    users.unlock_amount(&exchange.quote, client_user_id, quantity * exchange_price);
-    users.withdraw(&exchange.quote, quantity * exchange_price, client_user_id);
-    users.deposit(&exchange.quote, quantity * exchange_price, user_id);
-
-    let user = users.users.get(&user_id).unwrap().clone();
-    let client = users.users.get(&client_user_id).unwrap().clone();
-    PostUsers {
-        client,
-        user,
-    }
+    trade_id: TradeId,
+    settlement: &Settlement
+) {
+    let timestamp = get_epoch_micro();
+    let user_ids = match order.order_side {
+        OrderSide::Bid => (limit_order.user_id, order.user_id),
+        OrderSide::Ask => (order.user_id, limit_order.user_id),
+    };
+    let (post_users, entries) = match exchange.kind {
+        MarketKind::Spot => {
+            // the buyer locked at its own price and pays the trade price, taker or maker
+            let (buyer_order, buyer_price) = match order.order_side {
+                OrderSide::Bid => (order.id, settlement.limit_price),
+                OrderSide::Ask => (limit_order.id, settlement.resting_price),
+            };
+            let released = match buyer_price {
+                Some(buyer_price) => (buyer_price - exchange_price) * quantity,
+                None => dec!(0),
+            };
+            let post_users = settlement.balances.settle_trade(
+                exchange,
+                quantity,
+                exchange_price,
+                released,
+                user_ids.0,
+                user_ids.1
+            );
+            let mut entries = ledger::trade(
+                exchange,
+                quantity,
+                exchange_price,
+                user_ids.0,
+                user_ids.1,
+                trade_id,
+                timestamp as u64
+            );
+            entries.extend(
+                ledger::unlock(user_ids.1, &exchange.quote, released, buyer_order, timestamp as u64)
+            );
+            (post_users, entries)
+        }
+        MarketKind::Perpetual => {
+            let taker = PerpFill {
+                user_id: order.user_id,
+                order_side: order.order_side.clone(),
+                lock_price: settlement.limit_price,
+            };
+            let maker = PerpFill {
+                user_id: limit_order.user_id,
+                order_side: limit_order.order_side.clone(),
+                lock_price: settlement.resting_price,
+            };
+            let (seller, buyer) = match order.order_side {
+                OrderSide::Bid => (&maker, &taker),
+                OrderSide::Ask => (&taker, &maker),
+            };
+            let (post_users, legs) = settlement.balances.settle_perp_trade(
+                exchange,
+                quantity,
+                exchange_price,
+                seller,
+                buyer
+            );
+            (post_users, ledger::perp_trade(legs, trade_id, timestamp as u64))
+        }
+    };
+    // the incoming order always takes, so the buyer made the market when it is the seller
+    let is_buyer_maker = order.order_side == OrderSide::Ask;
+    let trade = Filler {
+        trade_id,
+        post_users,
+        exchange: exchange.clone(),
+        quantity,
+        exchange_price,
+        is_buyer_maker,
+        order_status: order.order_status.clone(),
+        client_order_status: limit_order.order_status.clone(),
+        order_id: order.id,
+        client_order_id: limit_order.id,
+        timestamp,
+        ledger: entries,
+    };
+    let order_update = OrderUpdate {
+        order_id: order.id,
+        client_order_id: limit_order.id,
+        trade_id: trade.trade_id,
+        user_id: order.user_id,
+        trade_timestamp: timestamp as u64,
+        order_side: order.order_side.clone(),
+        order_status: order.order_status.clone(),
+        symbol: exchange.symbol.clone(),
+        price: exchange_price,
+        executed_quantity: quantity,
+        executed_quote_quantity: exchange_price * quantity,
+    };
+    let client_order_update = OrderUpdate {
+        order_id: limit_order.id,
+        client_order_id: order.id,
+        trade_id: trade.trade_id,
+        user_id: limit_order.user_id,
+        trade_timestamp: timestamp as u64,
+        order_side: limit_order.order_side.clone(),
+        order_status: limit_order.order_status.clone(),
+        symbol: exchange.symbol.clone(),
+        price: exchange_price,
+        executed_quantity: quantity,
+        executed_quote_quantity: exchange_price * quantity,
+    };
+    let publish_trade = Trade {
+        id: trade.trade_id,
+        symbol: exchange.symbol.clone(),
+        price: exchange_price,
+        quantity,
+        quote_quantity: exchange_price * quantity,
+        is_buyer_maker,
+        timestamp: timestamp as u64,
+    };
+    // Replays settle balances again but never re-emit what was already published
+    let Some(events) = settlement.events else {
+        return;
+    };
+    events.trade(TradeEvents {
+        order_update,
+        client_order_update,
+        trade: publish_trade,
+        filler: trade,
+    });
 }
@@ -0,0 +1,536 @@
+use std::collections::{ HashMap, HashSet };
+
+use common::ledger::{ Account, LedgerEntry };
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use scylla::{ FromRow, Session };
+use serde::{ Deserialize, Serialize };
+use serde_json::{ from_str, to_string };
+
+use crate::PersistPositions;
+
+use super::{
+    error::MatchingEngineErrors,
+    ledger,
+    margin::{ LiquidationOrder, HOUR },
+    orderbook::Orderbook,
+    Exchange,
+    Id,
+    OrderSide,
+    Price,
+    Quantity,
+    RecievedOrder,
+    Symbol,
+    Users,
+};
+
+// Orders lock a tenth of their notional, positions are liquidated once what backs them falls
+// under a twentieth of theirs at the mark price
+pub const INITIAL_MARGIN: Decimal = dec!(0.1);
+pub const MAINTENANCE_MARGIN: Decimal = dec!(0.05);
+pub const FUNDING_INTERVAL: u64 = 8 * HOUR;
+const MAX_FUNDING_RATE: Decimal = dec!(0.0075);
+
+// Margin is taken out of the balance when a position opens and paid back with the PnL when it
+// closes, losses past the margin are covered by the insurance account
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Position {
+    // Positive when long, negative when short
+    pub quantity: Quantity,
+    pub entry_price: Price,
+    pub margin: Quantity,
+}
+impl Position {
+    pub fn unrealized_pnl(&self, mark_price: Price) -> Quantity {
+        self.quantity * (mark_price - self.entry_price)
+    }
+    pub fn equity(&self, mark_price: Price) -> Quantity {
+        self.margin + self.unrealized_pnl(mark_price)
+    }
+    pub fn maintenance_margin(&self, mark_price: Price) -> Quantity {
+        self.quantity.abs() * mark_price * MAINTENANCE_MARGIN
+    }
+}
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Perpetuals {
+    // Funding intervals since the epoch, the last one funding was paid at
+    #[serde(default)]
+    pub last_funding: u64,
+    // Losses past the margin of the positions they closed, by market
+    #[serde(default)]
+    pub shortfall: HashMap<Symbol, Quantity>,
+    // Reference prices of the perpetual books, published like the mark prices of spot markets
+    #[serde(skip)]
+    pub prices: HashMap<Symbol, Price>,
+    // Accounts whose positions were moved by fills and are not persisted yet
+    #[serde(skip)]
+    pub moved: HashSet<Id>,
+}
+// A position as the account sees it, valued at the mark price of its spot market
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PositionView {
+    pub symbol: Symbol,
+    pub quantity: Quantity,
+    pub entry_price: Price,
+    pub margin: Quantity,
+    pub mark_price: Option<Price>,
+    pub unrealized_pnl: Option<Quantity>,
+}
+// Positive amounts are paid by the position, longs pay shorts while the perpetual trades above
+// its mark price
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FundingPayment {
+    pub user_id: Id,
+    pub symbol: Symbol,
+    pub rate: Decimal,
+    pub amount: Quantity,
+}
+// user, from or to account, asset, amount
+pub type PerpLeg = (Id, Account, String, Quantity);
+// One side of a perpetual fill. Limit orders locked margin at their own price for every unit,
+// the units that open a position post it and the units that close one get it back. Market
+// orders only locked for the units they open, at the prices they fill at.
+#[derive(Debug, Clone)]
+pub struct PerpFill {
+    pub user_id: Id,
+    pub order_side: OrderSide,
+    pub lock_price: Option<Price>,
+}
+
+impl Users {
+    pub fn position_quantity(&self, user_id: Id, symbol: &Symbol) -> Quantity {
+        self.users
+            .get(&user_id)
+            .and_then(|user| user.positions.get(symbol))
+            .map(|position| position.quantity)
+            .unwrap_or_default()
+    }
+    // What of an order on the side closes the open position instead of adding to it
+    pub fn reducible(
+        &self,
+        user_id: Id,
+        symbol: &Symbol,
+        order_side: &OrderSide,
+        quantity: Quantity
+    ) -> Quantity {
+        let open = self.position_quantity(user_id, symbol);
+        let closable = match order_side {
+            OrderSide::Bid => -open,
+            OrderSide::Ask => open,
+        };
+        closable.max(dec!(0)).min(quantity)
+    }
+    pub fn set_perp_price(&mut self, symbol: &Symbol, price: Price) {
+        self.perpetuals.prices.insert(symbol.clone(), price);
+    }
+    // Closes what the fill reduces, realizing its PnL, then opens the rest at the fill price.
+    // Returns the ledger legs of the side.
+    pub fn settle_perp_fill(
+        &mut self,
+        exchange: &Exchange,
+        fill: &PerpFill,
+        quantity: Quantity,
+        price: Price
+    ) -> Vec<PerpLeg> {
+        let PerpFill { user_id, order_side, lock_price } = fill;
+        let user_id = *user_id;
+        let closing = self.reducible(user_id, &exchange.symbol, order_side, quantity);
+        let opening = quantity - closing;
+        let posted = opening * lock_price.unwrap_or(price) * INITIAL_MARGIN;
+        let released = closing * lock_price.unwrap_or_default() * INITIAL_MARGIN;
+        self.unlock_amount(&exchange.quote, user_id, posted + released);
+        let user = self.users.get_mut(&user_id).unwrap();
+        let position = user.positions.entry(exchange.symbol.clone()).or_default();
+        let direction = match order_side {
+            OrderSide::Bid => dec!(1),
+            OrderSide::Ask => dec!(-1),
+        };
+        let (mut freed, mut pnl) = (dec!(0), dec!(0));
+        if closing > dec!(0) {
+            freed = (position.margin * closing) / position.quantity.abs();
+            pnl = -direction * (price - position.entry_price) * closing;
+            position.margin -= freed;
+            position.quantity += direction * closing;
+        }
+        if opening > dec!(0) {
+            let open = position.quantity.abs();
+            let cost = position.entry_price * open + price * opening;
+            position.entry_price = cost / (open + opening);
+            position.quantity += direction * opening;
+            position.margin += posted;
+        }
+        if position.quantity.is_zero() {
+            freed += position.margin;
+            user.positions.remove(&exchange.symbol);
+        }
+        // the balance never pays for a loss past the margin the fill frees
+        let shortfall = (-(freed + pnl)).max(dec!(0));
+        if shortfall > dec!(0) {
+            *self.perpetuals.shortfall.entry(exchange.symbol.clone()).or_default() += shortfall;
+        }
+        *user.balance.entry(exchange.quote).or_default() += freed + pnl + shortfall - posted;
+        self.perpetuals.moved.insert(user_id);
+        let quote = exchange.quote.to_string();
+        [
+            (Account::Locked, Account::Position, posted),
+            (Account::Locked, Account::Available, released),
+            (Account::Position, Account::Available, freed),
+            (Account::Settlement, Account::Available, pnl),
+            (Account::Insurance, Account::Available, shortfall),
+        ]
+            .into_iter()
+            .filter(|(_, _, amount)| !amount.is_zero())
+            .flat_map(|(from, to, amount)| {
+                [(user_id, from, quote.clone(), -amount), (user_id, to, quote.clone(), amount)]
+            })
+            .collect()
+    }
+    pub fn positions(&self, user_id: Id) -> Result<Vec<PositionView>, MatchingEngineErrors> {
+        let user = self.users.get(&user_id).ok_or(MatchingEngineErrors::UserNotFound)?;
+        let mut positions: Vec<PositionView> = user.positions
+            .iter()
+            .map(|(symbol, position)| {
+                let mark_price = self.perp_mark_price(symbol);
+                PositionView {
+                    symbol: symbol.clone(),
+                    quantity: position.quantity,
+                    entry_price: position.entry_price,
+                    margin: position.margin,
+                    mark_price,
+                    unrealized_pnl: mark_price.map(|mark| position.unrealized_pnl(mark)),
+                }
+            })
+            .collect();
+        positions.sort_by(|a, b| a.symbol.cmp(&b.symbol));
+        Ok(positions)
+    }
+    // Perpetuals are marked at the reference price of the spot market of their base
+    pub fn perp_mark_price(&self, symbol: &Symbol) -> Option<Price> {
+        let exchange = Exchange::from_symbol(symbol.clone()).ok()?;
+        self.mark_price(&exchange.base)
+    }
+    // How far the perpetual trades from its mark price, capped either way
+    pub fn funding_rate(&self, symbol: &Symbol) -> Option<Decimal> {
+        let mark_price = self.perp_mark_price(symbol)?;
+        let price = self.perpetuals.prices.get(symbol)?;
+        let premium = (price - mark_price) / mark_price;
+        Some(premium.clamp(-MAX_FUNDING_RATE, MAX_FUNDING_RATE))
+    }
+    // Worked out before the request is journaled so a replay pays exactly the same
+    pub fn funding_payments(&self) -> Vec<FundingPayment> {
+        let mut payments: Vec<FundingPayment> = self.users
+            .values()
+            .flat_map(|user| {
+                user.positions.iter().filter_map(|(symbol, position)| {
+                    let rate = self.funding_rate(symbol)?;
+                    let mark_price = self.perp_mark_price(symbol)?;
+                    Some(FundingPayment {
+                        user_id: user.id,
+                        symbol: symbol.clone(),
+                        rate,
+                        amount: position.quantity * mark_price * rate,
+                    })
+                })
+            })
+            .filter(|payment| !payment.amount.is_zero())
+            .collect();
+        payments.sort_by(|a, b| (a.user_id, &a.symbol).cmp(&(b.user_id, &b.symbol)));
+        payments
+    }
+    // Payments move between position margins, an interval that was paid is not paid again.
+    // Intervals missed while the engine was down are paid at the rates it is back with, each
+    // with its own entries. Returns the entries of every account that paid or was paid.
+    pub fn pay_funding(
+        &mut self,
+        interval: u64,
+        payments: &[FundingPayment],
+        timestamp: u64
+    ) -> Vec<(Id, Vec<LedgerEntry>)> {
+        if interval <= self.perpetuals.last_funding {
+            return Vec::new();
+        }
+        // nothing was ever paid before the first interval
+        let first_interval = match self.perpetuals.last_funding {
+            0 => interval,
+            last_funding => last_funding + 1,
+        };
+        self.perpetuals.last_funding = interval;
+        let mut paid: Vec<(Id, Vec<LedgerEntry>)> = Vec::new();
+        for payment in payments {
+            let Ok(exchange) = Exchange::from_symbol(payment.symbol.clone()) else {
+                continue;
+            };
+            let Some(user) = self.users.get_mut(&payment.user_id) else {
+                continue;
+            };
+            let Some(position) = user.positions.get_mut(&payment.symbol) else {
+                continue;
+            };
+            let mut entries = Vec::new();
+            for paid_interval in first_interval..=interval {
+                position.margin -= payment.amount;
+                entries.extend(
+                    ledger::funding(
+                        payment.user_id,
+                        &exchange.quote,
+                        payment.amount,
+                        paid_interval,
+                        timestamp
+                    )
+                );
+            }
+            match paid.last_mut() {
+                Some((user_id, paid_entries)) if *user_id == payment.user_id => {
+                    paid_entries.extend(entries);
+                }
+                _ => paid.push((payment.user_id, entries)),
+            }
+        }
+        paid
+    }
+    // Fills move positions in every market thread, whichever persists next writes them all
+    pub fn take_moved_positions(&mut self) -> Vec<PersistPositions> {
+        let moved: Vec<Id> = self.perpetuals.moved.drain().collect();
+        moved
+            .into_iter()
+            .filter_map(|user_id| {
+                let user = self.users.get(&user_id)?.clone();
+                Some(PersistPositions { user, stamp: self.stamp(), ledger: Vec::new() })
+            })
+            .collect()
+    }
+    // Market orders closing every position whose equity is under its maintenance margin
+    pub fn positions_below_maintenance(&self) -> Vec<(Id, LiquidationOrder)> {
+        let mut liquidations: Vec<(Id, LiquidationOrder)> = self.users
+            .values()
+            .flat_map(|user| {
+                user.positions.iter().filter_map(|(symbol, position)| {
+                    let mark_price = self.perp_mark_price(symbol)?;
+                    if position.equity(mark_price) >= position.maintenance_margin(mark_price) {
+                        return None;
+                    }
+                    let order_side = match position.quantity > dec!(0) {
+                        true => OrderSide::Ask,
+                        false => OrderSide::Bid,
+                    };
+                    Some((user.id, LiquidationOrder {
+                        symbol: symbol.clone(),
+                        order_side,
+                        quantity: position.quantity.abs(),
+                    }))
+                })
+            })
+            .collect();
+        liquidations.sort_by(|a, b| (a.0, &a.1.symbol).cmp(&(b.0, &b.1.symbol)));
+        liquidations
+    }
+}
+
+impl Orderbook {
+    // Market orders on a perpetual lock margin for the quote of the units that open a position,
+    // the units walking the book first close what is open
+    pub fn perp_market_lock(
+        &mut self,
+        order: &RecievedOrder
+    ) -> Result<Quantity, MatchingEngineErrors> {
+        let reducible = self.balances.reducible(
+            order.user_id as u64,
+            &self.exchange.symbol,
+            &order.order_side,
+            order.initial_quantity
+        );
+        let quote = self.get_quote(&order.order_side, order.initial_quantity)?;
+        let closing_quote = match reducible > dec!(0) {
+            true => self.get_quote(&order.order_side, reducible)?,
+            false => dec!(0),
+        };
+        Ok((quote - closing_quote) * INITIAL_MARGIN)
+    }
+}
+
+// The positions column of user_table, each position as json
+#[derive(Debug, Clone, FromRow)]
+pub struct ScyllaPositions {
+    pub id: i64,
+    pub positions: Option<HashMap<String, String>>,
+}
+impl ScyllaPositions {
+    pub fn recover(self, users: &mut Users) -> Result<(), serde_json::Error> {
+        let Some(user) = users.users.get_mut(&(self.id as u64)) else {
+            return Ok(());
+        };
+        user.positions = self.positions
+            .unwrap_or_default()
+            .iter()
+            .map(|(symbol, position)| Ok((symbol.clone(), from_str(position)?)))
+            .collect::<Result<_, serde_json::Error>>()?;
+        Ok(())
+    }
+}
+pub fn scylla_positions(positions: &HashMap<Symbol, Position>) -> HashMap<String, String> {
+    positions
+        .iter()
+        .map(|(symbol, position)| (symbol.clone(), to_string(position).unwrap()))
+        .collect()
+}
+pub async fn persist_positions(session: &Session, persist: PersistPositions) {
+    let update_positions =
+        r#"
+        UPDATE keyspace_1.user_table USING TIMESTAMP ?
+        SET
+            positions = ?
+        WHERE id = ?;
+    "#;
+    let user_id = persist.user.id as i64;
+    let positions = scylla_positions(&persist.user.positions);
+    let res = session.query(update_positions, (persist.stamp, positions, user_id)).await;
+    if let Err(err) = res {
+        eprintln!("Could not persist positions of {}: {}", user_id, err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{ Arc, Mutex };
+
+    use crate::matching_engine::{ events::NoEvents, Asset, OrderStatus, OrderType };
+
+    use super::*;
+
+    fn perp_book() -> (Orderbook, Arc<Mutex<Users>>) {
+        let mut users = Users::default();
+        for user_id in 1..=3 {
+            users.new_user(user_id);
+            users.deposit(&Asset::USDT, dec!(10000), user_id).unwrap();
+        }
+        let users = Arc::new(Mutex::new(users));
+        let mut orderbook = Orderbook::new(Exchange::perpetual(Asset::BTC, Asset::USDT));
+        orderbook.attach(users.clone(), Arc::new(NoEvents));
+        (orderbook, users)
+    }
+    fn order(
+        user_id: Id,
+        order_side: OrderSide,
+        order_type: OrderType,
+        price: Price
+    ) -> RecievedOrder {
+        RecievedOrder {
+            id: 0,
+            user_id: user_id as i64,
+            symbol: "BTC_USDT_PERP".to_string(),
+            price,
+            initial_quantity: dec!(1),
+            filled_quantity: dec!(0),
+            quote_quantity: price,
+            filled_quote_quantity: dec!(0),
+            order_type,
+            order_side,
+            order_status: OrderStatus::InProgress,
+            timestamp: 0,
+        }
+    }
+    fn execute(orderbook: &mut Orderbook, order: RecievedOrder) {
+        orderbook.lock_order(&order).unwrap();
+        let order_id = orderbook.increment_order_id();
+        orderbook.process_order(order, order_id);
+    }
+
+    #[test]
+    fn liquidations_skip_the_checks_of_orders_users_send() {
+        let (mut orderbook, users) = perp_book();
+        execute(&mut orderbook, order(1, OrderSide::Bid, OrderType::Limit, dec!(1000)));
+        execute(&mut orderbook, order(2, OrderSide::Ask, OrderType::Limit, dec!(1000)));
+        execute(&mut orderbook, order(3, OrderSide::Bid, OrderType::Limit, dec!(800)));
+        users.lock().unwrap().set_frozen(1, true).unwrap();
+        let close = order(1, OrderSide::Ask, OrderType::Market, dec!(0));
+        assert!(matches!(
+            orderbook.lock_order(&close),
+            Err(MatchingEngineErrors::PriceOutsideBand)
+        ));
+        orderbook.lock_liquidation(&close).unwrap();
+        let order_id = orderbook.increment_order_id();
+        orderbook.process_order(close, order_id);
+        let users = users.lock().unwrap();
+        assert!(!users.users[&1].positions.contains_key("BTC_USDT_PERP"));
+        assert_eq!(users.users[&3].positions["BTC_USDT_PERP"].quantity, dec!(1));
+    }
+    #[test]
+    fn fills_open_and_close_positions_with_their_pnl() {
+        let (mut orderbook, users) = perp_book();
+        execute(&mut orderbook, order(1, OrderSide::Bid, OrderType::Limit, dec!(1000)));
+        execute(&mut orderbook, order(2, OrderSide::Ask, OrderType::Limit, dec!(1000)));
+        {
+            let users = users.lock().unwrap();
+            let long = &users.users[&1].positions["BTC_USDT_PERP"];
+            assert_eq!(
+                (long.quantity, long.entry_price, long.margin),
+                (dec!(1), dec!(1000), dec!(100))
+            );
+            assert_eq!(users.users[&2].positions["BTC_USDT_PERP"].quantity, dec!(-1));
+            assert_eq!(users.users[&1].balance[&Asset::USDT], dec!(9900));
+            assert_eq!(users.users[&1].locked_balance[&Asset::USDT], dec!(0));
+        }
+        // the long closes into a new bid from a third account, the short stays open against it
+        execute(&mut orderbook, order(3, OrderSide::Bid, OrderType::Limit, dec!(1100)));
+        execute(&mut orderbook, order(1, OrderSide::Ask, OrderType::Market, dec!(0)));
+        let users = users.lock().unwrap();
+        assert!(users.users[&1].positions.is_empty());
+        assert_eq!(users.users[&1].balance[&Asset::USDT], dec!(10100));
+        assert_eq!(users.users[&1].locked_balance[&Asset::USDT], dec!(0));
+        assert_eq!(users.users[&3].positions["BTC_USDT_PERP"].entry_price, dec!(1100));
+    }
+
+    #[test]
+    fn losses_past_the_margin_are_covered_and_recorded() {
+        let (mut orderbook, users) = perp_book();
+        orderbook.max_price_deviation = None;
+        execute(&mut orderbook, order(1, OrderSide::Bid, OrderType::Limit, dec!(1000)));
+        execute(&mut orderbook, order(2, OrderSide::Ask, OrderType::Limit, dec!(1000)));
+        execute(&mut orderbook, order(3, OrderSide::Bid, OrderType::Limit, dec!(850)));
+        execute(&mut orderbook, order(1, OrderSide::Ask, OrderType::Market, dec!(0)));
+        let mut users = users.lock().unwrap();
+        // the long loses 150 on 100 of margin, its balance is left where the margin took it
+        assert_eq!(users.users[&1].balance[&Asset::USDT], dec!(9900));
+        assert_eq!(users.perpetuals.shortfall["BTC_USDT_PERP"], dec!(50));
+        let mut moved: Vec<Id> = users
+            .take_moved_positions()
+            .iter()
+            .map(|persist| persist.user.id)
+            .collect();
+        moved.sort();
+        assert_eq!(moved, vec![1, 2, 3]);
+        assert!(users.take_moved_positions().is_empty());
+    }
+
+    #[test]
+    fn funding_is_paid_once_and_underwater_positions_are_liquidated() {
+        let (mut orderbook, users) = perp_book();
+        execute(&mut orderbook, order(1, OrderSide::Bid, OrderType::Limit, dec!(1000)));
+        execute(&mut orderbook, order(2, OrderSide::Ask, OrderType::Limit, dec!(1000)));
+        let mut users = users.lock().unwrap();
+        users.set_mark_price(&Asset::BTC, dec!(990));
+        users.set_perp_price(&"BTC_USDT_PERP".to_string(), dec!(1000));
+        let payments = users.funding_payments();
+        // 1% over the mark is capped at 0.75%, the long pays the short
+        assert_eq!(payments[0].amount, dec!(7.425));
+        assert_eq!(payments[1].amount, dec!(-7.425));
+        assert_eq!(users.pay_funding(1, &payments, 0).len(), 2);
+        assert!(users.pay_funding(1, &payments, 0).is_empty());
+        assert_eq!(users.users[&1].positions["BTC_USDT_PERP"].margin, dec!(92.575));
+        assert!(users.positions_below_maintenance().is_empty());
+        // back after missing the second interval, both are paid
+        let paid = users.pay_funding(3, &payments, 0);
+        assert_eq!(paid[0].1.len(), 4);
+        assert_eq!(users.users[&1].positions["BTC_USDT_PERP"].margin, dec!(77.725));
+
+        users.set_mark_price(&Asset::BTC, dec!(950));
+        let liquidations = users.positions_below_maintenance();
+        assert_eq!(liquidations, vec![
+            (1, LiquidationOrder {
+                symbol: "BTC_USDT_PERP".to_string(),
+                order_side: OrderSide::Ask,
+                quantity: dec!(1),
+            })
+        ]);
+    }
+}
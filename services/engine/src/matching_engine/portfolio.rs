@@ -0,0 +1,147 @@
+use common::numeric::Numeric;
+use rust_decimal_macros::dec;
+use scylla::Session;
+use serde::{ Deserialize, Serialize };
+use serde_json::to_string;
+use strum::IntoEnumIterator;
+
+use crate::PersistPortfolio;
+
+use super::{
+    error::MatchingEngineErrors,
+    margin::HOUR,
+    perpetual::PositionView,
+    Asset,
+    Id,
+    Price,
+    Quantity,
+    Users,
+};
+
+pub const DAY: u64 = 24 * HOUR;
+
+// What the account holds of an asset less what it owes of it, valued in USDT at the reference
+// price of the asset's market
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Holding {
+    pub asset: Asset,
+    pub balance: Quantity,
+    pub locked: Quantity,
+    pub owed: Quantity,
+    pub price: Option<Price>,
+    pub value: Option<Quantity>,
+}
+// Assets whose market has no price yet are listed without a value and left out of the total.
+// Positions count with their margin and unrealized PnL.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Valuation {
+    pub user_id: Id,
+    pub value: Quantity,
+    pub holdings: Vec<Holding>,
+    pub positions: Vec<PositionView>,
+    pub timestamp: u64,
+}
+
+impl Users {
+    pub fn valuation(&self, user_id: Id, timestamp: u64) -> Result<Valuation, MatchingEngineErrors> {
+        let user = self.users.get(&user_id).ok_or(MatchingEngineErrors::UserNotFound)?;
+        let holdings: Vec<Holding> = Asset::iter()
+            .filter_map(|asset| {
+                let balance = user.balance.get(&asset).copied().unwrap_or_default();
+                let locked = user.locked_balance.get(&asset).copied().unwrap_or_default();
+                let owed = user.debt(&asset);
+                if balance.is_zero() && owed.is_zero() {
+                    return None;
+                }
+                let price = self.mark_price(&asset);
+                Some(Holding {
+                    asset,
+                    balance,
+                    locked,
+                    owed,
+                    price,
+                    value: price.map(|price| (balance - owed) * price),
+                })
+            })
+            .collect();
+        let positions = self.positions(user_id)?;
+        let held: Quantity = holdings.iter().filter_map(|holding| holding.value).sum();
+        let margined: Quantity = positions
+            .iter()
+            .map(|position| position.margin + position.unrealized_pnl.unwrap_or_default())
+            .sum();
+        Ok(Valuation { user_id, value: held + margined, holdings, positions, timestamp })
+    }
+    pub fn valuations(&self, timestamp: u64) -> Vec<Valuation> {
+        let mut user_ids: Vec<Id> = self.users.keys().copied().collect();
+        user_ids.sort();
+        user_ids
+            .into_iter()
+            .filter_map(|user_id| self.valuation(user_id, timestamp).ok())
+            .collect()
+    }
+}
+
+// One row per account and day, a later snapshot of the same day replaces the earlier one
+// Every account gets its own row write, a snapshot of every account is too large for one batch.
+// An account that fails is logged and the rest are still written.
+pub async fn persist_portfolio(session: &Session, persist: PersistPortfolio) {
+    if persist.valuations.is_empty() {
+        return;
+    }
+    let new_snapshot =
+        r#"
+        INSERT INTO keyspace_1.portfolio_table (
+            user_id,
+            day,
+            value,
+            holdings,
+            timestamp
+        ) VALUES (?, ?, ?, ?, ?);
+    "#;
+    let prepared = match session.prepare(new_snapshot).await {
+        Ok(prepared) => prepared,
+        Err(err) => {
+            eprintln!("Could not persist portfolios of day {}: {}", persist.day, err);
+            return;
+        }
+    };
+    for valuation in persist.valuations.iter() {
+        let values = (
+            valuation.user_id as i64,
+            persist.day as i64,
+            Numeric(valuation.value),
+            to_string(&valuation.holdings).unwrap(),
+            valuation.timestamp as i64,
+        );
+        if let Err(err) = session.execute(&prepared, values).await {
+            eprintln!("Could not persist portfolio of {}: {}", valuation.user_id, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_what_is_held_less_what_is_owed_at_market_prices() {
+        let mut users = Users::default();
+        users.new_user(1);
+        users.deposit(&Asset::USDT, dec!(500), 1).unwrap();
+        users.deposit(&Asset::SOL, dec!(3), 1).unwrap();
+        users.deposit(&Asset::ETH, dec!(1), 1).unwrap();
+        users.lock_amount(&Asset::SOL, 1, dec!(1));
+        users.users.get_mut(&1).unwrap().borrowed.insert(Asset::USDT, dec!(100));
+        users.set_mark_price(&Asset::SOL, dec!(20));
+
+        let valuation = users.valuation(1, 7).unwrap();
+        // ETH has no market price yet, so it is listed without a value
+        assert_eq!(valuation.value, dec!(460));
+        let sol = valuation.holdings.iter().find(|holding| holding.asset == Asset::SOL).unwrap();
+        assert_eq!((sol.locked, sol.value), (dec!(1), Some(dec!(60))));
+        let eth = valuation.holdings.iter().find(|holding| holding.asset == Asset::ETH).unwrap();
+        assert_eq!(eth.value, None);
+        assert!(users.valuation(2, 7).is_err());
+    }
+}
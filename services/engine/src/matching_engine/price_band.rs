@@ -0,0 +1,96 @@
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+
+use super::{
+    error::MatchingEngineErrors,
+    orderbook::{ Limit, Orderbook },
+    OrderSide,
+    Price,
+    Quantity,
+};
+
+pub fn default_max_price_deviation() -> Option<Decimal> {
+    Some(dec!(10))
+}
+
+impl Orderbook {
+    // The last trade of the market, or the middle of the book before it traded
+    pub fn reference_price(&self) -> Option<Price> {
+        if self.last_price.is_some() {
+            return self.last_price;
+        }
+        let resting = |limit: &&Limit| !limit.orders.is_empty();
+        let best_bid = self.bids.values().filter(resting).map(|limit| limit.price).max()?;
+        let best_ask = self.asks.values().filter(resting).map(|limit| limit.price).min()?;
+        Some((best_bid + best_ask) / dec!(2))
+    }
+    // Only the side that would sweep the book is bounded, a bid far below the market or an ask
+    // far above it just rests. Market orders are checked at the average price of their quote.
+    pub fn check_price_band(
+        &self,
+        order_side: &OrderSide,
+        price: Price,
+        quantity: Quantity,
+        quote: Option<Quantity>
+    ) -> Result<(), MatchingEngineErrors> {
+        let (Some(max_deviation), Some(reference)) = (
+            self.max_price_deviation,
+            self.reference_price(),
+        ) else {
+            return Ok(());
+        };
+        let price = match quote {
+            Some(quote) =>
+                quote.checked_div(quantity).ok_or(MatchingEngineErrors::InvalidQuantity)?,
+            None => price,
+        };
+        let deviation = reference * max_deviation / dec!(100);
+        let outside = match order_side {
+            OrderSide::Bid => price > reference + deviation,
+            OrderSide::Ask => price < reference - deviation,
+        };
+        match outside {
+            true => Err(MatchingEngineErrors::PriceOutsideBand),
+            false => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matching_engine::{ orderbook::Order, Asset, Exchange, OrderType };
+
+    fn resting(orderbook: &mut Orderbook, id: u64, order_side: OrderSide, price: Price) {
+        let order = Order::new(id, 0, order_side, dec!(1), OrderType::Limit, 1);
+        orderbook.add_limit_order(price, order);
+    }
+
+    #[test]
+    fn prices_are_checked_against_the_last_trade_or_the_middle_of_the_book() {
+        let mut orderbook = Orderbook::new(Exchange::new(Asset::SOL, Asset::USDT));
+        orderbook.check_price_band(&OrderSide::Bid, dec!(1000), dec!(1), None).unwrap();
+        resting(&mut orderbook, 1, OrderSide::Bid, dec!(90));
+        resting(&mut orderbook, 2, OrderSide::Ask, dec!(110));
+        assert_eq!(orderbook.reference_price(), Some(dec!(100)));
+        orderbook.check_price_band(&OrderSide::Bid, dec!(110), dec!(1), None).unwrap();
+        assert!(matches!(
+            orderbook.check_price_band(&OrderSide::Bid, dec!(111), dec!(1), None),
+            Err(MatchingEngineErrors::PriceOutsideBand)
+        ));
+        orderbook.check_price_band(&OrderSide::Bid, dec!(1), dec!(1), None).unwrap();
+
+        orderbook.last_price = Some(dec!(200));
+        assert!(matches!(
+            orderbook.check_price_band(&OrderSide::Ask, dec!(0), dec!(2), Some(dec!(350))),
+            Err(MatchingEngineErrors::PriceOutsideBand)
+        ));
+        orderbook.check_price_band(&OrderSide::Ask, dec!(0), dec!(2), Some(dec!(360))).unwrap();
+        assert!(matches!(
+            orderbook.check_price_band(&OrderSide::Ask, dec!(0), dec!(0), Some(dec!(0))),
+            Err(MatchingEngineErrors::InvalidQuantity)
+        ));
+        orderbook.max_price_deviation = None;
+        orderbook.check_price_band(&OrderSide::Bid, dec!(1000), dec!(1), None).unwrap();
+    }
+}
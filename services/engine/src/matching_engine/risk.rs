@@ -0,0 +1,321 @@
+use std::collections::{ HashMap, HashSet };
+
+use rust_decimal_macros::dec;
+use serde::{ Deserialize, Serialize };
+
+use super::{
+    error::MatchingEngineErrors,
+    orderbook::Orderbook,
+    Asset,
+    Exchange,
+    Id,
+    Quantity,
+    Symbol,
+    Users,
+};
+
+// Checked before an order is locked, notionals are valued in VALUATION_ASSET at the mark price of
+// the quote asset of their market
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RiskLimits {
+    // Resting in one market
+    pub max_open_orders: usize,
+    pub max_order_notional: Quantity,
+    // Resting across every market
+    pub max_open_notional: Quantity,
+    pub max_orders_per_second: u32,
+}
+impl Default for RiskLimits {
+    fn default() -> RiskLimits {
+        RiskLimits {
+            max_open_orders: 200,
+            max_order_notional: dec!(1_000_000),
+            max_open_notional: dec!(5_000_000),
+            max_orders_per_second: 50,
+        }
+    }
+}
+// What an account has resting in one market, the notional in its quote asset
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Exposure {
+    pub open_orders: usize,
+    pub notional: Quantity,
+}
+// What every account has resting in a book, kept as orders rest, fill and are cancelled so an
+// order is checked without going through the book
+#[derive(Debug, Clone, Default)]
+pub struct Exposures {
+    exposures: HashMap<Id, Exposure>,
+    // Accounts whose exposure changed since the market last published it
+    changed: HashSet<Id>,
+}
+impl Exposures {
+    pub fn get(&self, user_id: Id) -> Exposure {
+        self.exposures.get(&user_id).copied().unwrap_or_default()
+    }
+    pub fn change(&mut self, user_id: Id, open_orders: isize, notional: Quantity) {
+        let exposure = self.exposures.entry(user_id).or_default();
+        exposure.open_orders = exposure.open_orders.saturating_add_signed(open_orders);
+        exposure.notional += notional;
+        if exposure.open_orders == 0 {
+            self.exposures.remove(&user_id);
+        }
+        self.changed.insert(user_id);
+    }
+    pub fn remove(&mut self, user_id: Id) {
+        self.exposures.remove(&user_id);
+        self.changed.insert(user_id);
+    }
+    // The open notional of each account that changed, zero for those with nothing left
+    fn take_changes(&mut self) -> HashMap<Id, Quantity> {
+        self.changed
+            .drain()
+            .map(|user_id| (user_id, self.exposures.get(&user_id).map_or(dec!(0), |e| e.notional)))
+            .collect()
+    }
+}
+// Rebuilt rather than snapshotted, every market publishes what rests in it when it starts and
+// what changed after each request that changes its book
+#[derive(Debug, Clone, Default)]
+pub struct RiskState {
+    // In the quote asset of each market
+    open_notional: HashMap<Id, HashMap<Symbol, Quantity>>,
+    quotes: HashMap<Symbol, Asset>,
+    // The second orders were last counted in and how many were
+    order_rate: HashMap<Id, (u64, u32)>,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccountRisk {
+    pub user_id: Id,
+    pub limits: RiskLimits,
+    pub open_notional: HashMap<Symbol, Quantity>,
+}
+
+impl Users {
+    pub fn risk_limits(&self, user_id: Id) -> RiskLimits {
+        self.risk_limits.get(&user_id).copied().unwrap_or_default()
+    }
+    // Without limits the account goes back to the defaults
+    pub fn set_risk_limits(
+        &mut self,
+        user_id: Id,
+        limits: Option<RiskLimits>
+    ) -> Result<AccountRisk, MatchingEngineErrors> {
+        if !self.users.contains_key(&user_id) {
+            return Err(MatchingEngineErrors::UserNotFound);
+        }
+        match limits {
+            Some(limits) => self.risk_limits.insert(user_id, limits),
+            None => self.risk_limits.remove(&user_id),
+        };
+        self.account_risk(user_id)
+    }
+    pub fn account_risk(&self, user_id: Id) -> Result<AccountRisk, MatchingEngineErrors> {
+        if !self.users.contains_key(&user_id) {
+            return Err(MatchingEngineErrors::UserNotFound);
+        }
+        Ok(AccountRisk {
+            user_id,
+            limits: self.risk_limits(user_id),
+            open_notional: self.risk.open_notional.get(&user_id).cloned().unwrap_or_default(),
+        })
+    }
+    fn valued(&self, quote: &Asset, notional: Quantity) -> Result<Quantity, MatchingEngineErrors> {
+        let price = self.mark_price(quote).ok_or(MatchingEngineErrors::MarkPriceUnavailable)?;
+        Ok(notional * price)
+    }
+    // Every order reaching the checks counts towards the rate, `exposure` is what the account
+    // has resting in the order's market right now
+    pub fn check_risk(
+        &mut self,
+        user_id: Id,
+        exchange: &Exchange,
+        exposure: Exposure,
+        notional: Quantity,
+        timestamp: u64
+    ) -> Result<(), MatchingEngineErrors> {
+        let symbol = &exchange.symbol;
+        self.check_margin_market(user_id, symbol)?;
+        let limits = self.risk_limits(user_id);
+        let second = timestamp / 1_000_000;
+        let (counted_in, count) = self.risk.order_rate.entry(user_id).or_insert((second, 0));
+        if *counted_in != second {
+            *counted_in = second;
+            *count = 0;
+        }
+        if *count >= limits.max_orders_per_second {
+            return Err(MatchingEngineErrors::OrderRateLimitExceeded);
+        }
+        *count += 1;
+        if exposure.open_orders >= limits.max_open_orders {
+            return Err(MatchingEngineErrors::OpenOrdersLimitExceeded);
+        }
+        let notional = self.valued(&exchange.quote, notional)?;
+        if notional > limits.max_order_notional {
+            return Err(MatchingEngineErrors::OrderNotionalLimitExceeded);
+        }
+        let mut open_notional = self.valued(&exchange.quote, exposure.notional)? + notional;
+        let elsewhere = self.risk.open_notional.get(&user_id).into_iter().flatten();
+        for (market, market_notional) in elsewhere.filter(|(market, _)| *market != symbol) {
+            let quote = self.risk.quotes.get(market).unwrap_or(&exchange.quote);
+            open_notional += self.valued(quote, *market_notional)?;
+        }
+        if open_notional > limits.max_open_notional {
+            return Err(MatchingEngineErrors::OpenNotionalLimitExceeded);
+        }
+        Ok(())
+    }
+    pub fn open_markets(&self, user_id: Id) -> Vec<Symbol> {
+        let mut markets: Vec<Symbol> = self.risk.open_notional
+            .get(&user_id)
+            .map(|markets| markets.keys().cloned().collect())
+            .unwrap_or_default();
+        markets.sort();
+        markets
+    }
+    // Sets what the accounts have resting in the market, none is no longer resting there
+    pub fn set_open_notional(&mut self, exchange: &Exchange, notional: HashMap<Id, Quantity>) {
+        let symbol = &exchange.symbol;
+        self.risk.quotes.insert(symbol.clone(), exchange.quote);
+        for (user_id, notional) in notional {
+            let markets = self.risk.open_notional.entry(user_id).or_default();
+            match notional.is_zero() {
+                true => {
+                    markets.remove(symbol);
+                }
+                false => {
+                    markets.insert(symbol.clone(), notional);
+                }
+            }
+            if markets.is_empty() {
+                self.risk.open_notional.remove(&user_id);
+            }
+        }
+    }
+}
+
+impl Orderbook {
+    pub fn exposure(&self, user_id: Id) -> Exposure {
+        self.exposures.get(user_id)
+    }
+    // Exposures are not snapshotted, a restored book goes through every order once and publishes
+    // all of them
+    pub fn rebuild_exposures(&mut self) {
+        let mut exposures = Exposures::default();
+        for limit in self.bids.values().chain(self.asks.values()) {
+            for order in limit.orders.iter() {
+                exposures.change(order.user_id, 1, order.quantity * limit.price);
+            }
+        }
+        self.exposures = exposures;
+    }
+    pub fn publish_open_notional(&mut self) {
+        let changes = self.exposures.take_changes();
+        if !changes.is_empty() {
+            self.balances.set_open_notional(&self.exchange, changes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matching_engine::{ orderbook::Order, OrderSide, OrderType };
+
+    fn limits() -> RiskLimits {
+        RiskLimits {
+            max_open_orders: 2,
+            max_order_notional: dec!(100),
+            max_open_notional: dec!(250),
+            max_orders_per_second: 3,
+        }
+    }
+
+    #[test]
+    fn orders_are_rejected_with_the_limit_they_breach() {
+        let mut users = Users::default();
+        users.new_user(1);
+        users.set_risk_limits(1, Some(limits())).unwrap();
+        let market = Exchange::new(Asset::SOL, Asset::USDT);
+        let exposure = Exposure { open_orders: 1, notional: dec!(100) };
+        assert!(matches!(
+            users.check_risk(1, &market, exposure, dec!(101), 0),
+            Err(MatchingEngineErrors::OrderNotionalLimitExceeded)
+        ));
+        let full = Exposure { open_orders: 2, notional: dec!(100) };
+        assert!(matches!(
+            users.check_risk(1, &market, full, dec!(10), 0),
+            Err(MatchingEngineErrors::OpenOrdersLimitExceeded)
+        ));
+        let btc = Exchange::new(Asset::BTC, Asset::USDT);
+        users.set_open_notional(&btc, HashMap::from([(1, dec!(100))]));
+        assert!(matches!(
+            users.check_risk(1, &market, exposure, dec!(60), 0),
+            Err(MatchingEngineErrors::OpenNotionalLimitExceeded)
+        ));
+        assert!(matches!(
+            users.check_risk(1, &market, exposure, dec!(50), 0),
+            Err(MatchingEngineErrors::OrderRateLimitExceeded)
+        ));
+        users.check_risk(1, &market, exposure, dec!(50), 1_000_000).unwrap();
+        users.set_open_notional(&btc, HashMap::from([(1, dec!(0))]));
+        assert!(users.open_markets(1).is_empty());
+    }
+    #[test]
+    fn notionals_are_valued_at_the_price_of_their_quote() {
+        let mut users = Users::default();
+        users.new_user(1);
+        users.set_risk_limits(1, Some(limits())).unwrap();
+        let second = |n: u64| n * 1_000_000;
+        let sol_eth = Exchange::new(Asset::SOL, Asset::ETH);
+        let nothing = Exposure::default();
+        assert!(matches!(
+            users.check_risk(1, &sol_eth, nothing, dec!(1), second(0)),
+            Err(MatchingEngineErrors::MarkPriceUnavailable)
+        ));
+        users.set_mark_price(&Asset::ETH, dec!(50));
+        users.check_risk(1, &sol_eth, nothing, dec!(2), second(1)).unwrap();
+        assert!(matches!(
+            users.check_risk(1, &sol_eth, nothing, dec!(3), second(2)),
+            Err(MatchingEngineErrors::OrderNotionalLimitExceeded)
+        ));
+        // 2 ETH resting are 100 USDT, not 2
+        users.set_open_notional(&sol_eth, HashMap::from([(1, dec!(2))]));
+        let sol_usdt = Exchange::new(Asset::SOL, Asset::USDT);
+        let exposure = Exposure { open_orders: 1, notional: dec!(60) };
+        assert!(matches!(
+            users.check_risk(1, &sol_usdt, exposure, dec!(100), second(3)),
+            Err(MatchingEngineErrors::OpenNotionalLimitExceeded)
+        ));
+        users.check_risk(1, &sol_usdt, exposure, dec!(90), second(4)).unwrap();
+    }
+    #[test]
+    fn exposures_follow_the_book() {
+        let mut orderbook = Orderbook::new(Exchange::new(Asset::SOL, Asset::USDT));
+        let ask = Order::new(1, 1, OrderSide::Ask, dec!(5), OrderType::Limit, 1);
+        orderbook.add_limit_order(dec!(10), ask);
+        let bid = Order::new(2, 2, OrderSide::Bid, dec!(2), OrderType::Limit, 1);
+        orderbook.add_limit_order(dec!(9), bid);
+        assert_eq!(orderbook.exposure(1), Exposure { open_orders: 2, notional: dec!(68) });
+        let bid = Order::new(3, 3, OrderSide::Bid, dec!(3), OrderType::Limit, 2);
+        orderbook.fill_limit_order(dec!(10), bid, false, false);
+        assert_eq!(orderbook.exposure(1), Exposure { open_orders: 2, notional: dec!(38) });
+        orderbook.cancel_order(2, 1, &OrderSide::Bid, &dec!(9)).unwrap();
+        assert_eq!(orderbook.exposure(1), Exposure { open_orders: 1, notional: dec!(20) });
+        let exposures = orderbook.exposures.clone();
+        orderbook.rebuild_exposures();
+        assert_eq!(orderbook.exposure(1), exposures.get(1));
+        orderbook.remove_user_orders(1);
+        assert_eq!(orderbook.exposure(1), Exposure::default());
+    }
+    #[test]
+    fn accounts_go_back_to_the_default_limits() {
+        let mut users = Users::default();
+        users.new_user(1);
+        users.set_risk_limits(1, Some(limits())).unwrap();
+        assert_eq!(users.risk_limits(1), limits());
+        let risk = users.set_risk_limits(1, None).unwrap();
+        assert_eq!(risk.limits, RiskLimits::default());
+        assert!(matches!(users.set_risk_limits(2, None), Err(MatchingEngineErrors::UserNotFound)));
+    }
+}
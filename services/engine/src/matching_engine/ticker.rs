@@ -0,0 +1,88 @@
+use std::collections::VecDeque;
+
+use common::events::Ticker;
+use rust_decimal_macros::dec;
+use serde::{ Deserialize, Serialize };
+
+use super::{ orderbook::Orderbook, Price, Quantity };
+
+// Trades older than this fall out of the ticker
+pub const TICKER_WINDOW_MICROS: u64 = 24 * 60 * 60 * 1_000_000;
+
+// The trades of the last 24 hours, oldest first. Trades are timed by the taker order so a
+// replayed journal rebuilds the same window.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TickerWindow {
+    trades: VecDeque<(u64, Price, Quantity)>,
+}
+impl TickerWindow {
+    pub fn record(&mut self, timestamp: u64, price: Price, quantity: Quantity) {
+        if quantity > dec!(0) {
+            self.trades.push_back((timestamp, price, quantity));
+        }
+    }
+    pub fn ticker(&mut self, symbol: &str, now: u64) -> Option<Ticker> {
+        let since = now.saturating_sub(TICKER_WINDOW_MICROS);
+        while self.trades.front().is_some_and(|(timestamp, _, _)| *timestamp < since) {
+            self.trades.pop_front();
+        }
+        let (_, open_price, _) = *self.trades.front()?;
+        let (_, last_price, _) = *self.trades.back()?;
+        let mut ticker = Ticker {
+            symbol: symbol.to_string(),
+            base_volume: dec!(0),
+            quote_volume: dec!(0),
+            price_change: last_price - open_price,
+            price_change_percent: ((last_price - open_price) / open_price * dec!(100)).round_dp(2),
+            high_price: open_price,
+            low_price: open_price,
+            last_price,
+        };
+        for (_, price, quantity) in &self.trades {
+            ticker.base_volume += quantity;
+            ticker.quote_volume += quantity * price;
+            ticker.high_price = ticker.high_price.max(*price);
+            ticker.low_price = ticker.low_price.min(*price);
+        }
+        Some(ticker)
+    }
+}
+
+impl Orderbook {
+    // Nothing is published before the market traded within the window
+    pub fn publish_ticker(&mut self, now: u64) {
+        if let Some(ticker) = self.ticker.ticker(&self.exchange.symbol, now) {
+            self.events.ticker(ticker);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    #[test]
+    fn ticker_covers_the_last_24_hours() {
+        let mut window = TickerWindow::default();
+        let hour = 60 * 60 * 1_000_000;
+        window.record(hour, dec!(90), dec!(5));
+        window.record(2 * hour, dec!(100), dec!(1));
+        window.record(3 * hour, dec!(110), dec!(2));
+        window.record(4 * hour, dec!(105), dec!(1));
+        window.record(4 * hour, dec!(104), dec!(0));
+
+        let ticker = window.ticker("SOL_USDT", 25 * hour + 1).unwrap();
+        assert_eq!(ticker.symbol, "SOL_USDT");
+        assert_eq!(ticker.base_volume, dec!(4));
+        assert_eq!(ticker.quote_volume, dec!(425));
+        assert_eq!(ticker.price_change, dec!(5));
+        assert_eq!(ticker.price_change_percent, dec!(5));
+        assert_eq!(ticker.high_price, dec!(110));
+        assert_eq!(ticker.low_price, dec!(100));
+        assert_eq!(ticker.last_price, dec!(105));
+
+        assert!(window.ticker("SOL_USDT", 29 * hour).is_none());
+    }
+}
@@ -1,4 +1,6 @@
 use rust_decimal::Decimal;
+use common::numeric::Numeric;
+use scylla::transport::errors::QueryError;
 use serde::{ Deserialize, Serialize };
 use strum::IntoEnumIterator;
 use std::{ collections::HashMap, str::FromStr, sync::atomic::Ordering };
@@ -7,27 +9,47 @@ use rust_decimal_macros::dec;
 
 use crate::{ engine::MatchingEngine, Exchange, OrderSide, Price, ScyllaUser, User, Users };
 
-use super::{ error::MatchingEngineErrors, Asset, Id, Quantity };
+use super::{ error::MatchingEngineErrors, invalid_value, ledger, Asset, Id, Quantity };
 
 impl ScyllaUser {
-    pub fn from_scylla_user(&self) -> User {
+    pub fn from_scylla_user(&self) -> Result<User, QueryError> {
+        let asset = |asset: &str| {
+            Asset::from_str(asset).ok_or_else(|| invalid_value("user_table", "balance", asset))
+        };
         let mut balance_map: HashMap<Asset, Quantity> = HashMap::new();
         for (asset_str, balance) in &self.balance {
-            let asset = Asset::from_str(&asset_str).unwrap();
-            let balance = Decimal::from_str(&balance).unwrap();
-            balance_map.insert(asset, balance);
+            balance_map.insert(asset(asset_str)?, balance.0);
         }
         let mut locked_balance_map: HashMap<Asset, Quantity> = HashMap::new();
         for (asset_str, locked_balance) in &self.locked_balance {
-            let asset = Asset::from_str(&asset_str).unwrap();
-            let locked_balance = Decimal::from_str(&locked_balance).unwrap();
-            locked_balance_map.insert(asset, locked_balance);
+            locked_balance_map.insert(asset(asset_str)?, locked_balance.0);
         }
 
-        User {
+        Ok(User {
             id: self.id as u64,
             balance: balance_map,
             locked_balance: locked_balance_map,
+            master_id: None,
+            frozen: false,
+            margin: None,
+            borrowed: HashMap::new(),
+            interest: HashMap::new(),
+            positions: HashMap::new(),
+        })
+    }
+}
+impl User {
+    pub fn to_scylla_user(&self) -> ScyllaUser {
+        let to_numerics = |balances: &HashMap<Asset, Quantity>| {
+            balances
+                .iter()
+                .map(|(asset, quantity)| (asset.to_string(), Numeric(*quantity)))
+                .collect()
+        };
+        ScyllaUser {
+            id: self.id as i64,
+            balance: to_numerics(&self.balance),
+            locked_balance: to_numerics(&self.locked_balance),
         }
     }
 }
@@ -97,6 +119,13 @@ impl Users {
         locked_balance -= quantity;
         user
     }
+    // Balance writes are spawned and land in any order, each carries the stamp of the state it
+    // writes as its Scylla write time so the newest state is kept whatever lands last. Taken
+    // under the same lock the state is read in, in microseconds and never repeated.
+    pub fn stamp(&mut self) -> i64 {
+        self.balance_clock = (ledger::now() as i64).max(self.balance_clock + 1);
+        self.balance_clock
+    }
     pub fn does_exist(&self, user_id: Id) -> bool {
         self.users.contains_key(&user_id)
     }
@@ -113,9 +142,30 @@ impl Users {
             id,
             balance,
             locked_balance,
+            master_id: None,
+            frozen: false,
+            margin: None,
+            borrowed: HashMap::new(),
+            interest: HashMap::new(),
+            positions: HashMap::new(),
         });
         id
     }
+    pub fn next_user_id(&mut self) -> Id {
+        self.last_user_id += 1;
+        self.last_user_id
+    }
+    // Called once users are loaded, never hands out an id that is already taken
+    pub fn recover_last_ids(&mut self) {
+        let highest = self.users.keys().copied().max().unwrap_or(0);
+        self.last_user_id = self.last_user_id.max(highest);
+        let withdrawals = &mut self.withdrawals;
+        let highest = withdrawals.withdrawals.keys().copied().max().unwrap_or(0);
+        withdrawals.last_withdrawal_id = withdrawals.last_withdrawal_id.max(highest);
+        let deposits = &mut self.deposits;
+        let highest = deposits.deposits.values().map(|deposit| deposit.id).max().unwrap_or(0);
+        deposits.last_deposit_id = deposits.last_deposit_id.max(highest);
+    }
     pub fn recover_user(&mut self, user: User) {
         self.users.insert(user.id, user);
     }
@@ -179,6 +229,17 @@ impl Users {
         asset: &Asset,
         user_id: Id,
         cmp_quantity: Quantity
+    ) -> Result<Quantity, MatchingEngineErrors> {
+        if self.is_frozen(user_id) {
+            return Err(MatchingEngineErrors::AccountFrozen);
+        }
+        self.lock_available(asset, user_id, cmp_quantity)
+    }
+    pub fn lock_available(
+        &mut self,
+        asset: &Asset,
+        user_id: Id,
+        cmp_quantity: Quantity
     ) -> Result<Quantity, MatchingEngineErrors> {
         let available_balance = self.available_balance(asset, user_id)?;
         if available_balance < cmp_quantity {
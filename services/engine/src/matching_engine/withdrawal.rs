@@ -0,0 +1,475 @@
+use std::{ cmp::Ordering, collections::{ BTreeMap, HashMap }, str::FromStr };
+
+use common::{ ids, ledger::{ Account, LedgerEntry }, numeric::Numeric };
+use enum_stringify::EnumStringify;
+use rust_decimal_macros::dec;
+use scylla::{ batch::Batch, transport::errors::QueryError, FromRow, SerializeRow, Session };
+use serde::{ Deserialize, Serialize };
+
+use crate::PersistWithdrawal;
+
+use super::{
+    error::MatchingEngineErrors,
+    invalid_value,
+    ledger,
+    portfolio::DAY,
+    Asset,
+    Id,
+    Quantity,
+    Users,
+    SET_LAST_ID,
+    WITHDRAWAL_ID,
+};
+
+// A withdrawal locks its amount as soon as it is requested. Small ones are approved right away,
+// larger ones stay locked until an admin approves them. Once the custody side reports it sent,
+// it completes and the amount leaves the user's balance, or it fails and the amount is released
+// again.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, EnumStringify)]
+pub enum WithdrawalState {
+    Locked,
+    Approved,
+    Sent,
+    Completed,
+    Failed,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Withdrawal {
+    pub id: Id,
+    pub user_id: Id,
+    pub asset: Asset,
+    pub quantity: Quantity,
+    pub destination: String,
+    pub state: WithdrawalState,
+    // Set once sent, the transaction of the payout
+    pub tx_id: Option<String>,
+    pub reason: Option<String>,
+    pub requested_at: u64,
+    pub updated_at: u64,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum WithdrawalAction {
+    Approve,
+    Sent {
+        tx_id: String,
+    },
+    Complete,
+    Fail {
+        reason: String,
+    },
+}
+
+impl WithdrawalState {
+    pub fn is_final(&self) -> bool {
+        matches!(self, WithdrawalState::Completed | WithdrawalState::Failed)
+    }
+}
+// What a user withdrew or is withdrawing of an asset on one day
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct DailyTotal {
+    pub day: u64,
+    pub total: Quantity,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Withdrawals {
+    // Only open withdrawals, finished ones are kept in scylla
+    pub withdrawals: BTreeMap<Id, Withdrawal>,
+    // Last id handed out, recovered from the snapshot and the users journal
+    pub last_withdrawal_id: Id,
+    // Counted against the daily limit, by user and asset
+    #[serde(default)]
+    pub daily: HashMap<Id, HashMap<Asset, DailyTotal>>,
+}
+impl Default for Withdrawals {
+    fn default() -> Withdrawals {
+        Withdrawals {
+            withdrawals: BTreeMap::new(),
+            last_withdrawal_id: ids::base(ids::WITHDRAWALS_NAMESPACE),
+            daily: HashMap::new(),
+        }
+    }
+}
+impl Withdrawals {
+    pub fn next_withdrawal_id(&mut self) -> Id {
+        self.last_withdrawal_id += 1;
+        self.last_withdrawal_id
+    }
+    // What the user withdrew or is withdrawing of the asset on the day of `now`
+    pub fn withdrawn_in_day(&self, user_id: Id, asset: &Asset, now: u64) -> Quantity {
+        match self.daily.get(&user_id).and_then(|assets| assets.get(asset)) {
+            Some(daily) if daily.day == now / DAY => daily.total,
+            _ => dec!(0),
+        }
+    }
+    // Adds to the total of the day the withdrawal was requested on, failed ones take it back.
+    // Returns the total after.
+    fn count(&mut self, withdrawal: &Withdrawal, quantity: Quantity) -> DailyTotal {
+        let day = withdrawal.requested_at / DAY;
+        let daily = self.daily
+            .entry(withdrawal.user_id)
+            .or_default()
+            .entry(withdrawal.asset)
+            .or_insert(DailyTotal { day, total: dec!(0) });
+        match daily.day.cmp(&day) {
+            Ordering::Less => {
+                *daily = DailyTotal { day, total: quantity };
+            }
+            Ordering::Equal => {
+                daily.total += quantity;
+            }
+            // a day that is already over does not count anymore
+            Ordering::Greater => {}
+        }
+        *daily
+    }
+}
+
+// Most a user can withdraw of an asset in a day
+pub fn daily_limit(asset: &Asset) -> Quantity {
+    match asset {
+        Asset::USDT => dec!(100000),
+        Asset::BTC => dec!(2),
+        Asset::SOL => dec!(1000),
+        Asset::ETH => dec!(40),
+    }
+}
+// Withdrawals above this wait for an admin to approve them
+pub fn approval_threshold(asset: &Asset) -> Quantity {
+    match asset {
+        Asset::USDT => dec!(10000),
+        Asset::BTC => dec!(0.2),
+        Asset::SOL => dec!(100),
+        Asset::ETH => dec!(4),
+    }
+}
+
+impl Users {
+    // Returns the withdrawal and the ledger entries of what it locked
+    pub fn request_withdrawal(
+        &mut self,
+        withdrawal: Withdrawal
+    ) -> Result<(Withdrawal, Vec<LedgerEntry>), MatchingEngineErrors> {
+        let Withdrawal { id, user_id, asset, quantity, requested_at, .. } = withdrawal;
+        if quantity <= dec!(0) || withdrawal.destination.trim().is_empty() {
+            return Err(MatchingEngineErrors::InvalidWithdrawal);
+        }
+        if self.is_frozen(user_id) {
+            return Err(MatchingEngineErrors::AccountFrozen);
+        }
+        let available = self.available_balance(&asset, user_id)?;
+        if available < quantity {
+            return Err(MatchingEngineErrors::OverWithdrawl);
+        }
+        self.check_margin_withdrawal(user_id, &asset, quantity)?;
+        let withdrawn = self.withdrawals.withdrawn_in_day(user_id, &asset, requested_at);
+        if withdrawn + quantity > daily_limit(&asset) {
+            return Err(MatchingEngineErrors::WithdrawalLimitExceeded);
+        }
+        self.lock_amount(&asset, user_id, quantity);
+        let state = match quantity > approval_threshold(&asset) {
+            true => WithdrawalState::Locked,
+            false => WithdrawalState::Approved,
+        };
+        let entries = ledger::withdrawal(
+            user_id,
+            &asset,
+            quantity,
+            id,
+            Account::Available,
+            Account::Locked
+        );
+        let withdrawal = Withdrawal { state, updated_at: requested_at, ..withdrawal };
+        self.withdrawals.count(&withdrawal, quantity);
+        self.withdrawals.withdrawals.insert(id, withdrawal.clone());
+        Ok((withdrawal, entries))
+    }
+    // Moves a withdrawal on, completing takes the amount out of the user's balance and failing
+    // releases it. Returns the withdrawal and the ledger entries of what moved.
+    pub fn update_withdrawal(
+        &mut self,
+        id: Id,
+        action: WithdrawalAction,
+        timestamp: u64
+    ) -> Result<(Withdrawal, Vec<LedgerEntry>), MatchingEngineErrors> {
+        let withdrawal = self.withdrawals.withdrawals
+            .get(&id)
+            .ok_or(MatchingEngineErrors::WithdrawalNotFound)?;
+        let (user_id, asset, quantity) = (withdrawal.user_id, withdrawal.asset, withdrawal.quantity);
+        let (state, accounts) = match (&withdrawal.state, &action) {
+            (WithdrawalState::Locked, WithdrawalAction::Approve) => (WithdrawalState::Approved, None),
+            (WithdrawalState::Approved, WithdrawalAction::Sent { .. }) =>
+                (WithdrawalState::Sent, None),
+            (WithdrawalState::Sent, WithdrawalAction::Complete) =>
+                (WithdrawalState::Completed, Some((Account::Locked, Account::External))),
+            (
+                WithdrawalState::Locked | WithdrawalState::Approved | WithdrawalState::Sent,
+                WithdrawalAction::Fail { .. },
+            ) => (WithdrawalState::Failed, Some((Account::Locked, Account::Available))),
+            _ => {
+                return Err(MatchingEngineErrors::InvalidWithdrawalState);
+            }
+        };
+        let entries = match accounts {
+            Some((from, to)) => {
+                self.unlock_amount(&asset, user_id, quantity);
+                if to == Account::External {
+                    self.withdraw(&asset, quantity, user_id)?;
+                }
+                ledger::withdrawal(user_id, &asset, quantity, id, from, to)
+            }
+            None => Vec::new(),
+        };
+        let withdrawals = &mut self.withdrawals;
+        let withdrawal = withdrawals.withdrawals.get_mut(&id).unwrap();
+        withdrawal.state = state;
+        withdrawal.updated_at = timestamp;
+        match action {
+            WithdrawalAction::Sent { tx_id } => {
+                withdrawal.tx_id = Some(tx_id);
+            }
+            WithdrawalAction::Fail { reason } => {
+                withdrawal.reason = Some(reason);
+            }
+            WithdrawalAction::Approve | WithdrawalAction::Complete => {}
+        }
+        let withdrawal = withdrawal.clone();
+        if withdrawal.state.is_final() {
+            withdrawals.withdrawals.remove(&id);
+        }
+        if withdrawal.state == WithdrawalState::Failed {
+            withdrawals.count(&withdrawal, -quantity);
+        }
+        Ok((withdrawal, entries))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, SerializeRow, FromRow)]
+pub struct ScyllaWithdrawal {
+    pub id: i64,
+    pub user_id: i64,
+    pub asset: String,
+    pub quantity: Numeric,
+    pub destination: String,
+    pub state: String,
+    pub tx_id: Option<String>,
+    pub reason: Option<String>,
+    pub requested_at: i64,
+    pub updated_at: i64,
+}
+impl ScyllaWithdrawal {
+    pub fn from_scylla_withdrawal(&self) -> Result<Withdrawal, QueryError> {
+        let invalid = |column: &str, value: &str| {
+            invalid_value("open_withdrawal_table", column, value)
+        };
+        Ok(Withdrawal {
+            id: self.id as u64,
+            user_id: self.user_id as u64,
+            asset: Asset::from_str(&self.asset).ok_or_else(|| invalid("asset", &self.asset))?,
+            quantity: self.quantity.0,
+            destination: self.destination.clone(),
+            state: WithdrawalState::from_str(&self.state).map_err(|_| {
+                invalid("state", &self.state)
+            })?,
+            tx_id: self.tx_id.clone(),
+            reason: self.reason.clone(),
+            requested_at: self.requested_at as u64,
+            updated_at: self.updated_at as u64,
+        })
+    }
+}
+impl Withdrawal {
+    fn to_scylla_withdrawal(&self) -> ScyllaWithdrawal {
+        ScyllaWithdrawal {
+            id: self.id as i64,
+            user_id: self.user_id as i64,
+            asset: self.asset.to_string(),
+            quantity: Numeric(self.quantity),
+            destination: self.destination.clone(),
+            state: self.state.to_string(),
+            tx_id: self.tx_id.clone(),
+            reason: self.reason.clone(),
+            requested_at: self.requested_at as i64,
+            updated_at: self.updated_at as i64,
+        }
+    }
+}
+// Writes of one withdrawal are spawned in any order, they all carry the balance stamp of the
+// state they write so scylla keeps the newest one. Open withdrawals are also kept apart for
+// recovery, with the daily total of their user and the last id handed out.
+pub async fn persist_withdrawal(session: &Session, persist: PersistWithdrawal) {
+    let upsert_withdrawal =
+        r#"
+        INSERT INTO keyspace_1.withdrawal_table (
+            id,
+            user_id,
+            asset,
+            quantity,
+            destination,
+            state,
+            tx_id,
+            reason,
+            requested_at,
+            updated_at
+        ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        USING TIMESTAMP ?;
+    "#;
+    let upsert_open =
+        r#"
+        INSERT INTO keyspace_1.open_withdrawal_table (
+            id,
+            user_id,
+            asset,
+            quantity,
+            destination,
+            state,
+            tx_id,
+            reason,
+            requested_at,
+            updated_at
+        ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        USING TIMESTAMP ?;
+    "#;
+    let delete_open =
+        r#"
+        DELETE FROM keyspace_1.open_withdrawal_table USING TIMESTAMP ? WHERE id = ?;
+    "#;
+    let update_balances =
+        r#"
+        UPDATE keyspace_1.user_table USING TIMESTAMP ?
+        SET
+            balance = ?,
+            locked_balance = ?
+        WHERE id = ?;
+    "#;
+    let update_total =
+        r#"
+        UPDATE keyspace_1.withdrawal_total_table USING TIMESTAMP ?
+        SET
+            total = ?
+        WHERE day = ? AND user_id = ? AND asset = ?;
+    "#;
+    let stamp = persist.stamp;
+    let withdrawal = persist.withdrawal.to_scylla_withdrawal();
+    let values = (
+        withdrawal.id,
+        withdrawal.user_id,
+        withdrawal.asset,
+        withdrawal.quantity,
+        withdrawal.destination,
+        withdrawal.state,
+        withdrawal.tx_id,
+        withdrawal.reason,
+        withdrawal.requested_at,
+        withdrawal.updated_at,
+        stamp,
+    );
+    let open = match persist.withdrawal.state.is_final() {
+        true => session.query(delete_open, (stamp, values.0)).await,
+        false => session.query(upsert_open, values.clone()).await,
+    };
+    let user = persist.user.to_scylla_user();
+    let mut batch: Batch = Default::default();
+    batch.append_statement(upsert_withdrawal);
+    batch.append_statement(update_balances);
+    batch.append_statement(update_total);
+    batch.append_statement(SET_LAST_ID);
+    let res = match session.prepare_batch(&batch).await {
+        Ok(prepared_batch) =>
+            session.batch(&prepared_batch, (
+                values,
+                (stamp, user.balance, user.locked_balance, user.id),
+                (
+                    stamp,
+                    Numeric(persist.daily.total),
+                    persist.daily.day as i64,
+                    user.id,
+                    persist.withdrawal.asset.to_string(),
+                ),
+                (stamp, persist.last_withdrawal_id as i64, WITHDRAWAL_ID),
+            )).await,
+        Err(err) => Err(err),
+    };
+    if let Err(err) = open.and(res) {
+        eprintln!("Could not persist withdrawal {}: {}", persist.withdrawal.id, err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn withdrawal(id: Id, quantity: Quantity, requested_at: u64) -> Withdrawal {
+        Withdrawal {
+            id,
+            user_id: 1,
+            asset: Asset::SOL,
+            quantity,
+            destination: "sol-address".to_string(),
+            state: WithdrawalState::Locked,
+            tx_id: None,
+            reason: None,
+            requested_at,
+            updated_at: requested_at,
+        }
+    }
+    fn user_with(sol: Quantity) -> Users {
+        let mut users = Users::default();
+        users.new_user(1);
+        users.deposit(&Asset::SOL, sol, 1).unwrap();
+        users
+    }
+
+    #[test]
+    fn locks_what_is_available_and_approves_small_withdrawals() {
+        let mut users = user_with(dec!(50));
+        let (requested, entries) = users.request_withdrawal(withdrawal(1, dec!(50), 0)).unwrap();
+        assert_eq!(requested.state, WithdrawalState::Approved);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(users.locked_balance(&Asset::SOL, 1).unwrap(), &dec!(50));
+        assert!(matches!(
+            users.request_withdrawal(withdrawal(2, dec!(1), 0)),
+            Err(MatchingEngineErrors::OverWithdrawl)
+        ));
+
+        users.update_withdrawal(1, WithdrawalAction::Sent { tx_id: "tx".to_string() }, 1).unwrap();
+        let (completed, _) = users.update_withdrawal(1, WithdrawalAction::Complete, 2).unwrap();
+        assert_eq!(completed.state, WithdrawalState::Completed);
+        assert_eq!(completed.tx_id.as_deref(), Some("tx"));
+        assert!(users.withdrawals.withdrawals.is_empty());
+        assert_eq!(users.withdrawals.withdrawn_in_day(1, &Asset::SOL, 2), dec!(50));
+        assert_eq!(users.balance(&Asset::SOL, 1).unwrap(), &dec!(0));
+        assert_eq!(users.locked_balance(&Asset::SOL, 1).unwrap(), &dec!(0));
+    }
+    #[test]
+    fn large_withdrawals_wait_for_approval_and_failures_release_the_lock() {
+        let mut users = user_with(dec!(500));
+        let (requested, _) = users.request_withdrawal(withdrawal(1, dec!(300), 0)).unwrap();
+        assert_eq!(requested.state, WithdrawalState::Locked);
+        assert!(matches!(
+            users.update_withdrawal(1, WithdrawalAction::Sent { tx_id: "tx".to_string() }, 1),
+            Err(MatchingEngineErrors::InvalidWithdrawalState)
+        ));
+        users.update_withdrawal(1, WithdrawalAction::Approve, 1).unwrap();
+        let fail = WithdrawalAction::Fail { reason: "rejected by custody".to_string() };
+        let (failed, entries) = users.update_withdrawal(1, fail, 2).unwrap();
+        assert_eq!(failed.state, WithdrawalState::Failed);
+        assert_eq!(entries[1].account, Account::Available);
+        assert_eq!(users.balance(&Asset::SOL, 1).unwrap(), &dec!(500));
+        assert_eq!(users.locked_balance(&Asset::SOL, 1).unwrap(), &dec!(0));
+    }
+    #[test]
+    fn daily_limit_counts_everything_but_failed_withdrawals() {
+        let mut users = user_with(dec!(5000));
+        users.request_withdrawal(withdrawal(1, dec!(600), 0)).unwrap();
+        assert!(matches!(
+            users.request_withdrawal(withdrawal(2, dec!(500), 10)),
+            Err(MatchingEngineErrors::WithdrawalLimitExceeded)
+        ));
+        let fail = WithdrawalAction::Fail { reason: "cancelled".to_string() };
+        users.update_withdrawal(1, fail, 20).unwrap();
+        users.request_withdrawal(withdrawal(2, dec!(500), 30)).unwrap();
+        // a day later the limit is free again
+        users.request_withdrawal(withdrawal(3, dec!(900), 30 + DAY)).unwrap();
+    }
+}
@@ -0,0 +1,299 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+    time::{ SystemTime, UNIX_EPOCH },
+};
+
+use common::{ buckets::buckets, ids, numeric::Numeric };
+use scylla::Session;
+use serde_json::to_string;
+
+use crate::{
+    handle_order_request::{ CancelOrder, EngineRequests },
+    handle_user_requests::UserRequests,
+    journal::{ self, Journal, USERS_JOURNAL },
+    matching_engine::{
+        custody::ScyllaDeposit,
+        engine::MatchingEngine,
+        events::NoEvents,
+        margin::ScyllaMargin,
+        orderbook::Orderbook,
+        perpetual::ScyllaPositions,
+        portfolio::DAY,
+        withdrawal::{ DailyTotal, ScyllaWithdrawal },
+        invalid_value,
+        Asset,
+        Exchange,
+        OrderSide,
+        ScyllaCancelOrder,
+        ScyllaOrder,
+        ScyllaUser,
+        Symbol,
+        User,
+        USERS,
+        WITHDRAWAL_ID,
+        TRANSFER_ID,
+        ADJUSTMENT_ID,
+    },
+    snapshot::{ latest_checkpoint, Checkpoint, SNAPSHOT_DIR },
+    GlobalUsers,
+};
+
+// Restores the books and USERS before the market threads start, from the latest snapshot and
+// the journals when there is one and from scylla otherwise. Rows that cannot be read stop the
+// recovery with the error instead of being left out.
+pub async fn recover_all_orderbooks(
+    engine: &mut MatchingEngine,
+    session: &Session
+) -> Result<(), Box<dyn Error>> {
+    let symbols = engine.registered_exchanges();
+    if let Some(checkpoint) = latest_checkpoint(Path::new(SNAPSHOT_DIR), &symbols) {
+        recover_from_checkpoint(engine, checkpoint);
+        return Ok(());
+    }
+    let mut orderbooks = &mut engine.orderbooks;
+    println!("Recovering users...");
+    let res = session
+        .query("SELECT id, balance, locked_balance FROM keyspace_1.user_table", &[]).await?;
+    let users = res
+        .rows_typed::<ScyllaUser>()?
+        .map(|user| Ok(user?.from_scylla_user()?))
+        .collect::<Result<Vec<User>, Box<dyn Error>>>()?;
+    let mut users_global = USERS.lock().unwrap();
+    for user in users {
+        users_global.users.insert(user.id, user);
+    }
+    let margins = "SELECT id, borrowed, interest, margin_mode FROM keyspace_1.user_table";
+    let res = session.query(margins, &[]).await?;
+    for margin in res.rows_typed::<ScyllaMargin>()? {
+        margin?.recover(&mut users_global)?;
+    }
+    let positions = "SELECT id, positions FROM keyspace_1.user_table";
+    let res = session.query(positions, &[]).await?;
+    for positions in res.rows_typed::<ScyllaPositions>()? {
+        positions?.recover(&mut users_global)?;
+    }
+    let withdrawals =
+        r#"
+        SELECT
+            id,
+            user_id,
+            asset,
+            quantity,
+            destination,
+            state,
+            tx_id,
+            reason,
+            requested_at,
+            updated_at
+        FROM keyspace_1.open_withdrawal_table;
+    "#;
+    let res = session.query(withdrawals, &[]).await?;
+    for withdrawal in res.rows_typed::<ScyllaWithdrawal>()? {
+        let withdrawal = withdrawal?.from_scylla_withdrawal()?;
+        users_global.withdrawals.withdrawals.insert(withdrawal.id, withdrawal);
+    }
+    // Only today's totals still count against the daily limit
+    let today = get_epoch_micros() / DAY;
+    let totals =
+        "SELECT user_id, asset, total FROM keyspace_1.withdrawal_total_table WHERE day = ?";
+    let res = session.query(totals, (today as i64,)).await?;
+    for row in res.rows_typed::<(i64, String, Numeric)>()? {
+        let (user_id, asset, total) = row?;
+        let asset = Asset::from_str(&asset).ok_or_else(|| {
+            invalid_value("withdrawal_total_table", "asset", &asset)
+        })?;
+        let daily = DailyTotal { day: today, total: total.0 };
+        let assets = users_global.withdrawals.daily.entry(user_id as u64).or_default();
+        assets.insert(asset, daily);
+    }
+    let res = session.query("SELECT name, id FROM keyspace_1.last_id_table", &[]).await?;
+    for row in res.rows_typed::<(String, i64)>()? {
+        let (name, id) = row?;
+        match name.as_str() {
+            WITHDRAWAL_ID => {
+                let withdrawals = &mut users_global.withdrawals;
+                withdrawals.last_withdrawal_id = withdrawals.last_withdrawal_id.max(id as u64);
+            }
+            TRANSFER_ID => {
+                users_global.last_transfer_id = users_global.last_transfer_id.max(id as u64);
+            }
+            ADJUSTMENT_ID => {
+                users_global.last_adjustment_id = users_global.last_adjustment_id.max(id as u64);
+            }
+            _ => {}
+        }
+    }
+    let deposits =
+        r#"
+        SELECT
+            id,
+            tx_id,
+            user_id,
+            asset,
+            amount,
+            confirmations,
+            state,
+            seen_at,
+            updated_at
+        FROM keyspace_1.deposit_table;
+    "#;
+    let res = session.query(deposits, &[]).await?;
+    for deposit in res.rows_typed::<ScyllaDeposit>()? {
+        let deposit = deposit?.from_scylla_deposit()?;
+        users_global.deposits.deposits.insert(deposit.tx_id.clone(), deposit);
+    }
+    let res = session.query("SELECT master_id, id FROM keyspace_1.sub_account_table", &[]).await?;
+    for row in res.rows_typed::<(i64, i64)>()? {
+        let (master_id, id) = row?;
+        if let Some(sub_account) = users_global.users.get_mut(&(id as u64)) {
+            sub_account.master_id = Some(master_id as u64);
+        }
+    }
+    let entries = Journal::read(&Journal::path(USERS_JOURNAL)).expect(
+        "Could not read users journal"
+    );
+    UserRequests::recover_last_ids(&mut users_global, &entries);
+    for symbol in symbols {
+        println!("Recovering {:?} orderbook...", symbol);
+        let exchange = Exchange::from_symbol(symbol.to_string()).unwrap();
+        let mut orderbook = Orderbook::new(exchange.clone());
+        recover_orderbook(&mut orderbook, session).await;
+        orderbooks.insert(exchange, orderbook);
+    }
+    println!("\nOrderbook recovering complete.");
+    Ok(())
+}
+// Loads the snapshot and replays the journal records written after it. Deposits and trades
+// of every market touch the same balances, so the journals are replayed merged in the order
+// their records were written.
+fn recover_from_checkpoint(engine: &mut MatchingEngine, mut checkpoint: Checkpoint) {
+    println!("Recovering from snapshot {}...", checkpoint.id);
+    let after = |name: &str, seq: u64| {
+        let entries = Journal::read(&Journal::path(name)).unwrap_or_else(|err| {
+            panic!("Could not read {} journal: {}", name, err)
+        });
+        let entries = entries.into_iter().filter(|entry| entry.seq > seq).collect();
+        (name.to_string(), entries)
+    };
+    let mut journals = vec![after(USERS_JOURNAL, checkpoint.users.journal_seq)];
+    let mut users = USERS.lock().unwrap();
+    *users = checkpoint.users.users;
+    users.recover_last_ids();
+    drop(users);
+    let mut orderbooks = HashMap::new();
+    for symbol in engine.registered_exchanges() {
+        let snapshot = checkpoint.orderbooks.remove(&symbol).unwrap();
+        let mut orderbook = snapshot.orderbook;
+        // Snapshots from before ids were namespaced start counting at the market's base
+        let base = ids::base(orderbook.exchange.id_namespace());
+        orderbook.order_id = orderbook.order_id.max(base);
+        orderbook.trade_id = orderbook.trade_id.max(base);
+        // Nothing was published for the replayed requests yet, their trades are only settled
+        orderbook.attach(Arc::new(GlobalUsers), Arc::new(NoEvents));
+        journals.push(after(&symbol, snapshot.journal_seq));
+        orderbooks.insert(symbol, orderbook);
+    }
+    for (name, entry) in journal::merge(journals) {
+        match orderbooks.get_mut(&name) {
+            Some(orderbook) => orderbook.replay_journal(vec![entry], true),
+            None => UserRequests::replay_journal(&mut USERS.lock().unwrap(), vec![entry]),
+        }
+    }
+    for (symbol, orderbook) in orderbooks {
+        println!("Recovered {:?} orderbook", symbol);
+        engine.orderbooks.insert(orderbook.exchange.clone(), orderbook);
+    }
+    println!("\nOrderbook recovering complete.")
+}
+// Rebuilds the book from its journal, markets that ran before the journal existed are seeded
+// once from scylla
+async fn recover_orderbook(orderbook: &mut Orderbook, session: &Session) {
+    let path = Journal::path(&orderbook.exchange.symbol);
+    let mut entries = Journal::read(&path).expect("Could not read journal");
+    if entries.is_empty() {
+        let requests = legacy_requests(session, &orderbook.exchange.symbol).await;
+        let mut journal = Journal::open(&path).expect("Could not open journal");
+        for request in requests.iter() {
+            journal.append(to_string(request).unwrap().as_bytes()).unwrap();
+        }
+        entries = Journal::read(&path).expect("Could not read journal");
+    }
+    orderbook.replay_journal(entries, false);
+}
+async fn legacy_requests(session: &Session, symbol: &Symbol) -> Vec<EngineRequests> {
+    let current_time = get_epoch_micros() as i64;
+    let since = 1_000_000 * 60 * 60 * 24; // 24 hours in micros
+    let from_time = current_time - since;
+    let canceled_order_s =
+        r#"
+    SELECT 
+        id,
+        user_id,
+        order_side,
+        symbol,
+        price,
+        timestamp
+    FROM keyspace_1.cancel_order_by_market_table
+    WHERE symbol = ? AND bucket = ? AND timestamp > ?;
+        "#;
+    let normal_order_s =
+        r#"
+    SELECT 
+        id,
+        user_id,
+        symbol,
+        price,
+        initial_quantity,
+        filled_quantity, 
+        quote_quantity,
+        filled_quote_quantity,
+        order_type,
+        order_side,
+        order_status,
+        timestamp
+    FROM keyspace_1.order_by_market_table
+    WHERE symbol = ? AND bucket = ? AND timestamp > ?;
+        "#;
+    let mut orders: Vec<ScyllaOrder> = Vec::new();
+    let mut canceled_orders: Vec<ScyllaCancelOrder> = Vec::new();
+    for bucket in buckets(from_time, current_time) {
+        let res = session.query(normal_order_s, (symbol, bucket, from_time)).await.unwrap();
+        orders.extend(res.rows_typed::<ScyllaOrder>().unwrap().map(|order| order.unwrap()));
+        let res = session.query(canceled_order_s, (symbol, bucket, from_time)).await.unwrap();
+        canceled_orders.extend(
+            res.rows_typed::<ScyllaCancelOrder>().unwrap().map(|order| order.unwrap())
+        );
+    }
+    let mut requests: Vec<EngineRequests> = orders
+        .into_iter()
+        .map(|order| EngineRequests::ExecuteOrder(order.from_scylla_order()))
+        .collect();
+    requests.extend(
+        canceled_orders.into_iter().map(|order| {
+            EngineRequests::CancelOrder(CancelOrder {
+                id: order.id as u64,
+                user_id: order.user_id as u64,
+                symbol: order.symbol,
+                price: order.price.into(),
+                order_side: OrderSide::from_str(&order.order_side).unwrap(),
+                sub_id: 0,
+                timestamp: order.timestamp,
+            })
+        })
+    );
+    requests.sort_by_key(|request| {
+        match request {
+            EngineRequests::ExecuteOrder(order) => order.timestamp,
+            EngineRequests::CancelOrder(order) => order.timestamp,
+            _ => 0,
+        }
+    });
+    requests
+}
+fn get_epoch_micros() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros() as u64
+}
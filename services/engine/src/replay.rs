@@ -0,0 +1,360 @@
+use std::collections::{ BTreeMap, BTreeSet, HashMap };
+
+use rust_decimal_macros::dec;
+
+use crate::{
+    handle_order_request::EngineRequests,
+    journal::JournalEntry,
+    matching_engine::{
+        orderbook::{ Limit, Order, Orderbook },
+        Asset,
+        Id,
+        OrderSide,
+        Price,
+        Quantity,
+        Users,
+    },
+};
+
+// Replays a market's journal offline to reconstruct its book at any point, used by the
+// `replay` binary to look into disputed fills and to check snapshots against the journal.
+
+pub fn request_timestamp(request: &EngineRequests) -> Option<i64> {
+    match request {
+        EngineRequests::ExecuteOrder(order) | EngineRequests::Liquidate(order) =>
+            Some(order.timestamp),
+        EngineRequests::CancelOrder(cancel_order) => Some(cancel_order.timestamp),
+        EngineRequests::CancelAll(cancel_all) => Some(cancel_all.timestamp),
+        EngineRequests::Halt(control) | EngineRequests::Resume(control) => Some(control.timestamp),
+        EngineRequests::SetPriceBand(price_band) => Some(price_band.timestamp),
+        EngineRequests::OpenOrders(_) |
+        EngineRequests::OpenOrder(_) |
+        EngineRequests::MarketState(_) => None,
+    }
+}
+pub fn parse_entry(entry: &JournalEntry) -> EngineRequests {
+    serde_json::from_slice(&entry.payload).expect("Journal entry is not an engine request")
+}
+
+// The entries up to `to_seq` that were requested at or before `until` (micros). Requests are
+// stamped before they queue, so a later one can carry an earlier time and is still included.
+pub fn entries_until(
+    entries: &[JournalEntry],
+    to_seq: Option<u64>,
+    until: Option<i64>
+) -> Vec<JournalEntry> {
+    entries
+        .iter()
+        .filter(|entry| to_seq.is_none_or(|to_seq| entry.seq <= to_seq))
+        .filter(|entry| {
+            match (until, request_timestamp(&parse_entry(entry))) {
+                (Some(until), Some(timestamp)) => timestamp <= until,
+                _ => true,
+            }
+        })
+        .cloned()
+        .collect()
+}
+
+// Amounts each user has locked in resting orders of the book, as (base, quote)
+pub fn locked_by_orders(orderbook: &Orderbook) -> BTreeMap<Id, (Quantity, Quantity)> {
+    let mut locked: BTreeMap<Id, (Quantity, Quantity)> = BTreeMap::new();
+    for limit in orderbook.asks.values().chain(orderbook.bids.values()) {
+        for order in limit.orders.iter() {
+            let amounts = locked.entry(order.user_id).or_insert((dec!(0), dec!(0)));
+            let (asset, amount) = orderbook.exchange.order_lock(
+                &order.order_side,
+                limit.price,
+                order.quantity
+            );
+            match asset == orderbook.exchange.base {
+                true => {
+                    amounts.0 += amount;
+                }
+                false => {
+                    amounts.1 += amount;
+                }
+            }
+        }
+    }
+    locked
+}
+
+fn resting_orders(limits: &HashMap<Price, Limit>) -> BTreeMap<(Price, u64), &Order> {
+    limits
+        .values()
+        .flat_map(|limit| limit.orders.iter().map(|order| ((limit.price, order.id), order)))
+        .collect()
+}
+fn diff_side(
+    side: OrderSide,
+    expected: &HashMap<Price, Limit>,
+    actual: &HashMap<Price, Limit>,
+    diffs: &mut Vec<String>
+) {
+    let expected = resting_orders(expected);
+    let actual = resting_orders(actual);
+    for ((price, id), order) in expected.iter() {
+        match actual.get(&(*price, *id)) {
+            None => diffs.push(format!("{} {} @ {} is missing", side, id, price)),
+            Some(actual) if actual.quantity != order.quantity =>
+                diffs.push(
+                    format!(
+                        "{} {} @ {} has quantity {}, expected {}",
+                        side,
+                        id,
+                        price,
+                        actual.quantity,
+                        order.quantity
+                    )
+                ),
+            Some(_) => {}
+        }
+    }
+    for (price, id) in actual.keys() {
+        if !expected.contains_key(&(*price, *id)) {
+            diffs.push(format!("{} {} @ {} is not expected", side, id, price));
+        }
+    }
+}
+// Empty when both books hold the same resting orders and counters
+pub fn diff_books(expected: &Orderbook, actual: &Orderbook) -> Vec<String> {
+    let mut diffs = Vec::new();
+    if expected.order_id != actual.order_id {
+        diffs.push(format!("order_id is {}, expected {}", actual.order_id, expected.order_id));
+    }
+    if expected.trade_id != actual.trade_id {
+        diffs.push(format!("trade_id is {}, expected {}", actual.trade_id, expected.trade_id));
+    }
+    diff_side(OrderSide::Bid, &expected.bids, &actual.bids, &mut diffs);
+    diff_side(OrderSide::Ask, &expected.asks, &actual.asks, &mut diffs);
+    diffs
+}
+
+// Empty when every user has the same balance and locked balance of every asset in both
+pub fn diff_users(expected: &Users, actual: &Users) -> Vec<String> {
+    let mut diffs = Vec::new();
+    let user_ids: BTreeSet<Id> = expected.users
+        .keys()
+        .chain(actual.users.keys())
+        .copied()
+        .collect();
+    for user_id in user_ids {
+        let (Some(expected), Some(actual)) = (
+            expected.users.get(&user_id),
+            actual.users.get(&user_id),
+        ) else {
+            let state = match actual.users.contains_key(&user_id) {
+                true => "not expected",
+                false => "missing",
+            };
+            diffs.push(format!("user {} is {}", user_id, state));
+            continue;
+        };
+        let assets: BTreeSet<Asset> = expected.balance
+            .keys()
+            .chain(expected.locked_balance.keys())
+            .chain(actual.balance.keys())
+            .chain(actual.locked_balance.keys())
+            .copied()
+            .collect();
+        for asset in assets {
+            let amounts = [
+                ("balance", &expected.balance, &actual.balance),
+                ("locked", &expected.locked_balance, &actual.locked_balance),
+            ];
+            for (name, expected, actual) in amounts {
+                let expected = expected.get(&asset).copied().unwrap_or_default();
+                let actual = actual.get(&asset).copied().unwrap_or_default();
+                if expected != actual {
+                    diffs.push(
+                        format!(
+                            "user {} {} {} is {}, expected {}",
+                            user_id,
+                            asset,
+                            name,
+                            actual,
+                            expected
+                        )
+                    );
+                }
+            }
+        }
+    }
+    diffs
+}
+
+pub fn print_book(orderbook: &mut Orderbook) {
+    println!(
+        "{} order_id {} trade_id {}",
+        orderbook.exchange.symbol,
+        orderbook.order_id,
+        orderbook.trade_id
+    );
+    // asks from worst to best so the spread sits in the middle
+    for limit in Orderbook::ask_limits(&mut orderbook.asks).iter().rev() {
+        print_limit(OrderSide::Ask, limit);
+    }
+    for limit in Orderbook::bid_limits(&mut orderbook.bids) {
+        print_limit(OrderSide::Bid, limit);
+    }
+}
+fn print_limit(side: OrderSide, limit: &Limit) {
+    for order in limit.orders.iter() {
+        println!(
+            "  {} {:>12} {:>12} order {} user {}",
+            side,
+            limit.price,
+            order.quantity,
+            order.id,
+            order.user_id
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::ids;
+    use rust_decimal_macros::dec;
+
+    use crate::{
+        handle_order_request::CancelOrder,
+        matching_engine::{ Asset, Exchange, OrderStatus, OrderType, RecievedOrder },
+    };
+    use super::*;
+
+    fn id(sequence: u64) -> u64 {
+        ids::compose(Exchange::new(Asset::SOL, Asset::USDT).id_namespace(), sequence)
+    }
+    fn limit_order(
+        sequence: u64,
+        order_side: OrderSide,
+        price: Price,
+        timestamp: i64
+    ) -> EngineRequests {
+        EngineRequests::ExecuteOrder(RecievedOrder {
+            id: id(sequence) as i64,
+            user_id: sequence as i64,
+            symbol: "SOL_USDT".to_string(),
+            price,
+            initial_quantity: dec!(4),
+            filled_quantity: dec!(0),
+            quote_quantity: dec!(4) * price,
+            filled_quote_quantity: dec!(0),
+            order_type: OrderType::Limit,
+            order_side,
+            order_status: OrderStatus::InProgress,
+            timestamp,
+        })
+    }
+    fn entries(requests: &[EngineRequests]) -> Vec<JournalEntry> {
+        requests
+            .iter()
+            .enumerate()
+            .map(|(index, request)| JournalEntry {
+                seq: (index as u64) + 1,
+                stamp: (index as u64) + 1,
+                payload: serde_json::to_vec(request).unwrap(),
+            })
+            .collect()
+    }
+    fn journal() -> Vec<JournalEntry> {
+        entries(
+            &[
+                limit_order(1, OrderSide::Ask, dec!(10), 100),
+                limit_order(2, OrderSide::Bid, dec!(9), 200),
+                limit_order(3, OrderSide::Bid, dec!(10), 300),
+                EngineRequests::CancelOrder(CancelOrder {
+                    id: id(2),
+                    user_id: 2,
+                    symbol: "SOL_USDT".to_string(),
+                    price: dec!(9),
+                    order_side: OrderSide::Bid,
+                    sub_id: 0,
+                    timestamp: 400,
+                }),
+            ]
+        )
+    }
+
+    fn replay(orderbook: &mut Orderbook, to_seq: Option<u64>, until: Option<i64>) -> Option<u64> {
+        let entries = entries_until(&journal(), to_seq, until);
+        let last_seq = entries.last().map(|entry| entry.seq);
+        orderbook.replay_journal(entries, false);
+        last_seq
+    }
+
+    #[test]
+    fn stops_at_sequence_or_time() {
+        let exchange = Exchange::new(Asset::SOL, Asset::USDT);
+        let mut by_seq = Orderbook::new(exchange.clone());
+        assert_eq!(replay(&mut by_seq, Some(2), None), Some(2));
+        let mut by_time = Orderbook::new(exchange.clone());
+        assert_eq!(replay(&mut by_time, None, Some(250)), Some(2));
+        assert!(diff_books(&by_seq, &by_time).is_empty());
+
+        assert_eq!(locked_by_orders(&by_seq).get(&1), Some(&(dec!(4), dec!(0))));
+        assert_eq!(locked_by_orders(&by_seq).get(&2), Some(&(dec!(0), dec!(36))));
+
+        let mut full = Orderbook::new(exchange);
+        assert_eq!(replay(&mut full, None, None), Some(4));
+        assert!(locked_by_orders(&full).is_empty());
+    }
+    #[test]
+    fn keeps_late_requests_with_an_earlier_time() {
+        let journal = entries(
+            &[
+                limit_order(1, OrderSide::Ask, dec!(10), 200),
+                limit_order(2, OrderSide::Bid, dec!(9), 300),
+                limit_order(3, OrderSide::Bid, dec!(8), 150),
+            ]
+        );
+        let seqs = |entries: Vec<JournalEntry>| -> Vec<u64> {
+            entries.iter().map(|entry| entry.seq).collect()
+        };
+        assert_eq!(seqs(entries_until(&journal, None, Some(250))), vec![1, 3]);
+        assert_eq!(seqs(entries_until(&journal, Some(2), Some(250))), vec![1]);
+        assert_eq!(seqs(entries_until(&journal, None, None)), vec![1, 2, 3]);
+    }
+    #[test]
+    fn reports_balances_that_differ() {
+        let mut expected = Users::default();
+        expected.new_user(1);
+        expected.new_user(2);
+        expected.deposit(&Asset::USDT, dec!(100), 1).unwrap();
+        expected.lock_amount(&Asset::USDT, 1, dec!(40));
+        let mut actual = Users::default();
+        actual.new_user(1);
+        actual.new_user(3);
+        actual.deposit(&Asset::USDT, dec!(90), 1).unwrap();
+        actual.lock_amount(&Asset::USDT, 1, dec!(40));
+
+        let diffs = diff_users(&expected, &actual);
+        assert_eq!(diffs, vec![
+            "user 1 USDT balance is 90, expected 100".to_string(),
+            "user 2 is missing".to_string(),
+            "user 3 is not expected".to_string()
+        ]);
+        assert!(diff_users(&expected, &expected).is_empty());
+    }
+    #[test]
+    fn reports_what_differs() {
+        let exchange = Exchange::new(Asset::SOL, Asset::USDT);
+        let mut expected = Orderbook::new(exchange.clone());
+        replay(&mut expected, Some(2), None);
+        let mut actual = Orderbook::new(exchange);
+        replay(&mut actual, Some(1), None);
+        actual.add_limit_order(
+            dec!(11),
+            Order::new(7, 7, OrderSide::Ask, dec!(1), OrderType::Limit, 7)
+        );
+        actual.asks.get_mut(&dec!(10)).unwrap().orders[0].quantity = dec!(3);
+
+        let diffs = diff_books(&expected, &actual);
+        assert_eq!(diffs.len(), 4, "{:?}", diffs);
+        assert!(diffs.iter().any(|diff| diff.starts_with("order_id is")));
+        assert!(diffs.contains(&format!("Bid {} @ 9 is missing", id(2))));
+        assert!(diffs.contains(&format!("Ask {} @ 10 has quantity 3, expected 4", id(1))));
+        assert!(diffs.contains(&"Ask 7 @ 11 is not expected".to_string()));
+    }
+}
@@ -0,0 +1,273 @@
+// Runs every golden scenario in `scenarios/` against an orderbook settling on its own users.
+// A scenario gives the starting balances, the requests sent to one market and what has to come
+// out of them, so matching rules can be read and reviewed without reading the engine.
+use std::{ collections::BTreeMap, fs, path::{ Path, PathBuf }, sync::{ Arc, Mutex } };
+
+use common::ids;
+use rust_decimal_macros::dec;
+use serde::{ Deserialize, Serialize };
+
+use crate::matching_engine::{
+    error::MatchingEngineErrors,
+    events::RecordedEvents,
+    orderbook::Orderbook,
+    risk::RiskLimits,
+    Asset,
+    Exchange,
+    Id,
+    OrderSide,
+    OrderStatus,
+    OrderType,
+    Price,
+    Quantity,
+    RecievedOrder,
+    Symbol,
+    Users,
+};
+
+pub const SCENARIO_DIR: &str = "scenarios";
+
+#[derive(Debug, Deserialize)]
+pub struct Scenario {
+    pub description: String,
+    pub market: Symbol,
+    // Deposited before the first request
+    pub balances: BTreeMap<Id, BTreeMap<Asset, Quantity>>,
+    // Accounts not listed keep the default limits
+    #[serde(default)]
+    pub risk_limits: BTreeMap<Id, RiskLimits>,
+    pub requests: Vec<Request>,
+    pub expect: Outcome,
+}
+// Orders are referred to by their sequence in the market, 1 for the first accepted order
+#[derive(Debug, Deserialize)]
+pub enum Request {
+    ExecuteOrder {
+        user_id: Id,
+        order_side: OrderSide,
+        order_type: OrderType,
+        #[serde(default)]
+        price: Price,
+        quantity: Quantity,
+    },
+    CancelOrder {
+        user_id: Id,
+        order: u64,
+    },
+    CancelAll {
+        user_id: Id,
+    },
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Outcome {
+    // One per request, in order
+    pub responses: Vec<Response>,
+    pub trades: Vec<Fill>,
+    pub order_updates: Vec<OrderUpdate>,
+    // Best price first, as (price, quantity)
+    pub bids: Vec<(Price, Quantity)>,
+    pub asks: Vec<(Price, Quantity)>,
+    // As (balance, locked)
+    pub balances: BTreeMap<Id, BTreeMap<Asset, (Quantity, Quantity)>>,
+}
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub enum Response {
+    Order {
+        order: u64,
+        filled_quantity: Quantity,
+        filled_quote_quantity: Quantity,
+        order_status: OrderStatus,
+    },
+    Cancelled {
+        orders: Vec<u64>,
+    },
+    Error(MatchingEngineErrors),
+}
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Fill {
+    pub trade: u64,
+    pub taker_order: u64,
+    pub maker_order: u64,
+    pub price: Price,
+    pub quantity: Quantity,
+    pub is_buyer_maker: bool,
+}
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct OrderUpdate {
+    pub user_id: Id,
+    pub order: u64,
+    pub order_status: OrderStatus,
+    pub price: Price,
+    pub executed_quantity: Quantity,
+}
+
+pub fn load(path: &Path) -> Scenario {
+    let scenario = fs::read_to_string(path).expect("Could not read scenario");
+    serde_json
+        ::from_str(&scenario)
+        .unwrap_or_else(|err| panic!("Invalid scenario {}: {}", path.display(), err))
+}
+
+// Requests go through the same locking, matching and unlocking the market thread does
+pub fn run(scenario: &Scenario) -> Outcome {
+    let exchange = Exchange::from_symbol(scenario.market.clone()).expect("Unknown market");
+    let mut users = Users::default();
+    for (user_id, deposits) in scenario.balances.iter() {
+        users.new_user(*user_id);
+        for (asset, quantity) in deposits.iter() {
+            users.deposit(asset, *quantity, *user_id).unwrap();
+        }
+    }
+    for (user_id, limits) in scenario.risk_limits.iter() {
+        users.set_risk_limits(*user_id, Some(*limits)).unwrap();
+    }
+    let users = Arc::new(Mutex::new(users));
+    let events = Arc::new(RecordedEvents::default());
+    let mut orderbook = Orderbook::new(exchange.clone());
+    orderbook.attach(users.clone(), events.clone());
+    let namespace = exchange.id_namespace();
+
+    let mut outcome = Outcome::default();
+    for request in scenario.requests.iter() {
+        let response = match request {
+            Request::ExecuteOrder { user_id, order_side, order_type, price, quantity } => {
+                let mut order = RecievedOrder {
+                    id: 0,
+                    user_id: *user_id as i64,
+                    symbol: exchange.symbol.clone(),
+                    price: *price,
+                    initial_quantity: *quantity,
+                    filled_quantity: dec!(0),
+                    quote_quantity: price * quantity,
+                    filled_quote_quantity: dec!(0),
+                    order_type: order_type.clone(),
+                    order_side: order_side.clone(),
+                    order_status: OrderStatus::InProgress,
+                    timestamp: 0,
+                };
+                match orderbook.lock_order(&order) {
+                    Err(err) => Response::Error(err),
+                    Ok((_, locked_amount, _)) => {
+                        let order_id = orderbook.increment_order_id();
+                        order.id = order_id as i64;
+                        let (filled_quantity, filled_quote_quantity, order_status) =
+                            orderbook.process_order(order.clone(), order_id);
+                        orderbook.release_unspent(&order, locked_amount, filled_quote_quantity);
+                        Response::Order {
+                            order: ids::sequence(order_id),
+                            filled_quantity,
+                            filled_quote_quantity,
+                            order_status,
+                        }
+                    }
+                }
+            }
+            Request::CancelOrder { user_id, order } => {
+                let order_id = ids::compose(namespace, *order);
+                let resting = orderbook
+                    .get_open_orders(*user_id)
+                    .into_iter()
+                    .find(|(_, resting)| resting.id == order_id)
+                    .map(|(price, resting)| (price, resting.order_side.clone()));
+                match resting {
+                    None => Response::Error(MatchingEngineErrors::InvalidOrderId),
+                    Some((price, order_side)) => {
+                        let cancelled = orderbook
+                            .cancel_order(order_id, *user_id, &order_side, &price)
+                            .unwrap();
+                        orderbook.unlock_cancelled(&cancelled, &price);
+                        Response::Cancelled { orders: vec![*order] }
+                    }
+                }
+            }
+            Request::CancelAll { user_id } => {
+                let (orders, _, _) = orderbook.cancel_all_orders(*user_id);
+                let mut orders: Vec<u64> = orders
+                    .iter()
+                    .map(|order| ids::sequence(order.id as u64))
+                    .collect();
+                orders.sort();
+                Response::Cancelled { orders }
+            }
+        };
+        outcome.responses.push(response);
+    }
+
+    for events in events.0.lock().unwrap().iter() {
+        outcome.trades.push(Fill {
+            trade: ids::sequence(events.trade.id),
+            taker_order: ids::sequence(events.order_update.order_id),
+            maker_order: ids::sequence(events.client_order_update.order_id),
+            price: events.trade.price,
+            quantity: events.trade.quantity,
+            is_buyer_maker: events.trade.is_buyer_maker,
+        });
+        for update in [&events.order_update, &events.client_order_update] {
+            outcome.order_updates.push(OrderUpdate {
+                user_id: update.user_id,
+                order: ids::sequence(update.order_id),
+                order_status: update.order_status.clone(),
+                price: update.price,
+                executed_quantity: update.executed_quantity,
+            });
+        }
+    }
+    let depth = |limits: Vec<&mut crate::matching_engine::orderbook::Limit>| {
+        limits
+            .into_iter()
+            .map(|limit| (limit.price, limit.orders.iter().map(|order| order.quantity).sum()))
+            .filter(|(_, quantity): &(Price, Quantity)| *quantity > dec!(0))
+            .collect()
+    };
+    outcome.bids = depth(Orderbook::bid_limits(&mut orderbook.bids));
+    outcome.asks = depth(Orderbook::ask_limits(&mut orderbook.asks));
+    let users = users.lock().unwrap();
+    for user_id in scenario.balances.keys() {
+        let user = users.users.get(user_id).unwrap();
+        let balances = [exchange.base, exchange.quote]
+            .into_iter()
+            .map(|asset| (asset, (user.balance[&asset], user.locked_balance[&asset])))
+            .collect();
+        outcome.balances.insert(*user_id, balances);
+    }
+    outcome
+}
+
+pub fn scenario_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs
+        ::read_dir(dir)
+        .expect("Could not read scenarios")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "json"))
+        .collect();
+    files.sort();
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scenarios_match_their_golden_outcome() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join(SCENARIO_DIR);
+        let files = scenario_files(&dir);
+        assert!(!files.is_empty(), "No scenarios in {}", dir.display());
+        let mut failed = Vec::new();
+        for path in files {
+            let scenario = load(&path);
+            let outcome = run(&scenario);
+            if outcome != scenario.expect {
+                eprintln!(
+                    "{} ({}) produced\n{}",
+                    path.display(),
+                    scenario.description,
+                    serde_json::to_string_pretty(&outcome).unwrap()
+                );
+                failed.push(path.display().to_string());
+            }
+        }
+        assert!(failed.is_empty(), "Scenarios differ from their expectation: {:?}", failed);
+    }
+}
@@ -0,0 +1,315 @@
+use std::{
+    collections::HashMap,
+    fs::{ self, File },
+    io::{ self, ErrorKind, Write },
+    path::{ Path, PathBuf },
+    sync::{ atomic::{ AtomicBool, AtomicU64, Ordering }, Barrier },
+    thread,
+    time::{ Duration, SystemTime, UNIX_EPOCH },
+};
+
+use once_cell::sync::Lazy;
+use serde::{ de::DeserializeOwned, Deserialize, Serialize };
+use strum::IntoEnumIterator;
+
+use crate::{
+    journal::Journal,
+    matching_engine::{ orderbook::Orderbook, RegisteredSymbols, Symbol, Users, USERS },
+};
+
+pub const SNAPSHOT_DIR: &str = "snapshots";
+pub const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const SNAPSHOTS_KEPT: usize = 3;
+// Written last, a checkpoint without it was interrupted and is never loaded
+const COMPLETE: &str = "COMPLETE";
+const USERS_SNAPSHOT: &str = "users.snapshot";
+
+// `journal_seq` is the last journal record already applied to the snapshotted state
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderbookSnapshot {
+    pub journal_seq: u64,
+    pub orderbook: Orderbook,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UsersSnapshot {
+    pub journal_seq: u64,
+    pub users: Users,
+}
+#[derive(Debug)]
+pub struct Checkpoint {
+    pub id: u64,
+    pub users: UsersSnapshot,
+    pub orderbooks: HashMap<Symbol, OrderbookSnapshot>,
+}
+
+// Every market thread and the user thread pause between requests while a checkpoint is taken,
+// so the books and USERS in it are a consistent cut across all of their journals.
+pub struct Checkpointer {
+    requested: AtomicBool,
+    failed: AtomicBool,
+    id: AtomicU64,
+    barrier: Barrier,
+}
+// market threads + user thread + the checkpoint thread itself
+pub static CHECKPOINTER: Lazy<Checkpointer> = Lazy::new(|| {
+    Checkpointer::new(RegisteredSymbols::iter().count() + 2)
+});
+
+impl Checkpointer {
+    pub fn new(participants: usize) -> Checkpointer {
+        Checkpointer {
+            requested: AtomicBool::new(false),
+            failed: AtomicBool::new(false),
+            id: AtomicU64::new(0),
+            barrier: Barrier::new(participants),
+        }
+    }
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::Acquire)
+    }
+    fn dir(&self) -> PathBuf {
+        checkpoint_dir(Path::new(SNAPSHOT_DIR), self.id.load(Ordering::Acquire))
+    }
+    fn record(&self, result: io::Result<()>) {
+        if let Err(err) = result {
+            eprintln!("Could not write snapshot: {}", err);
+            self.failed.store(true, Ordering::Release);
+        }
+    }
+    pub fn snapshot_orderbook(&self, orderbook: &Orderbook, journal: &Journal) {
+        let snapshot = OrderbookSnapshot {
+            journal_seq: journal.next_seq() - 1,
+            orderbook: orderbook.clone(),
+        };
+        let path = self.dir().join(format!("{}.snapshot", orderbook.exchange.symbol));
+        self.record(write_snapshot(&path, &snapshot));
+        self.barrier.wait();
+        self.barrier.wait();
+    }
+    // USERS is only read once every market thread is parked on the barrier
+    pub fn snapshot_users(&self, journal: &Journal) {
+        self.barrier.wait();
+        let snapshot = UsersSnapshot {
+            journal_seq: journal.next_seq() - 1,
+            users: USERS.lock().unwrap().clone(),
+        };
+        self.record(write_snapshot(&self.dir().join(USERS_SNAPSHOT), &snapshot));
+        self.barrier.wait();
+    }
+    pub fn run(&self, interval: Duration) -> impl FnMut() + '_ {
+        move || {
+            loop {
+                thread::sleep(interval);
+                let id = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+                let dir = checkpoint_dir(Path::new(SNAPSHOT_DIR), id);
+                if let Err(err) = fs::create_dir_all(&dir) {
+                    eprintln!("Could not create snapshot directory: {}", err);
+                    continue;
+                }
+                self.id.store(id, Ordering::Release);
+                self.failed.store(false, Ordering::Release);
+                self.requested.store(true, Ordering::Release);
+                self.barrier.wait();
+                self.requested.store(false, Ordering::Release);
+                self.barrier.wait();
+                if self.failed.load(Ordering::Acquire) {
+                    let _ = fs::remove_dir_all(&dir);
+                    continue;
+                }
+                match write_snapshot(&dir.join(COMPLETE), &id) {
+                    Ok(_) => println!("Snapshot {} taken", id),
+                    Err(err) => eprintln!("Could not complete snapshot {}: {}", id, err),
+                }
+                prune_checkpoints(Path::new(SNAPSHOT_DIR), id);
+            }
+        }
+    }
+}
+
+pub fn checkpoint_dir(base: &Path, id: u64) -> PathBuf {
+    base.join(id.to_string())
+}
+pub fn checkpoint_ids(base: &Path) -> Vec<u64> {
+    let mut ids: Vec<u64> = fs::read_dir(base)
+        .map(|dir| {
+            dir.filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<u64>().ok()).collect()
+        })
+        .unwrap_or_default();
+    ids.sort_unstable_by(|a, b| b.cmp(a));
+    ids
+}
+// Keeps the newest few complete checkpoints and anything newer than `current`
+fn prune_checkpoints(base: &Path, current: u64) {
+    let mut kept = 0;
+    for id in checkpoint_ids(base) {
+        let dir = checkpoint_dir(base, id);
+        if id > current {
+            continue;
+        }
+        if dir.join(COMPLETE).exists() && kept < SNAPSHOTS_KEPT {
+            kept += 1;
+            continue;
+        }
+        let _ = fs::remove_dir_all(dir);
+    }
+}
+
+// Newest checkpoint that is complete and holds a valid snapshot of every given market
+pub fn latest_checkpoint(base: &Path, symbols: &[Symbol]) -> Option<Checkpoint> {
+    for id in checkpoint_ids(base) {
+        match load_checkpoint(&checkpoint_dir(base, id), id, symbols) {
+            Ok(checkpoint) => {
+                return Some(checkpoint);
+            }
+            Err(err) => eprintln!("Skipping snapshot {}: {}", id, err),
+        }
+    }
+    None
+}
+pub fn load_checkpoint(dir: &Path, id: u64, symbols: &[Symbol]) -> io::Result<Checkpoint> {
+    let complete: u64 = read_snapshot(&dir.join(COMPLETE))?;
+    if complete != id {
+        return Err(io::Error::new(ErrorKind::InvalidData, "Snapshot was not completed"));
+    }
+    let users = read_snapshot(&dir.join(USERS_SNAPSHOT))?;
+    let mut orderbooks = HashMap::new();
+    for symbol in symbols {
+        let snapshot = read_snapshot(&dir.join(format!("{}.snapshot", symbol)))?;
+        orderbooks.insert(symbol.clone(), snapshot);
+    }
+    Ok(Checkpoint { id, users, orderbooks })
+}
+
+// crc32 of the payload (u32 LE) followed by the json payload, replaced atomically
+pub fn write_snapshot<T: Serialize>(path: &Path, value: &T) -> io::Result<()> {
+    let payload = serde_json::to_vec(value)?;
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(&crc32fast::hash(&payload).to_le_bytes())?;
+    file.write_all(&payload)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+pub fn read_snapshot<T: DeserializeOwned>(path: &Path) -> io::Result<T> {
+    let bytes = fs::read(path)?;
+    if bytes.len() < 4 {
+        return Err(io::Error::new(ErrorKind::InvalidData, "Snapshot is truncated"));
+    }
+    let (crc, payload) = bytes.split_at(4);
+    if crc32fast::hash(payload) != u32::from_le_bytes(crc.try_into().unwrap()) {
+        return Err(
+            io::Error::new(ErrorKind::InvalidData, format!("{} is corrupted", path.display()))
+        );
+    }
+    Ok(serde_json::from_slice(payload)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use crate::matching_engine::{
+        orderbook::Order,
+        Asset,
+        Exchange,
+        OrderSide,
+        OrderType,
+        User,
+    };
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("velocity-snapshot-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+    fn write_checkpoint(base: &Path, id: u64, symbols: &[Symbol], complete: bool) {
+        let dir = checkpoint_dir(base, id);
+        fs::create_dir_all(&dir).unwrap();
+        let users = UsersSnapshot {
+            journal_seq: id,
+            users: Users::default(),
+        };
+        write_snapshot(&dir.join(USERS_SNAPSHOT), &users).unwrap();
+        for symbol in symbols {
+            let exchange = Exchange::from_symbol(symbol.clone()).unwrap();
+            let snapshot = OrderbookSnapshot {
+                journal_seq: id,
+                orderbook: Orderbook::new(exchange),
+            };
+            write_snapshot(&dir.join(format!("{}.snapshot", symbol)), &snapshot).unwrap();
+        }
+        if complete {
+            write_snapshot(&dir.join(COMPLETE), &id).unwrap();
+        }
+    }
+
+    #[test]
+    fn orderbook_and_users_round_trip() {
+        let dir = temp_dir();
+        let mut orderbook = Orderbook::new(Exchange::new(Asset::SOL, Asset::USDT));
+        orderbook.trade_id = 4;
+        orderbook.order_id = 9;
+        orderbook.add_limit_order(
+            dec!(101.5),
+            Order::new(9, 9, OrderSide::Ask, dec!(2.25), OrderType::Limit, 1)
+        );
+        let path = dir.join("SOL_USDT.snapshot");
+        write_snapshot(&path, &(OrderbookSnapshot { journal_seq: 12, orderbook })).unwrap();
+        let snapshot: OrderbookSnapshot = read_snapshot(&path).unwrap();
+        assert_eq!(snapshot.journal_seq, 12);
+        assert_eq!(snapshot.orderbook.trade_id, 4);
+        assert_eq!(snapshot.orderbook.order_id, 9);
+        let limit = snapshot.orderbook.asks.get(&dec!(101.5)).unwrap();
+        assert_eq!(limit.orders[0].quantity, dec!(2.25));
+
+        let mut users = Users::default();
+        users.new_user(1);
+        users.deposit(&Asset::USDT, dec!(10), 1).unwrap();
+        let path = dir.join(USERS_SNAPSHOT);
+        write_snapshot(&path, &(UsersSnapshot { journal_seq: 3, users })).unwrap();
+        let snapshot: UsersSnapshot = read_snapshot(&path).unwrap();
+        let user: &User = snapshot.users.users.get(&1).unwrap();
+        assert_eq!(user.balance.get(&Asset::USDT), Some(&dec!(10)));
+        fs::remove_dir_all(dir).unwrap();
+    }
+    #[test]
+    fn refuses_corrupted_snapshot() {
+        let dir = temp_dir();
+        let path = dir.join("value.snapshot");
+        write_snapshot(&path, &vec![1, 2, 3]).unwrap();
+        let mut bytes = fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        fs::write(&path, bytes).unwrap();
+        let err = read_snapshot::<Vec<u8>>(&path).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        fs::remove_dir_all(dir).unwrap();
+    }
+    #[test]
+    fn loads_newest_complete_checkpoint() {
+        let base = temp_dir();
+        let symbols = vec!["SOL_USDT".to_string(), "BTC_USDT".to_string()];
+        write_checkpoint(&base, 50, &symbols, true);
+        write_checkpoint(&base, 100, &symbols, true);
+        write_checkpoint(&base, 200, &symbols, true);
+        // missing a market
+        write_checkpoint(&base, 300, &symbols[..1], true);
+        // interrupted before it was marked complete
+        write_checkpoint(&base, 400, &symbols, false);
+
+        let checkpoint = latest_checkpoint(&base, &symbols).unwrap();
+        assert_eq!(checkpoint.id, 200);
+        assert_eq!(checkpoint.users.journal_seq, 200);
+        assert_eq!(checkpoint.orderbooks.len(), 2);
+
+        prune_checkpoints(&base, 400);
+        assert_eq!(checkpoint_ids(&base), vec![300, 200, 100]);
+        assert!(latest_checkpoint(&temp_dir().join("missing"), &symbols).is_none());
+        fs::remove_dir_all(base).unwrap();
+    }
+}
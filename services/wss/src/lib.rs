@@ -1,18 +1,19 @@
 #![allow(non_camel_case_types)]
-use std::sync::{ Arc, Mutex };
+use std::{ sync::{ Arc, Mutex }, thread, time::Duration };
 
+use common::{ events::{ self, Depth, OrderUpdate, Ticker, Trade }, listen_key };
 use enum_stringify::EnumStringify;
 use manager::UserManager;
 use once_cell::sync::Lazy;
 use redis::Connection;
 use rust_decimal::Decimal;
-use serde::{ Deserialize, Serialize };
-use serde_json::from_str;
+use serde::Deserialize;
 use strum::IntoEnumIterator;
 use strum_macros::EnumIter;
 use tokio::{ net::TcpStream, runtime::{ Builder, Runtime } };
 use tokio_tungstenite::{ accept_async, WebSocketStream };
 pub mod manager;
+pub use common::OrderStatus;
 pub async fn handshake(
     raw_stream: TcpStream
 ) -> Result<WebSocketStream<TcpStream>, tokio_tungstenite::tungstenite::Error> {
@@ -25,7 +26,8 @@ pub async fn handshake(
 
 #[derive(Deserialize)]
 pub struct Payload {
-    pub user_id: Option<u64>,
+    // Issued by the backend, required to subscribe to ORDER_UPDATE
+    pub listen_key: Option<String>,
     pub method: Method,
     pub event: Event,
     pub symbol: RegisteredSymbols,
@@ -47,6 +49,7 @@ pub enum RegisteredSymbols {
     SOL_USDT,
     BTC_USDT,
     ETH_USDT,
+    BTC_USDT_PERP,
 }
 impl RegisteredSymbols {
     pub fn from_str(asset_to_match: &str) -> Result<Self, ()> {
@@ -65,24 +68,6 @@ pub type OrderId = i64;
 pub type Quantity = Decimal;
 pub type Price = Decimal;
 
-#[derive(Debug, Clone, Deserialize, EnumIter, EnumStringify)]
-pub enum OrderStatus {
-    InProgress,
-    Filled,
-    PartiallyFilled,
-    Cancelled
-}
-impl OrderStatus {
-    pub fn from_str(asset_to_match: &str) -> Result<Self, ()> {
-        for asset in OrderStatus::iter() {
-            let current_asset = asset.to_string();
-            if asset_to_match.to_string() == current_asset {
-                return Ok(asset);
-            }
-        }
-        Err(())
-    }
-}
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, EnumIter, Deserialize, EnumStringify)]
 pub enum Asset {
     USDT,
@@ -144,7 +129,7 @@ pub fn handle_brodcasting_trades(
         let mut pub_sub = con.as_pubsub();
         for symbol in RegisteredSymbols::iter() {
             let symbol = symbol.to_string();
-            if let Err(err) = pub_sub.subscribe(format!("trades:{}", symbol)) {
+            if let Err(err) = pub_sub.subscribe(events::topic::<Trade>(&symbol)) {
                 println!("Could not subscribe to trade pubsub for symbol :{}, {}", symbol, err);
             }
         }
@@ -152,7 +137,7 @@ pub fn handle_brodcasting_trades(
             if let Ok(msg) = pub_sub.get_message() {
                 if let Ok(trade) = msg.get_payload::<String>() {
                     let mut manager = manager.lock().unwrap();
-                    let symbol_str = msg.get_channel_name().split(":").last().unwrap();
+                    let symbol_str = events::topic_key(msg.get_channel_name()).unwrap();
                     let symbol = RegisteredSymbols::from_str(symbol_str).unwrap();
                     TOKIO_RUNTIME.block_on(manager.brodcast_trade(symbol, trade));
                 }
@@ -168,7 +153,7 @@ pub fn handle_brodcasting_ticker(
         let mut pub_sub = con.as_pubsub();
         for symbol in RegisteredSymbols::iter() {
             let symbol = symbol.to_string();
-            if let Err(err) = pub_sub.subscribe(format!("ticker:{}", symbol)) {
+            if let Err(err) = pub_sub.subscribe(events::topic::<Ticker>(&symbol)) {
                 println!("Could not subscribe to ticker pubsub symbol :{}, {}", symbol, err);
             }
         }
@@ -176,7 +161,7 @@ pub fn handle_brodcasting_ticker(
             if let Ok(msg) = pub_sub.get_message() {
                 if let Ok(ticker) = msg.get_payload::<String>() {
                     let mut manager = manager.lock().unwrap();
-                    let symbol_str = msg.get_channel_name().split(":").last().unwrap();
+                    let symbol_str = events::topic_key(msg.get_channel_name()).unwrap();
                     let symbol = RegisteredSymbols::from_str(symbol_str).unwrap();
                     TOKIO_RUNTIME.block_on(manager.brodcast_ticker(symbol, ticker));
                 }
@@ -192,7 +177,7 @@ pub fn handle_brodcasting_depth(
         let mut pub_sub = con.as_pubsub();
         for symbol in RegisteredSymbols::iter() {
             let symbol = symbol.to_string();
-            if let Err(err) = pub_sub.subscribe(format!("depth:{}", symbol)) {
+            if let Err(err) = pub_sub.subscribe(events::topic::<Depth>(&symbol)) {
                 println!("Could not subscribe to  depth pubsub symbol :{}, {}", symbol, err);
             }
         }
@@ -200,7 +185,7 @@ pub fn handle_brodcasting_depth(
             if let Ok(msg) = pub_sub.get_message() {
                 if let Ok(depth) = msg.get_payload::<String>() {
                     let mut manager = manager.lock().unwrap();
-                    let symbol_str = msg.get_channel_name().split(":").last().unwrap();
+                    let symbol_str = events::topic_key(msg.get_channel_name()).unwrap();
                     let symbol = RegisteredSymbols::from_str(symbol_str).unwrap();
                     TOKIO_RUNTIME.block_on(manager.brodcast_depth(symbol, depth));
                 }
@@ -216,14 +201,14 @@ pub fn handle_order_update_stream(
         let mut pub_sub = con.as_pubsub();
         for symbol in RegisteredSymbols::iter() {
             let symbol = symbol.to_string();
-            if let Err(err) = pub_sub.subscribe(format!("order_update:{}", symbol)) {
+            if let Err(err) = pub_sub.subscribe(events::topic::<OrderUpdate>(&symbol)) {
                 println!("Could not subscribe to  order_update pubsub symbol :{}, {}", symbol, err);
             }
         }
         loop {
             if let Ok(msg) = pub_sub.get_message() {
                 if let Ok(order_update_string) = msg.get_payload::<String>() {
-                    if let Ok(order_update) = from_str::<OrderUpdate>(&order_update_string) {
+                    if let Ok(order_update) = events::decode::<OrderUpdate>(&order_update_string) {
                         TOKIO_RUNTIME.block_on(
                             manager.lock().unwrap().send_order_update(order_update.user_id, &order_update_string)
                         );
@@ -233,17 +218,104 @@ pub fn handle_order_update_stream(
         }
     }
 }
-#[derive(Debug, Serialize, Deserialize)]
-pub struct OrderUpdate {
-    order_id: i64,
-    client_order_id: i64,
-    trade_id: u64,
-    user_id: u64,
-    trade_timestamp: u128,
-    order_side: String,
-    order_status: String,
-    symbol: String,
-    price: Decimal,
-    executed_quantity: Decimal,
-    executed_quote_quantity: Decimal,
+
+// How often connections streaming private events have their listen key checked
+pub const LISTEN_KEY_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+pub fn resolve_listen_key(con: &mut Connection, listen_key: &str) -> Option<u64> {
+    redis
+        ::cmd("GET")
+        .arg(listen_key::redis_key(listen_key))
+        .query::<Option<u64>>(con)
+        .ok()
+        .flatten()
+}
+// The user a subscription's listen key proves, None for an unknown or expired key
+pub fn listen_key_user(
+    listen_key: String,
+    resolve: impl FnOnce(&str) -> Option<u64>
+) -> Option<(u64, String)> {
+    resolve(&listen_key).map(|user_id| (user_id, listen_key))
+}
+// The connections whose listen key no longer resolves to the user they stream for
+pub fn expired_listen_keys(
+    listen_keys: Vec<(String, u64, String)>,
+    mut resolve: impl FnMut(&str) -> Option<u64>
+) -> Vec<String> {
+    listen_keys
+        .into_iter()
+        .filter(|(_, user_id, listen_key)| resolve(listen_key) != Some(*user_id))
+        .map(|(user_addr, _, _)| user_addr)
+        .collect()
+}
+// Stops streaming to connections whose listen key expired or was deleted
+pub fn handle_listen_key_expiry(
+    manager: Arc<Mutex<UserManager>>,
+    mut con: Connection
+) -> impl FnMut() {
+    move || {
+        loop {
+            thread::sleep(LISTEN_KEY_CHECK_INTERVAL);
+            let listen_keys = manager.lock().unwrap().listen_keys();
+            let expired = expired_listen_keys(listen_keys, |listen_key| {
+                resolve_listen_key(&mut con, listen_key)
+            });
+            for user_addr in expired {
+                TOKIO_RUNTIME.block_on(manager.lock().unwrap().expire_listen_key(user_addr));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use common::listen_key::LISTEN_KEY_TTL;
+
+    use super::*;
+
+    // Listen keys as the backend stores them, with the second each one expires at
+    fn resolver<'a>(
+        keys: &'a HashMap<&str, (u64, u64)>,
+        now: u64
+    ) -> impl Fn(&str) -> Option<u64> + 'a {
+        move |listen_key| {
+            keys.get(listen_key)
+                .filter(|(_, expires_at)| *expires_at > now)
+                .map(|(user_id, _)| *user_id)
+        }
+    }
+
+    #[test]
+    fn a_valid_listen_key_streams_for_its_user() {
+        let keys = HashMap::from([("key-7", (7, LISTEN_KEY_TTL))]);
+        let subscribed = listen_key_user("key-7".to_string(), resolver(&keys, 0));
+        assert_eq!(subscribed, Some((7, "key-7".to_string())));
+
+        let streaming = vec![("addr".to_string(), 7, "key-7".to_string())];
+        assert!(expired_listen_keys(streaming, resolver(&keys, LISTEN_KEY_TTL - 1)).is_empty());
+    }
+
+    #[test]
+    fn an_unknown_listen_key_is_refused() {
+        let keys = HashMap::from([("key-7", (7, LISTEN_KEY_TTL))]);
+        assert_eq!(listen_key_user("key-8".to_string(), resolver(&keys, 0)), None);
+
+        // Nor does a key prove any user but its own
+        let streaming = vec![("addr".to_string(), 8, "key-7".to_string())];
+        assert_eq!(expired_listen_keys(streaming, resolver(&keys, 0)), vec!["addr".to_string()]);
+    }
+
+    #[test]
+    fn an_expired_listen_key_stops_streaming() {
+        let keys = HashMap::from([("key-7", (7, LISTEN_KEY_TTL))]);
+        let streaming = vec![
+            ("expired".to_string(), 7, "key-7".to_string()),
+            ("public".to_string(), 9, "key-9".to_string())
+        ];
+        let expired = expired_listen_keys(streaming, resolver(&keys, LISTEN_KEY_TTL));
+        assert_eq!(expired, vec!["expired".to_string(), "public".to_string()]);
+        assert_eq!(listen_key_user("key-7".to_string(), resolver(&keys, LISTEN_KEY_TTL)), None);
+    }
 }
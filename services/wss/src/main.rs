@@ -1,15 +1,19 @@
 use std::{ sync::{ Arc, Mutex }, thread };
 
 use futures_util::StreamExt;
+use redis::Connection;
 use tokio::net::{ TcpListener, TcpStream };
 use tokio_tungstenite::{ tungstenite::protocol::Message, WebSocketStream };
 use wss::{
     handle_brodcasting_depth,
     handle_brodcasting_ticker,
     handle_brodcasting_trades,
+    handle_listen_key_expiry,
     handle_order_update_stream,
     handshake,
+    listen_key_user,
     manager::UserManager,
+    resolve_listen_key,
     Event,
     Method,
     Payload,
@@ -21,6 +25,10 @@ fn main() {
     let ticker_con = client.get_connection().expect("Could not connect");
     let depth_con = client.get_connection().expect("Could not connect");
     let order_update_con = client.get_connection().expect("Could not connect");
+    let listen_key_expiry_con = client.get_connection().expect("Could not connect");
+    let listen_key_con = Arc::new(
+        Mutex::new(client.get_connection().expect("Could not connect"))
+    );
 
     let user_manager = Arc::new(Mutex::new(UserManager::new()));
 
@@ -28,19 +36,23 @@ fn main() {
     let ticker_user_manager = user_manager.clone();
     let depth_user_manager = user_manager.clone();
     let order_update_user_manager = user_manager.clone();
+    let listen_key_user_manager = user_manager.clone();
     thread::spawn(handle_brodcasting_trades(trade_user_manager, trade_con));
     thread::spawn(handle_brodcasting_ticker(ticker_user_manager, ticker_con));
     thread::spawn(handle_brodcasting_depth(depth_user_manager, depth_con));
     thread::spawn(handle_order_update_stream(order_update_user_manager, order_update_con));
+    thread::spawn(handle_listen_key_expiry(listen_key_user_manager, listen_key_expiry_con));
 
     let ws_server = async move {
         let listener = TcpListener::bind(&addr).await.expect("Failed to bind");
         println!("Listening on: {}", addr);
         while let Ok((stream, user_addr)) = listener.accept().await {
             let user_manager = user_manager.clone();
+            let listen_key_con = listen_key_con.clone();
             let new_ws_connection = async move {
                 if let Ok(ws_stream) = handshake(stream).await {
-                    handle_stream(ws_stream, user_manager, user_addr.to_string()).await;
+                    let user_addr = user_addr.to_string();
+                    handle_stream(ws_stream, user_manager, listen_key_con, user_addr).await;
                 }
             };
             tokio::spawn(new_ws_connection);
@@ -52,6 +64,7 @@ fn main() {
 async fn handle_stream(
     ws_stream: WebSocketStream<TcpStream>,
     user_manager: Arc<Mutex<UserManager>>,
+    listen_key_con: Arc<Mutex<Connection>>,
     user_addr: String
 ) {
     let (write, mut read) = ws_stream.split();
@@ -65,7 +78,8 @@ async fn handle_stream(
             match msg {
                 Message::Text(text) => {
                     if let Ok(payload) = serde_json::from_str::<Payload>(&text) {
-                        handle_payload(payload, user_addr.clone(), user_manager.clone());
+                        let user_manager = user_manager.clone();
+                        handle_payload(payload, user_addr.clone(), user_manager, &listen_key_con);
                     }
                 }
                 Message::Close(_) => {
@@ -80,7 +94,20 @@ async fn handle_stream(
     tokio::spawn(handle_client_incoming);
 }
 
-fn handle_payload(payload: Payload, user_addr: String, user_manager: Arc<Mutex<UserManager>>) {
+fn handle_payload(
+    payload: Payload,
+    user_addr: String,
+    user_manager: Arc<Mutex<UserManager>>,
+    listen_key_con: &Mutex<Connection>
+) {
+    // Resolved before taking the manager, so broadcasting does not wait on redis
+    let listen_key = match (&payload.event, &payload.method, payload.listen_key) {
+        (Event::ORDER_UPDATE, Method::SUBSCRIBE, Some(listen_key)) => {
+            let mut con = listen_key_con.lock().unwrap();
+            listen_key_user(listen_key, |listen_key| resolve_listen_key(&mut con, listen_key))
+        }
+        _ => None,
+    };
     let mut user_manager = user_manager.lock().unwrap();
     match payload.event {
         Event::TRADE => {
@@ -113,11 +140,15 @@ fn handle_payload(payload: Payload, user_addr: String, user_manager: Arc<Mutex<U
                 }
             }
         }
-        // This needs authentication layer
         Event::ORDER_UPDATE => {
             match payload.method {
                 Method::SUBSCRIBE => {
-                    user_manager.assign_user_id(user_addr, payload.user_id);
+                    match listen_key {
+                        Some((user_id, listen_key)) => {
+                            user_manager.assign_user_id(user_addr, user_id, listen_key);
+                        }
+                        None => println!("Invalid or expired listen key from: {}", user_addr),
+                    }
                 }
                 Method::UNSUBSCRIBE => {
                     user_manager.dissociate_user_id(user_addr);
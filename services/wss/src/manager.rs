@@ -10,6 +10,8 @@ pub struct UserManager {
 }
 pub struct UserInfo {
     pub user_id: Option<u64>,
+    // The listen key the connection proved its user with, checked until it expires
+    pub listen_key: Option<String>,
     pub transmitter: SplitSink<WebSocketStream<TcpStream>, Message>,
     pub trade_subscriptions: Vec<RegisteredSymbols>,
     pub ticker_subscriptions: Vec<RegisteredSymbols>,
@@ -30,34 +32,51 @@ impl UserManager {
         self.users.insert(user_addr, UserInfo {
             transmitter: tx,
             user_id: None,
+            listen_key: None,
             depth_subscriptions: Vec::new(),
             ticker_subscriptions: Vec::new(),
             trade_subscriptions: Vec::new(),
         });
     }
-    // Will be used to send order_update stream
-    pub fn assign_user_id(&mut self, user_addr: String, user_id: Option<u64>) {
+    // Only for a user_id resolved from a listen key, used to send order_update stream
+    pub fn assign_user_id(&mut self, user_addr: String, user_id: u64, listen_key: String) {
         if let Some(user_info) = self.users.get_mut(&user_addr) {
-            if let Some(user_id) = user_id {
-                user_info.user_id = Some(user_id);
-                println!("Assigned user_id for the ws connection")
-            }
+            user_info.user_id = Some(user_id);
+            user_info.listen_key = Some(listen_key);
+            println!("Assigned user_id for the ws connection")
         }
     }
     pub fn dissociate_user_id(&mut self, user_addr: String) {
         if let Some(user_info) = self.users.get_mut(&user_addr) {
             user_info.user_id = None;
+            user_info.listen_key = None;
+        }
+    }
+    // Connections streaming private events, with their user and listen key
+    pub fn listen_keys(&self) -> Vec<(String, u64, String)> {
+        self.users
+            .iter()
+            .filter_map(|(user_addr, user)| {
+                let user_id = user.user_id?;
+                let listen_key = user.listen_key.clone()?;
+                Some((user_addr.clone(), user_id, listen_key))
+            })
+            .collect()
+    }
+    pub async fn expire_listen_key(&mut self, user_addr: String) {
+        self.dissociate_user_id(user_addr.clone());
+        if let Some(user) = self.users.get_mut(&user_addr) {
+            let message = Message::text(r#"{"event":"LISTEN_KEY_EXPIRED"}"#);
+            if let Err(err) = user.transmitter.send(message).await {
+                eprintln!("Could not send listen key expiry, error occured: {}", err);
+            }
         }
     }
     pub async fn send_order_update(&mut self, user_id: u64, order_update: &str) {
-        let user = self.users.values_mut().find(|u| {
-            if let Some(uid) = u.user_id {
-                return uid == user_id;
+        for user in self.users.values_mut() {
+            if user.user_id == Some(user_id) {
+                let _ = user.transmitter.send(Message::text(order_update)).await;
             }
-            false
-        });
-        if let Some(user) = user {
-            let _ = user.transmitter.send(Message::text(order_update)).await;
         }
     }
     pub fn remove_user(&mut self, user_addr: String) {